@@ -0,0 +1,678 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister storing documents as plain files in a directory.
+//!
+//! ```rust
+//! # use automerge_persistent::PersistentBackend;
+//! # use automerge_persistent_fs::FsPersister;
+//! # use automerge_persistent_fs::FsPersisterError;
+//! # use automerge::Backend;
+//! # fn main() -> Result<(), FsPersisterError> {
+//! # let dir = tempfile::tempdir()?;
+//! let persister = FsPersister::new(dir.path().join("document"))?;
+//! let backend = PersistentBackend::<_, Backend>::load(persister);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Layout
+//!
+//! Each document gets its own directory containing:
+//!
+//! - `snapshot`: the compacted document along with the sync states, actor id, any changes not
+//!   covered by the document and any quarantined changes. It is only ever replaced as a whole by writing `snapshot.tmp` and
+//!   renaming it over the old one.
+//! - `log`: an append-only log of the change, quarantine and sync state updates since the
+//!   snapshot was written.
+//!
+//! Both files start with a magic number and a generation, which is bumped every time a new
+//! snapshot is written. A log with an older generation than the snapshot has already been folded
+//! into it and is discarded on load.
+//!
+//! Every record in these files is length prefixed and checksummed. A record at the end of the log
+//! that was only partially written, such as after a crash, is truncated on load; any other
+//! damaged record is reported as [`FsPersisterError::Corrupt`]. The length isn't covered by the
+//! checksum, so a record that claims to run past the end of the log is only taken as torn if no
+//! intact record after it ends exactly at the end of the log.
+
+mod record;
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use automerge_persistent::{ChangeIter, KeyedChangeIter, Persister, StoredSizes};
+use automerge_protocol::ActorId;
+
+use crate::record::{FrameError, Record, TooLarge};
+
+const LOG_MAGIC: [u8; 8] = *b"amp-log1";
+const SNAPSHOT_MAGIC: [u8; 8] = *b"amp-snp1";
+const HEADER_LEN: usize = 16;
+
+const LOG_FILE: &str = "log";
+const LOG_TMP_FILE: &str = "log.tmp";
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+
+/// The persister that stores changes and documents in files in a directory.
+///
+/// All of the stored data is also kept in memory so reads do not touch the disk.
+#[derive(Debug)]
+pub struct FsPersister {
+    dir: PathBuf,
+    log: File,
+    /// The length of the log up to the last complete append.
+    log_len: u64,
+    generation: u64,
+    state: State,
+    unflushed: usize,
+}
+
+/// The stored data, as of the end of the log.
+#[derive(Debug, Default)]
+struct State {
+    changes: HashMap<(ActorId, u64), Vec<u8>>,
+    quarantined: HashMap<(ActorId, u64), Vec<u8>>,
+    document: Option<Vec<u8>>,
+    sync_states: HashMap<Vec<u8>, Vec<u8>>,
+    actor_id: Option<ActorId>,
+    sizes: StoredSizes,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum FsPersisterError {
+    /// An IO error from reading or writing the files.
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+    /// A file contained data that could not be read back.
+    #[error("corrupt data in {path:?} at offset {offset}")]
+    Corrupt {
+        /// The file containing the corrupt data.
+        path: PathBuf,
+        /// The offset in the file of the first unreadable record.
+        offset: u64,
+    },
+    /// A value was too large to store, records are limited to 4GiB.
+    #[error("record larger than 4GiB")]
+    RecordTooLarge,
+}
+
+impl From<TooLarge> for FsPersisterError {
+    fn from(_: TooLarge) -> Self {
+        Self::RecordTooLarge
+    }
+}
+
+impl FsPersister {
+    /// Construct a new persister storing its files in `dir`, creating it if it does not exist.
+    ///
+    /// Any existing data in the directory is loaded, truncating a torn record at the end of the
+    /// log.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the directory cannot be created or if the existing files are corrupt.
+    pub fn new<P>(dir: P) -> Result<Self, FsPersisterError>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut state = State::default();
+        let mut generation = 0;
+        remove_if_exists(&dir.join(SNAPSHOT_TMP_FILE))?;
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        if let Some(snapshot) = read_if_exists(&snapshot_path)? {
+            generation = read_header(&snapshot_path, &snapshot, SNAPSHOT_MAGIC)?;
+            let end = state.replay(&snapshot_path, &snapshot)?;
+            if end != snapshot.len() {
+                return Err(corrupt(&snapshot_path, end));
+            }
+        }
+
+        let (log, log_len) = open_log(&dir, generation, &mut state)?;
+        Ok(Self {
+            dir,
+            log,
+            log_len,
+            generation,
+            state,
+            unflushed: 0,
+        })
+    }
+
+    /// Append the records to the log in a single write.
+    ///
+    /// If the write fails the log is truncated back so that a partial write can't be followed by
+    /// later appends.
+    fn append(&mut self, records: &[Record<'_>]) -> Result<(), FsPersisterError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        for record in records {
+            record.encode_into(&mut buf)?;
+        }
+        if let Err(e) = self.log.write_all(&buf) {
+            self.log.set_len(self.log_len)?;
+            return Err(e.into());
+        }
+        self.log_len += buf.len() as u64;
+        self.unflushed += buf.len();
+        Ok(())
+    }
+
+    /// Write a new snapshot from `document` and the current state minus the given changes and
+    /// sync states, then start a new log.
+    ///
+    /// Renaming the snapshot into place is the commit point, until then the old snapshot and log
+    /// remain valid.
+    fn write_snapshot(
+        &mut self,
+        document: Vec<u8>,
+        removed_changes: &HashSet<(ActorId, u64)>,
+        removed_sync_states: &HashSet<&[u8]>,
+    ) -> Result<(), FsPersisterError> {
+        let generation = self.generation + 1;
+
+        let actor_id = self.state.actor_id.as_ref().map(ActorId::to_bytes);
+        let changes = self
+            .state
+            .changes
+            .iter()
+            .filter(|(key, _)| !removed_changes.contains(key))
+            .map(|((a, s), c)| (a.to_bytes(), *s, c))
+            .collect::<Vec<_>>();
+
+        let mut snapshot = make_header(SNAPSHOT_MAGIC, generation).to_vec();
+        Record::SetDocument {
+            document: &document,
+        }
+        .encode_into(&mut snapshot)?;
+        if let Some(actor_id) = &actor_id {
+            Record::SetActorId { actor_id }.encode_into(&mut snapshot)?;
+        }
+        for (peer_id, sync_state) in &self.state.sync_states {
+            if !removed_sync_states.contains(peer_id.as_slice()) {
+                Record::SetSyncState {
+                    peer_id,
+                    sync_state,
+                }
+                .encode_into(&mut snapshot)?;
+            }
+        }
+        for (actor_id, seq, change) in &changes {
+            Record::InsertChange {
+                actor_id,
+                seq: *seq,
+                change,
+            }
+            .encode_into(&mut snapshot)?;
+        }
+        // quarantined changes are written as changes and then moved
+        for ((actor_id, seq), change) in &self.state.quarantined {
+            let actor_id = actor_id.to_bytes();
+            Record::InsertChange {
+                actor_id: &actor_id,
+                seq: *seq,
+                change,
+            }
+            .encode_into(&mut snapshot)?;
+            Record::QuarantineChange {
+                actor_id: &actor_id,
+                seq: *seq,
+            }
+            .encode_into(&mut snapshot)?;
+        }
+
+        let log_tmp_path = self.dir.join(LOG_TMP_FILE);
+        let snapshot_tmp_path = self.dir.join(SNAPSHOT_TMP_FILE);
+        write_file(&log_tmp_path, &make_header(LOG_MAGIC, generation))?;
+        let log = OpenOptions::new().append(true).open(&log_tmp_path)?;
+        write_file(&snapshot_tmp_path, &snapshot)?;
+        fs::rename(&snapshot_tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+
+        // committed, anything appended from here on belongs to the new log even if syncing the
+        // directory fails, loading moves the new log into place if it doesn't get renamed below
+        self.generation = generation;
+        self.log = log;
+        self.log_len = HEADER_LEN as u64;
+        self.unflushed = 0;
+        for (a, s) in removed_changes {
+            self.state.remove_change(a, *s);
+        }
+        for peer_id in removed_sync_states {
+            self.state.remove_sync_state(peer_id);
+        }
+        self.state.sizes.document = document.len();
+        self.state.document = Some(document);
+
+        // the snapshot has to be durable before the new log replaces the old one
+        sync_dir(&self.dir)?;
+        fs::rename(&log_tmp_path, self.dir.join(LOG_FILE))?;
+        sync_dir(&self.dir)?;
+        Ok(())
+    }
+}
+
+impl State {
+    /// Apply the records in `data` after the header, returning the offset of the torn record at
+    /// the end, if any.
+    fn replay(&mut self, path: &Path, data: &[u8]) -> Result<usize, FsPersisterError> {
+        let mut offset = HEADER_LEN;
+        while offset < data.len() {
+            match Record::decode(&data[offset..]) {
+                Ok((record, len)) => {
+                    self.apply(record);
+                    offset += len;
+                }
+                Err(FrameError::Truncated) if !record_follows(&data[offset..]) => break,
+                Err(FrameError::Checksum { len }) if offset + len == data.len() => break,
+                Err(
+                    FrameError::Truncated | FrameError::Checksum { .. } | FrameError::Malformed,
+                ) => return Err(corrupt(path, offset)),
+            }
+        }
+        Ok(offset)
+    }
+
+    fn apply(&mut self, record: Record<'_>) {
+        match record {
+            Record::InsertChange {
+                actor_id,
+                seq,
+                change,
+            } => self.insert_change(ActorId::from_bytes(actor_id), seq, change.to_vec()),
+            Record::RemoveChange { actor_id, seq } => {
+                self.remove_change(&ActorId::from_bytes(actor_id), seq);
+            }
+            Record::QuarantineChange { actor_id, seq } => {
+                self.quarantine_change(ActorId::from_bytes(actor_id), seq);
+            }
+            Record::RemoveQuarantinedChange { actor_id, seq } => {
+                self.quarantined
+                    .remove(&(ActorId::from_bytes(actor_id), seq));
+            }
+            Record::SetSyncState {
+                peer_id,
+                sync_state,
+            } => self.insert_sync_state(peer_id.to_vec(), sync_state.to_vec()),
+            Record::RemoveSyncState { peer_id } => self.remove_sync_state(peer_id),
+            Record::SetActorId { actor_id } => {
+                self.actor_id = Some(ActorId::from_bytes(actor_id));
+            }
+            Record::SetDocument { document } => {
+                self.sizes.document = document.len();
+                self.document = Some(document.to_vec());
+            }
+        }
+    }
+
+    fn insert_change(&mut self, actor_id: ActorId, seq: u64, change: Vec<u8>) {
+        self.sizes.changes += change.len();
+        if let Some(old) = self.changes.insert((actor_id, seq), change) {
+            self.sizes.changes -= old.len();
+        }
+    }
+
+    fn remove_change(&mut self, actor_id: &ActorId, seq: u64) {
+        if let Some(old) = self.changes.remove(&(actor_id.clone(), seq)) {
+            self.sizes.changes -= old.len();
+        }
+    }
+
+    fn quarantine_change(&mut self, actor_id: ActorId, seq: u64) {
+        let key = (actor_id, seq);
+        if let Some(change) = self.changes.remove(&key) {
+            self.sizes.changes -= change.len();
+            self.quarantined.insert(key, change);
+        }
+    }
+
+    fn insert_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) {
+        self.sizes.sync_states += sync_state.len();
+        if let Some(old) = self.sync_states.insert(peer_id, sync_state) {
+            self.sizes.sync_states -= old.len();
+        }
+    }
+
+    fn remove_sync_state(&mut self, peer_id: &[u8]) {
+        if let Some(old) = self.sync_states.remove(peer_id) {
+            self.sizes.sync_states -= old.len();
+        }
+    }
+}
+
+impl Persister for FsPersister {
+    type Error = FsPersisterError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.state.changes.values().cloned().collect())
+    }
+
+    /// Copy the changes out one at a time.
+    fn iter_changes(&self) -> Result<ChangeIter<'_, Self::Error>, Self::Error> {
+        Ok(Box::new(self.state.changes.values().cloned().map(Ok)))
+    }
+
+    fn get_changes_with_keys(&self) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        Ok(Some(
+            self.state
+                .changes
+                .iter()
+                .map(|((a, s), c)| (a.clone(), *s, c.clone()))
+                .collect(),
+        ))
+    }
+
+    /// Copy the changes out one at a time.
+    fn iter_changes_with_keys(
+        &self,
+    ) -> Result<Option<KeyedChangeIter<'_, Self::Error>>, Self::Error> {
+        Ok(Some(Box::new(
+            self.state
+                .changes
+                .iter()
+                .map(|((a, s), c)| Ok((a.clone(), *s, c.clone()))),
+        )))
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error> {
+        let actor_ids = changes
+            .iter()
+            .map(|(a, _, _)| a.to_bytes())
+            .collect::<Vec<_>>();
+        let records = changes
+            .iter()
+            .zip(&actor_ids)
+            .map(|((_, seq, change), actor_id)| Record::InsertChange {
+                actor_id,
+                seq: *seq,
+                change,
+            })
+            .collect::<Vec<_>>();
+        self.append(&records)?;
+        for (a, s, c) in changes {
+            self.state.insert_change(a.clone(), s, c.to_vec());
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let actor_ids = changes
+            .iter()
+            .map(|(a, _)| a.to_bytes())
+            .collect::<Vec<_>>();
+        let records = changes
+            .iter()
+            .zip(&actor_ids)
+            .map(|((_, seq), actor_id)| Record::RemoveChange {
+                actor_id,
+                seq: *seq,
+            })
+            .collect::<Vec<_>>();
+        self.append(&records)?;
+        for (a, s) in changes {
+            self.state.remove_change(a, s);
+        }
+        Ok(())
+    }
+
+    fn quarantine_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<bool, Self::Error> {
+        let actor_ids = changes
+            .iter()
+            .map(|(a, _)| a.to_bytes())
+            .collect::<Vec<_>>();
+        let records = changes
+            .iter()
+            .zip(&actor_ids)
+            .map(|((_, seq), actor_id)| Record::QuarantineChange {
+                actor_id,
+                seq: *seq,
+            })
+            .collect::<Vec<_>>();
+        self.append(&records)?;
+        for (a, s) in changes {
+            self.state.quarantine_change(a.clone(), s);
+        }
+        Ok(true)
+    }
+
+    fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        Ok(self
+            .state
+            .quarantined
+            .iter()
+            .map(|((a, s), c)| (a.clone(), *s, c.clone()))
+            .collect())
+    }
+
+    fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        let actor_ids = changes
+            .iter()
+            .map(|(a, _)| a.to_bytes())
+            .collect::<Vec<_>>();
+        let records = changes
+            .iter()
+            .zip(&actor_ids)
+            .map(|((_, seq), actor_id)| Record::RemoveQuarantinedChange {
+                actor_id,
+                seq: *seq,
+            })
+            .collect::<Vec<_>>();
+        self.append(&records)?;
+        for (a, s) in changes {
+            self.state.quarantined.remove(&(a.clone(), s));
+        }
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.state.document.clone())
+    }
+
+    /// Write a new snapshot with the document.
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.write_snapshot(data, &HashSet::new(), &HashSet::new())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.state.sync_states.get(peer_id).cloned())
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.append(&[Record::SetSyncState {
+            peer_id: &peer_id,
+            sync_state: &sync_state,
+        }])?;
+        self.state.insert_sync_state(peer_id, sync_state);
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let records = peer_ids
+            .iter()
+            .map(|peer_id| Record::RemoveSyncState { peer_id })
+            .collect::<Vec<_>>();
+        self.append(&records)?;
+        for peer_id in peer_ids {
+            self.state.remove_sync_state(peer_id);
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.state.sync_states.keys().cloned().collect())
+    }
+
+    fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        Ok(self.state.actor_id.clone())
+    }
+
+    fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        self.append(&[Record::SetActorId {
+            actor_id: &actor_id.to_bytes(),
+        }])?;
+        self.state.actor_id = Some(actor_id);
+        Ok(())
+    }
+
+    /// Write a new snapshot without the compacted changes and removed sync states, replacing the
+    /// log.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let removed_changes = changes
+            .into_iter()
+            .map(|(a, s)| (a.clone(), s))
+            .collect::<HashSet<_>>();
+        let removed_sync_states = old_peer_ids.iter().copied().collect::<HashSet<_>>();
+        self.write_snapshot(document, &removed_changes, &removed_sync_states)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.state.sizes.clone()
+    }
+
+    /// Sync the log to disk, snapshots are always synced when they are written.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.log.sync_data()?;
+        let flushed = self.unflushed;
+        self.unflushed = 0;
+        Ok(flushed)
+    }
+}
+
+/// Whether an intact record after the start of `data` ends exactly at its end, meaning the record
+/// at the start isn't just torn but has a damaged length and more were appended after it.
+///
+/// A torn record can hold bytes that look like a record, but not one that ends where the tear
+/// does. Only records whose length reaches the end are checksummed, keeping this linear.
+fn record_follows(data: &[u8]) -> bool {
+    (1..data.len()).any(|i| Record::reaches_end(&data[i..]) && Record::decode(&data[i..]).is_ok())
+}
+
+/// Open the log matching the current generation, replaying its records, and return it along with
+/// its length.
+fn open_log(
+    dir: &Path,
+    generation: u64,
+    state: &mut State,
+) -> Result<(File, u64), FsPersisterError> {
+    let log_path = dir.join(LOG_FILE);
+    let tmp_path = dir.join(LOG_TMP_FILE);
+
+    let mut log = read_if_exists(&log_path)?;
+    if let Some(data) = &log {
+        let log_generation = read_header(&log_path, data, LOG_MAGIC)?;
+        if log_generation > generation {
+            // the snapshot this log follows on from is missing
+            return Err(corrupt(&log_path, 0));
+        } else if log_generation < generation {
+            log = None;
+        }
+    }
+    if log.is_none() {
+        // a compaction may have committed the snapshot without getting to move the new log
+        // into place
+        if let Some(data) = read_if_exists(&tmp_path)? {
+            if read_header(&tmp_path, &data, LOG_MAGIC).ok() == Some(generation) {
+                fs::rename(&tmp_path, &log_path)?;
+                log = Some(data);
+            }
+        }
+    }
+    remove_if_exists(&tmp_path)?;
+
+    let data = if let Some(data) = log {
+        data
+    } else {
+        let header = make_header(LOG_MAGIC, generation);
+        write_file(&tmp_path, &header)?;
+        fs::rename(&tmp_path, &log_path)?;
+        sync_dir(dir)?;
+        header.to_vec()
+    };
+
+    let end = state.replay(&log_path, &data)?;
+    let file = OpenOptions::new().append(true).open(&log_path)?;
+    if end != data.len() {
+        file.set_len(end as u64)?;
+        file.sync_data()?;
+    }
+    Ok((file, end as u64))
+}
+
+fn make_header(magic: [u8; 8], generation: u64) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[..8].copy_from_slice(&magic);
+    header[8..].copy_from_slice(&generation.to_le_bytes());
+    header
+}
+
+/// Check the magic number of a file and return its generation.
+fn read_header(path: &Path, data: &[u8], magic: [u8; 8]) -> Result<u64, FsPersisterError> {
+    if data.len() < HEADER_LEN || data[..8] != magic {
+        return Err(corrupt(path, 0));
+    }
+    Ok(u64::from_le_bytes(data[8..HEADER_LEN].try_into().unwrap()))
+}
+
+fn corrupt(path: &Path, offset: usize) -> FsPersisterError {
+    FsPersisterError::Corrupt {
+        path: path.to_path_buf(),
+        offset: offset as u64,
+    }
+}
+
+fn read_if_exists(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Create the file at `path` with `data` and sync it to disk.
+fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Make renames within the directory durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened as files on other platforms so this is best effort there.
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+const fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,263 @@
+//! The on-disk encoding of the records stored in the log and snapshot files.
+//!
+//! Each record is framed as:
+//!
+//! ```text
+//! | payload length: u32 LE | crc32 of payload: u32 LE | payload |
+//! ```
+//!
+//! The payload starts with a tag byte identifying the record followed by its fields. Byte string
+//! fields are prefixed with their length as a `u32` and integers are little endian.
+
+use std::convert::TryInto;
+
+const FRAME_HEADER_LEN: usize = 8;
+
+const INSERT_CHANGE: u8 = 1;
+const REMOVE_CHANGE: u8 = 2;
+const SET_SYNC_STATE: u8 = 3;
+const REMOVE_SYNC_STATE: u8 = 4;
+const SET_ACTOR_ID: u8 = 5;
+const SET_DOCUMENT: u8 = 6;
+const QUARANTINE_CHANGE: u8 = 7;
+const REMOVE_QUARANTINED_CHANGE: u8 = 8;
+
+/// A single operation on the stored state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Record<'a> {
+    InsertChange {
+        actor_id: &'a [u8],
+        seq: u64,
+        change: &'a [u8],
+    },
+    RemoveChange {
+        actor_id: &'a [u8],
+        seq: u64,
+    },
+    /// Moves a change to the quarantined changes.
+    QuarantineChange {
+        actor_id: &'a [u8],
+        seq: u64,
+    },
+    RemoveQuarantinedChange {
+        actor_id: &'a [u8],
+        seq: u64,
+    },
+    SetSyncState {
+        peer_id: &'a [u8],
+        sync_state: &'a [u8],
+    },
+    RemoveSyncState {
+        peer_id: &'a [u8],
+    },
+    SetActorId {
+        actor_id: &'a [u8],
+    },
+    /// Only written to snapshots.
+    SetDocument {
+        document: &'a [u8],
+    },
+}
+
+/// A record was too large to be framed, as the lengths in a frame are a `u32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooLarge;
+
+/// Why a frame could not be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The buffer ends before the frame does.
+    Truncated,
+    /// The frame is complete but the payload does not match its checksum.
+    Checksum {
+        /// The length of the whole frame.
+        len: usize,
+    },
+    /// The payload matches its checksum but is not a valid record.
+    Malformed,
+}
+
+impl<'a> Record<'a> {
+    /// Append the framed record to `out`, leaving it as it was if the record is too large.
+    pub fn encode_into(&self, out: &mut Vec<u8>) -> Result<(), TooLarge> {
+        let start = out.len();
+        let result = self.encode_frame(out, start);
+        if result.is_err() {
+            out.truncate(start);
+        }
+        result
+    }
+
+    fn encode_frame(&self, out: &mut Vec<u8>, start: usize) -> Result<(), TooLarge> {
+        out.extend_from_slice(&[0; FRAME_HEADER_LEN]);
+        match *self {
+            Self::InsertChange {
+                actor_id,
+                seq,
+                change,
+            } => {
+                out.push(INSERT_CHANGE);
+                put_bytes(out, actor_id)?;
+                out.extend_from_slice(&seq.to_le_bytes());
+                put_bytes(out, change)?;
+            }
+            Self::RemoveChange { actor_id, seq } => {
+                out.push(REMOVE_CHANGE);
+                put_bytes(out, actor_id)?;
+                out.extend_from_slice(&seq.to_le_bytes());
+            }
+            Self::QuarantineChange { actor_id, seq } => {
+                out.push(QUARANTINE_CHANGE);
+                put_bytes(out, actor_id)?;
+                out.extend_from_slice(&seq.to_le_bytes());
+            }
+            Self::RemoveQuarantinedChange { actor_id, seq } => {
+                out.push(REMOVE_QUARANTINED_CHANGE);
+                put_bytes(out, actor_id)?;
+                out.extend_from_slice(&seq.to_le_bytes());
+            }
+            Self::SetSyncState {
+                peer_id,
+                sync_state,
+            } => {
+                out.push(SET_SYNC_STATE);
+                put_bytes(out, peer_id)?;
+                put_bytes(out, sync_state)?;
+            }
+            Self::RemoveSyncState { peer_id } => {
+                out.push(REMOVE_SYNC_STATE);
+                put_bytes(out, peer_id)?;
+            }
+            Self::SetActorId { actor_id } => {
+                out.push(SET_ACTOR_ID);
+                put_bytes(out, actor_id)?;
+            }
+            Self::SetDocument { document } => {
+                out.push(SET_DOCUMENT);
+                put_bytes(out, document)?;
+            }
+        }
+        let payload = &out[start + FRAME_HEADER_LEN..];
+        let len = len_u32(payload.len())?;
+        let crc = crc32fast::hash(payload);
+        out[start..start + 4].copy_from_slice(&len.to_le_bytes());
+        out[start + 4..start + FRAME_HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+        Ok(())
+    }
+
+    /// Whether the length in the frame header at the start of `buf` says that the frame ends
+    /// exactly at the end of `buf`, without checking the payload.
+    pub fn reaches_end(buf: &[u8]) -> bool {
+        buf.len() >= FRAME_HEADER_LEN
+            && u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize
+                == buf.len() - FRAME_HEADER_LEN
+    }
+
+    /// Decode the frame at the start of `buf`, returning the record and the length of the frame.
+    pub fn decode(buf: &'a [u8]) -> Result<(Self, usize), FrameError> {
+        if buf.len() < FRAME_HEADER_LEN {
+            return Err(FrameError::Truncated);
+        }
+        let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(buf[4..FRAME_HEADER_LEN].try_into().unwrap());
+        let end = FRAME_HEADER_LEN
+            .checked_add(len)
+            .ok_or(FrameError::Truncated)?;
+        if buf.len() < end {
+            return Err(FrameError::Truncated);
+        }
+        let payload = &buf[FRAME_HEADER_LEN..end];
+        if crc32fast::hash(payload) != crc {
+            return Err(FrameError::Checksum { len: end });
+        }
+        let record = Reader { buf: payload }
+            .record()
+            .ok_or(FrameError::Malformed)?;
+        Ok((record, end))
+    }
+}
+
+/// Records are limited to 4GiB.
+fn len_u32(len: usize) -> Result<u32, TooLarge> {
+    len.try_into().map_err(|_| TooLarge)
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) -> Result<(), TooLarge> {
+    out.extend_from_slice(&len_u32(bytes.len())?.to_le_bytes());
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// Reads the fields of a payload, returning `None` if it is too short.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self
+            .take(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))?;
+        self.take(len as usize)
+    }
+
+    fn record(mut self) -> Option<Record<'a>> {
+        let record = match self.u8()? {
+            INSERT_CHANGE => Record::InsertChange {
+                actor_id: self.bytes()?,
+                seq: self.u64()?,
+                change: self.bytes()?,
+            },
+            REMOVE_CHANGE => Record::RemoveChange {
+                actor_id: self.bytes()?,
+                seq: self.u64()?,
+            },
+            QUARANTINE_CHANGE => Record::QuarantineChange {
+                actor_id: self.bytes()?,
+                seq: self.u64()?,
+            },
+            REMOVE_QUARANTINED_CHANGE => Record::RemoveQuarantinedChange {
+                actor_id: self.bytes()?,
+                seq: self.u64()?,
+            },
+            SET_SYNC_STATE => Record::SetSyncState {
+                peer_id: self.bytes()?,
+                sync_state: self.bytes()?,
+            },
+            REMOVE_SYNC_STATE => Record::RemoveSyncState {
+                peer_id: self.bytes()?,
+            },
+            SET_ACTOR_ID => Record::SetActorId {
+                actor_id: self.bytes()?,
+            },
+            SET_DOCUMENT => Record::SetDocument {
+                document: self.bytes()?,
+            },
+            _ => return None,
+        };
+        // trailing bytes mean we don't understand this record
+        if self.buf.is_empty() {
+            Some(record)
+        } else {
+            None
+        }
+    }
+}
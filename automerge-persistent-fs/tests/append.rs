@@ -0,0 +1,55 @@
+//! Kept in its own test binary as the file size limit applies to the whole process.
+#![cfg(unix)]
+
+use std::{fs, io};
+
+use automerge_persistent::Persister;
+use automerge_persistent_fs::{FsPersister, FsPersisterError};
+use automerge_protocol::ActorId;
+
+fn set_file_size_limit(limit: libc::rlim_t) {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        assert_eq!(libc::getrlimit(libc::RLIMIT_FSIZE, &mut rlimit), 0);
+        rlimit.rlim_cur = limit.min(rlimit.rlim_max);
+        assert_eq!(libc::setrlimit(libc::RLIMIT_FSIZE, &rlimit), 0);
+    }
+}
+
+#[test]
+fn failed_append_is_truncated() {
+    let dir = tempfile::tempdir().unwrap();
+    let log_path = dir.path().join("log");
+    let actor_id = ActorId::random();
+    let mut persister = FsPersister::new(dir.path()).unwrap();
+    persister
+        .insert_changes(vec![(&actor_id, 1, &[1; 32][..])])
+        .unwrap();
+    let len = fs::metadata(&log_path).unwrap().len();
+
+    // writes past the limit fail with EFBIG rather than raising SIGXFSZ
+    unsafe {
+        libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+    }
+    set_file_size_limit(len + 100);
+    let result = persister.insert_changes(vec![(&actor_id, 2, &[2; 1000][..])]);
+    set_file_size_limit(libc::RLIM_INFINITY);
+    match result {
+        Err(FsPersisterError::IoError(e)) => assert_ne!(e.kind(), io::ErrorKind::NotFound),
+        other => panic!("expected the append to fail, got {:?}", other),
+    }
+    assert_eq!(fs::metadata(&log_path).unwrap().len(), len);
+    assert_eq!(persister.get_changes().unwrap(), vec![vec![1; 32]]);
+
+    persister
+        .insert_changes(vec![(&actor_id, 3, &[3; 32][..])])
+        .unwrap();
+    drop(persister);
+    let persister = FsPersister::new(dir.path()).unwrap();
+    let mut changes = persister.get_changes().unwrap();
+    changes.sort();
+    assert_eq!(changes, vec![vec![1; 32], vec![3; 32]]);
+}
@@ -0,0 +1,25 @@
+use automerge_persistent_fs::FsPersister;
+use automerge_persistent_test_suite::{persister_tests, PersisterFixture};
+use tempfile::TempDir;
+
+struct FsFixture {
+    dir: TempDir,
+}
+
+impl FsFixture {
+    fn new() -> Self {
+        Self {
+            dir: tempfile::tempdir().unwrap(),
+        }
+    }
+}
+
+impl PersisterFixture for FsFixture {
+    type Persister = FsPersister;
+
+    fn open(&mut self, name: &str) -> Self::Persister {
+        FsPersister::new(self.dir.path().join(name)).unwrap()
+    }
+}
+
+persister_tests!(FsFixture::new());
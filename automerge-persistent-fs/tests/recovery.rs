@@ -0,0 +1,94 @@
+use std::fs;
+
+use automerge_persistent::Persister;
+use automerge_persistent_fs::{FsPersister, FsPersisterError};
+use automerge_protocol::ActorId;
+
+/// The length of the magic number and generation at the start of the log.
+const HEADER_LEN: usize = 16;
+
+fn insert(persister: &mut FsPersister, actor_id: &ActorId, seq: u64) {
+    persister
+        .insert_changes(vec![(actor_id, seq, &[seq as u8; 32][..])])
+        .unwrap();
+}
+
+fn sorted_changes(persister: &FsPersister) -> Vec<Vec<u8>> {
+    let mut changes = persister.get_changes().unwrap();
+    changes.sort();
+    changes
+}
+
+#[test]
+fn torn_record_at_end_of_log_is_truncated() {
+    let dir = tempfile::tempdir().unwrap();
+    let actor_id = ActorId::random();
+    let mut persister = FsPersister::new(dir.path()).unwrap();
+    insert(&mut persister, &actor_id, 1);
+    insert(&mut persister, &actor_id, 2);
+    drop(persister);
+
+    let log_path = dir.path().join("log");
+    let log = fs::read(&log_path).unwrap();
+    fs::write(&log_path, &log[..log.len() - 5]).unwrap();
+
+    let mut persister = FsPersister::new(dir.path()).unwrap();
+    assert_eq!(sorted_changes(&persister), vec![vec![1; 32]]);
+    insert(&mut persister, &actor_id, 3);
+    drop(persister);
+
+    let persister = FsPersister::new(dir.path()).unwrap();
+    assert_eq!(sorted_changes(&persister), vec![vec![1; 32], vec![3; 32]]);
+}
+
+#[test]
+fn torn_record_holding_a_record_is_truncated() {
+    // a change holding the bytes of a whole record, as appended to another log
+    let other = tempfile::tempdir().unwrap();
+    let actor_id = ActorId::random();
+    insert(&mut FsPersister::new(other.path()).unwrap(), &actor_id, 1);
+    let mut change = fs::read(other.path().join("log")).unwrap()[HEADER_LEN..].to_vec();
+    change.extend(&[0; 64]);
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut persister = FsPersister::new(dir.path()).unwrap();
+    insert(&mut persister, &actor_id, 1);
+    persister
+        .insert_changes(vec![(&actor_id, 2, &change[..])])
+        .unwrap();
+    drop(persister);
+
+    // tear the last record after the record it holds
+    let log_path = dir.path().join("log");
+    let log = fs::read(&log_path).unwrap();
+    fs::write(&log_path, &log[..log.len() - 32]).unwrap();
+
+    let persister = FsPersister::new(dir.path()).unwrap();
+    assert_eq!(sorted_changes(&persister), vec![vec![1; 32]]);
+}
+
+#[test]
+fn damaged_length_in_middle_of_log_is_corrupt() {
+    let dir = tempfile::tempdir().unwrap();
+    let actor_id = ActorId::random();
+    let mut persister = FsPersister::new(dir.path()).unwrap();
+    for seq in 1..=3 {
+        insert(&mut persister, &actor_id, seq);
+    }
+    drop(persister);
+
+    // make the first record claim to run past the end of the log
+    let log_path = dir.path().join("log");
+    let mut log = fs::read(&log_path).unwrap();
+    log[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&0x00ff_ffff_u32.to_le_bytes());
+    fs::write(&log_path, &log).unwrap();
+
+    match FsPersister::new(dir.path()) {
+        Err(FsPersisterError::Corrupt { path, offset }) => {
+            assert_eq!(path, log_path);
+            assert_eq!(offset, HEADER_LEN as u64);
+        }
+        other => panic!("expected corrupt log, got {:?}", other),
+    }
+    assert_eq!(fs::read(&log_path).unwrap(), log);
+}
@@ -0,0 +1,67 @@
+use automerge::{InvalidChangeRequest, LocalChange, Path, Primitive, Value};
+use automerge_persistent::{PersistentBackend, Persister};
+use automerge_persistent_localstorage::{LocalStoragePersister, MemoryStorage};
+use automerge_protocol::ActorId;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+fn persister() -> LocalStoragePersister<MemoryStorage> {
+    LocalStoragePersister::new(
+        MemoryStorage::default(),
+        "document".to_owned(),
+        "changes".to_owned(),
+        "sync-states".to_owned(),
+    )
+    .unwrap()
+}
+
+fn small_backend_apply_local_change(c: &mut Criterion) {
+    c.bench_function("small backend apply local change", |b| {
+        b.iter_batched(
+            || {
+                let backend: PersistentBackend<
+                    LocalStoragePersister<MemoryStorage>,
+                    automerge::Backend,
+                > = PersistentBackend::load(persister()).unwrap();
+                let mut frontend = automerge::Frontend::new();
+                let ((), change) = frontend
+                    .change::<_, _, InvalidChangeRequest>(None, |doc| {
+                        doc.add_change(LocalChange::set(
+                            Path::root().key("a"),
+                            Value::Primitive(Primitive::Str("abcdef".into())),
+                        ))
+                        .unwrap();
+                        Ok(())
+                    })
+                    .unwrap();
+
+                (backend, change.unwrap())
+            },
+            |(mut persistent_doc, change)| persistent_doc.apply_local_change(change),
+            criterion::BatchSize::SmallInput,
+        )
+    });
+}
+
+fn insert_change_with_many_stored(c: &mut Criterion) {
+    c.bench_function("insert change with many stored", |b| {
+        let actor = ActorId::from_bytes(&[1; 16]);
+        let change = [0; 100];
+        b.iter_batched(
+            || {
+                let mut p = persister();
+                p.insert_changes((1..=1000).map(|s| (&actor, s, &change[..])).collect())
+                    .unwrap();
+                p
+            },
+            |mut p| p.insert_changes(vec![(&actor, 1001, &change[..])]),
+            criterion::BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(50);
+    targets = small_backend_apply_local_change, insert_change_with_many_stored
+}
+criterion_main!(benches);
@@ -0,0 +1,95 @@
+use automerge::{AutomergeError, Change};
+use automerge_persistent::{
+    Backend, Error, PersistentAutomerge, PersistentAutomergeError, PersistentBackend,
+};
+use automerge_protocol::Patch;
+
+use crate::{LocalStoragePersister, LocalStoragePersisterError, Storage};
+
+/// Apply a `storage` event from another tab to a backend, returning the patch for its frontend if
+/// the event added changes.
+///
+/// The changes are already stored by the other tab so they are only applied in memory, and the
+/// persister only receives the event once they have been applied. See
+/// [`LocalStoragePersister::receive_storage_event`].
+///
+/// ```rust,no_run
+/// # use automerge_persistent::PersistentBackend;
+/// # use automerge_persistent_localstorage::{apply_storage_event_to_backend, LocalStoragePersister};
+/// # fn handle(
+/// #     backend: &mut PersistentBackend<LocalStoragePersister, automerge::Backend>,
+/// #     frontend: &mut automerge::Frontend,
+/// #     event: web_sys::StorageEvent,
+/// # ) {
+/// if let Some(patch) =
+///     apply_storage_event_to_backend(backend, event.key().as_deref(), event.new_value().as_deref())
+///         .unwrap()
+/// {
+///     frontend.apply_patch(patch).unwrap();
+/// }
+/// # }
+/// ```
+#[allow(clippy::type_complexity)]
+pub fn apply_storage_event_to_backend<S, B>(
+    backend: &mut PersistentBackend<LocalStoragePersister<S>, B>,
+    key: Option<&str>,
+    new_value: Option<&str>,
+) -> Result<Option<Patch>, Error<LocalStoragePersisterError<S::Error>, B::Error>>
+where
+    S: Storage + 'static,
+    B: Backend,
+{
+    let changes = backend
+        .persister()
+        .storage_event_changes(key, new_value)
+        .map_err(Error::PersisterError)?
+        .into_iter()
+        .map(|c| Change::from_bytes(c).map_err(|e| Error::AutomergeError(e.into())))
+        .collect::<Result<Vec<_>, _>>()?;
+    let patch = if changes.is_empty() {
+        None
+    } else {
+        Some(backend.apply_stored_changes(changes)?)
+    };
+    backend
+        .persister_mut()
+        .receive_storage_event(key, new_value)
+        .map_err(Error::PersisterError)?;
+    Ok(patch)
+}
+
+/// Apply a `storage` event from another tab to a document, returning whether the event added
+/// changes.
+///
+/// The changes are already stored by the other tab so they are only applied in memory, and the
+/// persister only receives the event once they have been applied. See
+/// [`LocalStoragePersister::receive_storage_event`].
+pub fn apply_storage_event_to_document<S>(
+    document: &mut PersistentAutomerge<LocalStoragePersister<S>>,
+    key: Option<&str>,
+    new_value: Option<&str>,
+) -> Result<bool, PersistentAutomergeError<LocalStoragePersisterError<S::Error>>>
+where
+    S: Storage + 'static,
+{
+    let changes = document
+        .persister()
+        .storage_event_changes(key, new_value)
+        .map_err(PersistentAutomergeError::PersisterError)?
+        .into_iter()
+        .map(|c| {
+            Change::from_bytes(c).map_err(|e| {
+                PersistentAutomergeError::AutomergeError(AutomergeError::BackendError(e.into()))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let added = !changes.is_empty();
+    if added {
+        document.apply_stored_changes(changes)?;
+    }
+    document
+        .persister_mut()
+        .receive_storage_event(key, new_value)
+        .map_err(PersistentAutomergeError::PersisterError)?;
+    Ok(added)
+}
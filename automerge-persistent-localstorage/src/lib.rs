@@ -22,196 +22,598 @@
 //! # Ok(())
 //! # }
 //! ```
+//!
+//! `sessionStorage` works the same way, through `web_sys::window().unwrap().session_storage()`.
+//! Outside of the browser a [`MemoryStorage`] can be used instead.
+
+mod events;
+mod storage;
 
 use std::collections::HashMap;
 
-use automerge_persistent::{Persister, StoredSizes};
+use automerge_persistent::{ChangeIter, KeyedChangeIter, Persister, StoredSizes};
 use automerge_protocol::ActorId;
+pub use events::{apply_storage_event_to_backend, apply_storage_event_to_document};
+use serde::{Deserialize, Serialize};
+pub use storage::{MemoryStorage, MemoryStorageError, Storage};
 
 /// Persist changes and documents in to `LocalStorage`.
 ///
-/// While aimed at `LocalStorage`, it accepts any storage that  conforms to the [`web_sys::Storage`]
-/// API.
+/// While aimed at `LocalStorage`, it accepts any [`Storage`], such as `sessionStorage` or a
+/// [`MemoryStorage`].
+///
+/// Each change is stored in its own item, under `changes_key` followed by a `/` and the actor id
+/// and sequence number, so persisting a change doesn't rewrite the others. Sync states are stored
+/// the same way under `sync_states_key` and the URL safe base64 of the peer id, and quarantined
+/// changes under `changes_key` with a `-quarantined` suffix. Values are base64 encoded.
 ///
-/// Since `LocalStorage` is limited we store changes in a JSON map in one key.
+/// Changes and sync states kept in the single JSON maps of earlier versions, including under the
+/// change keys that could collide, are moved to their own items when the persister is
+/// constructed.
+///
+/// Writes that would take the storage over its quota fail with
+/// [`LocalStoragePersisterError::QuotaExceeded`], leaving what was stored as it was. Compacting
+/// the document frees the space taken by its changes.
 #[derive(Debug)]
-pub struct LocalStoragePersister {
-    storage: web_sys::Storage,
+pub struct LocalStoragePersister<S = web_sys::Storage> {
+    storage: S,
+    /// Keyed by the names of the changes, without the changes key.
     changes: HashMap<String, Vec<u8>>,
-    /// Base64 encoded peer_ids are used for the keys so they can be serialized to json.
+    /// Keyed by the URL safe base64 encoded peer ids.
     sync_states: HashMap<String, Vec<u8>>,
     document_key: String,
     changes_key: String,
     sync_states_key: String,
+    actor_id_key: String,
+    quarantine_key: String,
     sizes: StoredSizes,
 }
 
+/// The value stored under the document key.
+///
+/// Compaction writes the document together with the keys of the changes and sync states it
+/// supersedes in this single value. If the removal of those entries is interrupted it is finished
+/// the next time the persister is constructed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoredDocument {
+    document: Vec<u8>,
+    compacted_changes: Vec<String>,
+    removed_sync_states: Vec<String>,
+}
+
+/// Documents were previously stored as just the bytes so we need to be able to read both forms.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredDocumentFormat {
+    Current(StoredDocument),
+    Legacy(Vec<u8>),
+}
+
+impl StoredDocument {
+    fn from_json(s: &str) -> Result<Self, serde_json::Error> {
+        Ok(match serde_json::from_str(s)? {
+            StoredDocumentFormat::Current(doc) => doc,
+            StoredDocumentFormat::Legacy(document) => Self {
+                document,
+                ..Self::default()
+            },
+        })
+    }
+}
+
 /// Possible errors from persisting.
 #[derive(Debug, thiserror::Error)]
-pub enum LocalStoragePersisterError {
+pub enum LocalStoragePersisterError<E = wasm_bindgen::JsValue> {
     /// Serde failure, converting the change/document into JSON.
     #[error(transparent)]
     SerdeError(#[from] serde_json::Error),
+    /// A stored value wasn't valid base64.
+    #[error(transparent)]
+    Base64Error(#[from] base64::DecodeError),
+    /// The storage is full.
+    #[error("storage quota exceeded {0:?}")]
+    QuotaExceeded(E),
     /// An underlying storage error.
     #[error("storage error {0:?}")]
-    StorageError(wasm_bindgen::JsValue),
+    StorageError(E),
+}
+
+/// Wrap an error from the storage, telling apart the storage being full.
+fn storage_error<S>(error: S::Error) -> LocalStoragePersisterError<S::Error>
+where
+    S: Storage,
+{
+    if S::is_quota_exceeded(&error) {
+        LocalStoragePersisterError::QuotaExceeded(error)
+    } else {
+        LocalStoragePersisterError::StorageError(error)
+    }
+}
+
+/// The name of the change or sync state with the item `key`, if it starts with `prefix`.
+///
+/// Names never contain a '/', those that do belong to the keys of another persister that
+/// `prefix` is the start of.
+fn item_name<'a>(key: &'a str, prefix: &str) -> Option<&'a str> {
+    key.strip_prefix(prefix).filter(|name| !name.contains('/'))
+}
+
+fn encode_peer_id(peer_id: &[u8]) -> String {
+    base64::encode_config(peer_id, base64::URL_SAFE)
 }
 
-impl LocalStoragePersister {
+impl<S> LocalStoragePersister<S>
+where
+    S: Storage,
+{
     /// Construct a new `LocalStoragePersister`.
+    ///
+    /// The actor id is stored under `document_key` with an `-actor-id` suffix.
     pub fn new(
-        storage: web_sys::Storage,
+        storage: S,
         document_key: String,
         changes_key: String,
         sync_states_key: String,
-    ) -> Result<Self, LocalStoragePersisterError> {
-        let changes = if let Some(stored) = storage
-            .get_item(&changes_key)
-            .map_err(LocalStoragePersisterError::StorageError)?
-        {
-            serde_json::from_str(&stored)?
-        } else {
-            HashMap::new()
-        };
-        let sync_states = if let Some(stored) = storage
-            .get_item(&sync_states_key)
-            .map_err(LocalStoragePersisterError::StorageError)?
-        {
-            serde_json::from_str(&stored)?
-        } else {
-            HashMap::new()
-        };
-        let document = if let Some(doc_string) = storage
+    ) -> Result<Self, LocalStoragePersisterError<S::Error>> {
+        let document = storage
             .get_item(&document_key)
-            .map_err(LocalStoragePersisterError::StorageError)?
-        {
-            let doc = serde_json::from_str::<Vec<u8>>(&doc_string)?;
-            Some(doc)
-        } else {
-            None
-        };
-        let sizes = StoredSizes {
-            changes: changes.values().map(Vec::len).sum(),
-            document: document.unwrap_or_default().len(),
-            sync_states: sync_states.values().map(Vec::len).sum(),
-        };
-        Ok(Self {
+            .map_err(storage_error::<S>)?
+            .map(|d| StoredDocument::from_json(&d))
+            .transpose()?;
+        let (document, compacted_changes, removed_sync_states) = document
+            .map(|d| (d.document, d.compacted_changes, d.removed_sync_states))
+            .unwrap_or_default();
+
+        let actor_id_key = format!("{}-actor-id", document_key);
+        let quarantine_key = format!("{}-quarantined", changes_key);
+        let mut s = Self {
             storage,
-            changes,
-            sync_states,
+            changes: HashMap::new(),
+            sync_states: HashMap::new(),
             document_key,
             changes_key,
             sync_states_key,
-            sizes,
-        })
+            actor_id_key,
+            quarantine_key,
+            sizes: StoredSizes::default(),
+        };
+        s.migrate_maps(&compacted_changes, &removed_sync_states)?;
+        s.load_items()?;
+
+        // finish off a compaction that was interrupted before it could remove everything
+        for name in &compacted_changes {
+            if s.changes.remove(name).is_some() {
+                s.remove_item(&s.change_item_key(name))?;
+            }
+        }
+        for peer_id in &removed_sync_states {
+            if s.sync_states.remove(peer_id).is_some() {
+                s.remove_item(&s.sync_state_item_key(peer_id))?;
+            }
+        }
+
+        s.sizes = StoredSizes {
+            changes: s.changes.values().map(Vec::len).sum(),
+            document: document.len(),
+            sync_states: s.sync_states.values().map(Vec::len).sum(),
+            uncompressed: None,
+        };
+        Ok(s)
+    }
+
+    /// Move the changes and sync states from the JSON maps that earlier versions kept them in to
+    /// their own items, skipping those an interrupted compaction superseded.
+    ///
+    /// The maps are only removed once everything has been moved, so an interrupted migration is
+    /// finished the next time the persister is constructed.
+    fn migrate_maps(
+        &self,
+        compacted_changes: &[String],
+        removed_sync_states: &[String],
+    ) -> Result<(), LocalStoragePersisterError<S::Error>> {
+        if let Some(stored) = self.get_item(&self.changes_key)? {
+            let mut changes: HashMap<String, Vec<u8>> = serde_json::from_str(&stored)?;
+            for name in compacted_changes {
+                changes.remove(name);
+            }
+            migrate_change_keys(&mut changes);
+            for (name, change) in &changes {
+                self.set_item(&self.change_item_key(name), &base64::encode(change))?;
+            }
+            self.remove_item(&self.changes_key)?;
+        }
+        if let Some(stored) = self.get_item(&self.sync_states_key)? {
+            // the maps were keyed by standard base64 encoded peer ids
+            let sync_states: HashMap<String, Vec<u8>> = serde_json::from_str(&stored)?;
+            for (peer_id, sync_state) in &sync_states {
+                if removed_sync_states.contains(peer_id) {
+                    continue;
+                }
+                let peer_id = encode_peer_id(&base64::decode(peer_id)?);
+                self.set_item(
+                    &self.sync_state_item_key(&peer_id),
+                    &base64::encode(sync_state),
+                )?;
+            }
+            self.remove_item(&self.sync_states_key)?;
+        }
+        Ok(())
+    }
+
+    /// Read the changes and sync states from their items.
+    fn load_items(&mut self) -> Result<(), LocalStoragePersisterError<S::Error>> {
+        let changes_prefix = self.change_item_key("");
+        let sync_states_prefix = self.sync_state_item_key("");
+        for key in self.storage.keys().map_err(storage_error::<S>)? {
+            let (map, name) = if let Some(name) = item_name(&key, &changes_prefix) {
+                (&mut self.changes, name)
+            } else if let Some(name) = item_name(&key, &sync_states_prefix) {
+                (&mut self.sync_states, name)
+            } else {
+                continue;
+            };
+            if let Some(value) = self.storage.get_item(&key).map_err(storage_error::<S>)? {
+                map.insert(name.to_owned(), base64::decode(value)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Update the persister from a `storage` event, fired when another tab writes to the storage,
+    /// returning any changes that the write added.
+    ///
+    /// `key` and `new_value` are those of the event, with no key meaning the storage was cleared.
+    /// Writes to keys the persister doesn't use are ignored.
+    ///
+    /// The returned changes still need applying to the backend or document, which
+    /// [`apply_storage_event_to_backend`] and [`apply_storage_event_to_document`] do, only
+    /// receiving the event once the changes from [`Self::storage_event_changes`] are applied.
+    pub fn receive_storage_event(
+        &mut self,
+        key: Option<&str>,
+        new_value: Option<&str>,
+    ) -> Result<Vec<Vec<u8>>, LocalStoragePersisterError<S::Error>> {
+        let key = if let Some(key) = key {
+            key
+        } else {
+            self.reload()?;
+            return Ok(Vec::new());
+        };
+        if let Some(name) = item_name(key, &self.change_item_key("")) {
+            let change = new_value.map(base64::decode).transpose()?;
+            let old = self.changes.remove(name);
+            if let Some(old) = &old {
+                self.sizes.changes -= old.len();
+            }
+            if let Some(change) = change {
+                self.sizes.changes += change.len();
+                self.changes.insert(name.to_owned(), change.clone());
+                if old.is_none() {
+                    return Ok(vec![change]);
+                }
+            }
+        } else if let Some(peer_id) = item_name(key, &self.sync_state_item_key("")) {
+            let sync_state = new_value.map(base64::decode).transpose()?;
+            if let Some(old) = self.sync_states.remove(peer_id) {
+                self.sizes.sync_states -= old.len();
+            }
+            if let Some(sync_state) = sync_state {
+                self.sizes.sync_states += sync_state.len();
+                self.sync_states.insert(peer_id.to_owned(), sync_state);
+            }
+        } else if key == self.document_key {
+            self.sizes.document = self.get_document()?.map_or(0, |d| d.len());
+        }
+        Ok(Vec::new())
+    }
+
+    /// The changes that a `storage` event would add, without updating the persister.
+    ///
+    /// See [`Self::receive_storage_event`].
+    pub fn storage_event_changes(
+        &self,
+        key: Option<&str>,
+        new_value: Option<&str>,
+    ) -> Result<Vec<Vec<u8>>, LocalStoragePersisterError<S::Error>> {
+        let name = key.and_then(|key| item_name(key, &self.change_item_key("")));
+        match (name, new_value) {
+            (Some(name), Some(value)) if !self.changes.contains_key(name) => {
+                Ok(vec![base64::decode(value)?])
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Read the changes and sync states from storage again, after it was cleared.
+    fn reload(&mut self) -> Result<(), LocalStoragePersisterError<S::Error>> {
+        self.changes.clear();
+        self.sync_states.clear();
+        self.load_items()?;
+        self.sizes = StoredSizes {
+            changes: self.changes.values().map(Vec::len).sum(),
+            document: self.get_document()?.map_or(0, |d| d.len()),
+            sync_states: self.sync_states.values().map(Vec::len).sum(),
+            uncompressed: None,
+        };
+        Ok(())
+    }
+
+    /// The key of the item for the change with the given name.
+    fn change_item_key(&self, name: &str) -> String {
+        format!("{}/{}", self.changes_key, name)
+    }
+
+    /// The key of the item for the quarantined change with the given name.
+    fn quarantine_item_key(&self, name: &str) -> String {
+        format!("{}/{}", self.quarantine_key, name)
+    }
+
+    /// The key of the item for the sync state with the given encoded peer id.
+    fn sync_state_item_key(&self, peer_id: &str) -> String {
+        format!("{}/{}", self.sync_states_key, peer_id)
+    }
+
+    fn get_item(&self, key: &str) -> Result<Option<String>, LocalStoragePersisterError<S::Error>> {
+        self.storage.get_item(key).map_err(storage_error::<S>)
+    }
+
+    fn set_item(&self, key: &str, value: &str) -> Result<(), LocalStoragePersisterError<S::Error>> {
+        self.storage
+            .set_item(key, value)
+            .map_err(storage_error::<S>)
+    }
+
+    fn remove_item(&self, key: &str) -> Result<(), LocalStoragePersisterError<S::Error>> {
+        self.storage.remove_item(key).map_err(storage_error::<S>)
+    }
+
+    /// Put the item for a change back to how it was before a failed write, as far as the storage
+    /// allows.
+    fn restore_change(&self, name: &str) {
+        let key = self.change_item_key(name);
+        // the write already failed, so there's nothing more to do if this does too
+        let _ = self.changes.get(name).map_or_else(
+            || self.storage.remove_item(&key),
+            |old| self.storage.set_item(&key, &base64::encode(old)),
+        );
+    }
+
+    fn store_document(
+        &self,
+        document: &StoredDocument,
+    ) -> Result<(), LocalStoragePersisterError<S::Error>> {
+        self.set_item(&self.document_key, &serde_json::to_string(document)?)
     }
 }
 
-impl Persister for LocalStoragePersister {
-    type Error = LocalStoragePersisterError;
+impl<S> Persister for LocalStoragePersister<S>
+where
+    S: Storage,
+{
+    type Error = LocalStoragePersisterError<S::Error>;
 
     fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
         Ok(self.changes.values().cloned().collect())
     }
 
-    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
-        for (a, s, c) in changes {
-            let key = make_key(&a, s);
+    /// Copy the changes out one at a time.
+    fn iter_changes(&self) -> Result<ChangeIter<'_, Self::Error>, Self::Error> {
+        Ok(Box::new(self.changes.values().cloned().map(Ok)))
+    }
+
+    /// Read the actor id and sequence number back out of the keys, unless some changes couldn't
+    /// be moved from the legacy keys.
+    fn get_changes_with_keys(&self) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        Ok(self
+            .changes
+            .iter()
+            .map(|(key, change)| {
+                let (actor_id, seq) = parse_key(key)?;
+                Some((actor_id, seq, change.clone()))
+            })
+            .collect())
+    }
 
+    /// Copy the changes out one at a time, unless some couldn't be moved from the legacy keys.
+    fn iter_changes_with_keys(
+        &self,
+    ) -> Result<Option<KeyedChangeIter<'_, Self::Error>>, Self::Error> {
+        if self.changes.keys().any(|key| parse_key(key).is_none()) {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(self.changes.iter().filter_map(
+            |(key, change)| {
+                let (actor_id, seq) = parse_key(key)?;
+                Some(Ok((actor_id, seq, change.clone())))
+            },
+        ))))
+    }
+
+    /// Write each change to its own item, removing the ones already written if one fails.
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error> {
+        let mut written: Vec<(String, &[u8])> = Vec::with_capacity(changes.len());
+        for (a, s, c) in changes {
+            let name = make_key(a, s);
+            if let Err(e) = self.set_item(&self.change_item_key(&name), &base64::encode(c)) {
+                for (name, _) in &written {
+                    self.restore_change(name);
+                }
+                return Err(e);
+            }
+            written.push((name, c));
+        }
+        for (name, c) in written {
             self.sizes.changes += c.len();
-            if let Some(old) = self.changes.insert(key, c) {
+            if let Some(old) = self.changes.insert(name, c.to_vec()) {
                 self.sizes.changes -= old.len();
             }
         }
-        self.storage
-            .set_item(&self.changes_key, &serde_json::to_string(&self.changes)?)
-            .map_err(LocalStoragePersisterError::StorageError)?;
         Ok(())
     }
 
     fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
-        let mut some_removal = false;
         for (a, s) in changes {
-            let key = make_key(a, s);
-            if let Some(old) = self.changes.remove(&key) {
+            let name = make_key(a, s);
+            if self.changes.contains_key(&name) {
+                self.remove_item(&self.change_item_key(&name))?;
+            }
+            if let Some(old) = self.changes.remove(&name) {
+                self.sizes.changes -= old.len();
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy each change to its quarantine item before removing its item.
+    fn quarantine_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<bool, Self::Error> {
+        for (a, s) in changes {
+            let name = make_key(a, s);
+            if let Some(change) = self.changes.get(&name) {
+                self.set_item(&self.quarantine_item_key(&name), &base64::encode(change))?;
+                self.remove_item(&self.change_item_key(&name))?;
+            }
+            if let Some(old) = self.changes.remove(&name) {
                 self.sizes.changes -= old.len();
-                some_removal = true;
             }
         }
+        Ok(true)
+    }
+
+    /// Read the quarantined changes from storage, they aren't kept in memory.
+    fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        let prefix = self.quarantine_item_key("");
+        let mut changes = Vec::new();
+        for key in self.storage.keys().map_err(storage_error::<S>)? {
+            let (actor_id, seq) = match item_name(&key, &prefix).and_then(parse_key) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            if let Some(value) = self.get_item(&key)? {
+                changes.push((actor_id, seq, base64::decode(value)?));
+            }
+        }
+        Ok(changes)
+    }
 
-        if some_removal {
-            let s = serde_json::to_string(&self.changes)?;
-            self.storage
-                .set_item(&self.changes_key, &s)
-                .map_err(LocalStoragePersisterError::StorageError)?;
+    fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        for (a, s) in changes {
+            self.remove_item(&self.quarantine_item_key(&make_key(a, s)))?;
         }
         Ok(())
     }
 
     fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
-        if let Some(doc_string) = self
-            .storage
-            .get_item(&self.document_key)
-            .map_err(LocalStoragePersisterError::StorageError)?
-        {
-            let doc = serde_json::from_str(&doc_string)?;
-            Ok(Some(doc))
+        if let Some(doc_string) = self.get_item(&self.document_key)? {
+            let doc = StoredDocument::from_json(&doc_string)?;
+            Ok(Some(doc.document))
         } else {
             Ok(None)
         }
     }
 
     fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
-        self.sizes.document = data.len();
-        let data = serde_json::to_string(&data)?;
-        self.storage
-            .set_item(&self.document_key, &data)
-            .map_err(LocalStoragePersisterError::StorageError)?;
+        let len = data.len();
+        self.store_document(&StoredDocument {
+            document: data,
+            ..StoredDocument::default()
+        })?;
+        self.sizes.document = len;
         Ok(())
     }
 
     fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
-        let peer_id = base64::encode(peer_id);
-        Ok(self.sync_states.get(&peer_id).cloned())
+        Ok(self.sync_states.get(&encode_peer_id(peer_id)).cloned())
     }
 
     fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let peer_id = encode_peer_id(&peer_id);
+        self.set_item(
+            &self.sync_state_item_key(&peer_id),
+            &base64::encode(&sync_state),
+        )?;
         self.sizes.sync_states += sync_state.len();
-        let peer_id = base64::encode(peer_id);
         if let Some(old) = self.sync_states.insert(peer_id, sync_state) {
             self.sizes.sync_states -= old.len();
         }
-        self.storage
-            .set_item(
-                &self.sync_states_key,
-                &serde_json::to_string(&self.sync_states)?,
-            )
-            .map_err(LocalStoragePersisterError::StorageError)?;
         Ok(())
     }
 
     fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
         for peer_id in peer_ids {
-            let peer_id = base64::encode(peer_id);
+            let peer_id = encode_peer_id(peer_id);
+            if self.sync_states.contains_key(&peer_id) {
+                self.remove_item(&self.sync_state_item_key(&peer_id))?;
+            }
             if let Some(old) = self.sync_states.remove(&peer_id) {
                 self.sizes.sync_states -= old.len();
             }
         }
-        self.storage
-            .set_item(
-                &self.sync_states_key,
-                &serde_json::to_string(&self.sync_states)?,
-            )
-            .map_err(LocalStoragePersisterError::StorageError)?;
         Ok(())
     }
 
     fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
-        Ok(self
-            .sync_states
+        self.sync_states
             .keys()
-            .map(|key| base64::decode(key).expect("Failed to base64 decode they peer_id"))
-            .collect())
+            .map(|key| Ok(base64::decode_config(key, base64::URL_SAFE)?))
+            .collect()
+    }
+
+    fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        if let Some(actor_id) = self.get_item(&self.actor_id_key)? {
+            let bytes: Vec<u8> = serde_json::from_str(&actor_id)?;
+            Ok(Some(ActorId::from_bytes(&bytes)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        self.set_item(
+            &self.actor_id_key,
+            &serde_json::to_string(&actor_id.to_bytes())?,
+        )
+    }
+
+    /// Write the document along with the keys it supersedes in a single write, then remove the
+    /// superseded changes and sync states.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let compacted_changes = changes
+            .into_iter()
+            .map(|(a, s)| make_key(a, s))
+            .collect::<Vec<_>>();
+        let removed_sync_states = old_peer_ids
+            .iter()
+            .map(|id| encode_peer_id(id))
+            .collect::<Vec<_>>();
+        let stored = StoredDocument {
+            document,
+            compacted_changes,
+            removed_sync_states,
+        };
+        self.store_document(&stored)?;
+        self.sizes.document = stored.document.len();
+
+        for name in &stored.compacted_changes {
+            if let Some(old) = self.changes.remove(name) {
+                self.remove_item(&self.change_item_key(name))?;
+                self.sizes.changes -= old.len();
+            }
+        }
+        for peer_id in &stored.removed_sync_states {
+            if let Some(old) = self.sync_states.remove(peer_id) {
+                self.remove_item(&self.sync_state_item_key(peer_id))?;
+                self.sizes.sync_states -= old.len();
+            }
+        }
+        Ok(())
     }
 
     fn sizes(&self) -> StoredSizes {
@@ -223,11 +625,40 @@ impl Persister for LocalStoragePersister {
     }
 }
 
+/// Separates the actor id from the sequence number in change keys, it never appears in hex.
+const KEY_SEPARATOR: char = '-';
+
 /// Make a key from the `actor_id` and `sequence_number`.
 ///
-/// Converts the `actor_id` to a string and appends the `sequence_number`.
+/// Converts the `actor_id` to a hex string and appends the `sequence_number` after a separator.
 fn make_key(actor_id: &ActorId, seq: u64) -> String {
-    let mut key = actor_id.to_hex_string();
-    key.push_str(&seq.to_string());
-    key
+    format!("{}{}{}", actor_id.to_hex_string(), KEY_SEPARATOR, seq)
+}
+
+/// Read the `actor_id` and `sequence_number` from a key made by [`make_key`].
+fn parse_key(key: &str) -> Option<(ActorId, u64)> {
+    let (actor_id, seq) = key.split_once(KEY_SEPARATOR)?;
+    Some((actor_id.parse().ok()?, seq.parse().ok()?))
+}
+
+/// Move changes stored under legacy keys, the actor id and sequence number without a separator,
+/// to the current keys.
+///
+/// Legacy keys can't be split reliably so the keys are read from the changes themselves. Changes
+/// that can't be decoded are left where they are.
+fn migrate_change_keys(changes: &mut HashMap<String, Vec<u8>>) {
+    let legacy_keys = changes
+        .keys()
+        .filter(|key| !key.contains(KEY_SEPARATOR))
+        .cloned()
+        .collect::<Vec<_>>();
+    for legacy_key in legacy_keys {
+        let change = match automerge::Change::from_bytes(changes[&legacy_key].clone()) {
+            Ok(change) => change,
+            Err(_) => continue,
+        };
+        if let Some(data) = changes.remove(&legacy_key) {
+            changes.insert(make_key(change.actor_id(), change.seq), data);
+        }
+    }
 }
@@ -0,0 +1,168 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use wasm_bindgen::JsCast;
+
+/// The key-value storage that a [`LocalStoragePersister`](crate::LocalStoragePersister) keeps its
+/// data in.
+///
+/// This follows the [Web Storage API](https://developer.mozilla.org/en-US/docs/Web/API/Storage),
+/// which is implemented for [`web_sys::Storage`] so both `localStorage` and `sessionStorage` can
+/// be used. [`MemoryStorage`] keeps the data in memory instead, for use outside of the browser.
+pub trait Storage {
+    /// The error type that the operations can produce.
+    type Error: std::fmt::Debug + 'static;
+
+    /// Get the value stored under `key`.
+    fn get_item(&self, key: &str) -> Result<Option<String>, Self::Error>;
+
+    /// Store `value` under `key`, replacing any existing value.
+    fn set_item(&self, key: &str, value: &str) -> Result<(), Self::Error>;
+
+    /// Remove the value stored under `key`, if there is one.
+    fn remove_item(&self, key: &str) -> Result<(), Self::Error>;
+
+    /// The keys of all of the stored items.
+    fn keys(&self) -> Result<Vec<String>, Self::Error>;
+
+    /// Whether the error is from the storage being full.
+    ///
+    /// Defaults to false.
+    fn is_quota_exceeded(_error: &Self::Error) -> bool {
+        false
+    }
+}
+
+impl Storage for web_sys::Storage {
+    type Error = wasm_bindgen::JsValue;
+
+    fn get_item(&self, key: &str) -> Result<Option<String>, Self::Error> {
+        Self::get_item(self, key)
+    }
+
+    fn set_item(&self, key: &str, value: &str) -> Result<(), Self::Error> {
+        Self::set_item(self, key, value)
+    }
+
+    fn remove_item(&self, key: &str) -> Result<(), Self::Error> {
+        Self::remove_item(self, key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Self::Error> {
+        (0..self.length()?)
+            .filter_map(|i| self.key(i).transpose())
+            .collect()
+    }
+
+    /// Browsers throw a `DOMException` named `QuotaExceededError`, or
+    /// `NS_ERROR_DOM_QUOTA_REACHED` in older versions of Firefox.
+    fn is_quota_exceeded(error: &Self::Error) -> bool {
+        error.dyn_ref::<web_sys::DomException>().map_or(false, |e| {
+            let name = e.name();
+            name == "QuotaExceededError" || name == "NS_ERROR_DOM_QUOTA_REACHED"
+        })
+    }
+}
+
+/// Errors from a [`MemoryStorage`].
+#[derive(Debug, thiserror::Error)]
+pub enum MemoryStorageError {
+    /// Storing the value would take the storage over its quota.
+    #[error("storing {needed} units would exceed the quota of {quota}")]
+    QuotaExceeded {
+        /// The size the storage would have been with the value stored.
+        needed: usize,
+        /// The quota of the storage.
+        quota: usize,
+    },
+}
+
+/// A [`Storage`] keeping items in memory.
+///
+/// Clones share the same items, so one can be kept to reopen persisters or inspect what they
+/// stored.
+///
+/// Like browsers, the size of the storage is counted in UTF-16 code units of the keys and values
+/// and an optional quota limits it.
+///
+/// ```rust
+/// # use automerge_persistent_localstorage::{
+/// #     LocalStoragePersister, LocalStoragePersisterError, MemoryStorage, MemoryStorageError,
+/// # };
+/// # fn main() -> Result<(), LocalStoragePersisterError<MemoryStorageError>> {
+/// let storage = MemoryStorage::default().with_quota(5 * 1024 * 1024);
+/// let persister = LocalStoragePersister::new(
+///     storage.clone(),
+///     "document".to_owned(),
+///     "changes".to_owned(),
+///     "sync-states".to_owned(),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage {
+    items: Rc<RefCell<HashMap<String, String>>>,
+    quota: Option<usize>,
+}
+
+impl MemoryStorage {
+    /// Limit the total size of the keys and values to `quota` UTF-16 code units.
+    #[must_use]
+    pub const fn with_quota(mut self, quota: usize) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// The total size of the keys and values in UTF-16 code units.
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.items
+            .borrow()
+            .iter()
+            .map(|(key, value)| item_size(key, value))
+            .sum()
+    }
+}
+
+fn item_size(key: &str, value: &str) -> usize {
+    key.encode_utf16().count() + value.encode_utf16().count()
+}
+
+impl Storage for MemoryStorage {
+    type Error = MemoryStorageError;
+
+    fn get_item(&self, key: &str) -> Result<Option<String>, Self::Error> {
+        Ok(self.items.borrow().get(key).cloned())
+    }
+
+    fn set_item(&self, key: &str, value: &str) -> Result<(), Self::Error> {
+        if let Some(quota) = self.quota {
+            let replaced = self
+                .items
+                .borrow()
+                .get(key)
+                .map_or(0, |old| item_size(key, old));
+            let needed = self.size() - replaced + item_size(key, value);
+            if needed > quota {
+                return Err(MemoryStorageError::QuotaExceeded { needed, quota });
+            }
+        }
+        self.items
+            .borrow_mut()
+            .insert(key.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn remove_item(&self, key: &str) -> Result<(), Self::Error> {
+        self.items.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Self::Error> {
+        Ok(self.items.borrow().keys().cloned().collect())
+    }
+
+    fn is_quota_exceeded(error: &Self::Error) -> bool {
+        matches!(error, MemoryStorageError::QuotaExceeded { .. })
+    }
+}
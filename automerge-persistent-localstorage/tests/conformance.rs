@@ -0,0 +1,24 @@
+use automerge_persistent_localstorage::{LocalStoragePersister, MemoryStorage};
+use automerge_persistent_test_suite::{persister_tests, PersisterFixture};
+
+/// All documents share the same storage, under their own keys.
+#[derive(Default)]
+struct MemoryStorageFixture {
+    storage: MemoryStorage,
+}
+
+impl PersisterFixture for MemoryStorageFixture {
+    type Persister = LocalStoragePersister<MemoryStorage>;
+
+    fn open(&mut self, name: &str) -> Self::Persister {
+        LocalStoragePersister::new(
+            self.storage.clone(),
+            format!("{}-document", name),
+            format!("{}-changes", name),
+            format!("{}-sync-states", name),
+        )
+        .unwrap()
+    }
+}
+
+persister_tests!(MemoryStorageFixture::default());
@@ -0,0 +1,160 @@
+use std::{cell::Cell, rc::Rc};
+
+use automerge::{Frontend, InvalidChangeRequest, LocalChange, Path, Primitive, Value};
+use automerge_persistent::{PersistentAutomerge, PersistentBackend, Persister};
+use automerge_persistent_localstorage::{
+    apply_storage_event_to_backend, apply_storage_event_to_document, LocalStoragePersister,
+    MemoryStorage, Storage,
+};
+use automerge_protocol::ActorId;
+
+/// Memory storage counting the writes made through it.
+#[derive(Debug, Clone, Default)]
+struct CountingStorage {
+    inner: MemoryStorage,
+    writes: Rc<Cell<usize>>,
+}
+
+impl Storage for CountingStorage {
+    type Error = <MemoryStorage as Storage>::Error;
+
+    fn get_item(&self, key: &str) -> Result<Option<String>, Self::Error> {
+        self.inner.get_item(key)
+    }
+
+    fn set_item(&self, key: &str, value: &str) -> Result<(), Self::Error> {
+        self.writes.set(self.writes.get() + 1);
+        self.inner.set_item(key, value)
+    }
+
+    fn remove_item(&self, key: &str) -> Result<(), Self::Error> {
+        self.writes.set(self.writes.get() + 1);
+        self.inner.remove_item(key)
+    }
+
+    fn keys(&self) -> Result<Vec<String>, Self::Error> {
+        self.inner.keys()
+    }
+}
+
+fn persister<S: Storage>(storage: S) -> LocalStoragePersister<S> {
+    LocalStoragePersister::new(
+        storage,
+        "document".to_owned(),
+        "changes".to_owned(),
+        "sync-states".to_owned(),
+    )
+    .unwrap()
+}
+
+fn set(doc: &mut dyn automerge::MutableDocument, key: &str) -> Result<(), InvalidChangeRequest> {
+    doc.add_change(LocalChange::set(
+        Path::root().key(key),
+        Value::Primitive(Primitive::Str(key.into())),
+    ))
+}
+
+/// Make a change as the actor in another tab, returning the key and value of the item it wrote.
+fn write_change(storage: &MemoryStorage, actor_id: &ActorId, key: &str) -> (String, String) {
+    let other_storage = MemoryStorage::default();
+    let mut other_tab =
+        PersistentBackend::<_, automerge::Backend>::load(persister(other_storage.clone())).unwrap();
+    let ((), change) = Frontend::new_with_actor_id(&actor_id.to_bytes())
+        .change(None, |doc| set(doc, key))
+        .unwrap();
+    other_tab.apply_local_change(change.unwrap()).unwrap();
+    let item_key = other_storage
+        .keys()
+        .unwrap()
+        .into_iter()
+        .find(|key| key.starts_with("changes/"))
+        .unwrap();
+    let value = other_storage.get_item(&item_key).unwrap().unwrap();
+    storage.set_item(&item_key, &value).unwrap();
+    (item_key, value)
+}
+
+#[test]
+fn backend_applies_changes_without_writing() {
+    let storage = CountingStorage::default();
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(persister(storage.clone())).unwrap();
+    let writes = storage.writes.get();
+
+    let (key, value) = write_change(&storage.inner, &ActorId::random(), "a");
+    let patch = apply_storage_event_to_backend(&mut backend, Some(&key), Some(&value)).unwrap();
+    assert!(patch.is_some());
+    assert_eq!(backend.get_changes(&[]).len(), 1);
+    assert_eq!(backend.persister().get_changes().unwrap().len(), 1);
+    // the other tab's item isn't written again
+    assert_eq!(storage.writes.get(), writes);
+
+    // the change is already known
+    assert!(
+        apply_storage_event_to_backend(&mut backend, Some(&key), Some(&value))
+            .unwrap()
+            .is_none()
+    );
+    assert_eq!(storage.writes.get(), writes);
+}
+
+#[test]
+fn backend_receives_event_only_once_applied() {
+    let storage = MemoryStorage::default();
+    let actor_id = ActorId::random();
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(persister(storage.clone())).unwrap();
+    let ((), change) = Frontend::new_with_actor_id(&actor_id.to_bytes())
+        .change(None, |doc| set(doc, "a"))
+        .unwrap();
+    backend.apply_local_change(change.unwrap()).unwrap();
+
+    // a different first change from the same actor can't be applied
+    let (key, value) = write_change(&MemoryStorage::default(), &actor_id, "b");
+    let conflicting = format!("{}-conflicting", key);
+    assert!(
+        apply_storage_event_to_backend(&mut backend, Some(&conflicting), Some(&value)).is_err()
+    );
+    assert_eq!(backend.persister().get_changes().unwrap().len(), 1);
+    assert_eq!(
+        backend.persister().sizes().changes,
+        backend.get_changes(&[])[0].raw_bytes().len()
+    );
+
+    // nor can a change that can't be decoded
+    assert!(
+        apply_storage_event_to_backend(&mut backend, Some("changes/bad"), Some("AQID")).is_err()
+    );
+    assert_eq!(backend.persister().get_changes().unwrap().len(), 1);
+    assert_eq!(
+        backend
+            .persister()
+            .storage_event_changes(Some("changes/bad"), Some("AQID"))
+            .unwrap(),
+        vec![vec![1, 2, 3]]
+    );
+}
+
+#[test]
+fn document_applies_changes_without_writing() {
+    let storage = CountingStorage::default();
+    let mut document = PersistentAutomerge::load(persister(storage.clone())).unwrap();
+    let writes = storage.writes.get();
+
+    let (key, value) = write_change(&storage.inner, &ActorId::random(), "a");
+    assert!(apply_storage_event_to_document(&mut document, Some(&key), Some(&value)).unwrap());
+    assert_eq!(
+        document.get_value(&Path::root().key("a")),
+        Some(Value::Primitive(Primitive::Str("a".into())))
+    );
+    assert_eq!(document.persister().get_changes().unwrap().len(), 1);
+    assert_eq!(storage.writes.get(), writes);
+    assert!(!apply_storage_event_to_document(&mut document, Some(&key), Some(&value)).unwrap());
+
+    // a change that can't be decoded is left for the event to be received again
+    assert!(
+        apply_storage_event_to_document(&mut document, Some("changes/bad"), Some("AQID")).is_err()
+    );
+    assert_eq!(document.persister().get_changes().unwrap().len(), 1);
+    assert_eq!(storage.writes.get(), writes);
+}
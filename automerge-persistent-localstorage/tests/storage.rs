@@ -0,0 +1,291 @@
+use automerge::{Frontend, InvalidChangeRequest, LocalChange, Path, Primitive, Value};
+use automerge_persistent::{MemoryPersister, PersistentBackend, Persister};
+use automerge_persistent_localstorage::{
+    LocalStoragePersister, LocalStoragePersisterError, MemoryStorage, MemoryStorageError, Storage,
+};
+use automerge_protocol::ActorId;
+
+fn persister(storage: &MemoryStorage) -> LocalStoragePersister<MemoryStorage> {
+    LocalStoragePersister::new(
+        storage.clone(),
+        "document".to_owned(),
+        "changes".to_owned(),
+        "sync-states".to_owned(),
+    )
+    .unwrap()
+}
+
+fn sorted_keys(storage: &MemoryStorage) -> Vec<String> {
+    let mut keys = storage.keys().unwrap();
+    keys.sort();
+    keys
+}
+
+#[test]
+fn item_layout() {
+    let storage = MemoryStorage::default();
+    let mut p = persister(&storage);
+    let actor = ActorId::from_bytes(&[1; 16]);
+    p.insert_changes(vec![(&actor, 12, &[1, 2][..]), (&actor, 13, &[3][..])])
+        .unwrap();
+    p.set_document(vec![3]).unwrap();
+    p.set_sync_state(vec![255], vec![4]).unwrap();
+    p.set_actor_id(actor.clone()).unwrap();
+
+    let change_key = format!("changes/{}-12", actor.to_hex_string());
+    assert_eq!(
+        sorted_keys(&storage),
+        vec![
+            change_key.clone(),
+            format!("changes/{}-13", actor.to_hex_string()),
+            "document".to_owned(),
+            "document-actor-id".to_owned(),
+            "sync-states/_w==".to_owned(),
+        ]
+    );
+    assert_eq!(storage.get_item(&change_key).unwrap().unwrap(), "AQI=");
+    assert_eq!(
+        storage.get_item("document").unwrap().unwrap(),
+        r#"{"document":[3],"compacted_changes":[],"removed_sync_states":[]}"#
+    );
+    assert_eq!(
+        storage.get_item("sync-states/_w==").unwrap().unwrap(),
+        "BA=="
+    );
+
+    p.remove_changes(vec![(&actor, 12)]).unwrap();
+    p.remove_sync_states(&[&[255]]).unwrap();
+    assert_eq!(storage.get_item(&change_key).unwrap(), None);
+    assert_eq!(storage.get_item("sync-states/_w==").unwrap(), None);
+}
+
+#[test]
+fn nested_keys_are_separate() {
+    let storage = MemoryStorage::default();
+    let mut nested = LocalStoragePersister::new(
+        storage.clone(),
+        "changes/document".to_owned(),
+        "changes/x".to_owned(),
+        "changes/y".to_owned(),
+    )
+    .unwrap();
+    nested
+        .insert_changes(vec![(&ActorId::from_bytes(&[1; 16]), 1, &[1][..])])
+        .unwrap();
+    nested.set_sync_state(vec![1], vec![1]).unwrap();
+
+    let p = persister(&storage);
+    assert!(p.get_changes().unwrap().is_empty());
+    assert!(p.get_peer_ids().unwrap().is_empty());
+}
+
+#[test]
+fn legacy_document() {
+    let storage = MemoryStorage::default();
+    storage.set_item("document", "[1,2,3]").unwrap();
+    let p = persister(&storage);
+    assert_eq!(p.get_document().unwrap(), Some(vec![1, 2, 3]));
+    assert_eq!(p.sizes().document, 3);
+}
+
+#[test]
+fn legacy_maps() {
+    let storage = MemoryStorage::default();
+    let actor = ActorId::from_bytes(&[1; 16]);
+    storage
+        .set_item(
+            "changes",
+            &format!(
+                r#"{{"{0}-1":[1],"{0}-2":[2,2],"{0}-3":[3]}}"#,
+                actor.to_hex_string()
+            ),
+        )
+        .unwrap();
+    storage
+        .set_item("sync-states", r#"{"/w==":[4],"AQ==":[5]}"#)
+        .unwrap();
+    // a compaction that was interrupted before it could remove the changes and sync states
+    storage
+        .set_item(
+            "document",
+            &format!(
+                r#"{{"document":[6],"compacted_changes":["{}-3"],"removed_sync_states":["AQ=="]}}"#,
+                actor.to_hex_string()
+            ),
+        )
+        .unwrap();
+
+    let p = persister(&storage);
+    let mut changes = p.get_changes_with_keys().unwrap().unwrap();
+    changes.sort_by_key(|(_, seq, _)| *seq);
+    assert_eq!(
+        changes,
+        vec![(actor.clone(), 1, vec![1]), (actor.clone(), 2, vec![2, 2])]
+    );
+    assert_eq!(p.get_peer_ids().unwrap(), vec![vec![255]]);
+    assert_eq!(p.get_sync_state(&[255]).unwrap(), Some(vec![4]));
+    assert_eq!(p.sizes().changes, 3);
+    assert_eq!(p.sizes().sync_states, 1);
+    assert_eq!(
+        sorted_keys(&storage),
+        vec![
+            format!("changes/{}-1", actor.to_hex_string()),
+            format!("changes/{}-2", actor.to_hex_string()),
+            "document".to_owned(),
+            "sync-states/_w==".to_owned(),
+        ]
+    );
+}
+
+/// The encoded changes from 1 to `n` made by the actor with the given id.
+fn encoded_changes(actor_id: &[u8], n: u64) -> Vec<Vec<u8>> {
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(MemoryPersister::default()).unwrap();
+    let mut frontend = Frontend::new_with_actor_id(actor_id);
+    for i in 0..n {
+        let ((), change) = frontend
+            .change::<_, _, InvalidChangeRequest>(None, |doc| {
+                doc.add_change(LocalChange::set(
+                    Path::root().key("n"),
+                    Value::Primitive(Primitive::Str(i.to_string())),
+                ))
+            })
+            .unwrap();
+        let patch = backend.apply_local_change(change.unwrap()).unwrap();
+        frontend.apply_patch(patch).unwrap();
+    }
+    let mut changes = backend
+        .persister()
+        .get_changes_with_keys()
+        .unwrap()
+        .unwrap();
+    changes.sort_by_key(|(_, seq, _)| *seq);
+    changes.into_iter().map(|(_, _, change)| change).collect()
+}
+
+#[test]
+fn legacy_change_keys() {
+    let storage = MemoryStorage::default();
+    let ab = ActorId::from_bytes(&[0xab]);
+    let ab12 = ActorId::from_bytes(&[0xab, 0x12]);
+    let ab_change = encoded_changes(&[0xab], 12).remove(11);
+    let ab12_change = encoded_changes(&[0xab, 0x12], 1).remove(0);
+    // "ab121" could be actor ab with seq 121 as well as actor ab12 with seq 1
+    let mut changes = std::collections::HashMap::new();
+    changes.insert("ab12", ab_change.clone());
+    changes.insert("ab121", ab12_change.clone());
+    changes.insert("cd1", vec![1, 2, 3]);
+    storage
+        .set_item("changes", &serde_json::to_string(&changes).unwrap())
+        .unwrap();
+
+    let p = persister(&storage);
+    let mut data = p.get_changes().unwrap();
+    data.sort();
+    let mut expected = vec![ab_change.clone(), ab12_change.clone(), vec![1, 2, 3]];
+    expected.sort();
+    assert_eq!(data, expected);
+    // the change that can't be decoded keeps its legacy key
+    assert_eq!(p.get_changes_with_keys().unwrap(), None);
+    assert_eq!(
+        sorted_keys(&storage),
+        vec![
+            format!("changes/{}-12", ab.to_hex_string()),
+            format!("changes/{}-1", ab12.to_hex_string()),
+            "changes/cd1".to_owned(),
+        ]
+    );
+
+    // once it is gone the others are read back under their own keys
+    storage.remove_item("changes/cd1").unwrap();
+    let mut changes = persister(&storage)
+        .get_changes_with_keys()
+        .unwrap()
+        .unwrap();
+    changes.sort_by_key(|(_, seq, _)| *seq);
+    assert_eq!(changes, vec![(ab12, 1, ab12_change), (ab, 12, ab_change)]);
+}
+
+#[test]
+fn quota_exceeded() {
+    let storage = MemoryStorage::default().with_quota(256);
+    let mut p = persister(&storage);
+    let actor = ActorId::from_bytes(&[1; 16]);
+    p.insert_changes(vec![(&actor, 1, &[1][..])]).unwrap();
+    let size = storage.size();
+    let keys = sorted_keys(&storage);
+
+    // the first change fits but the second doesn't
+    let change = [0; 64];
+    assert!(matches!(
+        p.insert_changes(vec![(&actor, 2, &change[..]), (&actor, 3, &change[..])]),
+        Err(LocalStoragePersisterError::QuotaExceeded(
+            MemoryStorageError::QuotaExceeded { quota: 256, .. }
+        ))
+    ));
+    assert!(matches!(
+        p.set_sync_state(b"peer".to_vec(), vec![0; 256]),
+        Err(LocalStoragePersisterError::QuotaExceeded(_))
+    ));
+    // what was already stored is untouched
+    assert_eq!(storage.size(), size);
+    assert_eq!(sorted_keys(&storage), keys);
+    assert_eq!(p.get_changes().unwrap(), vec![vec![1]]);
+    assert_eq!(p.sizes().changes, 1);
+    assert_eq!(persister(&storage).get_changes().unwrap(), vec![vec![1]]);
+}
+
+#[test]
+fn storage_events() {
+    let storage = MemoryStorage::default();
+    let mut tab1 = persister(&storage);
+    let mut tab2 = persister(&storage);
+    let actor = ActorId::from_bytes(&[1; 16]);
+    let change_key = format!("changes/{}-1", actor.to_hex_string());
+
+    tab1.insert_changes(vec![(&actor, 1, &[1, 2][..])]).unwrap();
+    let value = storage.get_item(&change_key).unwrap();
+    assert_eq!(
+        tab2.receive_storage_event(Some(&change_key), value.as_deref())
+            .unwrap(),
+        vec![vec![1, 2]]
+    );
+    assert_eq!(tab2.get_changes().unwrap(), vec![vec![1, 2]]);
+    assert_eq!(tab2.sizes().changes, 2);
+    // the change was already known
+    assert!(tab2
+        .receive_storage_event(Some(&change_key), value.as_deref())
+        .unwrap()
+        .is_empty());
+
+    tab1.set_sync_state(vec![255], vec![3]).unwrap();
+    let value = storage.get_item("sync-states/_w==").unwrap();
+    assert!(tab2
+        .receive_storage_event(Some("sync-states/_w=="), value.as_deref())
+        .unwrap()
+        .is_empty());
+    assert_eq!(tab2.get_sync_state(&[255]).unwrap(), Some(vec![3]));
+
+    tab1.set_document(vec![4, 4]).unwrap();
+    let value = storage.get_item("document").unwrap();
+    tab2.receive_storage_event(Some("document"), value.as_deref())
+        .unwrap();
+    assert_eq!(tab2.sizes().document, 2);
+
+    tab1.remove_changes(vec![(&actor, 1)]).unwrap();
+    tab2.receive_storage_event(Some(&change_key), None).unwrap();
+    assert!(tab2.get_changes().unwrap().is_empty());
+    assert_eq!(tab2.sizes().changes, 0);
+
+    // other keys are ignored
+    assert!(tab2
+        .receive_storage_event(Some("changes/nested/key"), Some("not base64"))
+        .unwrap()
+        .is_empty());
+
+    // the storage was cleared
+    storage.remove_item("sync-states/_w==").unwrap();
+    tab2.receive_storage_event(None, None).unwrap();
+    assert!(tab2.get_peer_ids().unwrap().is_empty());
+    assert_eq!(tab2.sizes().sync_states, 0);
+}
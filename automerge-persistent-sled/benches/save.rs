@@ -1,5 +1,5 @@
 use automerge::{InvalidChangeRequest, LocalChange, Path, Primitive, Value};
-use automerge_persistent::PersistentBackend;
+use automerge_persistent::{PersistentBackend, Persister};
 use criterion::{criterion_group, criterion_main, Criterion};
 
 fn small_backend_apply_local_change(c: &mut Criterion) {
@@ -38,6 +38,42 @@ fn small_backend_apply_local_change(c: &mut Criterion) {
     });
 }
 
+fn small_backend_apply_large_local_change(c: &mut Criterion) {
+    c.bench_function("small backend apply large local change", |b| {
+        b.iter_batched(
+            || {
+                let db = sled::Config::new().temporary(true).open().unwrap();
+                let sled = automerge_persistent_sled::SledPersister::new(
+                    db.open_tree("changes").unwrap(),
+                    db.open_tree("document").unwrap(),
+                    db.open_tree("sync_states").unwrap(),
+                    "".to_owned(),
+                )
+                .unwrap();
+                let backend: PersistentBackend<
+                    automerge_persistent_sled::SledPersister,
+                    automerge::Backend,
+                > = automerge_persistent::PersistentBackend::load(sled).unwrap();
+                let mut frontend = automerge::Frontend::new();
+                let ((), change) = frontend
+                    .change::<_, _, InvalidChangeRequest>(None, |doc| {
+                        doc.add_change(LocalChange::set(
+                            Path::root().key("a"),
+                            Value::Primitive(Primitive::Str("abcdef".repeat(10_000).into())),
+                        ))
+                        .unwrap();
+                        Ok(())
+                    })
+                    .unwrap();
+
+                (backend, change.unwrap())
+            },
+            |(mut persistent_doc, change)| persistent_doc.apply_local_change(change),
+            criterion::BatchSize::SmallInput,
+        )
+    });
+}
+
 fn small_backend_apply_local_change_flush(c: &mut Criterion) {
     c.bench_function("small backend apply local change flush", |b| {
         b.iter_batched(
@@ -119,6 +155,98 @@ fn small_backend_apply_changes(c: &mut Criterion) {
     });
 }
 
+fn small_backend_apply_large_changes(c: &mut Criterion) {
+    c.bench_function("small backend apply large changes", |b| {
+        b.iter_batched(
+            || {
+                let db = sled::Config::new().temporary(true).open().unwrap();
+                let sled = automerge_persistent_sled::SledPersister::new(
+                    db.open_tree("changes").unwrap(),
+                    db.open_tree("document").unwrap(),
+                    db.open_tree("sync_states").unwrap(),
+                    "".to_owned(),
+                )
+                .unwrap();
+                let mut other_backend = automerge::Backend::new();
+                let backend: PersistentBackend<
+                    automerge_persistent_sled::SledPersister,
+                    automerge::Backend,
+                > = automerge_persistent::PersistentBackend::load(sled).unwrap();
+                let mut frontend = automerge::Frontend::new();
+                let ((), change) = frontend
+                    .change::<_, _, InvalidChangeRequest>(None, |doc| {
+                        doc.add_change(LocalChange::set(
+                            Path::root().key("a"),
+                            Value::Primitive(Primitive::Str("abcdef".repeat(10_000).into())),
+                        ))
+                        .unwrap();
+                        Ok(())
+                    })
+                    .unwrap();
+                let (_patch, _change) = other_backend.apply_local_change(change.unwrap()).unwrap();
+                let changes = other_backend
+                    .get_changes(&[])
+                    .into_iter()
+                    .cloned()
+                    .collect();
+                (backend, changes)
+            },
+            |(mut persistent_doc, changes)| persistent_doc.apply_changes(changes),
+            criterion::BatchSize::SmallInput,
+        )
+    });
+}
+
+/// Inserting a large change as borrowed from the backend, against copying it out first as
+/// persisting used to.
+fn sled_insert_large_change(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sled insert large change");
+    let mut backend = automerge::Backend::new();
+    let mut frontend = automerge::Frontend::new();
+    let ((), change) = frontend
+        .change::<_, _, InvalidChangeRequest>(None, |doc| {
+            doc.add_change(LocalChange::set(
+                Path::root().key("a"),
+                Value::Primitive(Primitive::Str("abcdef".repeat(10_000).into())),
+            ))
+            .unwrap();
+            Ok(())
+        })
+        .unwrap();
+    let (_patch, change) = backend.apply_local_change(change.unwrap()).unwrap();
+    let new_sled = || {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        automerge_persistent_sled::SledPersister::new(
+            db.open_tree("changes").unwrap(),
+            db.open_tree("document").unwrap(),
+            db.open_tree("sync_states").unwrap(),
+            "".to_owned(),
+        )
+        .unwrap()
+    };
+
+    group.bench_function("borrowed", |b| {
+        b.iter_batched(
+            new_sled,
+            |mut sled| {
+                sled.insert_changes(vec![(change.actor_id(), change.seq, change.raw_bytes())])
+            },
+            criterion::BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("copied", |b| {
+        b.iter_batched(
+            new_sled,
+            |mut sled| {
+                let (actor_id, bytes) = (change.actor_id().clone(), change.raw_bytes().to_vec());
+                sled.insert_changes(vec![(&actor_id, change.seq, &bytes[..])])
+            },
+            criterion::BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
 fn small_backend_compact(c: &mut Criterion) {
     c.bench_function("small backend compact", |b| {
         b.iter_batched(
@@ -158,6 +286,6 @@ fn small_backend_compact(c: &mut Criterion) {
 criterion_group! {
     name = benches;
     config = Criterion::default().sample_size(50);
-    targets = small_backend_apply_local_change, small_backend_apply_local_change_flush, small_backend_apply_changes, small_backend_compact
+    targets = small_backend_apply_local_change, small_backend_apply_large_local_change, small_backend_apply_local_change_flush, small_backend_apply_changes, small_backend_apply_large_changes, sled_insert_large_change, small_backend_compact
 }
 criterion_main!(benches);
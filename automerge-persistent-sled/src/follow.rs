@@ -0,0 +1,222 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use automerge::Change;
+use automerge_persistent::{Backend, Error, PersistentBackend, Persister};
+use automerge_protocol::{ChangeHash, Patch};
+
+use crate::{SledPersister, SledPersisterError};
+
+/// A subscription to the changes inserted under the prefix of a [`SledPersister`], including those
+/// written by other instances sharing its trees.
+///
+/// Writers block once 1024 events are waiting for a subscriber so it should be checked regularly,
+/// or dropped when no longer needed.
+pub struct ChangeSubscriber {
+    subscriber: sled::Subscriber,
+}
+
+impl SledPersister {
+    /// Subscribe to the changes inserted under this persister's prefix from now on.
+    ///
+    /// The subscription also sees the changes inserted through this persister.
+    ///
+    /// ```rust
+    /// # use std::time::Duration;
+    /// # use automerge_persistent_sled::{SledPersister, SledPersisterError};
+    /// # fn main() -> Result<(), SledPersisterError> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let persister = SledPersister::new(
+    ///     db.open_tree("changes")?,
+    ///     db.open_tree("documents")?,
+    ///     db.open_tree("sync-states")?,
+    ///     "",
+    /// )?;
+    /// let mut subscriber = persister.watch_changes();
+    /// assert!(subscriber.wait(Duration::from_millis(10)).is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[must_use]
+    pub fn watch_changes(&self) -> ChangeSubscriber {
+        ChangeSubscriber {
+            subscriber: self.changes_tree.watch_prefix(&self.prefix),
+        }
+    }
+}
+
+impl ChangeSubscriber {
+    /// Wait up to `timeout` for a change to be inserted, returning it along with any others that
+    /// are already waiting.
+    pub fn wait(&mut self, timeout: Duration) -> Vec<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        let mut changes = Vec::new();
+        loop {
+            let timeout = if changes.is_empty() {
+                deadline.saturating_duration_since(Instant::now())
+            } else {
+                Duration::from_secs(0)
+            };
+            match self.subscriber.next_timeout(timeout) {
+                Ok(sled::Event::Insert { value, .. }) => changes.push(value.to_vec()),
+                Ok(sled::Event::Remove { .. }) => {}
+                Err(_) => return changes,
+            }
+        }
+    }
+
+    /// The changes inserted since this was last checked, without waiting.
+    pub fn pending(&mut self) -> Vec<Vec<u8>> {
+        self.wait(Duration::from_secs(0))
+    }
+}
+
+/// Decode the `changes` that the backend doesn't have yet, using `known` to look up whether it
+/// has a change by its hash.
+fn new_changes<E, B>(
+    changes: Vec<Vec<u8>>,
+    known: impl Fn(&ChangeHash) -> bool,
+) -> Result<Vec<Change>, Error<E, B>> {
+    let mut new = Vec::new();
+    for change in changes {
+        let change = Change::from_bytes(change).map_err(|e| Error::AutomergeError(e.into()))?;
+        if !known(&change.hash) {
+            new.push(change);
+        }
+    }
+    Ok(new)
+}
+
+/// Apply the changes from a [`ChangeSubscriber`] to a backend, returning the patch for its
+/// frontend if any of them were new to it.
+///
+/// This lets several instances share a prefix, each tailing the changes the others insert. The
+/// changes are already stored so they are only applied in memory, without inserting them again
+/// for the subscribers to see. The changes the backend already has, including the ones it
+/// inserted itself and those queued waiting for their dependencies, are skipped.
+///
+/// ```rust
+/// # use std::time::Duration;
+/// # use automerge_persistent::PersistentBackend;
+/// # use automerge_persistent_sled::{apply_subscribed_changes_to_backend, SledPersister};
+/// # let db = sled::Config::new().temporary(true).open().unwrap();
+/// # let persister = SledPersister::new(
+/// #     db.open_tree("changes").unwrap(),
+/// #     db.open_tree("documents").unwrap(),
+/// #     db.open_tree("sync-states").unwrap(),
+/// #     "",
+/// # )
+/// # .unwrap();
+/// # let mut backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
+/// # let mut frontend = automerge::Frontend::new();
+/// let mut subscriber = backend.persister().watch_changes();
+/// let changes = subscriber.wait(Duration::from_millis(10));
+/// if let Some(patch) = apply_subscribed_changes_to_backend(&mut backend, changes).unwrap() {
+///     frontend.apply_patch(patch).unwrap();
+/// }
+/// ```
+#[allow(clippy::type_complexity)]
+pub fn apply_subscribed_changes_to_backend<B>(
+    backend: &mut PersistentBackend<SledPersister, B>,
+    changes: Vec<Vec<u8>>,
+) -> Result<Option<Patch>, Error<SledPersisterError, B::Error>>
+where
+    B: Backend,
+{
+    let changes = new_changes(changes, |hash| backend.has_change(hash))?;
+    if changes.is_empty() {
+        return Ok(None);
+    }
+    backend.apply_stored_changes(changes).map(Some)
+}
+
+/// A read-only view of the document stored by a [`SledPersister`] that follows the changes
+/// written by other instances, such as for reporting.
+///
+/// Nothing is written to the trees so any number of followers can share a prefix with a writer.
+///
+/// ```rust
+/// # use automerge_persistent_sled::{SledFollower, SledPersister};
+/// # fn follow(persister: &SledPersister) {
+/// let mut follower = SledFollower::<automerge::Backend>::new(persister).unwrap();
+/// if let Some(patch) = follower.poll().unwrap() {
+///     // update the report
+/// }
+/// # }
+/// ```
+pub struct SledFollower<B> {
+    backend: B,
+    subscriber: ChangeSubscriber,
+    /// The changes the backend has queued as their dependencies are missing.
+    queued: HashSet<ChangeHash>,
+}
+
+impl<B> SledFollower<B>
+where
+    B: Backend,
+{
+    /// Load the document stored by the persister and follow the changes inserted from now on.
+    pub fn new(persister: &SledPersister) -> Result<Self, Error<SledPersisterError, B::Error>> {
+        // subscribe before reading so no inserts are missed, and read the changes before the
+        // document so that a compaction in between only removes changes that were read
+        let subscriber = persister.watch_changes();
+        let changes = persister.get_changes().map_err(Error::PersisterError)?;
+        let backend = match persister.get_document().map_err(Error::PersisterError)? {
+            Some(document) => B::load(document).map_err(Error::BackendError)?,
+            None => B::default(),
+        };
+        let mut follower = Self {
+            backend,
+            subscriber,
+            queued: HashSet::new(),
+        };
+        follower.apply(changes)?;
+        Ok(follower)
+    }
+
+    /// The backend holding the followed document.
+    pub const fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Wait up to `timeout` for changes to be inserted and apply them, returning the patch for a
+    /// frontend if any of them were new.
+    pub fn wait(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<Patch>, Error<SledPersisterError, B::Error>> {
+        let changes = self.subscriber.wait(timeout);
+        self.apply(changes)
+    }
+
+    /// Apply the changes inserted since this was last checked, without waiting.
+    pub fn poll(&mut self) -> Result<Option<Patch>, Error<SledPersisterError, B::Error>> {
+        let changes = self.subscriber.pending();
+        self.apply(changes)
+    }
+
+    fn apply(
+        &mut self,
+        changes: Vec<Vec<u8>>,
+    ) -> Result<Option<Patch>, Error<SledPersisterError, B::Error>> {
+        let (backend, queued) = (&self.backend, &self.queued);
+        let changes = new_changes(changes, |hash| {
+            queued.contains(hash) || backend.get_change_by_hash(hash).is_some()
+        })?;
+        if changes.is_empty() {
+            return Ok(None);
+        }
+        let hashes = changes.iter().map(|c| c.hash).collect::<Vec<_>>();
+        let patch = self
+            .backend
+            .apply_changes(changes)
+            .map_err(Error::BackendError)?;
+        self.queued.extend(hashes);
+        let backend = &self.backend;
+        self.queued
+            .retain(|hash| backend.get_change_by_hash(hash).is_none());
+        Ok(Some(patch))
+    }
+}
@@ -52,20 +52,39 @@
 //! # }
 //! ```
 
-use automerge_persistent::{Persister, StoredSizes};
+mod follow;
+mod store;
+
+use std::{
+    convert::{TryFrom, TryInto},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub use automerge_persistent::SyncStateInfo;
+use automerge_persistent::{ChangeIter, KeyedChangeIter, Persister, StoredSizes};
 use automerge_protocol::ActorId;
+pub use follow::{apply_subscribed_changes_to_backend, ChangeSubscriber, SledFollower};
+use sled::Transactional;
+pub use store::SledDocumentStore;
 
 /// The persister that stores changes and documents in sled trees.
 ///
-/// Changes and documents are kept in separate trees.
+/// Changes and documents are kept in separate trees, with quarantined changes kept alongside the
+/// document.
 ///
-/// An optional prefix can be used in case multiple persisters may share the same trees.
+/// An optional prefix can be used in case multiple persisters may share the same trees. Keys
+/// start with the length of the prefix so no prefix can see the data of another that it is the
+/// start of.
+///
+/// Trees written before keys held the length of the prefix need converting with
+/// [`migrate`](Self::migrate) before they can be opened.
 #[derive(Debug)]
 pub struct SledPersister {
     changes_tree: sled::Tree,
     document_tree: sled::Tree,
     sync_states_tree: sled::Tree,
-    prefix: String,
+    /// The encoded prefix that all keys start with.
+    prefix: Vec<u8>,
     sizes: StoredSizes,
 }
 
@@ -75,10 +94,50 @@ pub enum SledPersisterError {
     /// Internal errors from sled.
     #[error(transparent)]
     SledError(#[from] sled::Error),
+    /// Errors from sled transactions.
+    #[error(transparent)]
+    SledTransactionError(#[from] sled::transaction::TransactionError),
+    /// The document isn't in the [`SledDocumentStore`].
+    #[error("document {0:?} not found")]
+    DocumentNotFound(String),
+    /// The document is already in the [`SledDocumentStore`], or its prefix already holds data.
+    #[error("document {0:?} already exists")]
+    DocumentExists(String),
+    /// The trees hold data from before keys held the length of the prefix.
+    #[error("the trees use the legacy key layout and need migrating")]
+    LegacyLayout,
+    /// A key in trees being migrated didn't start with any of the given prefixes.
+    #[error("legacy key {0:?} doesn't start with any of the given prefixes")]
+    UnknownLegacyKey(Vec<u8>),
+    /// A legacy change key that could belong to several of the given prefixes, holding a change
+    /// that doesn't say which.
+    #[error("legacy change key {0:?} could belong to several of the given prefixes")]
+    AmbiguousLegacyKey(Vec<u8>),
+    /// A change was stored under a key that doesn't hold an actor id and sequence number.
+    #[error("malformed change key {0:?}")]
+    MalformedChangeKey(Vec<u8>),
 }
 
+/// Read the actor id and the big endian sequence number from the end of a change key, after its
+/// prefix.
+fn parse_change_key(key: &[u8]) -> Option<(ActorId, u64)> {
+    let (actor_id, seq) = key.split_at(key.len().checked_sub(8)?);
+    Some((
+        ActorId::from_bytes(actor_id),
+        u64::from_be_bytes(seq.try_into().ok()?),
+    ))
+}
+
+/// The key in the document tree recording the key layout, starting with a byte that no key
+/// written by either layout can start with.
+const LAYOUT_KEY: &[u8] = b"\xfflayout";
+/// The current key layout.
+const LAYOUT_VERSION: &[u8] = &[1];
+
 impl SledPersister {
     /// Construct a new persister.
+    ///
+    /// Fails with [`SledPersisterError::LegacyLayout`] if the trees need migrating.
     #[must_use]
     pub fn new<S>(
         changes_tree: sled::Tree,
@@ -89,7 +148,8 @@ impl SledPersister {
     where
         S: Into<String>,
     {
-        let prefix = prefix.into();
+        check_layout(&changes_tree, &document_tree, &sync_states_tree)?;
+        let prefix = Self::key_prefix(&prefix.into());
 
         let mut s = Self {
             changes_tree,
@@ -98,23 +158,194 @@ impl SledPersister {
             prefix,
             sizes: StoredSizes::default(),
         };
-        s.sizes.changes = s.get_changes()?.iter().map(Vec::len).sum();
+        for value in s.changes_tree.scan_prefix(&s.prefix).values() {
+            s.sizes.changes += value?.len();
+        }
         s.sizes.document = s.get_document()?.unwrap_or_default().len();
-        s.sizes.sync_states = s
-            .get_peer_ids()?
-            .iter()
-            .map(|id| s.get_sync_state(id).map(|o| o.unwrap_or_default().len()))
-            .collect::<Result<Vec<usize>, _>>()?
-            .iter()
-            .sum();
+        s.sizes.sync_states = s.sync_states()?.iter().map(|info| info.size).sum();
         Ok(s)
     }
 
+    /// The stored sync states along with their sizes and when they were last set.
+    ///
+    /// ```rust
+    /// # use std::time::{Duration, SystemTime};
+    /// # use automerge_persistent::Persister;
+    /// # use automerge_persistent_sled::{SledPersister, SledPersisterError};
+    /// # fn main() -> Result<(), SledPersisterError> {
+    /// # let db = sled::Config::new().temporary(true).open()?;
+    /// # let mut persister = SledPersister::new(
+    /// #     db.open_tree("changes")?,
+    /// #     db.open_tree("documents")?,
+    /// #     db.open_tree("sync-states")?,
+    /// #     "",
+    /// # )?;
+    /// let cutoff = SystemTime::now() - Duration::from_secs(30 * 24 * 60 * 60);
+    /// let stale = persister
+    ///     .sync_states()?
+    ///     .into_iter()
+    ///     .filter(|info| info.updated.map_or(true, |updated| updated < cutoff))
+    ///     .map(|info| info.peer_id)
+    ///     .collect::<Vec<_>>();
+    /// persister.remove_sync_states(&stale.iter().map(Vec::as_slice).collect::<Vec<_>>())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn sync_states(&self) -> Result<Vec<SyncStateInfo>, SledPersisterError> {
+        self.sync_states_tree
+            .scan_prefix(&self.prefix)
+            .map(|kv| {
+                let (k, v) = kv?;
+                let peer_id = k[self.prefix.len()..].to_vec();
+                let updated = self
+                    .document_tree
+                    .get(self.make_peer_updated_key(&peer_id))?
+                    .and_then(|t| Some(u64::from_be_bytes(t.as_ref().try_into().ok()?)))
+                    .map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+                Ok(SyncStateInfo {
+                    peer_id,
+                    size: v.len(),
+                    updated,
+                })
+            })
+            .collect()
+    }
+
+    /// Convert trees written before keys held the length of the prefix to the current layout,
+    /// returning the number of entries moved.
+    ///
+    /// The old layout can't tell which document a key belongs to by itself so `prefixes` should
+    /// list every prefix used with the trees. Changes are given to the prefix that leaves their
+    /// own actor id and sequence number as the rest of the key, or if they can't be decoded or
+    /// don't match their key, to the only prefix they start with, failing with
+    /// [`SledPersisterError::AmbiguousLegacyKey`] if there are several. Other entries are given
+    /// to the longest prefix they start with. Keys without a prefix fail with
+    /// [`SledPersisterError::UnknownLegacyKey`]. Trees that are already in the current layout are
+    /// left alone.
+    ///
+    /// Entries are moved in batches, first copied aside in the document tree, so the trees are
+    /// never all held in memory. The trees can't be opened until the migration has finished, and
+    /// an interrupted migration carries on from where it got to when run again.
+    ///
+    /// ```rust
+    /// # use automerge_persistent_sled::{SledPersister, SledPersisterError};
+    /// # fn main() -> Result<(), SledPersisterError> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let changes_tree = db.open_tree("changes")?;
+    /// let documents_tree = db.open_tree("documents")?;
+    /// let sync_states_tree = db.open_tree("sync-states")?;
+    ///
+    /// SledPersister::migrate(&changes_tree, &documents_tree, &sync_states_tree, &["1", "2"])?;
+    /// let persister = SledPersister::new(changes_tree, documents_tree, sync_states_tree, "1")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn migrate(
+        changes_tree: &sled::Tree,
+        document_tree: &sled::Tree,
+        sync_states_tree: &sled::Tree,
+        prefixes: &[&str],
+    ) -> Result<usize, SledPersisterError> {
+        if document_tree.contains_key(LAYOUT_KEY)? {
+            return Ok(0);
+        }
+        let trees = [changes_tree, document_tree, sync_states_tree];
+        let mut stage = document_tree
+            .get(MIGRATION_KEY)?
+            .map_or(MigrationStage::Staging, |v| MigrationStage::from(&*v));
+
+        if stage == MigrationStage::Staging {
+            let mut prefixes = prefixes.to_vec();
+            prefixes.sort_by_key(|p| std::cmp::Reverse(p.len()));
+            // start again from anything staged by an interrupted run
+            remove_in_batches(document_tree, || {
+                document_tree.scan_prefix(STAGING_PREFIX).keys()
+            })?;
+            for (index, tree) in trees.iter().enumerate() {
+                stage_legacy_entries(tree, document_tree, index, &prefixes)?;
+            }
+            stage = MigrationStage::Clearing;
+            document_tree.insert(MIGRATION_KEY, &[stage as u8])?;
+        }
+
+        if stage == MigrationStage::Clearing {
+            for (index, tree) in trees.iter().enumerate() {
+                remove_in_batches(tree, || legacy_entries(tree, index).keys())?;
+            }
+            stage = MigrationStage::Moving;
+            document_tree.insert(MIGRATION_KEY, &[stage as u8])?;
+        }
+
+        let mut moved = 0;
+        loop {
+            let batch = document_tree
+                .scan_prefix(STAGING_PREFIX)
+                .take(MIGRATION_BATCH_SIZE)
+                .collect::<Result<Vec<_>, _>>()?;
+            if batch.is_empty() {
+                break;
+            }
+            (changes_tree, document_tree, sync_states_tree).transaction(
+                |(changes_tree, document_tree, sync_states_tree)| {
+                    let trees = [changes_tree, document_tree, sync_states_tree];
+                    for (staged, value) in &batch {
+                        let key = &staged[STAGING_PREFIX.len()..];
+                        trees[usize::from(key[0])].insert(&key[1..], value)?;
+                        document_tree.remove(staged)?;
+                    }
+                    Ok(())
+                },
+            )?;
+            moved += batch.len();
+        }
+
+        let mut finish = sled::Batch::default();
+        finish.remove(MIGRATION_KEY);
+        finish.insert(LAYOUT_KEY, LAYOUT_VERSION);
+        document_tree.apply_batch(finish)?;
+        Ok(moved)
+    }
+
+    /// The bytes that all of the keys for the document with the given prefix start with: the
+    /// length of the prefix in big endian form followed by the prefix itself.
+    fn key_prefix(prefix: &str) -> Vec<u8> {
+        let len = u32::try_from(prefix.len()).expect("prefix longer than u32::MAX");
+        let mut key = len.to_be_bytes().to_vec();
+        key.extend(prefix.as_bytes());
+        key
+    }
+
+    /// Make the key for a quarantined change, stored alongside the document.
+    fn make_quarantine_key(&self, actor_id: &ActorId, seq: u64) -> Vec<u8> {
+        let mut key = self.quarantine_prefix();
+        key.extend(actor_id.to_bytes());
+        key.extend(&seq.to_be_bytes());
+        key
+    }
+
+    fn quarantine_prefix(&self) -> Vec<u8> {
+        let mut key = self.prefix.clone();
+        key.extend(QUARANTINE_INFIX);
+        key
+    }
+
+    /// Scan the changes tree, reading the actor id and sequence number back out of each key.
+    fn scan_changes_with_keys(
+        &self,
+    ) -> impl Iterator<Item = Result<(ActorId, u64, Vec<u8>), SledPersisterError>> + '_ {
+        self.changes_tree.scan_prefix(&self.prefix).map(move |kv| {
+            let (k, v) = kv?;
+            let (actor_id, seq) = parse_change_key(&k[self.prefix.len()..])
+                .ok_or_else(|| SledPersisterError::MalformedChangeKey(k.to_vec()))?;
+            Ok((actor_id, seq, v.to_vec()))
+        })
+    }
+
     /// Make a key from the prefix, `actor_id` and `sequence_number`.
     ///
     /// Converts the `actor_id` to bytes and appends the `sequence_number` in big endian form.
     fn make_key(&self, actor_id: &ActorId, seq: u64) -> Vec<u8> {
-        let mut key = self.prefix.as_bytes().to_vec();
+        let mut key = self.prefix.clone();
         key.extend(actor_id.to_bytes());
         key.extend(&seq.to_be_bytes());
         key
@@ -123,16 +354,199 @@ impl SledPersister {
     /// Make a key just from the prefix.
     /// Since each document only has one thing to store in this tree we can just use the prefix.
     fn make_document_key(&self) -> Vec<u8> {
-        self.prefix.as_bytes().to_vec()
+        self.prefix.clone()
+    }
+
+    /// Make the key for the actor id, stored alongside the document.
+    fn make_actor_id_key(&self) -> Vec<u8> {
+        let mut key = self.prefix.clone();
+        key.extend(ACTOR_ID_SUFFIX);
+        key
     }
 
     fn make_peer_key(&self, peer_id: &[u8]) -> Vec<u8> {
-        let mut key = self.prefix.as_bytes().to_vec();
+        let mut key = self.prefix.clone();
+        key.extend(peer_id);
+        key
+    }
+
+    /// Make the key for when the sync state for the peer was last set, stored alongside the
+    /// document.
+    fn make_peer_updated_key(&self, peer_id: &[u8]) -> Vec<u8> {
+        let mut key = self.prefix.clone();
+        key.extend(PEER_UPDATED_INFIX);
         key.extend(peer_id);
         key
     }
 }
 
+/// What the key for the actor id adds to the prefix.
+const ACTOR_ID_SUFFIX: &[u8] = b"\0actor_id";
+/// What the keys for when sync states were last set add between the prefix and the peer id.
+const PEER_UPDATED_INFIX: &[u8] = b"\0peer_updated";
+
+/// What the keys for quarantined changes add between the prefix and the actor id.
+const QUARANTINE_INFIX: &[u8] = b"\0quarantined";
+
+/// The current time as milliseconds since the unix epoch, in big endian form.
+fn now_millis() -> [u8; 8] {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    u64::try_from(millis).unwrap_or(u64::MAX).to_be_bytes()
+}
+
+/// Check that the trees use the current key layout, marking them as doing so if they are empty.
+fn check_layout(
+    changes_tree: &sled::Tree,
+    document_tree: &sled::Tree,
+    sync_states_tree: &sled::Tree,
+) -> Result<(), SledPersisterError> {
+    if document_tree.contains_key(LAYOUT_KEY)? {
+        return Ok(());
+    }
+    if changes_tree.is_empty() && document_tree.is_empty() && sync_states_tree.is_empty() {
+        document_tree.insert(LAYOUT_KEY, LAYOUT_VERSION)?;
+        Ok(())
+    } else {
+        Err(SledPersisterError::LegacyLayout)
+    }
+}
+
+/// How far a migration from the legacy layout has got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MigrationStage {
+    /// Copying the entries under their new keys into the document tree.
+    Staging = 0,
+    /// Removing the entries under their old keys.
+    Clearing = 1,
+    /// Moving the copies into place.
+    Moving = 2,
+}
+
+impl From<&[u8]> for MigrationStage {
+    fn from(value: &[u8]) -> Self {
+        match value {
+            [1] => Self::Clearing,
+            [2] => Self::Moving,
+            _ => Self::Staging,
+        }
+    }
+}
+
+/// The key in the document tree recording how far a migration has got.
+const MIGRATION_KEY: &[u8] = b"\xffmigration";
+/// What the keys in the document tree for entries being migrated start with, followed by the
+/// index of their tree and their new key. No legacy key in the document tree can start with it.
+const STAGING_PREFIX: &[u8] = b"\xfemigrating";
+/// The most entries read into memory at once while migrating.
+const MIGRATION_BATCH_SIZE: usize = 1024;
+
+/// The entries of a tree in the legacy layout. The keys used to migrate the document tree are
+/// skipped, they start with bytes that no legacy key in it can start with.
+fn legacy_entries(tree: &sled::Tree, index: usize) -> sled::Iter {
+    if index == DOCUMENT_TREE {
+        tree.range::<&[u8], _>(..&[STAGING_PREFIX[0]][..])
+    } else {
+        tree.iter()
+    }
+}
+
+/// The index of the changes tree amongst the trees being migrated.
+const CHANGES_TREE: usize = 0;
+/// The index of the document tree amongst the trees being migrated.
+const DOCUMENT_TREE: usize = 1;
+
+/// Copy the entries of the tree at `index` under their new keys into the document tree, a batch
+/// at a time.
+fn stage_legacy_entries(
+    tree: &sled::Tree,
+    document_tree: &sled::Tree,
+    index: usize,
+    prefixes: &[&str],
+) -> Result<(), SledPersisterError> {
+    let mut batch = sled::Batch::default();
+    let mut len = 0;
+    for kv in legacy_entries(tree, index) {
+        let (key, value) = kv?;
+        let prefix = match index {
+            CHANGES_TREE => legacy_change_prefix(&key, &value, prefixes)?,
+            DOCUMENT_TREE => prefixes.iter().copied().find(|p| {
+                let rest = key.strip_prefix(p.as_bytes());
+                rest == Some(&[]) || rest == Some(ACTOR_ID_SUFFIX)
+            }),
+            _ => prefixes
+                .iter()
+                .copied()
+                .find(|p| key.starts_with(p.as_bytes())),
+        }
+        .ok_or_else(|| SledPersisterError::UnknownLegacyKey(key.to_vec()))?;
+
+        let mut staged = STAGING_PREFIX.to_vec();
+        staged.push(u8::try_from(index).expect("only three trees are migrated"));
+        staged.extend(SledPersister::key_prefix(prefix));
+        staged.extend(&key[prefix.len()..]);
+        batch.insert(staged, value);
+        len += 1;
+        if len == MIGRATION_BATCH_SIZE {
+            document_tree.apply_batch(std::mem::take(&mut batch))?;
+            len = 0;
+        }
+    }
+    document_tree.apply_batch(batch)?;
+    Ok(())
+}
+
+/// Find the prefix of a legacy change key from the actor id and sequence number in the change,
+/// which the key must end with.
+///
+/// A change that can't be decoded or doesn't match its key is only given a prefix if no other
+/// prefix could hold it.
+fn legacy_change_prefix<'a>(
+    key: &[u8],
+    value: &[u8],
+    prefixes: &[&'a str],
+) -> Result<Option<&'a str>, SledPersisterError> {
+    // changes end with the sequence number after the actor id
+    let mut candidates = prefixes
+        .iter()
+        .copied()
+        .filter(|p| key.starts_with(p.as_bytes()) && key.len() >= p.len() + 8);
+    if let Ok(change) = automerge::Change::from_bytes(value.to_vec()) {
+        let mut rest = change.actor_id().to_bytes();
+        rest.extend(&change.seq.to_be_bytes());
+        if let Some(prefix) = candidates.clone().find(|p| key[p.len()..] == rest[..]) {
+            return Ok(Some(prefix));
+        }
+    }
+    match (candidates.next(), candidates.next()) {
+        (Some(_), Some(_)) => Err(SledPersisterError::AmbiguousLegacyKey(key.to_vec())),
+        (prefix, _) => Ok(prefix),
+    }
+}
+
+/// Remove the keys from the tree a batch at a time, calling `keys` again for each batch.
+fn remove_in_batches<F, I>(tree: &sled::Tree, keys: F) -> Result<(), SledPersisterError>
+where
+    F: Fn() -> I,
+    I: Iterator<Item = sled::Result<sled::IVec>>,
+{
+    loop {
+        let keys = keys()
+            .take(MIGRATION_BATCH_SIZE)
+            .collect::<Result<Vec<_>, _>>()?;
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut batch = sled::Batch::default();
+        for key in keys {
+            batch.remove(key);
+        }
+        tree.apply_batch(batch)?;
+    }
+}
+
 impl Persister for SledPersister {
     type Error = SledPersisterError;
 
@@ -145,10 +559,36 @@ impl Persister for SledPersister {
             .collect()
     }
 
+    /// Scan the tree for the changes as they are iterated.
+    fn iter_changes(&self) -> Result<ChangeIter<'_, Self::Error>, Self::Error> {
+        Ok(Box::new(
+            self.changes_tree
+                .scan_prefix(&self.prefix)
+                .values()
+                .map(|v| v.map(|v| v.to_vec()).map_err(Self::Error::SledError)),
+        ))
+    }
+
+    /// Get all of the current changes, reading the actor id and sequence number back out of the
+    /// keys.
+    fn get_changes_with_keys(&self) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        self.scan_changes_with_keys()
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    /// Scan the tree for the changes as they are iterated, reading the actor id and sequence
+    /// number back out of the keys.
+    fn iter_changes_with_keys(
+        &self,
+    ) -> Result<Option<KeyedChangeIter<'_, Self::Error>>, Self::Error> {
+        Ok(Some(Box::new(self.scan_changes_with_keys())))
+    }
+
     /// Insert all of the given changes into the tree.
-    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error> {
         for (a, s, c) in changes {
-            let key = self.make_key(&a, s);
+            let key = self.make_key(a, s);
             self.sizes.changes += c.len();
             if let Some(old) = self.changes_tree.insert(key, c)? {
                 self.sizes.changes -= old.len();
@@ -168,6 +608,51 @@ impl Persister for SledPersister {
         Ok(())
     }
 
+    /// Move the changes to alongside the document in a single transaction across the trees.
+    fn quarantine_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<bool, Self::Error> {
+        let keys = changes
+            .into_iter()
+            .map(|(a, s)| (self.make_key(a, s), self.make_quarantine_key(a, s)))
+            .collect::<Vec<_>>();
+        let removed = (&self.changes_tree, &self.document_tree).transaction(
+            |(changes_tree, document_tree)| {
+                let mut removed = 0;
+                for (key, quarantine_key) in &keys {
+                    if let Some(change) = changes_tree.remove(key.as_slice())? {
+                        removed += change.len();
+                        document_tree.insert(quarantine_key.as_slice(), change)?;
+                    }
+                }
+                Ok(removed)
+            },
+        )?;
+        self.sizes.changes -= removed;
+        Ok(true)
+    }
+
+    fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        let prefix = self.quarantine_prefix();
+        self.document_tree
+            .scan_prefix(&prefix)
+            .map(|kv| {
+                let (k, v) = kv?;
+                let (actor_id, seq) = parse_change_key(&k[prefix.len()..])
+                    .ok_or_else(|| SledPersisterError::MalformedChangeKey(k.to_vec()))?;
+                Ok((actor_id, seq, v.to_vec()))
+            })
+            .collect()
+    }
+
+    fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        for (a, s) in changes {
+            self.document_tree.remove(self.make_quarantine_key(a, s))?;
+        }
+        Ok(())
+    }
+
     /// Retrieve the document from the tree.
     fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
         Ok(self
@@ -191,10 +676,19 @@ impl Persister for SledPersister {
             .map(|v| v.to_vec()))
     }
 
+    /// Set the sync state and record when it was set in a single transaction.
     fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
         let sync_state_key = self.make_peer_key(&peer_id);
+        let updated_key = self.make_peer_updated_key(&peer_id);
+        let updated = now_millis();
+        let old = (&self.sync_states_tree, &self.document_tree).transaction(
+            |(sync_states_tree, document_tree)| {
+                document_tree.insert(updated_key.as_slice(), &updated[..])?;
+                Ok(sync_states_tree.insert(sync_state_key.as_slice(), sync_state.as_slice())?)
+            },
+        )?;
         self.sizes.sync_states += sync_state.len();
-        if let Some(old) = self.sync_states_tree.insert(sync_state_key, sync_state)? {
+        if let Some(old) = old {
             self.sizes.sync_states -= old.len();
         }
         Ok(())
@@ -203,6 +697,7 @@ impl Persister for SledPersister {
     fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
         for id in peer_ids {
             let key = self.make_peer_key(id);
+            self.document_tree.remove(self.make_peer_updated_key(id))?;
             if let Some(old) = self.sync_states_tree.remove(key)? {
                 self.sizes.sync_states -= old.len();
             }
@@ -210,14 +705,80 @@ impl Persister for SledPersister {
         Ok(())
     }
 
+    /// The peer ids with the prefix removed, so they can be passed back to the other methods.
     fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
         self.sync_states_tree
             .scan_prefix(&self.prefix)
             .keys()
-            .map(|v| v.map(|v| v.to_vec()).map_err(Self::Error::SledError))
+            .map(|k| Ok(k?[self.prefix.len()..].to_vec()))
             .collect()
     }
 
+    /// See [`SledPersister::sync_states`].
+    fn get_sync_state_infos(&self) -> Result<Vec<SyncStateInfo>, Self::Error> {
+        self.sync_states()
+    }
+
+    fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        Ok(self
+            .document_tree
+            .get(self.make_actor_id_key())?
+            .map(|v| ActorId::from_bytes(&v)))
+    }
+
+    fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        self.document_tree
+            .insert(self.make_actor_id_key(), actor_id.to_bytes())?;
+        Ok(())
+    }
+
+    /// Set the document and remove the changes and sync states in a single transaction across the
+    /// three trees.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let document_key = self.make_document_key();
+        let change_keys = changes
+            .into_iter()
+            .map(|(a, s)| self.make_key(a, s))
+            .collect::<Vec<_>>();
+        let peer_keys = old_peer_ids
+            .iter()
+            .map(|id| (self.make_peer_key(id), self.make_peer_updated_key(id)))
+            .collect::<Vec<_>>();
+
+        let (removed_changes, removed_sync_states) = (
+            &self.changes_tree,
+            &self.document_tree,
+            &self.sync_states_tree,
+        )
+            .transaction(|(changes_tree, document_tree, sync_states_tree)| {
+                document_tree.insert(document_key.as_slice(), document.as_slice())?;
+                let mut removed_changes = 0;
+                for key in &change_keys {
+                    if let Some(old) = changes_tree.remove(key.as_slice())? {
+                        removed_changes += old.len();
+                    }
+                }
+                let mut removed_sync_states = 0;
+                for (key, updated_key) in &peer_keys {
+                    document_tree.remove(updated_key.as_slice())?;
+                    if let Some(old) = sync_states_tree.remove(key.as_slice())? {
+                        removed_sync_states += old.len();
+                    }
+                }
+                Ok((removed_changes, removed_sync_states))
+            })?;
+
+        self.sizes.document = document.len();
+        self.sizes.changes -= removed_changes;
+        self.sizes.sync_states -= removed_sync_states;
+        Ok(())
+    }
+
     fn sizes(&self) -> StoredSizes {
         self.sizes.clone()
     }
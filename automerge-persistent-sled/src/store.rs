@@ -0,0 +1,244 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use automerge_persistent::DocumentStore;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Transactional,
+};
+
+use crate::{SledPersister, SledPersisterError};
+
+/// A [`DocumentStore`] keeping many documents in the same sled trees.
+///
+/// Each document is stored as a [`SledPersister`] with its id as the prefix, and the ids are
+/// recorded in a separate catalog tree.
+///
+/// Documents written through a [`SledPersister`] directly aren't in the catalog, and the store
+/// won't create a document whose prefix already holds data.
+///
+/// Sled transactions can't scan the trees, so deleting and renaming read the data of a document
+/// before moving it in a transaction. Clones of the store share a lock so that they don't change
+/// the documents in between, other stores on the same trees should not be used at the same time.
+///
+/// ```rust
+/// # use automerge_persistent::DocumentStore;
+/// # use automerge_persistent_sled::{SledDocumentStore, SledPersisterError};
+/// # fn main() -> Result<(), SledPersisterError> {
+/// let db = sled::Config::new().temporary(true).open()?;
+/// let store = SledDocumentStore::new(
+///     db.open_tree("changes")?,
+///     db.open_tree("documents")?,
+///     db.open_tree("sync-states")?,
+///     db.open_tree("catalog")?,
+/// );
+///
+/// store.create("notes")?;
+/// let backend = store.load_backend::<automerge::Backend>("notes");
+/// assert_eq!(store.list()?, vec!["notes".to_owned()]);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SledDocumentStore {
+    changes_tree: sled::Tree,
+    document_tree: sled::Tree,
+    sync_states_tree: sled::Tree,
+    catalog: sled::Tree,
+    /// Held while reading the data of a document to change it.
+    lock: Arc<Mutex<()>>,
+}
+
+impl SledDocumentStore {
+    /// Construct a new store over the given trees.
+    ///
+    /// The `catalog_tree` should only be used for this store.
+    #[must_use]
+    pub fn new(
+        changes_tree: sled::Tree,
+        document_tree: sled::Tree,
+        sync_states_tree: sled::Tree,
+        catalog_tree: sled::Tree,
+    ) -> Self {
+        Self {
+            changes_tree,
+            document_tree,
+            sync_states_tree,
+            catalog: catalog_tree,
+            lock: Arc::default(),
+        }
+    }
+
+    /// Convert the data trees from the legacy key layout, using the documents in the catalog as
+    /// the prefixes.
+    ///
+    /// See [`SledPersister::migrate`].
+    pub fn migrate(&self) -> Result<usize, SledPersisterError> {
+        let ids = self.list()?;
+        SledPersister::migrate(
+            &self.changes_tree,
+            &self.document_tree,
+            &self.sync_states_tree,
+            &ids.iter().map(String::as_str).collect::<Vec<_>>(),
+        )
+    }
+
+    fn persister(&self, id: &str) -> Result<SledPersister, SledPersisterError> {
+        SledPersister::new(
+            self.changes_tree.clone(),
+            self.document_tree.clone(),
+            self.sync_states_tree.clone(),
+            id,
+        )
+    }
+
+    /// Take the lock shared with the clones of the store, which only guards the trees so can't be
+    /// left inconsistent by a panic.
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The trees holding the document data, in the order used by transactions.
+    const fn data_trees(&self) -> [&sled::Tree; 3] {
+        [
+            &self.changes_tree,
+            &self.document_tree,
+            &self.sync_states_tree,
+        ]
+    }
+
+    /// Whether any of the data trees hold entries for the given document.
+    fn has_data(&self, id: &str) -> Result<bool, SledPersisterError> {
+        let prefix = SledPersister::key_prefix(id);
+        for tree in &self.data_trees() {
+            if tree.scan_prefix(&prefix).next().transpose()?.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Read all of the entries for the given document from each of the data trees.
+    #[allow(clippy::type_complexity)]
+    fn entries(&self, id: &str) -> Result<Vec<Vec<(sled::IVec, sled::IVec)>>, SledPersisterError> {
+        let prefix = SledPersister::key_prefix(id);
+        self.data_trees()
+            .iter()
+            .map(|tree| {
+                tree.scan_prefix(&prefix)
+                    .collect::<Result<_, _>>()
+                    .map_err(SledPersisterError::from)
+            })
+            .collect()
+    }
+}
+
+impl DocumentStore for SledDocumentStore {
+    type Persister = SledPersister;
+    type Error = SledPersisterError;
+
+    fn list(&self) -> Result<Vec<String>, Self::Error> {
+        self.catalog
+            .iter()
+            .keys()
+            .map(|k| Ok(String::from_utf8_lossy(&k?).into_owned()))
+            .collect()
+    }
+
+    fn exists(&self, id: &str) -> Result<bool, Self::Error> {
+        Ok(self.catalog.contains_key(id)?)
+    }
+
+    /// Fails with [`SledPersisterError::DocumentExists`] if the document is in the catalog or
+    /// its prefix already holds data.
+    fn create(&self, id: &str) -> Result<Self::Persister, Self::Error> {
+        let _lock = self.lock();
+        if self.exists(id)? || self.has_data(id)? {
+            return Err(SledPersisterError::DocumentExists(id.to_owned()));
+        }
+        // the persister is constructed first so that a failure leaves no catalog entry behind
+        let persister = self.persister(id)?;
+        self.catalog
+            .compare_and_swap(id, None as Option<&[u8]>, Some(&[]))?
+            .map_err(|_| SledPersisterError::DocumentExists(id.to_owned()))?;
+        Ok(persister)
+    }
+
+    fn open(&self, id: &str) -> Result<Self::Persister, Self::Error> {
+        if !self.exists(id)? {
+            return Err(SledPersisterError::DocumentNotFound(id.to_owned()));
+        }
+        self.persister(id)
+    }
+
+    /// The data is removed in a single transaction across the trees.
+    fn delete(&self, id: &str) -> Result<bool, Self::Error> {
+        let _lock = self.lock();
+        let entries = self.entries(id)?;
+        let existed = (
+            &self.changes_tree,
+            &self.document_tree,
+            &self.sync_states_tree,
+            &self.catalog,
+        )
+            .transaction(
+                |(changes_tree, document_tree, sync_states_tree, catalog_tree)| {
+                    for (tree, entries) in [changes_tree, document_tree, sync_states_tree]
+                        .iter()
+                        .zip(&entries)
+                    {
+                        for (key, _) in entries {
+                            tree.remove(key)?;
+                        }
+                    }
+                    Ok(catalog_tree.remove(id)?.is_some())
+                },
+            )?;
+        Ok(existed)
+    }
+
+    /// The data is moved in a single transaction across the trees, which also checks the catalog.
+    fn rename(&self, from: &str, to: &str) -> Result<(), Self::Error> {
+        let _lock = self.lock();
+        let from_prefix = SledPersister::key_prefix(from);
+        let to_prefix = SledPersister::key_prefix(to);
+        let entries = self.entries(from)?;
+        (
+            &self.changes_tree,
+            &self.document_tree,
+            &self.sync_states_tree,
+            &self.catalog,
+        )
+            .transaction(
+                |(changes_tree, document_tree, sync_states_tree, catalog_tree)| {
+                    if catalog_tree.get(from)?.is_none() {
+                        return Err(ConflictableTransactionError::Abort(
+                            SledPersisterError::DocumentNotFound(from.to_owned()),
+                        ));
+                    }
+                    if catalog_tree.get(to)?.is_some() {
+                        return Err(ConflictableTransactionError::Abort(
+                            SledPersisterError::DocumentExists(to.to_owned()),
+                        ));
+                    }
+                    for (tree, entries) in [changes_tree, document_tree, sync_states_tree]
+                        .iter()
+                        .zip(&entries)
+                    {
+                        for (key, value) in entries {
+                            let mut new_key = to_prefix.clone();
+                            new_key.extend(&key[from_prefix.len()..]);
+                            tree.remove(key)?;
+                            tree.insert(new_key, value)?;
+                        }
+                    }
+                    catalog_tree.remove(from)?;
+                    catalog_tree.insert(to, &[])?;
+                    Ok(())
+                },
+            )
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.into(),
+            })
+    }
+}
@@ -0,0 +1,64 @@
+use automerge_persistent_sled::SledPersister;
+use automerge_persistent_test_suite::{persister_tests, PersisterFixture};
+
+/// Each document gets its own trees.
+struct SledFixture {
+    db: sled::Db,
+}
+
+impl SledFixture {
+    fn new() -> Self {
+        Self {
+            db: sled::Config::new().temporary(true).open().unwrap(),
+        }
+    }
+}
+
+impl PersisterFixture for SledFixture {
+    type Persister = SledPersister;
+
+    fn open(&mut self, name: &str) -> Self::Persister {
+        SledPersister::new(
+            self.db.open_tree(format!("{}-changes", name)).unwrap(),
+            self.db.open_tree(format!("{}-document", name)).unwrap(),
+            self.db.open_tree(format!("{}-sync-states", name)).unwrap(),
+            "",
+        )
+        .unwrap()
+    }
+}
+
+persister_tests!(SledFixture::new());
+
+/// All documents share the same trees, told apart by their prefixes.
+struct SharedTreesFixture {
+    db: sled::Db,
+}
+
+impl SharedTreesFixture {
+    fn new() -> Self {
+        Self {
+            db: sled::Config::new().temporary(true).open().unwrap(),
+        }
+    }
+}
+
+impl PersisterFixture for SharedTreesFixture {
+    type Persister = SledPersister;
+
+    fn open(&mut self, name: &str) -> Self::Persister {
+        SledPersister::new(
+            self.db.open_tree("changes").unwrap(),
+            self.db.open_tree("documents").unwrap(),
+            self.db.open_tree("sync-states").unwrap(),
+            name,
+        )
+        .unwrap()
+    }
+}
+
+mod shared_trees {
+    use super::SharedTreesFixture;
+
+    automerge_persistent_test_suite::persister_tests!(SharedTreesFixture::new());
+}
@@ -0,0 +1,183 @@
+use std::{thread, time::Duration};
+
+use automerge::{Frontend, InvalidChangeRequest, LocalChange, Path, Primitive, Value};
+use automerge_persistent::{PersistentBackend, Persister};
+use automerge_persistent_sled::{apply_subscribed_changes_to_backend, SledFollower, SledPersister};
+use automerge_protocol::ActorId;
+
+fn persister(db: &sled::Db, prefix: &str) -> SledPersister {
+    SledPersister::new(
+        db.open_tree("changes").unwrap(),
+        db.open_tree("documents").unwrap(),
+        db.open_tree("sync-states").unwrap(),
+        prefix,
+    )
+    .unwrap()
+}
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn backend(db: &sled::Db) -> PersistentBackend<SledPersister, automerge::Backend> {
+    PersistentBackend::load(persister(db, "doc")).unwrap()
+}
+
+/// Make a local change setting `key` and apply it to the backend.
+fn change(
+    backend: &mut PersistentBackend<SledPersister, automerge::Backend>,
+    frontend: &mut Frontend,
+    key: &str,
+) {
+    let ((), change) = frontend
+        .change::<_, _, InvalidChangeRequest>(None, |doc| {
+            doc.add_change(LocalChange::set(
+                Path::root().key(key),
+                Value::Primitive(Primitive::Str(key.into())),
+            ))
+        })
+        .unwrap();
+    let patch = backend.apply_local_change(change.unwrap()).unwrap();
+    frontend.apply_patch(patch).unwrap();
+}
+
+#[test]
+fn subscriber_sees_changes_from_other_instances() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let p1 = persister(&db, "doc");
+    let mut p2 = persister(&db, "doc");
+    let mut subscriber = p1.watch_changes();
+    assert!(subscriber.pending().is_empty());
+
+    let actor = ActorId::from_bytes(&[1; 16]);
+    p2.insert_changes(vec![(&actor, 1, &[1][..]), (&actor, 2, &[2][..])])
+        .unwrap();
+    let mut changes = subscriber.wait(TIMEOUT);
+    changes.sort();
+    assert_eq!(changes, vec![vec![1], vec![2]]);
+
+    // removals aren't changes to follow
+    p2.remove_changes(vec![(&actor, 1)]).unwrap();
+    assert!(subscriber.wait(Duration::from_millis(10)).is_empty());
+}
+
+#[test]
+fn subscriber_ignores_other_prefixes() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let p1 = persister(&db, "doc");
+    let mut p2 = persister(&db, "doc2");
+    let mut subscriber = p1.watch_changes();
+
+    let actor = ActorId::from_bytes(&[1; 16]);
+    p2.insert_changes(vec![(&actor, 1, &[1][..])]).unwrap();
+    assert!(subscriber.wait(Duration::from_millis(10)).is_empty());
+}
+
+#[test]
+fn subscriber_waits_for_other_threads() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut subscriber = persister(&db, "doc").watch_changes();
+
+    let writer = {
+        let db = db.clone();
+        thread::spawn(move || {
+            let mut p = persister(&db, "doc");
+            let actor = ActorId::from_bytes(&[1; 16]);
+            for seq in 1..=3 {
+                p.insert_changes(vec![(&actor, seq, &[seq as u8][..])])
+                    .unwrap();
+            }
+        })
+    };
+
+    let mut changes = Vec::new();
+    while changes.len() < 3 {
+        let waited = subscriber.wait(TIMEOUT);
+        assert!(!waited.is_empty());
+        changes.extend(waited);
+    }
+    writer.join().unwrap();
+    assert_eq!(changes, vec![vec![1], vec![2], vec![3]]);
+}
+
+#[test]
+fn backend_follows_other_instances() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut writer = backend(&db);
+    let mut follower = backend(&db);
+    let mut subscriber = follower.persister().watch_changes();
+
+    change(&mut writer, &mut Frontend::new(), "a");
+    let changes = subscriber.wait(TIMEOUT);
+    assert!(apply_subscribed_changes_to_backend(&mut follower, changes)
+        .unwrap()
+        .is_some());
+    assert_eq!(follower.get_heads(), writer.get_heads());
+    // the change isn't inserted again, which the subscriber would see
+    assert!(subscriber.wait(Duration::from_millis(10)).is_empty());
+
+    // the follower's own changes are already applied
+    change(&mut follower, &mut Frontend::new(), "b");
+    let changes = subscriber.wait(TIMEOUT);
+    assert_eq!(changes.len(), 1);
+    assert!(apply_subscribed_changes_to_backend(&mut follower, changes)
+        .unwrap()
+        .is_none());
+    assert_eq!(follower.get_changes(&[]).len(), 2);
+    assert_eq!(follower.persister().get_changes().unwrap().len(), 2);
+}
+
+#[test]
+fn backend_skips_queued_changes() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut writer = backend(&db);
+    let mut frontend = Frontend::new();
+    change(&mut writer, &mut frontend, "a");
+    change(&mut writer, &mut frontend, "b");
+    let changes = writer
+        .get_changes(&[])
+        .into_iter()
+        .map(|c| c.raw_bytes().to_vec())
+        .collect::<Vec<_>>();
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut follower = backend(&db);
+    let mut subscriber = follower.persister().watch_changes();
+    // the second change is queued until the first arrives
+    apply_subscribed_changes_to_backend(&mut follower, vec![changes[1].clone()]).unwrap();
+    assert!(follower.get_changes(&[]).is_empty());
+    assert!(
+        apply_subscribed_changes_to_backend(&mut follower, vec![changes[1].clone()])
+            .unwrap()
+            .is_none()
+    );
+    assert!(
+        apply_subscribed_changes_to_backend(&mut follower, vec![changes[0].clone()])
+            .unwrap()
+            .is_some()
+    );
+    assert_eq!(follower.get_heads(), writer.get_heads());
+    // nothing was written for the followed changes
+    assert!(subscriber.pending().is_empty());
+    assert!(follower.persister().get_changes().unwrap().is_empty());
+}
+
+#[test]
+fn follower_follows_writer() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut writer = backend(&db);
+    let mut frontend = Frontend::new();
+    change(&mut writer, &mut frontend, "a");
+    writer.compact(&[]).unwrap();
+    change(&mut writer, &mut frontend, "b");
+
+    let mut follower = SledFollower::<automerge::Backend>::new(writer.persister()).unwrap();
+    assert_eq!(follower.backend().get_heads(), writer.get_heads());
+    assert!(follower.poll().unwrap().is_none());
+
+    let changes = db.open_tree("changes").unwrap().len();
+    change(&mut writer, &mut frontend, "c");
+    assert!(follower.wait(TIMEOUT).unwrap().is_some());
+    assert_eq!(follower.backend().get_heads(), writer.get_heads());
+    assert!(follower.poll().unwrap().is_none());
+    // only the writer's change was inserted
+    assert_eq!(db.open_tree("changes").unwrap().len(), changes + 1);
+}
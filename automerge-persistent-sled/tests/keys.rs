@@ -0,0 +1,59 @@
+use automerge_persistent::Persister;
+use automerge_persistent_sled::{SledPersister, SledPersisterError};
+use automerge_protocol::ActorId;
+
+fn persister(db: &sled::Db, prefix: &str) -> SledPersister {
+    SledPersister::new(
+        db.open_tree("changes").unwrap(),
+        db.open_tree("documents").unwrap(),
+        db.open_tree("sync-states").unwrap(),
+        prefix,
+    )
+    .unwrap()
+}
+
+/// The only key in `tree` with the actor id and sequence number cut off the end.
+fn key_prefix(tree: &sled::Tree, actor_id: &ActorId) -> Vec<u8> {
+    let (key, _) = tree.iter().next().unwrap().unwrap();
+    key[..key.len() - actor_id.to_bytes().len() - 8].to_vec()
+}
+
+fn is_malformed<T>(result: Result<T, SledPersisterError>, key: &[u8]) -> bool {
+    matches!(result, Err(SledPersisterError::MalformedChangeKey(k)) if k == key)
+}
+
+#[test]
+fn short_change_key_is_malformed() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut p = persister(&db, "doc");
+    let actor_id = ActorId::random();
+    p.insert_changes(vec![(&actor_id, 1, &[1][..])]).unwrap();
+    let changes_tree = db.open_tree("changes").unwrap();
+    let mut key = key_prefix(&changes_tree, &actor_id);
+    key.extend(&[1, 2, 3]);
+    changes_tree.insert(&key, vec![2]).unwrap();
+
+    assert!(is_malformed(p.get_changes_with_keys(), &key));
+    let iterated = p
+        .iter_changes_with_keys()
+        .unwrap()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>();
+    assert!(is_malformed(iterated, &key));
+}
+
+#[test]
+fn short_quarantined_change_key_is_malformed() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut p = persister(&db, "doc");
+    let actor_id = ActorId::random();
+    p.insert_changes(vec![(&actor_id, 1, &[1][..])]).unwrap();
+    assert!(p.quarantine_changes(vec![(&actor_id, 1)]).unwrap());
+    let document_tree = db.open_tree("documents").unwrap();
+    let mut key = key_prefix(&document_tree, &actor_id);
+    p.remove_quarantined_changes(vec![(&actor_id, 1)]).unwrap();
+    key.extend(&[1, 2, 3]);
+    document_tree.insert(&key, vec![2]).unwrap();
+
+    assert!(is_malformed(p.get_quarantined_changes(), &key));
+}
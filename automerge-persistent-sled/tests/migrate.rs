@@ -0,0 +1,247 @@
+use automerge::{Frontend, InvalidChangeRequest, LocalChange, Path, Primitive, Value};
+use automerge_persistent::{DocumentStore, MemoryPersister, PersistentBackend, Persister};
+use automerge_persistent_sled::{SledDocumentStore, SledPersister, SledPersisterError};
+use automerge_protocol::ActorId;
+
+struct Trees {
+    changes: sled::Tree,
+    documents: sled::Tree,
+    sync_states: sled::Tree,
+    catalog: sled::Tree,
+}
+
+fn trees() -> Trees {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    Trees {
+        changes: db.open_tree("changes").unwrap(),
+        documents: db.open_tree("documents").unwrap(),
+        sync_states: db.open_tree("sync-states").unwrap(),
+        catalog: db.open_tree("catalog").unwrap(),
+    }
+}
+
+/// The actor id, sequence number and encoding of a new change from a new actor.
+fn encoded_change() -> (ActorId, u64, Vec<u8>) {
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(MemoryPersister::default()).unwrap();
+    let ((), change) = Frontend::new()
+        .change::<_, _, InvalidChangeRequest>(None, |doc| {
+            doc.add_change(LocalChange::set(
+                Path::root().key("a"),
+                Value::Primitive(Primitive::Str("a".into())),
+            ))
+        })
+        .unwrap();
+    backend.apply_local_change(change.unwrap()).unwrap();
+    backend
+        .persister()
+        .get_changes_with_keys()
+        .unwrap()
+        .unwrap()
+        .remove(0)
+}
+
+/// Insert a change in the legacy layout, with the key made of the raw prefix, actor id and
+/// sequence number all run together.
+fn write_legacy_change(trees: &Trees, prefix: &str, actor_id: &ActorId, seq: u64, data: &[u8]) {
+    let mut key = prefix.as_bytes().to_vec();
+    key.extend(actor_id.to_bytes());
+    key.extend(&seq.to_be_bytes());
+    trees.changes.insert(key, data).unwrap();
+}
+
+/// Write a document in the legacy layout, with keys starting with the raw prefix, returning its
+/// change.
+fn write_legacy(trees: &Trees, prefix: &str, n: u8) -> (ActorId, u64, Vec<u8>) {
+    let change = encoded_change();
+    write_legacy_change(trees, prefix, &change.0, change.1, &change.2);
+
+    trees.documents.insert(prefix, vec![n, n]).unwrap();
+    let mut actor_id_key = prefix.as_bytes().to_vec();
+    actor_id_key.extend(b"\0actor_id");
+    trees.documents.insert(actor_id_key, vec![n; 16]).unwrap();
+
+    let mut peer_key = prefix.as_bytes().to_vec();
+    peer_key.extend(b"peer");
+    trees.sync_states.insert(peer_key, vec![n, n, n]).unwrap();
+    change
+}
+
+fn open(trees: &Trees, prefix: &str) -> Result<SledPersister, SledPersisterError> {
+    SledPersister::new(
+        trees.changes.clone(),
+        trees.documents.clone(),
+        trees.sync_states.clone(),
+        prefix,
+    )
+}
+
+fn assert_legacy_data(persister: &SledPersister, n: u8, change: (ActorId, u64, Vec<u8>)) {
+    assert_eq!(
+        persister.get_changes_with_keys().unwrap(),
+        Some(vec![change])
+    );
+    assert_eq!(persister.get_document().unwrap(), Some(vec![n, n]));
+    assert_eq!(
+        persister.get_actor_id().unwrap(),
+        Some(ActorId::from_bytes(&[n; 16]))
+    );
+    assert_eq!(persister.get_peer_ids().unwrap(), vec![b"peer".to_vec()]);
+    assert_eq!(
+        persister.get_sync_state(b"peer").unwrap(),
+        Some(vec![n, n, n])
+    );
+}
+
+#[test]
+fn legacy_trees_need_migrating() {
+    let trees = trees();
+    write_legacy(&trees, "1", 1);
+    assert!(matches!(
+        open(&trees, "1"),
+        Err(SledPersisterError::LegacyLayout)
+    ));
+}
+
+#[test]
+fn migrate_overlapping_prefixes() {
+    let trees = trees();
+    let change1 = write_legacy(&trees, "1", 1);
+    let change2 = write_legacy(&trees, "12", 2);
+    let moved = SledPersister::migrate(
+        &trees.changes,
+        &trees.documents,
+        &trees.sync_states,
+        &["1", "12"],
+    )
+    .unwrap();
+    assert_eq!(moved, 8);
+
+    assert_legacy_data(&open(&trees, "1").unwrap(), 1, change1);
+    assert_legacy_data(&open(&trees, "12").unwrap(), 2, change2);
+    assert!(open(&trees, "").unwrap().get_changes().unwrap().is_empty());
+
+    // migrating again does nothing
+    let moved = SledPersister::migrate(
+        &trees.changes,
+        &trees.documents,
+        &trees.sync_states,
+        &["1", "12"],
+    )
+    .unwrap();
+    assert_eq!(moved, 0);
+}
+
+#[test]
+fn migrate_unknown_prefix() {
+    let trees = trees();
+    write_legacy(&trees, "1", 1);
+    write_legacy(&trees, "2", 2);
+    assert!(matches!(
+        SledPersister::migrate(&trees.changes, &trees.documents, &trees.sync_states, &["1"]),
+        Err(SledPersisterError::UnknownLegacyKey(_))
+    ));
+    // nothing was moved
+    assert!(matches!(
+        open(&trees, "1"),
+        Err(SledPersisterError::LegacyLayout)
+    ));
+}
+
+#[test]
+fn migrate_after_failing() {
+    let trees = trees();
+    let change1 = write_legacy(&trees, "1", 1);
+    let change2 = write_legacy(&trees, "2", 2);
+    assert!(
+        SledPersister::migrate(&trees.changes, &trees.documents, &trees.sync_states, &["1"])
+            .is_err()
+    );
+    let moved = SledPersister::migrate(
+        &trees.changes,
+        &trees.documents,
+        &trees.sync_states,
+        &["1", "2"],
+    )
+    .unwrap();
+    assert_eq!(moved, 8);
+    assert_legacy_data(&open(&trees, "1").unwrap(), 1, change1);
+    assert_legacy_data(&open(&trees, "2").unwrap(), 2, change2);
+}
+
+#[test]
+fn migrate_undecodable_changes() {
+    let trees = trees();
+    let actor_id = ActorId::from_bytes(&[1; 16]);
+    write_legacy_change(&trees, "a", &actor_id, 1, &[1]);
+    let prefixes = &["a", "b"];
+    assert_eq!(
+        SledPersister::migrate(
+            &trees.changes,
+            &trees.documents,
+            &trees.sync_states,
+            prefixes
+        )
+        .unwrap(),
+        1
+    );
+    assert_eq!(
+        open(&trees, "a").unwrap().get_changes_with_keys().unwrap(),
+        Some(vec![(actor_id, 1, vec![1])])
+    );
+
+    // the change doesn't say whether it is under "a" or "ab"
+    let trees = self::trees();
+    write_legacy_change(&trees, "ab", &ActorId::from_bytes(&[1; 16]), 1, &[1]);
+    assert!(matches!(
+        SledPersister::migrate(
+            &trees.changes,
+            &trees.documents,
+            &trees.sync_states,
+            &["a", "ab"]
+        ),
+        Err(SledPersisterError::AmbiguousLegacyKey(_))
+    ));
+}
+
+#[test]
+fn migrate_in_batches() {
+    let trees = trees();
+    let (actor_id, _, _) = encoded_change();
+    for seq in 1..=3000 {
+        write_legacy_change(&trees, "1", &actor_id, seq, &seq.to_be_bytes());
+    }
+    let moved =
+        SledPersister::migrate(&trees.changes, &trees.documents, &trees.sync_states, &["1"])
+            .unwrap();
+    assert_eq!(moved, 3000);
+    let changes = open(&trees, "1")
+        .unwrap()
+        .get_changes_with_keys()
+        .unwrap()
+        .unwrap();
+    assert_eq!(changes.len(), 3000);
+    assert!(changes
+        .iter()
+        .all(|(a, seq, data)| a == &actor_id && data == &seq.to_be_bytes()));
+    // only the layout key is left besides the data
+    assert_eq!(trees.documents.len(), 1);
+}
+
+#[test]
+fn migrate_store() {
+    let trees = trees();
+    let change1 = write_legacy(&trees, "doc", 1);
+    let change2 = write_legacy(&trees, "doc2", 2);
+    trees.catalog.insert("doc", &[]).unwrap();
+    trees.catalog.insert("doc2", &[]).unwrap();
+    let store = SledDocumentStore::new(
+        trees.changes.clone(),
+        trees.documents.clone(),
+        trees.sync_states.clone(),
+        trees.catalog.clone(),
+    );
+    assert_eq!(store.migrate().unwrap(), 8);
+    assert_legacy_data(&store.open("doc").unwrap(), 1, change1);
+    assert_legacy_data(&store.open("doc2").unwrap(), 2, change2);
+}
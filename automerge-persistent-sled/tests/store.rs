@@ -0,0 +1,197 @@
+use automerge_persistent::{DocumentStore, Persister};
+use automerge_persistent_sled::{SledDocumentStore, SledPersister, SledPersisterError};
+use automerge_protocol::ActorId;
+
+fn store() -> SledDocumentStore {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    SledDocumentStore::new(
+        db.open_tree("changes").unwrap(),
+        db.open_tree("documents").unwrap(),
+        db.open_tree("sync-states").unwrap(),
+        db.open_tree("catalog").unwrap(),
+    )
+}
+
+fn fill(persister: &mut impl Persister) {
+    let actor = ActorId::from_bytes(&[1; 16]);
+    persister
+        .insert_changes(vec![(&actor, 1, &[1][..]), (&actor, 2, &[2, 2][..])])
+        .unwrap();
+    persister.set_document(vec![3, 3, 3]).unwrap();
+    persister.set_sync_state(b"peer".to_vec(), vec![4]).unwrap();
+}
+
+#[test]
+fn create_and_list() {
+    let store = store();
+    assert!(store.list().unwrap().is_empty());
+    store.create("b").unwrap();
+    store.create("a").unwrap();
+    assert_eq!(store.list().unwrap(), vec!["a".to_owned(), "b".to_owned()]);
+    assert!(store.exists("a").unwrap());
+    assert!(!store.exists("c").unwrap());
+    assert!(matches!(
+        store.create("a"),
+        Err(SledPersisterError::DocumentExists(id)) if id == "a"
+    ));
+    assert!(matches!(
+        store.open("c"),
+        Err(SledPersisterError::DocumentNotFound(id)) if id == "c"
+    ));
+}
+
+#[test]
+fn create_refuses_uncatalogued_data() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let trees = || {
+        (
+            db.open_tree("changes").unwrap(),
+            db.open_tree("documents").unwrap(),
+            db.open_tree("sync-states").unwrap(),
+        )
+    };
+    let (changes, documents, sync_states) = trees();
+    let mut persister = SledPersister::new(changes, documents, sync_states, "doc").unwrap();
+    persister.set_sync_state(b"peer".to_vec(), vec![4]).unwrap();
+
+    let (changes, documents, sync_states) = trees();
+    let store = SledDocumentStore::new(
+        changes,
+        documents,
+        sync_states,
+        db.open_tree("catalog").unwrap(),
+    );
+    assert!(matches!(
+        store.create("doc"),
+        Err(SledPersisterError::DocumentExists(id)) if id == "doc"
+    ));
+    assert!(store.list().unwrap().is_empty());
+    store.create("other").unwrap();
+}
+
+#[test]
+fn failed_create_leaves_no_catalog_entry() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    // an entry without the layout marker makes the persister ask for the trees to be migrated
+    let changes = db.open_tree("changes").unwrap();
+    changes.insert(b"legacy", vec![1]).unwrap();
+    let store = SledDocumentStore::new(
+        changes,
+        db.open_tree("documents").unwrap(),
+        db.open_tree("sync-states").unwrap(),
+        db.open_tree("catalog").unwrap(),
+    );
+    assert!(matches!(
+        store.create("doc"),
+        Err(SledPersisterError::LegacyLayout)
+    ));
+    assert!(store.list().unwrap().is_empty());
+}
+
+#[test]
+fn open_sees_data() {
+    let store = store();
+    fill(&mut store.create("doc").unwrap());
+    let persister = store.open("doc").unwrap();
+    assert_eq!(persister.get_changes().unwrap().len(), 2);
+    assert_eq!(persister.get_document().unwrap(), Some(vec![3, 3, 3]));
+    assert_eq!(persister.sizes().changes, 3);
+}
+
+#[test]
+fn delete() {
+    let store = store();
+    fill(&mut store.create("doc").unwrap());
+    fill(&mut store.create("other").unwrap());
+    assert!(store.delete("doc").unwrap());
+    assert!(!store.delete("doc").unwrap());
+    assert_eq!(store.list().unwrap(), vec!["other".to_owned()]);
+
+    let persister = store.create("doc").unwrap();
+    assert!(persister.get_changes().unwrap().is_empty());
+    assert_eq!(persister.get_document().unwrap(), None);
+    assert!(persister.get_sync_state(b"peer").unwrap().is_none());
+    assert_eq!(store.open("other").unwrap().get_changes().unwrap().len(), 2);
+}
+
+#[test]
+fn rename() {
+    let store = store();
+    fill(&mut store.create("old").unwrap());
+    store.create("taken").unwrap();
+    assert!(matches!(
+        store.rename("old", "taken"),
+        Err(SledPersisterError::DocumentExists(_))
+    ));
+    assert!(matches!(
+        store.rename("missing", "new"),
+        Err(SledPersisterError::DocumentNotFound(_))
+    ));
+
+    store.rename("old", "new").unwrap();
+    assert_eq!(
+        store.list().unwrap(),
+        vec!["new".to_owned(), "taken".to_owned()]
+    );
+    let persister = store.open("new").unwrap();
+    assert_eq!(persister.get_changes().unwrap().len(), 2);
+    assert_eq!(persister.get_document().unwrap(), Some(vec![3, 3, 3]));
+    assert_eq!(persister.get_sync_state(b"peer").unwrap(), Some(vec![4]));
+}
+
+#[test]
+fn concurrent_renames_move_the_data_once() {
+    for _ in 0..20 {
+        let store = store();
+        fill(&mut store.create("old").unwrap());
+        let renames = ["first", "second"]
+            .iter()
+            .map(|to| {
+                let store = store.clone();
+                std::thread::spawn(move || store.rename("old", to))
+            })
+            .collect::<Vec<_>>();
+        let results = renames
+            .into_iter()
+            .map(|rename| rename.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .any(|result| matches!(result, Err(SledPersisterError::DocumentNotFound(_)))));
+
+        let list = store.list().unwrap();
+        assert_eq!(list.len(), 1);
+        let persister = store.open(&list[0]).unwrap();
+        assert_eq!(persister.get_changes().unwrap().len(), 2);
+        assert_eq!(persister.get_document().unwrap(), Some(vec![3, 3, 3]));
+    }
+}
+
+#[test]
+fn concurrent_rename_and_delete_leave_no_stray_data() {
+    for _ in 0..20 {
+        let store = store();
+        fill(&mut store.create("old").unwrap());
+        let renamer = store.clone();
+        let rename = std::thread::spawn(move || renamer.rename("old", "new"));
+        let deleter = store.clone();
+        let delete = std::thread::spawn(move || deleter.delete("old").unwrap());
+        let renamed = rename.join().unwrap().is_ok();
+        let deleted = delete.join().unwrap();
+        assert!(renamed ^ deleted);
+
+        if renamed {
+            let persister = store.open("new").unwrap();
+            assert_eq!(persister.get_changes().unwrap().len(), 2);
+            assert_eq!(persister.get_document().unwrap(), Some(vec![3, 3, 3]));
+        } else {
+            assert!(store.list().unwrap().is_empty());
+            let persister = store.create("new").unwrap();
+            assert!(persister.get_changes().unwrap().is_empty());
+        }
+        let persister = store.create("old").unwrap();
+        assert!(persister.get_changes().unwrap().is_empty());
+        assert_eq!(persister.get_document().unwrap(), None);
+    }
+}
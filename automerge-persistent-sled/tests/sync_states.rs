@@ -0,0 +1,59 @@
+use std::time::SystemTime;
+
+use automerge_persistent::Persister;
+use automerge_persistent_sled::SledPersister;
+
+fn persister(db: &sled::Db, prefix: &str) -> SledPersister {
+    SledPersister::new(
+        db.open_tree("changes").unwrap(),
+        db.open_tree("documents").unwrap(),
+        db.open_tree("sync-states").unwrap(),
+        prefix,
+    )
+    .unwrap()
+}
+
+#[test]
+fn sync_state_info() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut p = persister(&db, "doc");
+    let before = SystemTime::now();
+    p.set_sync_state(b"a".to_vec(), vec![1, 2, 3]).unwrap();
+    p.set_sync_state(b"b".to_vec(), vec![1]).unwrap();
+
+    let infos = p.sync_states().unwrap();
+    assert_eq!(
+        infos
+            .iter()
+            .map(|info| (info.peer_id.clone(), info.size))
+            .collect::<Vec<_>>(),
+        vec![(b"a".to_vec(), 3), (b"b".to_vec(), 1)]
+    );
+    for info in &infos {
+        // the time is stored to the millisecond
+        let updated = info.updated.unwrap();
+        assert!(before
+            .duration_since(updated)
+            .map_or(true, |d| d.as_millis() < 1));
+        assert!(updated <= SystemTime::now());
+    }
+}
+
+#[test]
+fn prune_with_peer_ids() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let mut p = persister(&db, "doc");
+    p.set_sync_state(b"a".to_vec(), vec![1]).unwrap();
+    p.set_sync_state(b"b".to_vec(), vec![2]).unwrap();
+
+    let peer_ids = p.get_peer_ids().unwrap();
+    p.remove_sync_states(&[&peer_ids[0]]).unwrap();
+    assert_eq!(p.get_peer_ids().unwrap(), vec![b"b".to_vec()]);
+    p.compact(Vec::new(), Vec::new(), &[&peer_ids[1]]).unwrap();
+    assert!(p.get_peer_ids().unwrap().is_empty());
+    assert!(p.sync_states().unwrap().is_empty());
+    assert_eq!(p.sizes().sync_states, 0);
+
+    // only the layout marker and the document are left alongside the document
+    assert_eq!(db.open_tree("documents").unwrap().len(), 2);
+}
@@ -0,0 +1,522 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting [SQLite](https://sqlite.org) through [`rusqlite`].
+//!
+//! The data is stored in four tables, created if they do not already exist:
+//!
+//! - `automerge_changes`: changes keyed by `(doc, actor, seq)`
+//! - `automerge_quarantined_changes`: quarantined changes keyed by `(doc, actor, seq)`
+//! - `automerge_documents`: the compacted document and actor id keyed by `doc`
+//! - `automerge_sync_states`: sync states keyed by `(doc, peer)`
+//!
+//! so multiple documents can share the same database, along with any other tables.
+//!
+//! ```rust
+//! # use automerge_persistent::PersistentBackend;
+//! # use automerge_persistent_sqlite::SqlitePersister;
+//! # use automerge_persistent_sqlite::SqlitePersisterError;
+//! # use automerge::Backend;
+//! # fn main() -> Result<(), SqlitePersisterError> {
+//! let connection = rusqlite::Connection::open_in_memory()?;
+//!
+//! let persister = SqlitePersister::new(connection, "document")?;
+//! let backend = PersistentBackend::<_, Backend>::load(persister);
+//! # Ok(())
+//! # }
+//! ```
+
+use automerge_persistent::{ChangeIter, KeyedChangeIter, Persister, StoredSizes};
+use automerge_protocol::ActorId;
+use rusqlite::{params, Connection, OptionalExtension};
+
+/// The persister that stores changes and documents in an `SQLite` database.
+#[derive(Debug)]
+pub struct SqlitePersister {
+    connection: Connection,
+    doc_id: String,
+    sizes: StoredSizes,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum SqlitePersisterError {
+    /// Internal errors from `SQLite`.
+    #[error(transparent)]
+    SqliteError(#[from] rusqlite::Error),
+}
+
+impl SqlitePersister {
+    /// Construct a new persister for the document `doc_id`, creating the tables if needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tables could not be created or the existing data could not be read.
+    pub fn new<S>(connection: Connection, doc_id: S) -> Result<Self, SqlitePersisterError>
+    where
+        S: Into<String>,
+    {
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS automerge_changes (
+                doc TEXT NOT NULL,
+                actor BLOB NOT NULL,
+                seq INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (doc, actor, seq)
+            );
+            CREATE TABLE IF NOT EXISTS automerge_quarantined_changes (
+                doc TEXT NOT NULL,
+                actor BLOB NOT NULL,
+                seq INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (doc, actor, seq)
+            );
+            CREATE TABLE IF NOT EXISTS automerge_documents (
+                doc TEXT NOT NULL PRIMARY KEY,
+                data BLOB,
+                actor_id BLOB
+            );
+            CREATE TABLE IF NOT EXISTS automerge_sync_states (
+                doc TEXT NOT NULL,
+                peer BLOB NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (doc, peer)
+            );",
+        )?;
+        let doc_id = doc_id.into();
+
+        let sizes = StoredSizes {
+            changes: sum_lengths(&connection, "automerge_changes", &doc_id)?,
+            document: sum_lengths(&connection, "automerge_documents", &doc_id)?,
+            sync_states: sum_lengths(&connection, "automerge_sync_states", &doc_id)?,
+            uncompressed: None,
+        };
+        Ok(Self {
+            connection,
+            doc_id,
+            sizes,
+        })
+    }
+
+    /// Obtain a reference to the underlying connection, for instance to query other tables.
+    pub const fn connection(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+/// Total length of the `data` column for the document in `table`.
+fn sum_lengths(
+    connection: &Connection,
+    table: &str,
+    doc_id: &str,
+) -> Result<usize, SqlitePersisterError> {
+    let sum: i64 = connection.query_row(
+        &format!(
+            "SELECT COALESCE(SUM(LENGTH(data)), 0) FROM {} WHERE doc = ?",
+            table
+        ),
+        params![doc_id],
+        |row| row.get(0),
+    )?;
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Ok(sum as usize)
+}
+
+/// Length of the `data` column of the row matched by `query`, if there is one.
+fn data_length<P>(
+    transaction: &rusqlite::Transaction,
+    query: &str,
+    params: P,
+) -> Result<usize, SqlitePersisterError>
+where
+    P: IntoIterator,
+    P::Item: rusqlite::ToSql,
+{
+    let length: Option<i64> = transaction
+        .query_row(query, params, |row| row.get(0))
+        .optional()?
+        .flatten();
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Ok(length.unwrap_or_default() as usize)
+}
+
+/// Sequence numbers are stored as `SQLite` integers, which are signed.
+#[allow(clippy::cast_possible_wrap)]
+const fn seq_to_sql(seq: u64) -> i64 {
+    seq as i64
+}
+
+#[allow(clippy::cast_sign_loss)]
+const fn seq_from_sql(seq: i64) -> u64 {
+    seq as u64
+}
+
+/// The number of changes read by each query of a [`ChangePages`].
+const CHANGE_PAGE_SIZE: i64 = 256;
+
+/// A row of the changes table: its `rowid`, actor id, sequence number and change.
+type ChangeRow = (i64, ActorId, u64, Vec<u8>);
+
+/// Reads the changes for a document along with their keys a page at a time, in `rowid` order.
+struct ChangePages<'a> {
+    connection: &'a Connection,
+    doc_id: &'a str,
+    last_rowid: i64,
+    page: std::vec::IntoIter<ChangeRow>,
+}
+
+impl<'a> ChangePages<'a> {
+    fn new(connection: &'a Connection, doc_id: &'a str) -> Self {
+        Self {
+            connection,
+            doc_id,
+            last_rowid: i64::MIN,
+            page: Vec::new().into_iter(),
+        }
+    }
+
+    fn next_page(&self) -> Result<Vec<ChangeRow>, SqlitePersisterError> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT rowid, actor, seq, data FROM automerge_changes WHERE doc = ? AND rowid > ?
+             ORDER BY rowid LIMIT ?",
+        )?;
+        let page = statement
+            .query_map(
+                params![self.doc_id, self.last_rowid, CHANGE_PAGE_SIZE],
+                |row| {
+                    let actor_id: Vec<u8> = row.get(1)?;
+                    Ok((
+                        row.get(0)?,
+                        ActorId::from_bytes(&actor_id),
+                        seq_from_sql(row.get(2)?),
+                        row.get(3)?,
+                    ))
+                },
+            )?
+            .collect::<Result<_, _>>()?;
+        Ok(page)
+    }
+}
+
+impl Iterator for ChangePages<'_> {
+    type Item = Result<(ActorId, u64, Vec<u8>), SqlitePersisterError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.as_slice().is_empty() {
+            match self.next_page() {
+                Ok(page) => self.page = page.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let (rowid, actor_id, seq, data) = self.page.next()?;
+        self.last_rowid = rowid;
+        Some(Ok((actor_id, seq, data)))
+    }
+}
+
+impl Persister for SqlitePersister {
+    type Error = SqlitePersisterError;
+
+    /// Get all of the current changes.
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT data FROM automerge_changes WHERE doc = ?")?;
+        let changes = statement
+            .query_map(params![self.doc_id], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(changes)
+    }
+
+    /// Read the changes a page at a time as they are iterated.
+    fn iter_changes(&self) -> Result<ChangeIter<'_, Self::Error>, Self::Error> {
+        Ok(Box::new(
+            ChangePages::new(&self.connection, &self.doc_id).map(|c| c.map(|(_, _, c)| c)),
+        ))
+    }
+
+    /// Read the changes along with their keys a page at a time as they are iterated.
+    fn iter_changes_with_keys(
+        &self,
+    ) -> Result<Option<KeyedChangeIter<'_, Self::Error>>, Self::Error> {
+        Ok(Some(Box::new(ChangePages::new(
+            &self.connection,
+            &self.doc_id,
+        ))))
+    }
+
+    fn get_changes_with_keys(&self) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT actor, seq, data FROM automerge_changes WHERE doc = ?")?;
+        let changes = statement
+            .query_map(params![self.doc_id], |row| {
+                let actor_id: Vec<u8> = row.get(0)?;
+                Ok((
+                    ActorId::from_bytes(&actor_id),
+                    seq_from_sql(row.get(1)?),
+                    row.get(2)?,
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(Some(changes))
+    }
+
+    /// Insert all of the given changes in a single transaction.
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error> {
+        let transaction = self.connection.transaction()?;
+        let mut added = 0;
+        let mut removed = 0;
+        for (a, s, c) in changes {
+            let actor = a.to_bytes();
+            removed += data_length(
+                &transaction,
+                "SELECT LENGTH(data) FROM automerge_changes WHERE doc = ? AND actor = ? AND seq = ?",
+                params![self.doc_id, actor, seq_to_sql(s)],
+            )?;
+            added += c.len();
+            transaction.execute(
+                "INSERT OR REPLACE INTO automerge_changes (doc, actor, seq, data) VALUES (?, ?, ?, ?)",
+                params![self.doc_id, actor, seq_to_sql(s), c],
+            )?;
+        }
+        transaction.commit()?;
+        self.sizes.changes = self.sizes.changes + added - removed;
+        Ok(())
+    }
+
+    /// Remove all of the given changes in a single transaction.
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let transaction = self.connection.transaction()?;
+        let removed = remove_changes(&transaction, &self.doc_id, changes)?;
+        transaction.commit()?;
+        self.sizes.changes -= removed;
+        Ok(())
+    }
+
+    /// Move the changes to the quarantine table in a single transaction.
+    fn quarantine_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<bool, Self::Error> {
+        let transaction = self.connection.transaction()?;
+        for (a, s) in &changes {
+            transaction.execute(
+                "INSERT OR REPLACE INTO automerge_quarantined_changes (doc, actor, seq, data)
+                 SELECT doc, actor, seq, data FROM automerge_changes
+                 WHERE doc = ? AND actor = ? AND seq = ?",
+                params![self.doc_id, a.to_bytes(), seq_to_sql(*s)],
+            )?;
+        }
+        let removed = remove_changes(&transaction, &self.doc_id, changes)?;
+        transaction.commit()?;
+        self.sizes.changes -= removed;
+        Ok(true)
+    }
+
+    fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT actor, seq, data FROM automerge_quarantined_changes WHERE doc = ?",
+        )?;
+        let changes = statement
+            .query_map(params![self.doc_id], |row| {
+                let actor_id: Vec<u8> = row.get(0)?;
+                Ok((
+                    ActorId::from_bytes(&actor_id),
+                    seq_from_sql(row.get(1)?),
+                    row.get(2)?,
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(changes)
+    }
+
+    fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        let transaction = self.connection.transaction()?;
+        for (a, s) in changes {
+            transaction.execute(
+                "DELETE FROM automerge_quarantined_changes WHERE doc = ? AND actor = ? AND seq = ?",
+                params![self.doc_id, a.to_bytes(), seq_to_sql(s)],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Retrieve the document from the table.
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        let document = self
+            .connection
+            .query_row(
+                "SELECT data FROM automerge_documents WHERE doc = ?",
+                params![self.doc_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(document)
+    }
+
+    /// Set the document in the table.
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        set_document(&self.connection, &self.doc_id, &data)?;
+        self.sizes.document = data.len();
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let sync_state = self
+            .connection
+            .query_row(
+                "SELECT data FROM automerge_sync_states WHERE doc = ? AND peer = ?",
+                params![self.doc_id, peer_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(sync_state)
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let transaction = self.connection.transaction()?;
+        let removed = data_length(
+            &transaction,
+            "SELECT LENGTH(data) FROM automerge_sync_states WHERE doc = ? AND peer = ?",
+            params![self.doc_id, peer_id],
+        )?;
+        let added = sync_state.len();
+        transaction.execute(
+            "INSERT OR REPLACE INTO automerge_sync_states (doc, peer, data) VALUES (?, ?, ?)",
+            params![self.doc_id, peer_id, sync_state],
+        )?;
+        transaction.commit()?;
+        self.sizes.sync_states = self.sizes.sync_states + added - removed;
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let transaction = self.connection.transaction()?;
+        let removed = remove_sync_states(&transaction, &self.doc_id, peer_ids)?;
+        transaction.commit()?;
+        self.sizes.sync_states -= removed;
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT peer FROM automerge_sync_states WHERE doc = ?")?;
+        let peer_ids = statement
+            .query_map(params![self.doc_id], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(peer_ids)
+    }
+
+    fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        let actor_id: Option<Vec<u8>> = self
+            .connection
+            .query_row(
+                "SELECT actor_id FROM automerge_documents WHERE doc = ?",
+                params![self.doc_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(actor_id.map(|a| ActorId::from_bytes(&a)))
+    }
+
+    fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        self.connection.execute(
+            "INSERT INTO automerge_documents (doc, actor_id) VALUES (?, ?)
+            ON CONFLICT (doc) DO UPDATE SET actor_id = excluded.actor_id",
+            params![self.doc_id, actor_id.to_bytes()],
+        )?;
+        Ok(())
+    }
+
+    /// Set the document and remove the changes and sync states in a single transaction.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let transaction = self.connection.transaction()?;
+        set_document(&transaction, &self.doc_id, &document)?;
+        let removed_changes = remove_changes(&transaction, &self.doc_id, changes)?;
+        let removed_sync_states = remove_sync_states(&transaction, &self.doc_id, old_peer_ids)?;
+        transaction.commit()?;
+
+        self.sizes.document = document.len();
+        self.sizes.changes -= removed_changes;
+        self.sizes.sync_states -= removed_sync_states;
+        Ok(())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Every operation is committed as it happens so there is nothing to flush.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
+
+fn set_document(
+    connection: &Connection,
+    doc_id: &str,
+    data: &[u8],
+) -> Result<(), SqlitePersisterError> {
+    connection.execute(
+        "INSERT INTO automerge_documents (doc, data) VALUES (?, ?)
+        ON CONFLICT (doc) DO UPDATE SET data = excluded.data",
+        params![doc_id, data],
+    )?;
+    Ok(())
+}
+
+/// Remove the changes, returning the number of bytes removed.
+fn remove_changes(
+    transaction: &rusqlite::Transaction,
+    doc_id: &str,
+    changes: Vec<(&ActorId, u64)>,
+) -> Result<usize, SqlitePersisterError> {
+    let mut removed = 0;
+    for (a, s) in changes {
+        let actor = a.to_bytes();
+        removed += data_length(
+            transaction,
+            "SELECT LENGTH(data) FROM automerge_changes WHERE doc = ? AND actor = ? AND seq = ?",
+            params![doc_id, actor, seq_to_sql(s)],
+        )?;
+        transaction.execute(
+            "DELETE FROM automerge_changes WHERE doc = ? AND actor = ? AND seq = ?",
+            params![doc_id, actor, seq_to_sql(s)],
+        )?;
+    }
+    Ok(removed)
+}
+
+/// Remove the sync states, returning the number of bytes removed.
+fn remove_sync_states(
+    transaction: &rusqlite::Transaction,
+    doc_id: &str,
+    peer_ids: &[&[u8]],
+) -> Result<usize, SqlitePersisterError> {
+    let mut removed = 0;
+    for peer_id in peer_ids {
+        removed += data_length(
+            transaction,
+            "SELECT LENGTH(data) FROM automerge_sync_states WHERE doc = ? AND peer = ?",
+            params![doc_id, peer_id],
+        )?;
+        transaction.execute(
+            "DELETE FROM automerge_sync_states WHERE doc = ? AND peer = ?",
+            params![doc_id, peer_id],
+        )?;
+    }
+    Ok(removed)
+}
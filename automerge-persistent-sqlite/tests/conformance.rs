@@ -0,0 +1,27 @@
+use automerge_persistent_sqlite::SqlitePersister;
+use automerge_persistent_test_suite::{persister_tests, PersisterFixture};
+use tempfile::TempDir;
+
+/// All documents share one database file.
+struct SqliteFixture {
+    dir: TempDir,
+}
+
+impl SqliteFixture {
+    fn new() -> Self {
+        Self {
+            dir: tempfile::tempdir().unwrap(),
+        }
+    }
+}
+
+impl PersisterFixture for SqliteFixture {
+    type Persister = SqlitePersister;
+
+    fn open(&mut self, name: &str) -> Self::Persister {
+        let connection = rusqlite::Connection::open(self.dir.path().join("db.sqlite")).unwrap();
+        SqlitePersister::new(connection, name).unwrap()
+    }
+}
+
+persister_tests!(SqliteFixture::new());
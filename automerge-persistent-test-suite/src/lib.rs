@@ -0,0 +1,606 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A conformance test suite for [`Persister`] implementations.
+//!
+//! Implement [`PersisterFixture`] to describe how to open a persister for a named document, then
+//! use [`persister_tests!`] in a test module to generate a test for each check.
+//!
+//! ```rust
+//! use automerge_persistent::MemoryPersister;
+//! use automerge_persistent_test_suite::PersisterFixture;
+//!
+//! struct MemoryFixture;
+//!
+//! impl PersisterFixture for MemoryFixture {
+//!     type Persister = MemoryPersister;
+//!
+//!     fn open(&mut self, _name: &str) -> Self::Persister {
+//!         MemoryPersister::default()
+//!     }
+//!
+//!     fn persistent(&self) -> bool {
+//!         false
+//!     }
+//! }
+//!
+//! automerge_persistent_test_suite::persister_tests!(MemoryFixture);
+//! # fn main() {}
+//! ```
+//!
+//! The checks are also exported as plain functions so they can be run individually.
+
+use automerge_persistent::{Persister, UncompressedSizes};
+use automerge_protocol::ActorId;
+
+/// Describes how to get persisters for the tests.
+pub trait PersisterFixture {
+    /// The persister under test.
+    type Persister: Persister;
+
+    /// Open the persister for the document `name`.
+    ///
+    /// For persistent fixtures, opening a name that was previously closed should see the data
+    /// written before it was closed, and different names should not see each other's data.
+    fn open(&mut self, name: &str) -> Self::Persister;
+
+    /// Close a persister, after it has been flushed.
+    ///
+    /// By default this just drops it.
+    fn close(&mut self, persister: Self::Persister) {
+        drop(persister);
+    }
+
+    /// Whether data survives a persister being closed and opened again.
+    ///
+    /// The reload and isolation checks are skipped when this is false.
+    fn persistent(&self) -> bool {
+        true
+    }
+
+    /// The number of bytes the persister adds to each stored value, such as for encryption.
+    ///
+    /// The size checks allow for this on top of the data.
+    fn overhead(&self) -> usize {
+        0
+    }
+
+    /// The number of bytes the persister adds to each stored change, defaulting to
+    /// [`overhead`](Self::overhead).
+    ///
+    /// The actor ids used by the suite are all 16 bytes long.
+    fn change_overhead(&self) -> usize {
+        self.overhead()
+    }
+}
+
+/// A fixture that keeps hold of a store between persisters, for persisters that are built on a
+/// store rather than opening one themselves, such as wrappers around a
+/// [`KvPersister`](automerge_persistent::KvPersister).
+///
+/// ```rust
+/// use std::collections::BTreeMap;
+///
+/// use automerge_persistent::{ChecksumPersister, KvPersister};
+/// use automerge_persistent_test_suite::StoreFixture;
+///
+/// type Store = BTreeMap<Vec<u8>, Vec<u8>>;
+///
+/// fn fixture() -> StoreFixture<Store, ChecksumPersister<KvPersister<Store>>> {
+///     StoreFixture::new(
+///         |store, name| ChecksumPersister::new(KvPersister::new(store, name).unwrap()),
+///         |persister| persister.into_persister().into_store(),
+///     )
+///     .with_overhead(8)
+/// }
+///
+/// automerge_persistent_test_suite::persister_tests!(fixture());
+/// # fn main() {}
+/// ```
+#[derive(Debug)]
+pub struct StoreFixture<S, P> {
+    store: Option<S>,
+    open: fn(S, &str) -> P,
+    close: fn(P) -> S,
+    overhead: usize,
+    change_overhead: Option<usize>,
+}
+
+impl<S, P> StoreFixture<S, P>
+where
+    S: Default,
+{
+    /// Open persisters on the store with `open` and get the store back from them with `close`.
+    ///
+    /// The first persister gets a default store.
+    pub const fn new(open: fn(S, &str) -> P, close: fn(P) -> S) -> Self {
+        Self {
+            store: None,
+            open,
+            close,
+            overhead: 0,
+            change_overhead: None,
+        }
+    }
+
+    /// Set the [`overhead`](PersisterFixture::overhead) of the persister.
+    #[must_use]
+    pub const fn with_overhead(mut self, overhead: usize) -> Self {
+        self.overhead = overhead;
+        self
+    }
+
+    /// Set the [`change_overhead`](PersisterFixture::change_overhead) of the persister.
+    #[must_use]
+    pub const fn with_change_overhead(mut self, change_overhead: usize) -> Self {
+        self.change_overhead = Some(change_overhead);
+        self
+    }
+}
+
+impl<S, P> PersisterFixture for StoreFixture<S, P>
+where
+    S: Default,
+    P: Persister,
+{
+    type Persister = P;
+
+    fn open(&mut self, name: &str) -> Self::Persister {
+        let store = self.store.take().unwrap_or_default();
+        (self.open)(store, name)
+    }
+
+    fn close(&mut self, persister: Self::Persister) {
+        self.store = Some((self.close)(persister));
+    }
+
+    fn overhead(&self) -> usize {
+        self.overhead
+    }
+
+    fn change_overhead(&self) -> usize {
+        self.change_overhead.unwrap_or(self.overhead)
+    }
+}
+
+/// Generate a `#[test]` for each check in the suite, each with a fresh fixture from `$fixture`.
+#[macro_export]
+macro_rules! persister_tests {
+    ($fixture:expr) => {
+        $crate::persister_tests!(@tests $fixture;
+            insert_changes,
+            replace_change,
+            remove_changes,
+            iter_changes,
+            empty_batches,
+            document,
+            sync_states,
+            peer_ids,
+            actor_id,
+            compact,
+            quarantine_changes,
+            reload_after_flush,
+            document_isolation,
+            prefix_isolation,
+        );
+    };
+    (@tests $fixture:expr; $($name:ident,)*) => {
+        $(
+            #[test]
+            fn $name() {
+                $crate::$name($fixture);
+            }
+        )*
+    };
+}
+
+fn actor(n: u8) -> ActorId {
+    ActorId::from_bytes(&[n; 16])
+}
+
+fn sorted(mut values: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    values.sort();
+    values
+}
+
+/// Check that the sizes reported by the persister match what it returns plus the fixture's
+/// overhead for each value, using the uncompressed sizes when the persister compresses what it
+/// stores.
+///
+/// # Panics
+///
+/// Panics if the sizes do not match or the persister returns an error.
+pub fn assert_sizes<F: PersisterFixture>(fixture: &F, persister: &F::Persister) {
+    let overhead = fixture.overhead();
+    let stored = persister.sizes();
+    // the data is returned uncompressed
+    let sizes = stored.uncompressed.unwrap_or(UncompressedSizes {
+        changes: stored.changes,
+        document: stored.document,
+        sync_states: stored.sync_states,
+    });
+    let changes = persister.get_changes().unwrap();
+    assert_eq!(
+        sizes.changes,
+        changes
+            .iter()
+            .map(|c| c.len() + fixture.change_overhead())
+            .sum::<usize>(),
+        "changes size"
+    );
+    let document = persister.get_document().unwrap();
+    assert_eq!(
+        sizes.document,
+        document.map_or(0, |d| d.len() + overhead),
+        "document size"
+    );
+    let mut sync_states = 0;
+    for peer_id in persister.get_peer_ids().unwrap() {
+        sync_states += persister.get_sync_state(&peer_id).unwrap().unwrap().len() + overhead;
+    }
+    assert_eq!(sizes.sync_states, sync_states, "sync states size");
+}
+
+/// Inserted changes are all returned.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn insert_changes<F: PersisterFixture>(mut fixture: F) {
+    let mut p = fixture.open("doc");
+    assert!(p.get_changes().unwrap().is_empty());
+    p.insert_changes(vec![
+        (&actor(1), 1, &[1, 1][..]),
+        (&actor(1), 2, &[1, 2, 2][..]),
+        (&actor(2), 1, &[2][..]),
+    ])
+    .unwrap();
+    assert_eq!(
+        sorted(p.get_changes().unwrap()),
+        vec![vec![1, 1], vec![1, 2, 2], vec![2]]
+    );
+    assert_sizes(&fixture, &p);
+}
+
+/// Inserting a change at an existing address replaces it.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn replace_change<F: PersisterFixture>(mut fixture: F) {
+    let mut p = fixture.open("doc");
+    p.insert_changes(vec![(&actor(1), 1, &[1, 2, 3][..])])
+        .unwrap();
+    p.insert_changes(vec![(&actor(1), 1, &[4][..])]).unwrap();
+    assert_eq!(p.get_changes().unwrap(), vec![vec![4]]);
+    assert_sizes(&fixture, &p);
+}
+
+/// Removed changes are no longer returned, removing missing changes is not an error.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn remove_changes<F: PersisterFixture>(mut fixture: F) {
+    let mut p = fixture.open("doc");
+    p.insert_changes(vec![
+        (&actor(1), 1, &[1][..]),
+        (&actor(1), 2, &[2, 2][..]),
+        (&actor(2), 1, &[3, 3, 3][..]),
+    ])
+    .unwrap();
+    p.remove_changes(vec![(&actor(1), 2), (&actor(2), 1), (&actor(3), 1)])
+        .unwrap();
+    assert_eq!(p.get_changes().unwrap(), vec![vec![1]]);
+    assert_sizes(&fixture, &p);
+    p.remove_changes(vec![(&actor(1), 2)]).unwrap();
+    assert_eq!(p.get_changes().unwrap(), vec![vec![1]]);
+    assert_sizes(&fixture, &p);
+}
+
+/// Iterating over the changes, with or without their keys, returns the same as getting them all
+/// at once, including when there are more than a persister might read at a time.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn iter_changes<F: PersisterFixture>(mut fixture: F) {
+    let mut p = fixture.open("doc");
+    assert!(p.iter_changes().unwrap().next().is_none());
+    let actor_id = actor(1);
+    let changes = (1..=1000_u64).map(u64::to_be_bytes).collect::<Vec<_>>();
+    p.insert_changes(
+        changes
+            .iter()
+            .zip(1..)
+            .map(|(c, seq)| (&actor_id, seq, &c[..]))
+            .collect(),
+    )
+    .unwrap();
+    let iterated = p
+        .iter_changes()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(iterated.len(), 1000);
+    assert_eq!(sorted(iterated), sorted(p.get_changes().unwrap()));
+
+    if let Some(mut keyed) = p.get_changes_with_keys().unwrap() {
+        let mut iterated = p
+            .iter_changes_with_keys()
+            .unwrap()
+            .expect("the keys of the changes are known")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        iterated.sort_by_key(|(_, seq, _)| *seq);
+        keyed.sort_by_key(|(_, seq, _)| *seq);
+        assert_eq!(iterated, keyed);
+    }
+}
+
+/// Operations with nothing to do succeed.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn empty_batches<F: PersisterFixture>(mut fixture: F) {
+    let mut p = fixture.open("doc");
+    p.insert_changes(Vec::new()).unwrap();
+    p.remove_changes(Vec::new()).unwrap();
+    p.remove_sync_states(&[]).unwrap();
+    assert!(p.get_changes().unwrap().is_empty());
+    assert!(p.get_peer_ids().unwrap().is_empty());
+    assert_sizes(&fixture, &p);
+}
+
+/// The document round-trips and can be replaced.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn document<F: PersisterFixture>(mut fixture: F) {
+    let mut p = fixture.open("doc");
+    assert_eq!(p.get_document().unwrap(), None);
+    p.set_document(vec![1, 2, 3, 4]).unwrap();
+    assert_eq!(p.get_document().unwrap(), Some(vec![1, 2, 3, 4]));
+    assert_sizes(&fixture, &p);
+    p.set_document(vec![5]).unwrap();
+    assert_eq!(p.get_document().unwrap(), Some(vec![5]));
+    assert_sizes(&fixture, &p);
+}
+
+/// Sync states round-trip, can be replaced and removed.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn sync_states<F: PersisterFixture>(mut fixture: F) {
+    let mut p = fixture.open("doc");
+    assert_eq!(p.get_sync_state(b"peer").unwrap(), None);
+    p.set_sync_state(b"peer".to_vec(), vec![1, 2, 3]).unwrap();
+    assert_eq!(p.get_sync_state(b"peer").unwrap(), Some(vec![1, 2, 3]));
+    assert_sizes(&fixture, &p);
+    p.set_sync_state(b"peer".to_vec(), vec![4]).unwrap();
+    assert_eq!(p.get_sync_state(b"peer").unwrap(), Some(vec![4]));
+    assert_sizes(&fixture, &p);
+    p.remove_sync_states(&[b"peer", b"other"]).unwrap();
+    assert_eq!(p.get_sync_state(b"peer").unwrap(), None);
+    assert_sizes(&fixture, &p);
+}
+
+/// `get_peer_ids` returns exactly the peer ids that were set, in a form that can be passed back
+/// to remove them.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn peer_ids<F: PersisterFixture>(mut fixture: F) {
+    let mut p = fixture.open("doc");
+    for peer_id in &[&b"a"[..], b"b", b"peer\0id"] {
+        p.set_sync_state(peer_id.to_vec(), vec![1]).unwrap();
+    }
+    let peer_ids = sorted(p.get_peer_ids().unwrap());
+    assert_eq!(
+        peer_ids,
+        vec![b"a".to_vec(), b"b".to_vec(), b"peer\0id".to_vec()]
+    );
+    p.remove_sync_states(&peer_ids.iter().map(Vec::as_slice).collect::<Vec<_>>())
+        .unwrap();
+    assert!(p.get_peer_ids().unwrap().is_empty());
+    assert_sizes(&fixture, &p);
+}
+
+/// The actor id round-trips and can be replaced.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn actor_id<F: PersisterFixture>(mut fixture: F) {
+    let mut p = fixture.open("doc");
+    assert_eq!(p.get_actor_id().unwrap(), None);
+    p.set_actor_id(actor(1)).unwrap();
+    assert_eq!(p.get_actor_id().unwrap(), Some(actor(1)));
+    p.set_actor_id(actor(2)).unwrap();
+    assert_eq!(p.get_actor_id().unwrap(), Some(actor(2)));
+}
+
+/// Compaction sets the document and removes only the given changes and sync states.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn compact<F: PersisterFixture>(mut fixture: F) {
+    let mut p = fixture.open("doc");
+    p.insert_changes(vec![(&actor(1), 1, &[1][..]), (&actor(1), 2, &[2, 2][..])])
+        .unwrap();
+    p.set_sync_state(b"old".to_vec(), vec![3]).unwrap();
+    p.set_sync_state(b"new".to_vec(), vec![4, 4]).unwrap();
+    p.compact(vec![5, 5, 5], vec![(&actor(1), 1)], &[b"old"])
+        .unwrap();
+    assert_eq!(p.get_document().unwrap(), Some(vec![5, 5, 5]));
+    assert_eq!(p.get_changes().unwrap(), vec![vec![2, 2]]);
+    assert_eq!(p.get_peer_ids().unwrap(), vec![b"new".to_vec()]);
+    assert_sizes(&fixture, &p);
+}
+
+/// Quarantined changes are moved out of the changes, survive compaction and reloading, and can
+/// be removed. Persisters that don't support quarantining must leave the changes alone.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn quarantine_changes<F: PersisterFixture>(mut fixture: F) {
+    let mut p = fixture.open("doc");
+    p.insert_changes(vec![
+        (&actor(1), 1, &[1][..]),
+        (&actor(1), 2, &[2, 2][..]),
+        (&actor(2), 1, &[3, 3, 3][..]),
+    ])
+    .unwrap();
+    if !p
+        .quarantine_changes(vec![(&actor(1), 2), (&actor(3), 1)])
+        .unwrap()
+    {
+        assert_eq!(p.get_changes().unwrap().len(), 3);
+        return;
+    }
+    let quarantined = vec![(actor(1), 2, vec![2, 2])];
+    assert_eq!(
+        sorted(p.get_changes().unwrap()),
+        vec![vec![1], vec![3, 3, 3]]
+    );
+    assert_eq!(p.get_quarantined_changes().unwrap(), quarantined);
+    assert_sizes(&fixture, &p);
+
+    p.compact(vec![4], vec![(&actor(1), 1), (&actor(1), 2)], &[])
+        .unwrap();
+    assert_eq!(p.get_changes().unwrap(), vec![vec![3, 3, 3]]);
+    assert_eq!(p.get_quarantined_changes().unwrap(), quarantined);
+    assert_sizes(&fixture, &p);
+
+    if fixture.persistent() {
+        p.flush().unwrap();
+        fixture.close(p);
+        p = fixture.open("doc");
+        assert_eq!(p.get_changes().unwrap(), vec![vec![3, 3, 3]]);
+        assert_eq!(p.get_quarantined_changes().unwrap(), quarantined);
+        assert_sizes(&fixture, &p);
+        assert!(fixture
+            .open("other")
+            .get_quarantined_changes()
+            .unwrap()
+            .is_empty());
+    }
+
+    p.remove_quarantined_changes(vec![(&actor(1), 2), (&actor(3), 1)])
+        .unwrap();
+    assert!(p.get_quarantined_changes().unwrap().is_empty());
+    assert_eq!(p.get_changes().unwrap(), vec![vec![3, 3, 3]]);
+}
+
+/// Everything written is still there after flushing and opening the document again.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn reload_after_flush<F: PersisterFixture>(mut fixture: F) {
+    if !fixture.persistent() {
+        return;
+    }
+    let mut p = fixture.open("doc");
+    p.insert_changes(vec![(&actor(1), 1, &[1][..]), (&actor(1), 2, &[2, 2][..])])
+        .unwrap();
+    p.set_document(vec![3, 3, 3]).unwrap();
+    p.set_sync_state(b"peer".to_vec(), vec![4]).unwrap();
+    p.set_actor_id(actor(9)).unwrap();
+    p.remove_changes(vec![(&actor(1), 1)]).unwrap();
+    p.flush().unwrap();
+    fixture.close(p);
+
+    let p = fixture.open("doc");
+    assert_eq!(p.get_changes().unwrap(), vec![vec![2, 2]]);
+    assert_eq!(p.get_document().unwrap(), Some(vec![3, 3, 3]));
+    assert_eq!(p.get_sync_state(b"peer").unwrap(), Some(vec![4]));
+    assert_eq!(p.get_peer_ids().unwrap(), vec![b"peer".to_vec()]);
+    assert_eq!(p.get_actor_id().unwrap(), Some(actor(9)));
+    assert_sizes(&fixture, &p);
+}
+
+/// Documents with different names do not see each other's data.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn document_isolation<F: PersisterFixture>(mut fixture: F) {
+    if !fixture.persistent() {
+        return;
+    }
+    let mut alpha = fixture.open("alpha");
+    alpha
+        .insert_changes(vec![(&actor(1), 1, &[1][..])])
+        .unwrap();
+    alpha.set_document(vec![1]).unwrap();
+    alpha.set_sync_state(b"peer".to_vec(), vec![1]).unwrap();
+    alpha.set_actor_id(actor(1)).unwrap();
+    alpha.flush().unwrap();
+    fixture.close(alpha);
+
+    let mut beta = fixture.open("beta");
+    assert!(beta.get_changes().unwrap().is_empty());
+    assert!(beta.iter_changes().unwrap().next().is_none());
+    assert_eq!(beta.get_document().unwrap(), None);
+    assert!(beta.get_peer_ids().unwrap().is_empty());
+    assert_eq!(beta.get_actor_id().unwrap(), None);
+    assert_sizes(&fixture, &beta);
+    beta.insert_changes(vec![(&actor(1), 1, &[2, 2][..])])
+        .unwrap();
+    beta.set_sync_state(b"peer".to_vec(), vec![2, 2]).unwrap();
+    beta.flush().unwrap();
+    fixture.close(beta);
+
+    let alpha = fixture.open("alpha");
+    assert_eq!(alpha.get_changes().unwrap(), vec![vec![1]]);
+    assert_eq!(alpha.get_sync_state(b"peer").unwrap(), Some(vec![1]));
+    assert_sizes(&fixture, &alpha);
+}
+
+/// Documents whose names start with the name of another do not see each other's data.
+///
+/// # Panics
+///
+/// Panics if the check fails.
+pub fn prefix_isolation<F: PersisterFixture>(mut fixture: F) {
+    if !fixture.persistent() {
+        return;
+    }
+    let mut long = fixture.open("doc2");
+    long.insert_changes(vec![(&actor(1), 1, &[1][..])]).unwrap();
+    long.set_document(vec![1]).unwrap();
+    long.set_sync_state(b"peer".to_vec(), vec![1]).unwrap();
+    long.set_actor_id(actor(1)).unwrap();
+    long.flush().unwrap();
+    fixture.close(long);
+
+    let mut short = fixture.open("doc");
+    assert!(short.get_changes().unwrap().is_empty());
+    assert!(short.iter_changes().unwrap().next().is_none());
+    assert_eq!(short.get_document().unwrap(), None);
+    assert!(short.get_peer_ids().unwrap().is_empty());
+    assert_eq!(short.get_actor_id().unwrap(), None);
+    assert_sizes(&fixture, &short);
+    short
+        .insert_changes(vec![(&actor(2), 1, &[2, 2][..])])
+        .unwrap();
+    short.set_sync_state(b"2peer".to_vec(), vec![2, 2]).unwrap();
+    short.flush().unwrap();
+    fixture.close(short);
+
+    let long = fixture.open("doc2");
+    assert_eq!(long.get_changes().unwrap(), vec![vec![1]]);
+    assert_eq!(long.get_peer_ids().unwrap(), vec![b"peer".to_vec()]);
+    assert_eq!(long.get_sync_state(b"peer").unwrap(), Some(vec![1]));
+    assert_sizes(&fixture, &long);
+}
@@ -0,0 +1,363 @@
+use automerge::Change;
+use automerge_backend::SyncMessage;
+use automerge_protocol::{ActorId, ChangeHash, Patch};
+
+use crate::{
+    load, received_keys, received_to_insert, received_to_remove, Applied, AsyncPersister, Backend,
+    BackendCore, CompactionPolicy, Error, LoadOptions, LoadReport, PeerId, ReceivedKeys,
+};
+
+/// A wrapper for an async persister and an automerge Backend.
+///
+/// This is the async counterpart to [`PersistentBackend`](crate::PersistentBackend), sharing its
+/// in-memory logic and awaiting the persister's operations instead. Whether the current thread
+/// blocks on storage I/O is up to the persister, see [`AsyncPersister`].
+///
+/// ```rust
+/// # use automerge_persistent::AsyncPersistentBackend;
+/// # use automerge_persistent::MemoryPersister;
+/// # futures::executor::block_on(async {
+/// let persister = MemoryPersister::default();
+/// let backend = AsyncPersistentBackend::<_, automerge::Backend>::load(persister)
+///     .await
+///     .unwrap();
+/// # })
+/// ```
+#[derive(Debug)]
+pub struct AsyncPersistentBackend<P, B> {
+    core: BackendCore<B>,
+    persister: P,
+}
+
+impl<P, B> AsyncPersistentBackend<P, B>
+where
+    P: AsyncPersister + 'static,
+    B: Backend,
+{
+    /// Persist the local change added by an operation and compact if needed, rolling back on
+    /// failure.
+    async fn persist<T>(&mut self, applied: Applied<T>) -> Result<T, Error<P::Error, B::Error>> {
+        let result = self.insert_changes(applied).await?;
+        self.compact_if_needed().await?;
+        Ok(result)
+    }
+
+    /// Persist the `changes` received from a peer that aren't known yet, returning their keys.
+    async fn persist_received(
+        &mut self,
+        changes: &[Change],
+    ) -> Result<ReceivedKeys, Error<P::Error, B::Error>> {
+        let received = self.core.received_changes(changes);
+        if !received.is_empty() {
+            self.persister
+                .insert_changes(received_to_insert(&received))
+                .await
+                .map_err(Error::PersisterError)?;
+        }
+        Ok(received_keys(&received))
+    }
+
+    /// Apply changes persisted by `persist_received` with `apply`, removing the `received` ones
+    /// again if that fails.
+    async fn receive<T>(
+        &mut self,
+        received: &[(ActorId, u64)],
+        apply: impl FnOnce(&mut BackendCore<B>) -> Result<T, B::Error>,
+    ) -> Result<T, Error<P::Error, B::Error>> {
+        match apply(&mut self.core) {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                // the apply error is the one worth returning, changes left behind fail to apply
+                // again when loading and are dealt with there
+                if let Err(cleanup) = self
+                    .persister
+                    .remove_changes(received_to_remove(received))
+                    .await
+                {
+                    log::warn!(
+                        "failed to remove received changes that could not be applied: {}",
+                        cleanup
+                    );
+                }
+                Err(Error::BackendError(e))
+            }
+        }
+    }
+
+    async fn insert_changes<T>(
+        &mut self,
+        applied: Applied<T>,
+    ) -> Result<T, Error<P::Error, B::Error>> {
+        let changes = self.core.changes_to_insert(&applied);
+        let count = changes.len();
+        if let Err(e) = self.persister.insert_changes(changes).await {
+            self.core
+                .rollback(&applied.heads)
+                .map_err(Error::BackendError)?;
+            return Err(Error::PersisterError(e));
+        }
+        self.core.inserted(count);
+        Ok(applied.result)
+    }
+
+    /// Compact the storage if the compaction policy says so.
+    async fn compact_if_needed(&mut self) -> Result<(), Error<P::Error, B::Error>> {
+        if !self.core.should_compact(&self.persister.sizes()) {
+            return Ok(());
+        }
+        let sync_states = self
+            .persister
+            .get_sync_state_infos()
+            .await
+            .map_err(Error::PersisterError)?;
+        let old_peer_ids = self.core.select_peers_to_prune(sync_states);
+        self.compact(&old_peer_ids.iter().map(Vec::as_slice).collect::<Vec<_>>())
+            .await
+    }
+
+    async fn load_sync_state(&mut self, peer_id: &[u8]) -> Result<(), Error<P::Error, B::Error>> {
+        if !self.core.has_sync_state(peer_id) {
+            if let Some(sync_state) = self
+                .persister
+                .get_sync_state(peer_id)
+                .await
+                .map_err(Error::PersisterError)?
+            {
+                self.core.insert_sync_state(peer_id, &sync_state)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn store_sync_state(&mut self, peer_id: PeerId) -> Result<(), Error<P::Error, B::Error>> {
+        let sync_state = self.core.encode_sync_state(&peer_id)?;
+        self.persister
+            .set_sync_state(peer_id, sync_state)
+            .await
+            .map_err(Error::PersisterError)
+    }
+
+    /// Returns a serialized version of the current document.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors returned from [`Backend::save`].
+    pub fn save(&self) -> Result<Vec<u8>, B::Error> {
+        self.core.backend.save()
+    }
+
+    /// Load the persisted changes (both individual changes and a document) from storage and
+    /// rebuild the Backend.
+    pub async fn load(persister: P) -> Result<Self, Error<P::Error, B::Error>> {
+        Self::load_with_options(persister, &LoadOptions::default())
+            .await
+            .map(|(s, _)| s)
+    }
+
+    /// Load from storage with the given options, returning what was skipped.
+    ///
+    /// See [`PersistentBackend::load_with_options`](crate::PersistentBackend::load_with_options).
+    pub async fn load_with_options(
+        mut persister: P,
+        options: &LoadOptions,
+    ) -> Result<(Self, LoadReport), Error<P::Error, B::Error>> {
+        let mut report = LoadReport::default();
+        let document = load::document(persister.get_document().await, options, &mut report)
+            .map_err(Error::PersisterError)?;
+        let stored_document = document.is_some();
+        let mut core = BackendCore::load(document, options, &mut report)?;
+        if stored_document && report.discarded_document.is_some() {
+            load::fill_discarded_document(&mut report, persister.get_document().await);
+        }
+
+        // an async persister can only read the changes up front, so only the decoding is batched
+        let mut stored = load::stored_changes_async(&persister, options)
+            .await
+            .map_err(Error::PersisterError)?
+            .into_iter()
+            .map(Ok);
+        let mut undecoded = load::Undecoded::default();
+        loop {
+            let batch = load::next_batch(&mut stored, options).map_err(Error::PersisterError)?;
+            if batch.is_empty() {
+                break;
+            }
+            core.load_batch(batch, options, &mut report, &mut undecoded)?;
+        }
+        drop(stored);
+        if !undecoded.is_empty() {
+            let stored = load::stored_changes_async(&persister, options)
+                .await
+                .map_err(Error::PersisterError)?;
+            load::fill_discarded(&mut report, &undecoded, stored.into_iter().map(Ok))
+                .map_err(Error::PersisterError)?;
+        }
+
+        load::set_aside_async(&mut persister, options, &mut report)
+            .await
+            .map_err(Error::PersisterError)?;
+        Ok((Self { core, persister }, report))
+    }
+
+    /// Apply a sequence of changes, typically from a remote backend.
+    ///
+    /// See [`PersistentBackend::apply_changes`](crate::PersistentBackend::apply_changes).
+    pub async fn apply_changes(
+        &mut self,
+        changes: Vec<Change>,
+    ) -> Result<Patch, Error<P::Error, B::Error>> {
+        let received = self.persist_received(&changes).await?;
+        let patch = self
+            .receive(&received, |core| core.apply_changes(changes))
+            .await?;
+        self.compact_if_needed().await?;
+        Ok(patch)
+    }
+
+    /// Apply a local change, typically from a local frontend.
+    ///
+    /// See [`PersistentBackend::apply_local_change`](crate::PersistentBackend::apply_local_change).
+    pub async fn apply_local_change(
+        &mut self,
+        change: automerge_protocol::Change,
+    ) -> Result<Patch, Error<P::Error, B::Error>> {
+        let applied = self
+            .core
+            .apply_local_change(change)
+            .map_err(Error::BackendError)?;
+        self.persist(applied).await
+    }
+
+    /// Compact the storage.
+    ///
+    /// See [`PersistentBackend::compact`](crate::PersistentBackend::compact).
+    pub async fn compact(
+        &mut self,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Error<P::Error, B::Error>> {
+        let (saved_backend, changes) = self.core.compaction().map_err(Error::BackendError)?;
+        self.persister
+            .compact(saved_backend, changes, old_peer_ids)
+            .await
+            .map_err(Error::PersisterError)?;
+        self.core.compacted();
+        Ok(())
+    }
+
+    /// Set the policy used to compact the storage automatically.
+    ///
+    /// See [`PersistentBackend::set_compaction_policy`](crate::PersistentBackend::set_compaction_policy).
+    pub fn set_compaction_policy<C>(&mut self, policy: C)
+    where
+        C: CompactionPolicy + 'static,
+    {
+        self.core.compaction_policy = Some(Box::new(policy));
+    }
+
+    /// Get a patch from the current data in the backend to populate a frontend.
+    pub fn get_patch(&self) -> Result<Patch, Error<P::Error, B::Error>> {
+        self.core.backend.get_patch().map_err(Error::BackendError)
+    }
+
+    /// Get the changes performed by the given `actor_id`.
+    pub fn get_changes_for_actor_id(
+        &self,
+        actor_id: &ActorId,
+    ) -> Result<Vec<&Change>, Error<P::Error, B::Error>> {
+        self.core
+            .backend
+            .get_changes_for_actor_id(actor_id)
+            .map_err(Error::BackendError)
+    }
+
+    /// Get all changes that have the given dependencies (transitively obtains more recent ones).
+    pub fn get_changes(&self, have_deps: &[ChangeHash]) -> Vec<&Change> {
+        self.core.backend.get_changes(have_deps)
+    }
+
+    /// Get the missing dependencies in the hash graph that are required to be able to apply some
+    /// pending changes.
+    pub fn get_missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash> {
+        self.core.backend.get_missing_deps(heads)
+    }
+
+    /// Get the current heads of the hash graph (changes without successors).
+    pub fn get_heads(&self) -> Vec<ChangeHash> {
+        self.core.backend.get_heads()
+    }
+
+    /// Generate a sync message to be sent to a peer backend.
+    ///
+    /// See [`PersistentBackend::generate_sync_message`](crate::PersistentBackend::generate_sync_message).
+    pub async fn generate_sync_message(
+        &mut self,
+        peer_id: PeerId,
+    ) -> Result<Option<SyncMessage>, Error<P::Error, B::Error>> {
+        self.load_sync_state(&peer_id).await?;
+        let message = self
+            .core
+            .generate_sync_message(&peer_id)
+            .map_err(Error::BackendError)?;
+        self.store_sync_state(peer_id).await?;
+        Ok(message)
+    }
+
+    /// Receive a sync message from a peer backend.
+    ///
+    /// See [`PersistentBackend::receive_sync_message`](crate::PersistentBackend::receive_sync_message).
+    pub async fn receive_sync_message(
+        &mut self,
+        peer_id: PeerId,
+        message: SyncMessage,
+    ) -> Result<Option<Patch>, Error<P::Error, B::Error>> {
+        self.load_sync_state(&peer_id).await?;
+        let received = self.persist_received(&message.changes).await?;
+        let patch = self
+            .receive(&received, |core| {
+                core.receive_sync_message(&peer_id, message)
+            })
+            .await?;
+        self.store_sync_state(peer_id).await?;
+        self.compact_if_needed().await?;
+        Ok(patch)
+    }
+
+    /// Flush any data out to storage returning the number of bytes flushed.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error returned by the persister during flushing.
+    pub async fn flush(&mut self) -> Result<usize, P::Error> {
+        self.persister.flush().await
+    }
+
+    /// Close the document.
+    ///
+    /// This calls flush on the persister and returns it for potential use in other documents.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from flushing.
+    pub async fn close(mut self) -> Result<P, P::Error> {
+        self.flush().await?;
+        Ok(self.persister)
+    }
+
+    /// Obtain a reference to the persister.
+    pub fn persister(&self) -> &P {
+        &self.persister
+    }
+
+    /// Obtain a mutable reference to the persister.
+    ///
+    /// Writing through it bypasses the backend, so it is for updating persister state that the
+    /// backend doesn't track.
+    pub fn persister_mut(&mut self) -> &mut P {
+        &mut self.persister
+    }
+
+    /// Reset the sync state for a peer.
+    pub fn reset_sync_state(&mut self, peer_id: &[u8]) {
+        self.core.sync_states.remove(peer_id);
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+
+use automerge::{value_ref::RootRef, Change, Frontend, MutableDocument, Path, Value};
+use automerge_backend::SyncMessage;
+use automerge_protocol::{ActorId, ChangeHash, OpId};
+
+use crate::{
+    document::{DocumentCore, Error},
+    load, received_keys, received_to_insert, received_to_remove, Applied, AsyncPersister,
+    CompactionPolicy, LoadOptions, LoadReport, PeerId, ReceivedKeys,
+};
+
+/// A wrapper for an async persister and an automerge document.
+///
+/// This is the async counterpart to [`PersistentAutomerge`](crate::PersistentAutomerge), sharing
+/// its in-memory logic and awaiting the persister's operations instead. Whether the current
+/// thread blocks on storage I/O is up to the persister, see [`AsyncPersister`].
+///
+/// ```rust
+/// # use automerge_persistent::AsyncPersistentAutomerge;
+/// # use automerge_persistent::MemoryPersister;
+/// # futures::executor::block_on(async {
+/// let persister = MemoryPersister::default();
+/// let document = AsyncPersistentAutomerge::<_>::load(persister).await.unwrap();
+/// # })
+/// ```
+#[derive(Debug)]
+pub struct AsyncPersistentAutomerge<P> {
+    core: DocumentCore,
+    persister: P,
+}
+
+impl<P> AsyncPersistentAutomerge<P>
+where
+    P: AsyncPersister + 'static,
+{
+    /// Persist the local change added by an operation and compact if needed, rolling back on
+    /// failure.
+    async fn persist<T>(&mut self, applied: Applied<T>) -> Result<T, Error<P::Error>> {
+        let result = self.insert_changes(applied).await?;
+        self.compact_if_needed().await?;
+        Ok(result)
+    }
+
+    /// Persist the `changes` made elsewhere that aren't known yet, returning their keys.
+    async fn persist_received(
+        &mut self,
+        changes: &[Change],
+    ) -> Result<ReceivedKeys, Error<P::Error>> {
+        let received = self.core.received_changes(changes);
+        if !received.is_empty() {
+            self.persister
+                .insert_changes(received_to_insert(&received))
+                .await
+                .map_err(Error::PersisterError)?;
+        }
+        Ok(received_keys(&received))
+    }
+
+    /// Apply changes persisted by `persist_received` with `apply`, removing the `received` ones
+    /// again if that fails.
+    async fn receive<T>(
+        &mut self,
+        received: &[(ActorId, u64)],
+        apply: impl FnOnce(&mut DocumentCore) -> Result<T, Error<P::Error>>,
+    ) -> Result<T, Error<P::Error>> {
+        match apply(&mut self.core) {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                // the apply error is the one worth returning, changes left behind fail to apply
+                // again when loading and are dealt with there
+                if let Err(cleanup) = self
+                    .persister
+                    .remove_changes(received_to_remove(received))
+                    .await
+                {
+                    log::warn!(
+                        "failed to remove received changes that could not be applied: {}",
+                        cleanup
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn insert_changes<T>(&mut self, applied: Applied<T>) -> Result<T, Error<P::Error>> {
+        let changes = self.core.changes_to_insert(&applied);
+        let count = changes.len();
+        if let Err(e) = self.persister.insert_changes(changes).await {
+            self.core.rollback(&applied.heads)?;
+            return Err(Error::PersisterError(e));
+        }
+        self.core.inserted(count);
+        Ok(applied.result)
+    }
+
+    /// Compact the storage if the compaction policy says so.
+    async fn compact_if_needed(&mut self) -> Result<(), Error<P::Error>> {
+        if !self.core.should_compact(&self.persister.sizes()) {
+            return Ok(());
+        }
+        let sync_states = self
+            .persister
+            .get_sync_state_infos()
+            .await
+            .map_err(Error::PersisterError)?;
+        let old_peer_ids = self.core.select_peers_to_prune(sync_states);
+        self.compact(&old_peer_ids.iter().map(Vec::as_slice).collect::<Vec<_>>())
+            .await
+    }
+
+    async fn load_sync_state(&mut self, peer_id: &[u8]) -> Result<(), Error<P::Error>> {
+        if !self.core.has_sync_state(peer_id) {
+            if let Some(sync_state) = self
+                .persister
+                .get_sync_state(peer_id)
+                .await
+                .map_err(Error::PersisterError)?
+            {
+                self.core.insert_sync_state(peer_id, &sync_state)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn store_sync_state(&mut self, peer_id: PeerId) -> Result<(), Error<P::Error>> {
+        let sync_state = self.core.encode_sync_state(&peer_id)?;
+        self.persister
+            .set_sync_state(peer_id, sync_state)
+            .await
+            .map_err(Error::PersisterError)
+    }
+
+    /// Load the persisted changes (both individual changes and whole document) from storage and
+    /// rebuild the document.
+    ///
+    /// See [`PersistentAutomerge::load`](crate::PersistentAutomerge::load).
+    pub async fn load(persister: P) -> Result<Self, Error<P::Error>> {
+        Self::load_with_options(persister, &LoadOptions::default())
+            .await
+            .map(|(s, _)| s)
+    }
+
+    /// Load from storage with the given options, returning what was skipped.
+    ///
+    /// See [`PersistentAutomerge::load_with_options`](crate::PersistentAutomerge::load_with_options).
+    pub async fn load_with_options(
+        mut persister: P,
+        options: &LoadOptions,
+    ) -> Result<(Self, LoadReport), Error<P::Error>> {
+        let actor_id = if let Some(actor_id) = persister
+            .get_actor_id()
+            .await
+            .map_err(Error::PersisterError)?
+        {
+            actor_id
+        } else {
+            let actor_id = ActorId::random();
+            persister
+                .set_actor_id(actor_id.clone())
+                .await
+                .map_err(Error::PersisterError)?;
+            actor_id
+        };
+        let frontend = Frontend::new_with_actor_id(&actor_id.to_bytes());
+        let (mut s, report) =
+            Self::load_with_frontend_and_options(persister, frontend, options).await?;
+        s.core.actor_id = Some(actor_id);
+        Ok((s, report))
+    }
+
+    /// Load the persisted document using the given frontend.
+    ///
+    /// The actor id stored in the persister is not used or updated.
+    pub async fn load_with_frontend(
+        persister: P,
+        frontend: Frontend,
+    ) -> Result<Self, Error<P::Error>> {
+        Self::load_with_frontend_and_options(persister, frontend, &LoadOptions::default())
+            .await
+            .map(|(s, _)| s)
+    }
+
+    /// Load the persisted document using the given frontend and options, returning what was
+    /// skipped.
+    ///
+    /// The actor id stored in the persister is not used or updated.
+    pub async fn load_with_frontend_and_options(
+        mut persister: P,
+        frontend: Frontend,
+        options: &LoadOptions,
+    ) -> Result<(Self, LoadReport), Error<P::Error>> {
+        let mut report = LoadReport::default();
+        let document = load::document(persister.get_document().await, options, &mut report)
+            .map_err(Error::PersisterError)?;
+        let stored_document = document.is_some();
+        let mut core = DocumentCore::load(document, frontend, options, &mut report)?;
+        if stored_document && report.discarded_document.is_some() {
+            load::fill_discarded_document(&mut report, persister.get_document().await);
+        }
+
+        // an async persister can only read the changes up front, so only the decoding is batched
+        let mut stored = load::stored_changes_async(&persister, options)
+            .await
+            .map_err(Error::PersisterError)?
+            .into_iter()
+            .map(Ok);
+        let mut undecoded = load::Undecoded::default();
+        loop {
+            let batch = load::next_batch(&mut stored, options).map_err(Error::PersisterError)?;
+            if batch.is_empty() {
+                break;
+            }
+            core.load_batch(batch, options, &mut report, &mut undecoded)?;
+        }
+        drop(stored);
+        if !undecoded.is_empty() {
+            let stored = load::stored_changes_async(&persister, options)
+                .await
+                .map_err(Error::PersisterError)?;
+            load::fill_discarded(&mut report, &undecoded, stored.into_iter().map(Ok))
+                .map_err(Error::PersisterError)?;
+        }
+
+        load::set_aside_async(&mut persister, options, &mut report)
+            .await
+            .map_err(Error::PersisterError)?;
+        Ok((Self { core, persister }, report))
+    }
+
+    /// The actor id used for local changes, if it is managed by this document.
+    pub const fn actor_id(&self) -> Option<&ActorId> {
+        self.core.actor_id.as_ref()
+    }
+
+    /// Replace the actor id used for local changes with a new random one and persist it.
+    ///
+    /// See [`PersistentAutomerge::rotate_actor_id`](crate::PersistentAutomerge::rotate_actor_id).
+    pub async fn rotate_actor_id(&mut self) -> Result<ActorId, Error<P::Error>> {
+        let actor_id = ActorId::random();
+        let automerge = self.core.with_actor_id(&actor_id)?;
+        self.persister
+            .set_actor_id(actor_id.clone())
+            .await
+            .map_err(Error::PersisterError)?;
+        self.core.switch_actor_id(actor_id.clone(), automerge);
+        Ok(actor_id)
+    }
+
+    /// Get the current state of the document.
+    pub fn state(&mut self) -> &Value {
+        self.core.automerge.state()
+    }
+
+    /// Get a reference to the root of the document.
+    pub fn value_ref(&self) -> RootRef {
+        self.core.automerge.value_ref()
+    }
+
+    /// Make a change to the document and persist it.
+    ///
+    /// See [`PersistentAutomerge::change`](crate::PersistentAutomerge::change).
+    pub async fn change<F, O, E>(
+        &mut self,
+        message: Option<String>,
+        change_closure: F,
+    ) -> Result<O, Error<P::Error>>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+        F: FnOnce(&mut dyn MutableDocument) -> Result<O, E>,
+    {
+        let applied = self.core.change(message, change_closure)?;
+        self.persist(applied).await
+    }
+
+    /// Compact the storage.
+    ///
+    /// See [`PersistentAutomerge::compact`](crate::PersistentAutomerge::compact).
+    pub async fn compact(&mut self, old_peer_ids: &[&[u8]]) -> Result<(), Error<P::Error>> {
+        let (saved_document, changes) = self.core.compaction()?;
+        self.persister
+            .compact(saved_document, changes, old_peer_ids)
+            .await
+            .map_err(Error::PersisterError)?;
+        self.core.compacted();
+        Ok(())
+    }
+
+    /// Set the policy used to compact the storage automatically.
+    ///
+    /// See [`PersistentBackend::set_compaction_policy`](crate::PersistentBackend::set_compaction_policy).
+    pub fn set_compaction_policy<C>(&mut self, policy: C)
+    where
+        C: CompactionPolicy + 'static,
+    {
+        self.core.compaction_policy = Some(Box::new(policy));
+    }
+
+    /// Get the conflicts at the given path.
+    pub fn get_conflicts(&self, path: &Path) -> Option<HashMap<OpId, Value>> {
+        self.core.automerge.get_conflicts(path)
+    }
+
+    /// Get the value at the given path.
+    pub fn get_value(&self, path: &Path) -> Option<Value> {
+        self.core.automerge.get_value(path)
+    }
+
+    /// Get all changes that have the given dependencies (transitively obtains more recent ones).
+    pub fn get_changes(&self, have_deps: &[ChangeHash]) -> Vec<&Change> {
+        self.core.automerge.get_changes(have_deps)
+    }
+
+    /// Get the current heads of the hash graph (changes without successors).
+    pub fn get_heads(&self) -> Vec<ChangeHash> {
+        self.core.automerge.get_heads()
+    }
+
+    /// Generate a sync message to be sent to a peer document.
+    ///
+    /// See [`PersistentAutomerge::generate_sync_message`](crate::PersistentAutomerge::generate_sync_message).
+    pub async fn generate_sync_message(
+        &mut self,
+        peer_id: PeerId,
+    ) -> Result<Option<SyncMessage>, Error<P::Error>> {
+        self.load_sync_state(&peer_id).await?;
+        let message = self.core.generate_sync_message(&peer_id);
+        self.store_sync_state(peer_id).await?;
+        Ok(message)
+    }
+
+    /// Receive a sync message from a peer document.
+    ///
+    /// See [`PersistentAutomerge::receive_sync_message`](crate::PersistentAutomerge::receive_sync_message).
+    pub async fn receive_sync_message(
+        &mut self,
+        peer_id: PeerId,
+        message: SyncMessage,
+    ) -> Result<(), Error<P::Error>> {
+        self.load_sync_state(&peer_id).await?;
+        let received = self.persist_received(&message.changes).await?;
+        self.receive(&received, |core| {
+            core.receive_sync_message(&peer_id, message)
+        })
+        .await?;
+        self.store_sync_state(peer_id).await?;
+        self.compact_if_needed().await
+    }
+
+    /// Flush any data out to storage.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error returned by the persister during flushing.
+    pub async fn flush(&mut self) -> Result<usize, P::Error> {
+        self.persister.flush().await
+    }
+
+    /// Close the document.
+    ///
+    /// This calls flush on the persister and returns it for potential use in other documents.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from flushing.
+    pub async fn close(mut self) -> Result<P, P::Error> {
+        self.flush().await?;
+        Ok(self.persister)
+    }
+
+    /// Obtain a reference to the persister.
+    pub fn persister(&self) -> &P {
+        &self.persister
+    }
+
+    /// Obtain a mutable reference to the persister.
+    ///
+    /// Writing through it bypasses the document, so it is for updating persister state that the
+    /// document doesn't track.
+    pub fn persister_mut(&mut self) -> &mut P {
+        &mut self.persister
+    }
+}
@@ -0,0 +1,253 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+use automerge_protocol::ActorId;
+
+use crate::{Persister, StoredSizes, SyncStateInfo};
+
+/// An `AsyncPersister` persists both changes and documents to durable storage through async
+/// operations.
+///
+/// This mirrors [`Persister`] method for method, see there for the semantics of each operation,
+/// except for the iterators over the changes. Without them async loads read all of the stored
+/// changes up front.
+///
+/// Persisters are shared with the futures of the methods that borrow them, so have to be `Sync`
+/// as well as `Send`.
+///
+/// Every [`Persister`] that is `Send + Sync` is also an `AsyncPersister` through a blanket
+/// implementation which runs the blocking operation inline: the returned futures complete on
+/// their first poll and **block the executor thread** for the duration of the storage I/O. This
+/// is fine for in-memory or fast local storage but on a shared executor slow storage should be
+/// given a native implementation of this trait which offloads the work, e.g. to a blocking
+/// thread pool.
+#[async_trait]
+pub trait AsyncPersister: Send + Sync {
+    /// The error type that the operations can produce
+    type Error: Error + 'static;
+
+    /// Returns all of the changes that have been persisted through this persister.
+    /// Ordering is not specified as the automerge Backend should handle that.
+    async fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// Returns all of the changes along with their `actor_id` and `sequence_number`, or `None` if
+    /// the persister can't tell what they are.
+    ///
+    /// This lets a lenient load remove changes that can't be decoded.
+    #[allow(clippy::type_complexity)]
+    async fn get_changes_with_keys(
+        &self,
+    ) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error>;
+
+    /// Inserts the given change at the unique address specified by the `actor_id` and `sequence_number`.
+    ///
+    /// The changes are borrowed so that they don't need copying out of the backend first.
+    async fn insert_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64, &[u8])>,
+    ) -> Result<(), Self::Error>;
+
+    /// Removes the change at the unique address specified by the `actor_id` and `sequence_number`.
+    ///
+    /// If the change does not exist this should not return an error.
+    async fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error>;
+
+    /// Moves the changes at the given addresses out of the changes that are loaded.
+    ///
+    /// Returns whether the changes were moved, the default implementation leaves them where they
+    /// are and returns `false`.
+    async fn quarantine_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<bool, Self::Error> {
+        let _ = changes;
+        Ok(false)
+    }
+
+    /// Returns the quarantined changes along with their `actor_id` and `sequence_number`.
+    ///
+    /// The default implementation returns none.
+    #[allow(clippy::type_complexity)]
+    async fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    /// Removes the quarantined changes at the given addresses.
+    ///
+    /// If a change does not exist this should not return an error.
+    async fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        let _ = changes;
+        Ok(())
+    }
+
+    /// Returns the document, if one has been persisted previously.
+    async fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Sets the document to the given data.
+    async fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Returns the sync state for the given peer if one exists.
+    async fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Sets the sync state for the given peer.
+    async fn set_sync_state(
+        &mut self,
+        peer_id: Vec<u8>,
+        sync_state: Vec<u8>,
+    ) -> Result<(), Self::Error>;
+
+    /// Removes the sync states associated with the given `peer_ids`.
+    async fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error>;
+
+    /// Returns the list of peer ids with stored `SyncStates`.
+    async fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// Returns the stored sync states along with their sizes and when they were last set.
+    async fn get_sync_state_infos(&self) -> Result<Vec<SyncStateInfo>, Self::Error>;
+
+    /// Returns the actor id used for local changes to the document, if one has been set.
+    ///
+    /// The default implementation doesn't store an actor id so always returns `None`.
+    async fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Sets the actor id used for local changes to the document.
+    ///
+    /// The default implementation discards it.
+    async fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        let _ = actor_id;
+        Ok(())
+    }
+
+    /// Replaces the document and removes the changes and sync states it supersedes.
+    ///
+    /// See [`Persister::compact`].
+    async fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        self.set_document(document).await?;
+        self.remove_changes(changes).await?;
+        self.remove_sync_states(old_peer_ids).await?;
+        Ok(())
+    }
+
+    /// Returns the sizes components being stored consume.
+    ///
+    /// This is expected to be cheap so is not async.
+    fn sizes(&self) -> StoredSizes;
+
+    /// Flush the data out to disk.
+    async fn flush(&mut self) -> Result<usize, Self::Error>;
+}
+
+/// Runs the blocking [`Persister`] operations inline on the current thread.
+#[async_trait]
+impl<P> AsyncPersister for P
+where
+    P: Persister + Send + Sync,
+{
+    type Error = P::Error;
+
+    async fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Persister::get_changes(self)
+    }
+
+    async fn get_changes_with_keys(
+        &self,
+    ) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        Persister::get_changes_with_keys(self)
+    }
+
+    async fn insert_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64, &[u8])>,
+    ) -> Result<(), Self::Error> {
+        Persister::insert_changes(self, changes)
+    }
+
+    async fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        Persister::remove_changes(self, changes)
+    }
+
+    async fn quarantine_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<bool, Self::Error> {
+        Persister::quarantine_changes(self, changes)
+    }
+
+    async fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        Persister::get_quarantined_changes(self)
+    }
+
+    async fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        Persister::remove_quarantined_changes(self, changes)
+    }
+
+    async fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Persister::get_document(self)
+    }
+
+    async fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        Persister::set_document(self, data)
+    }
+
+    async fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Persister::get_sync_state(self, peer_id)
+    }
+
+    async fn set_sync_state(
+        &mut self,
+        peer_id: Vec<u8>,
+        sync_state: Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        Persister::set_sync_state(self, peer_id, sync_state)
+    }
+
+    async fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        Persister::remove_sync_states(self, peer_ids)
+    }
+
+    async fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Persister::get_peer_ids(self)
+    }
+
+    async fn get_sync_state_infos(&self) -> Result<Vec<SyncStateInfo>, Self::Error> {
+        Persister::get_sync_state_infos(self)
+    }
+
+    async fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        Persister::get_actor_id(self)
+    }
+
+    async fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        Persister::set_actor_id(self, actor_id)
+    }
+
+    async fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        Persister::compact(self, document, changes, old_peer_ids)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        Persister::sizes(self)
+    }
+
+    async fn flush(&mut self) -> Result<usize, Self::Error> {
+        Persister::flush(self)
+    }
+}
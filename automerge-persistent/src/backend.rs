@@ -20,6 +20,8 @@ pub trait Backend: Sized + Default {
 
     fn get_changes(&self, have_deps: &[ChangeHash]) -> Vec<&Change>;
 
+    fn get_change_by_hash(&self, hash: &ChangeHash) -> Option<&Change>;
+
     fn save(&self) -> Result<Vec<u8>, Self::Error>;
 
     fn get_patch(&self) -> Result<Patch, Self::Error>;
@@ -68,6 +70,10 @@ impl Backend for automerge::Backend {
         self.get_changes(have_deps)
     }
 
+    fn get_change_by_hash(&self, hash: &ChangeHash) -> Option<&Change> {
+        self.get_change_by_hash(hash)
+    }
+
     fn save(&self) -> Result<Vec<u8>, Self::Error> {
         self.save()
     }
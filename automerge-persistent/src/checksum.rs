@@ -0,0 +1,412 @@
+use std::convert::{TryFrom, TryInto};
+
+use automerge_protocol::ActorId;
+
+use crate::{ChangeIter, KeyedChangeIter, Persister, StoredKey, StoredSizes, SyncStateInfo};
+
+/// Marks values written by a [`ChecksumPersister`], automerge data never starts with a zero byte.
+const MAGIC: [u8; 4] = *b"\0amc";
+/// The magic bytes and the little endian CRC32.
+const HEADER_LEN: usize = MAGIC.len() + 4;
+
+const CHANGE_TAG: u8 = b'c';
+const DOCUMENT_TAG: u8 = b'd';
+const SYNC_STATE_TAG: u8 = b's';
+
+/// Errors returned by a [`ChecksumPersister`].
+#[derive(Debug, thiserror::Error)]
+pub enum ChecksumPersisterError<E> {
+    /// A stored value didn't match its checksum.
+    #[error("stored {0} is corrupt")]
+    Corrupt(StoredKey),
+    /// An error from the wrapped persister.
+    #[error(transparent)]
+    PersisterError(E),
+}
+
+/// A persister wrapper that stores a checksum with every value and verifies it on read.
+///
+/// Each change, document and sync state is stored with a CRC32 of its contents and a value that
+/// doesn't match fails with [`ChecksumPersisterError::Corrupt`] naming its key, rather than
+/// failing to decode later. The actor id and sequence number of a change are stored and
+/// checksummed with it and have to match the key the wrapped persister stores it under, so
+/// changes swapped between keys are corrupt too. A damaged change is named by the key the wrapped
+/// persister stores it under, or if it can't say by the actor id and sequence number stored with
+/// the change. When loading a [`PersistentBackend`](crate::PersistentBackend) this surfaces as a
+/// [`PersisterError`](crate::Error::PersisterError).
+///
+/// Values stored without a checksum are treated as corrupt unless
+/// [`allow_unchecked`](Self::allow_unchecked) is set, for reading data written before this was
+/// added.
+///
+/// ```rust
+/// # use automerge_persistent::{ChecksumPersister, MemoryPersister, PersistentBackend};
+/// let persister = ChecksumPersister::new(MemoryPersister::default());
+/// let backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct ChecksumPersister<P> {
+    persister: P,
+    allow_unchecked: bool,
+}
+
+impl<P> ChecksumPersister<P>
+where
+    P: Persister,
+{
+    /// Wrap a persister.
+    pub const fn new(persister: P) -> Self {
+        Self {
+            persister,
+            allow_unchecked: false,
+        }
+    }
+
+    /// Return values stored without a checksum as they are, rather than as corrupt.
+    #[must_use]
+    pub const fn allow_unchecked(mut self) -> Self {
+        self.allow_unchecked = true;
+        self
+    }
+
+    /// Obtain a reference to the wrapped persister.
+    pub const fn persister(&self) -> &P {
+        &self.persister
+    }
+
+    /// Return the wrapped persister.
+    #[allow(clippy::missing_const_for_fn)]
+    pub fn into_persister(self) -> P {
+        self.persister
+    }
+
+    /// Verify a value, returning what was stored after the header.
+    fn open<F>(
+        &self,
+        value: Vec<u8>,
+        tag: u8,
+        extra: &[u8],
+        key: F,
+    ) -> Result<Vec<u8>, ChecksumPersisterError<P::Error>>
+    where
+        F: FnOnce() -> StoredKey,
+    {
+        if verify(&value, tag, extra) {
+            Ok(value[HEADER_LEN..].to_vec())
+        } else if self.allow_unchecked && !value.starts_with(&MAGIC) {
+            Ok(value)
+        } else {
+            Err(ChecksumPersisterError::Corrupt(key()))
+        }
+    }
+
+    /// Verify a stored change, returning the change that follows the key stored with it.
+    ///
+    /// `key` is what the wrapped persister stores the change under, if it can say, which has to
+    /// match the key stored with the change and names the change if it is damaged. Otherwise the
+    /// key stored with the change is the best guess, though it may have been damaged along with
+    /// the rest.
+    fn open_change(
+        &self,
+        value: Vec<u8>,
+        key: Option<(&ActorId, u64)>,
+    ) -> Result<Vec<u8>, ChecksumPersisterError<P::Error>> {
+        if self.allow_unchecked && !value.starts_with(&MAGIC) {
+            return Ok(value);
+        }
+        let stored = if verify(&value, CHANGE_TAG, &[]) {
+            parse_change_key(&value)
+        } else {
+            None
+        };
+        match (stored, key) {
+            (Some((_, _, start)), None) => Ok(value[start..].to_vec()),
+            (Some((actor_id, seq, start)), Some((a, s))) if actor_id == *a && seq == s => {
+                Ok(value[start..].to_vec())
+            }
+            _ => {
+                let key = key
+                    .map(|(actor_id, seq)| (actor_id.clone(), seq))
+                    .or_else(|| parse_change_key(&value).map(|(actor_id, seq, _)| (actor_id, seq)));
+                Err(ChecksumPersisterError::Corrupt(key.map_or(
+                    StoredKey::UnidentifiedChange,
+                    |(actor_id, seq)| StoredKey::Change { actor_id, seq },
+                )))
+            }
+        }
+    }
+
+    /// Verify a change read along with its key, returning it as it was stored if it is damaged.
+    fn open_keyed_change(
+        &self,
+        actor_id: ActorId,
+        seq: u64,
+        value: Vec<u8>,
+    ) -> (ActorId, u64, Vec<u8>) {
+        match self.open_change(value.clone(), Some((&actor_id, seq))) {
+            Ok(change) => (actor_id, seq, change),
+            Err(_) => (actor_id, seq, value),
+        }
+    }
+}
+
+fn checksum(tag: u8, extra: &[u8], body: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[tag]);
+    hasher.update(extra);
+    hasher.update(body);
+    hasher.finalize()
+}
+
+/// Store `body` behind a header with its checksum, covering the kind of value with `tag` and
+/// `extra` so values can't be swapped between keys unnoticed.
+fn seal(tag: u8, extra: &[u8], body: Vec<u8>) -> Vec<u8> {
+    let mut value = Vec::with_capacity(HEADER_LEN + body.len());
+    value.extend(&MAGIC);
+    value.extend(&checksum(tag, extra, &body).to_le_bytes());
+    value.extend(body);
+    value
+}
+
+fn verify(value: &[u8], tag: u8, extra: &[u8]) -> bool {
+    value.len() >= HEADER_LEN
+        && value.starts_with(&MAGIC)
+        && value[MAGIC.len()..HEADER_LEN]
+            == checksum(tag, extra, &value[HEADER_LEN..]).to_le_bytes()
+}
+
+/// Make the body for a change: the length of the actor id, the actor id, the sequence number and
+/// the change itself.
+fn change_body(actor_id: &ActorId, seq: u64, change: &[u8]) -> Vec<u8> {
+    let actor_id = actor_id.to_bytes();
+    let actor_id_len = u32::try_from(actor_id.len()).expect("actor id longer than u32::MAX");
+    let mut body = actor_id_len.to_le_bytes().to_vec();
+    body.extend(actor_id);
+    body.extend(&seq.to_le_bytes());
+    body.extend(change);
+    body
+}
+
+/// Read the actor id and sequence number from a stored change along with where the change
+/// starts.
+fn parse_change_key(value: &[u8]) -> Option<(ActorId, u64, usize)> {
+    let len_end = HEADER_LEN + 4;
+    let actor_id_len = u32::from_le_bytes(value.get(HEADER_LEN..len_end)?.try_into().ok()?);
+    let actor_id_end = len_end.checked_add(usize::try_from(actor_id_len).ok()?)?;
+    let actor_id = ActorId::from_bytes(value.get(len_end..actor_id_end)?);
+    let seq_end = actor_id_end + 8;
+    let seq = u64::from_le_bytes(value.get(actor_id_end..seq_end)?.try_into().ok()?);
+    Some((actor_id, seq, seq_end))
+}
+
+impl<P> Persister for ChecksumPersister<P>
+where
+    P: Persister,
+{
+    type Error = ChecksumPersisterError<P::Error>;
+
+    /// The changes are read with their keys when the wrapped persister can say what they are.
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        if let Some(changes) = self
+            .persister
+            .get_changes_with_keys()
+            .map_err(ChecksumPersisterError::PersisterError)?
+        {
+            return changes
+                .into_iter()
+                .map(|(a, s, c)| self.open_change(c, Some((&a, s))))
+                .collect();
+        }
+        self.persister
+            .get_changes()
+            .map_err(ChecksumPersisterError::PersisterError)?
+            .into_iter()
+            .map(|c| self.open_change(c, None))
+            .collect()
+    }
+
+    /// The changes are read with their keys when the wrapped persister can say what they are.
+    fn iter_changes(&self) -> Result<ChangeIter<'_, Self::Error>, Self::Error> {
+        if let Some(changes) = self
+            .persister
+            .iter_changes_with_keys()
+            .map_err(ChecksumPersisterError::PersisterError)?
+        {
+            return Ok(Box::new(changes.map(move |c| {
+                let (a, s, c) = c.map_err(ChecksumPersisterError::PersisterError)?;
+                self.open_change(c, Some((&a, s)))
+            })));
+        }
+        Ok(Box::new(
+            self.persister
+                .iter_changes()
+                .map_err(ChecksumPersisterError::PersisterError)?
+                .map(move |c| {
+                    self.open_change(c.map_err(ChecksumPersisterError::PersisterError)?, None)
+                }),
+        ))
+    }
+
+    /// Damaged changes are returned as they were stored, rather than failing, as their keys are
+    /// known. They won't decode as changes so a lenient load discards them.
+    fn get_changes_with_keys(&self) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        Ok(self
+            .persister
+            .get_changes_with_keys()
+            .map_err(ChecksumPersisterError::PersisterError)?
+            .map(|changes| {
+                changes
+                    .into_iter()
+                    .map(|(a, s, c)| self.open_keyed_change(a, s, c))
+                    .collect()
+            }))
+    }
+
+    /// Damaged changes are returned as they were stored, like with `get_changes_with_keys`.
+    fn iter_changes_with_keys(
+        &self,
+    ) -> Result<Option<KeyedChangeIter<'_, Self::Error>>, Self::Error> {
+        Ok(self
+            .persister
+            .iter_changes_with_keys()
+            .map_err(ChecksumPersisterError::PersisterError)?
+            .map(|changes| {
+                Box::new(changes.map(move |c| {
+                    let (a, s, c) = c.map_err(ChecksumPersisterError::PersisterError)?;
+                    Ok(self.open_keyed_change(a, s, c))
+                })) as KeyedChangeIter<'_, _>
+            }))
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error> {
+        let sealed = changes
+            .iter()
+            .map(|(a, s, c)| seal(CHANGE_TAG, &[], change_body(a, *s, c)))
+            .collect::<Vec<_>>();
+        self.persister
+            .insert_changes(
+                changes
+                    .iter()
+                    .zip(&sealed)
+                    .map(|((a, s, _), c)| (*a, *s, &c[..]))
+                    .collect(),
+            )
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.persister
+            .remove_changes(changes)
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+
+    fn quarantine_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<bool, Self::Error> {
+        self.persister
+            .quarantine_changes(changes)
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+
+    /// Damaged changes are returned as they were stored, like with `get_changes_with_keys`.
+    fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        Ok(self
+            .persister
+            .get_quarantined_changes()
+            .map_err(ChecksumPersisterError::PersisterError)?
+            .into_iter()
+            .map(|(a, s, c)| self.open_keyed_change(a, s, c))
+            .collect())
+    }
+
+    fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        self.persister
+            .remove_quarantined_changes(changes)
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.persister
+            .get_document()
+            .map_err(ChecksumPersisterError::PersisterError)?
+            .map(|d| self.open(d, DOCUMENT_TAG, &[], || StoredKey::Document))
+            .transpose()
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.persister
+            .set_document(seal(DOCUMENT_TAG, &[], data))
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.persister
+            .get_sync_state(peer_id)
+            .map_err(ChecksumPersisterError::PersisterError)?
+            .map(|s| {
+                self.open(s, SYNC_STATE_TAG, peer_id, || StoredKey::SyncState {
+                    peer_id: peer_id.to_vec(),
+                })
+            })
+            .transpose()
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let value = seal(SYNC_STATE_TAG, &peer_id, sync_state);
+        self.persister
+            .set_sync_state(peer_id, value)
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.persister
+            .remove_sync_states(peer_ids)
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.persister
+            .get_peer_ids()
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+
+    fn get_sync_state_infos(&self) -> Result<Vec<SyncStateInfo>, Self::Error> {
+        self.persister
+            .get_sync_state_infos()
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+
+    fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        self.persister
+            .get_actor_id()
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+
+    fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        self.persister
+            .set_actor_id(actor_id)
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        self.persister
+            .compact(seal(DOCUMENT_TAG, &[], document), changes, old_peer_ids)
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.persister.sizes()
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.persister
+            .flush()
+            .map_err(ChecksumPersisterError::PersisterError)
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt::Debug;
+
+use crate::{StoredSizes, SyncStateInfo};
+
+/// A `CompactionPolicy` decides when a persistent backend or document should compact its storage
+/// without the user calling `compact` themselves.
+///
+/// The policy is consulted after every operation that persists new changes.
+pub trait CompactionPolicy: Debug + Send {
+    /// Returns whether storage should be compacted now.
+    ///
+    /// `changes_since_compaction` is the number of changes persisted since the last compaction (or
+    /// since loading). Received changes that are waiting for their dependencies can't be compacted
+    /// so are left out of both this and `sizes.changes`.
+    fn should_compact(&mut self, sizes: &StoredSizes, changes_since_compaction: usize) -> bool;
+
+    /// Selects which of the stored sync states should be removed during an automatic compaction,
+    /// returning their peer ids.
+    ///
+    /// By default no sync states are removed.
+    fn select_peers_to_prune(&mut self, sync_states: Vec<SyncStateInfo>) -> Vec<Vec<u8>> {
+        let _ = sync_states;
+        Vec::new()
+    }
+}
+
+/// A [`CompactionPolicy`] that compacts once any of its configured thresholds is crossed.
+///
+/// With no thresholds set it never compacts.
+///
+/// ```rust
+/// # use automerge_persistent::MemoryPersister;
+/// # use automerge_persistent::PersistentBackend;
+/// # use automerge_persistent::ThresholdPolicy;
+/// # let persister = MemoryPersister::default();
+/// # let mut backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
+/// backend.set_compaction_policy(
+///     ThresholdPolicy::new()
+///         .max_changes_bytes(1024 * 1024)
+///         .max_changes(1000),
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct ThresholdPolicy {
+    changes_bytes: Option<usize>,
+    changes_percent_of_document: Option<usize>,
+    changes_without_document: usize,
+    changes: Option<usize>,
+}
+
+impl Default for ThresholdPolicy {
+    fn default() -> Self {
+        Self {
+            changes_bytes: None,
+            changes_percent_of_document: None,
+            changes_without_document: 100,
+            changes: None,
+        }
+    }
+}
+
+impl ThresholdPolicy {
+    /// Construct a new policy with no thresholds set.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Compact when the stored changes take up more than `bytes`.
+    #[must_use]
+    pub const fn max_changes_bytes(mut self, bytes: usize) -> Self {
+        self.changes_bytes = Some(bytes);
+        self
+    }
+
+    /// Compact when the stored changes take up more than `percent` of the size of the stored
+    /// document.
+    ///
+    /// Before the first compaction there is no document to compare against, so this waits for
+    /// the number of changes set by [`min_changes_without_document`](Self::min_changes_without_document)
+    /// instead.
+    #[must_use]
+    pub const fn max_changes_percent_of_document(mut self, percent: usize) -> Self {
+        self.changes_percent_of_document = Some(percent);
+        self
+    }
+
+    /// The number of changes that [`max_changes_percent_of_document`](Self::max_changes_percent_of_document)
+    /// waits for when there is no document yet, 100 by default.
+    #[must_use]
+    pub const fn min_changes_without_document(mut self, count: usize) -> Self {
+        self.changes_without_document = count;
+        self
+    }
+
+    /// Compact after every `count` changes.
+    #[must_use]
+    pub const fn max_changes(mut self, count: usize) -> Self {
+        self.changes = Some(count);
+        self
+    }
+}
+
+impl CompactionPolicy for ThresholdPolicy {
+    fn should_compact(&mut self, sizes: &StoredSizes, changes_since_compaction: usize) -> bool {
+        let over_bytes = self.changes_bytes.map_or(false, |max| sizes.changes > max);
+        let over_percent = self.changes_percent_of_document.map_or(false, |percent| {
+            if sizes.document == 0 {
+                changes_since_compaction >= self.changes_without_document
+            } else {
+                sizes.changes.saturating_mul(100) > sizes.document.saturating_mul(percent)
+            }
+        });
+        let over_count = self
+            .changes
+            .map_or(false, |max| changes_since_compaction >= max);
+        over_bytes || over_percent || over_count
+    }
+}
@@ -0,0 +1,537 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    convert::TryFrom,
+    io::{self, Read, Write},
+};
+
+use automerge_protocol::ActorId;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+use crate::{
+    ChangeIter, KeyedChangeIter, Persister, StoredSizes, SyncStateInfo, UncompressedSizes,
+};
+
+/// Marks data written by a [`CompressedPersister`], automerge data never starts with a zero byte.
+const MAGIC: [u8; 4] = *b"\0amz";
+/// The data follows the header as is.
+const STORED: u8 = 0;
+/// The data following the header is deflated.
+const DEFLATE: u8 = 1;
+/// The magic bytes, the format and the little endian uncompressed length.
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+
+/// Errors returned by a [`CompressedPersister`].
+#[derive(Debug, thiserror::Error)]
+pub enum CompressedPersisterError<E> {
+    /// The stored data was compressed in a format this version does not know about.
+    #[error("unknown compression format {0}")]
+    UnknownFormat(u8),
+    /// The stored data could not be decompressed.
+    #[error("failed to decompress stored data")]
+    DecompressError(#[source] io::Error),
+    /// The decompressed data was not the length recorded when it was stored.
+    #[error("decompressed {actual} bytes but expected {expected}")]
+    LengthMismatch {
+        /// The length recorded with the data.
+        expected: u64,
+        /// The length of the decompressed data.
+        actual: usize,
+    },
+    /// An error from the wrapped persister.
+    #[error(transparent)]
+    PersisterError(E),
+}
+
+/// A persister wrapper that compresses the data before handing it to the wrapped persister.
+///
+/// Compression is opt-in for each of changes, the document and sync states. Compressed data is
+/// stored with a short header that tags the format, anything without it is returned as is so
+/// data stored before compression was turned on, or by a persister without this wrapper, still
+/// loads. Data that doesn't get any smaller is stored uncompressed.
+///
+/// [`sizes`](Persister::sizes) reports the bytes stored by the wrapped persister with the sizes
+/// before compression under [`uncompressed`](StoredSizes::uncompressed).
+///
+/// ```rust
+/// # use automerge_persistent::{CompressedPersister, MemoryPersister, PersistentBackend};
+/// let persister = CompressedPersister::new(MemoryPersister::default())
+///     .unwrap()
+///     .compress_changes()
+///     .compress_document();
+/// let backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
+/// ```
+#[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct CompressedPersister<P> {
+    persister: P,
+    changes: bool,
+    document: bool,
+    sync_states: bool,
+    level: Compression,
+    /// Uncompressed sizes of the stored changes, so replacing or removing them can be accounted
+    /// for. When the wrapped persister can't list its changes with their keys only the changes
+    /// written through this persister are known and the others are only counted in the total.
+    change_sizes: HashMap<(ActorId, u64), usize>,
+    /// Whether `change_sizes` has every stored change.
+    all_change_sizes: bool,
+    uncompressed_changes: usize,
+    uncompressed_document: usize,
+    uncompressed_sync_states: HashMap<Vec<u8>, usize>,
+}
+
+impl<P> CompressedPersister<P>
+where
+    P: Persister,
+{
+    /// Wrap a persister, with compression turned off for everything.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the persister when reading the existing data to calculate the
+    /// uncompressed sizes.
+    pub fn new(persister: P) -> Result<Self, CompressedPersisterError<P::Error>> {
+        let mut s = Self {
+            persister,
+            changes: false,
+            document: false,
+            sync_states: false,
+            level: Compression::default(),
+            change_sizes: HashMap::new(),
+            all_change_sizes: false,
+            uncompressed_changes: 0,
+            uncompressed_document: 0,
+            uncompressed_sync_states: HashMap::new(),
+        };
+        s.recount_changes()?;
+        s.uncompressed_document = s
+            .persister
+            .get_document()
+            .map_err(CompressedPersisterError::PersisterError)?
+            .map_or(0, |d| uncompressed_len(&d));
+        for peer_id in s
+            .persister
+            .get_peer_ids()
+            .map_err(CompressedPersisterError::PersisterError)?
+        {
+            if let Some(sync_state) = s
+                .persister
+                .get_sync_state(&peer_id)
+                .map_err(CompressedPersisterError::PersisterError)?
+            {
+                s.uncompressed_sync_states
+                    .insert(peer_id, uncompressed_len(&sync_state));
+            }
+        }
+        Ok(s)
+    }
+
+    /// Compress changes when they are inserted.
+    #[must_use]
+    pub const fn compress_changes(mut self) -> Self {
+        self.changes = true;
+        self
+    }
+
+    /// Compress the document when it is set.
+    #[must_use]
+    pub const fn compress_document(mut self) -> Self {
+        self.document = true;
+        self
+    }
+
+    /// Compress sync states when they are set.
+    #[must_use]
+    pub const fn compress_sync_states(mut self) -> Self {
+        self.sync_states = true;
+        self
+    }
+
+    /// Set the compression level, from 0 for none to 9 for the smallest output. Defaults to 6.
+    #[must_use]
+    pub const fn with_level(mut self, level: u32) -> Self {
+        self.level = Compression::new(level);
+        self
+    }
+
+    /// Obtain a reference to the wrapped persister.
+    pub const fn persister(&self) -> &P {
+        &self.persister
+    }
+
+    /// Return the wrapped persister.
+    #[allow(clippy::missing_const_for_fn)]
+    pub fn into_persister(self) -> P {
+        self.persister
+    }
+
+    /// Work out the uncompressed size of the changes from the lengths in their headers, without
+    /// decompressing them.
+    ///
+    /// The sizes are recorded by key when the wrapped persister can list them, otherwise only
+    /// the total is known and this is needed again when removing changes that weren't written
+    /// through this persister.
+    fn recount_changes(&mut self) -> Result<(), CompressedPersisterError<P::Error>> {
+        self.uncompressed_changes = 0;
+        if let Some(changes) = self
+            .persister
+            .iter_changes_with_keys()
+            .map_err(CompressedPersisterError::PersisterError)?
+        {
+            let mut change_sizes = HashMap::new();
+            for change in changes {
+                let (actor_id, seq, change) =
+                    change.map_err(CompressedPersisterError::PersisterError)?;
+                let len = uncompressed_len(&change);
+                self.uncompressed_changes += len;
+                change_sizes.insert((actor_id, seq), len);
+            }
+            self.change_sizes = change_sizes;
+            self.all_change_sizes = true;
+        } else {
+            for change in self
+                .persister
+                .iter_changes()
+                .map_err(CompressedPersisterError::PersisterError)?
+            {
+                let change = change.map_err(CompressedPersisterError::PersisterError)?;
+                self.uncompressed_changes += uncompressed_len(&change);
+            }
+        }
+        Ok(())
+    }
+
+    /// Account for the removal of the given changes once they are gone from storage.
+    fn changes_removed(
+        &mut self,
+        changes: Vec<(ActorId, u64)>,
+    ) -> Result<(), CompressedPersisterError<P::Error>> {
+        let mut all_known = true;
+        for key in changes {
+            match self.change_sizes.remove(&key) {
+                Some(len) => {
+                    self.uncompressed_changes = self.uncompressed_changes.saturating_sub(len);
+                }
+                // with every change known it was never stored
+                None if self.all_change_sizes => {}
+                None => all_known = false,
+            }
+        }
+        if all_known {
+            Ok(())
+        } else {
+            self.recount_changes()
+        }
+    }
+
+    fn encode<'a>(&self, data: Cow<'a, [u8]>, compress: bool) -> Cow<'a, [u8]> {
+        if compress {
+            let mut encoder = DeflateEncoder::new(make_header(DEFLATE, data.len()), self.level);
+            // writing to a vec can't fail
+            encoder
+                .write_all(&data)
+                .expect("failed to compress into memory");
+            let compressed = encoder.finish().expect("failed to compress into memory");
+            if compressed.len() < data.len() {
+                return Cow::Owned(compressed);
+            }
+        }
+        if data.starts_with(&MAGIC) {
+            // make sure it doesn't get mistaken for compressed data when read back
+            let mut stored = make_header(STORED, data.len());
+            stored.extend(data.iter());
+            Cow::Owned(stored)
+        } else {
+            data
+        }
+    }
+}
+
+fn make_header(format: u8, len: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend(&MAGIC);
+    header.push(format);
+    header.extend(&(len as u64).to_le_bytes());
+    header
+}
+
+/// Read the format and uncompressed length from the header, if the data has one.
+fn parse_header(data: &[u8]) -> Option<(u8, u64)> {
+    if data.len() < HEADER_LEN || !data.starts_with(&MAGIC) {
+        return None;
+    }
+    let mut len = [0; 8];
+    len.copy_from_slice(&data[MAGIC.len() + 1..HEADER_LEN]);
+    Some((data[MAGIC.len()], u64::from_le_bytes(len)))
+}
+
+/// The length of the data before it was compressed.
+fn uncompressed_len(data: &[u8]) -> usize {
+    parse_header(data)
+        .and_then(|(_, len)| usize::try_from(len).ok())
+        .unwrap_or(data.len())
+}
+
+/// Decompress data that has a header, reading no more than one byte past the length it declares.
+fn decode<E>(data: Vec<u8>) -> Result<Vec<u8>, CompressedPersisterError<E>> {
+    let (format, expected) = match parse_header(&data) {
+        Some(header) => header,
+        None if data.starts_with(&MAGIC) => {
+            return Err(CompressedPersisterError::DecompressError(
+                io::ErrorKind::UnexpectedEof.into(),
+            ))
+        }
+        // stored before compression was turned on
+        None => return Ok(data),
+    };
+    let body = &data[HEADER_LEN..];
+    let decoded = match format {
+        STORED => body.to_vec(),
+        DEFLATE => {
+            let mut decoded = Vec::new();
+            // a byte more than declared is enough to tell the length is wrong
+            DeflateDecoder::new(body)
+                .take(expected.saturating_add(1))
+                .read_to_end(&mut decoded)
+                .map_err(CompressedPersisterError::DecompressError)?;
+            decoded
+        }
+        other => return Err(CompressedPersisterError::UnknownFormat(other)),
+    };
+    if decoded.len() as u64 != expected {
+        return Err(CompressedPersisterError::LengthMismatch {
+            expected,
+            actual: decoded.len(),
+        });
+    }
+    Ok(decoded)
+}
+
+impl<P> Persister for CompressedPersister<P>
+where
+    P: Persister,
+{
+    type Error = CompressedPersisterError<P::Error>;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.persister
+            .get_changes()
+            .map_err(CompressedPersisterError::PersisterError)?
+            .into_iter()
+            .map(decode)
+            .collect()
+    }
+
+    fn iter_changes(&self) -> Result<ChangeIter<'_, Self::Error>, Self::Error> {
+        Ok(Box::new(
+            self.persister
+                .iter_changes()
+                .map_err(CompressedPersisterError::PersisterError)?
+                .map(|c| decode(c.map_err(CompressedPersisterError::PersisterError)?)),
+        ))
+    }
+
+    fn get_changes_with_keys(&self) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        self.persister
+            .get_changes_with_keys()
+            .map_err(CompressedPersisterError::PersisterError)?
+            .map(|changes| {
+                changes
+                    .into_iter()
+                    .map(|(a, s, c)| Ok((a, s, decode(c)?)))
+                    .collect()
+            })
+            .transpose()
+    }
+
+    fn iter_changes_with_keys(
+        &self,
+    ) -> Result<Option<KeyedChangeIter<'_, Self::Error>>, Self::Error> {
+        Ok(self
+            .persister
+            .iter_changes_with_keys()
+            .map_err(CompressedPersisterError::PersisterError)?
+            .map(|changes| {
+                Box::new(changes.map(|c| {
+                    let (a, s, c) = c.map_err(CompressedPersisterError::PersisterError)?;
+                    Ok((a, s, decode(c)?))
+                })) as KeyedChangeIter<'_, _>
+            }))
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error> {
+        let encoded = changes
+            .iter()
+            .map(|(_, _, c)| self.encode(Cow::Borrowed(*c), self.changes))
+            .collect::<Vec<_>>();
+        self.persister
+            .insert_changes(
+                changes
+                    .iter()
+                    .zip(&encoded)
+                    .map(|((a, s, _), c)| (*a, *s, &c[..]))
+                    .collect(),
+            )
+            .map_err(CompressedPersisterError::PersisterError)?;
+        for (a, s, c) in changes {
+            let len = c.len();
+            if let Some(old) = self.change_sizes.insert((a.clone(), s), len) {
+                self.uncompressed_changes = self.uncompressed_changes.saturating_sub(old);
+            }
+            self.uncompressed_changes += len;
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let keys = changes.iter().map(|(a, s)| ((*a).clone(), *s)).collect();
+        self.persister
+            .remove_changes(changes)
+            .map_err(CompressedPersisterError::PersisterError)?;
+        self.changes_removed(keys)
+    }
+
+    fn quarantine_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<bool, Self::Error> {
+        let keys = changes.iter().map(|(a, s)| ((*a).clone(), *s)).collect();
+        let quarantined = self
+            .persister
+            .quarantine_changes(changes)
+            .map_err(CompressedPersisterError::PersisterError)?;
+        if quarantined {
+            self.changes_removed(keys)?;
+        }
+        Ok(quarantined)
+    }
+
+    /// Changes that can't be decompressed are returned as they were stored.
+    fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        Ok(self
+            .persister
+            .get_quarantined_changes()
+            .map_err(CompressedPersisterError::PersisterError)?
+            .into_iter()
+            .map(|(a, s, c)| match decode::<P::Error>(c.clone()) {
+                Ok(change) => (a, s, change),
+                Err(_) => (a, s, c),
+            })
+            .collect())
+    }
+
+    fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        self.persister
+            .remove_quarantined_changes(changes)
+            .map_err(CompressedPersisterError::PersisterError)
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.persister
+            .get_document()
+            .map_err(CompressedPersisterError::PersisterError)?
+            .map(decode)
+            .transpose()
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let len = data.len();
+        self.persister
+            .set_document(self.encode(Cow::Owned(data), self.document).into_owned())
+            .map_err(CompressedPersisterError::PersisterError)?;
+        self.uncompressed_document = len;
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.persister
+            .get_sync_state(peer_id)
+            .map_err(CompressedPersisterError::PersisterError)?
+            .map(decode)
+            .transpose()
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let len = sync_state.len();
+        let sync_state = self
+            .encode(Cow::Owned(sync_state), self.sync_states)
+            .into_owned();
+        self.persister
+            .set_sync_state(peer_id.clone(), sync_state)
+            .map_err(CompressedPersisterError::PersisterError)?;
+        self.uncompressed_sync_states.insert(peer_id, len);
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.persister
+            .remove_sync_states(peer_ids)
+            .map_err(CompressedPersisterError::PersisterError)?;
+        for peer_id in peer_ids {
+            self.uncompressed_sync_states.remove(*peer_id);
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.persister
+            .get_peer_ids()
+            .map_err(CompressedPersisterError::PersisterError)
+    }
+
+    fn get_sync_state_infos(&self) -> Result<Vec<SyncStateInfo>, Self::Error> {
+        self.persister
+            .get_sync_state_infos()
+            .map_err(CompressedPersisterError::PersisterError)
+    }
+
+    fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        self.persister
+            .get_actor_id()
+            .map_err(CompressedPersisterError::PersisterError)
+    }
+
+    fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        self.persister
+            .set_actor_id(actor_id)
+            .map_err(CompressedPersisterError::PersisterError)
+    }
+
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let len = document.len();
+        let keys = changes.iter().map(|(a, s)| ((*a).clone(), *s)).collect();
+        let document = self
+            .encode(Cow::Owned(document), self.document)
+            .into_owned();
+        self.persister
+            .compact(document, changes, old_peer_ids)
+            .map_err(CompressedPersisterError::PersisterError)?;
+        self.uncompressed_document = len;
+        for peer_id in old_peer_ids {
+            self.uncompressed_sync_states.remove(*peer_id);
+        }
+        self.changes_removed(keys)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        StoredSizes {
+            uncompressed: Some(UncompressedSizes {
+                changes: self.uncompressed_changes,
+                document: self.uncompressed_document,
+                sync_states: self.uncompressed_sync_states.values().sum(),
+            }),
+            ..self.persister.sizes()
+        }
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.persister
+            .flush()
+            .map_err(CompressedPersisterError::PersisterError)
+    }
+}
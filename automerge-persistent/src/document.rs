@@ -1,13 +1,20 @@
-use std::{collections::HashMap, fmt::Debug};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+};
 
 use automerge::{
     value_ref::RootRef, Automerge, AutomergeBuilder, AutomergeError, Backend, Change, Frontend,
     MutableDocument, Path, Value,
 };
 use automerge_backend::{SyncMessage, SyncState};
-use automerge_protocol::{ChangeHash, OpId};
+use automerge_protocol::{ActorId, ChangeHash, OpId};
 
-use crate::Persister;
+use crate::{
+    load, received_changes, received_keys, received_to_insert, received_to_remove,
+    sizes_without_queued, track_queued, Applied, CompactionPolicy, DiscardedDocument, LoadOptions,
+    LoadReport, PeerId, Persister, ReceivedKeys, StoredSizes, SyncStateInfo,
+};
 
 /// Errors that persistent backends can return.
 #[derive(Debug, thiserror::Error)]
@@ -20,16 +27,315 @@ pub enum Error<E> {
     PersisterError(E),
     /// An error resulting from a user-provided change function.
     #[error("change error: {0}")]
-    ChangeError(Box<dyn std::error::Error>),
+    ChangeError(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Wrap an error from the automerge backend.
+fn backend_error<E>(e: automerge_backend::AutomergeError) -> Error<E> {
+    Error::AutomergeError(AutomergeError::BackendError(e))
+}
+
+/// The in-memory state of a persistent document.
+///
+/// This holds the logic of [`PersistentAutomerge`] without doing any storage I/O, so that
+/// [`AsyncPersistentAutomerge`](crate::AsyncPersistentAutomerge) can share it and only differ in
+/// how it calls the persister.
+#[derive(Debug)]
+pub struct DocumentCore {
+    /// The document itself.
+    pub automerge: Automerge,
+    /// The sync states of the peers that have been synced with since loading.
+    pub sync_states: HashMap<PeerId, SyncState>,
+    /// The persisted actor id of the frontend, if it was not supplied by the user.
+    pub actor_id: Option<ActorId>,
+    /// The policy used to compact automatically.
+    pub compaction_policy: Option<Box<dyn CompactionPolicy>>,
+    changes_since_compaction: usize,
+    /// The actor id of the frontend, kept for rebuilding it.
+    frontend_actor_id: ActorId,
+    /// The received changes that the backend has queued as their dependencies are missing.
+    queued: HashMap<ChangeHash, Change>,
 }
 
-type PeerId = Vec<u8>;
+impl DocumentCore {
+    /// Start from the stored document using the given frontend, with a lenient load falling back
+    /// to an empty document if it can't be loaded.
+    ///
+    /// Loading takes the document rather than a copy, so one that fails is reported without its
+    /// data, see [`load::fill_discarded_document`].
+    pub fn load<E>(
+        document: Option<Vec<u8>>,
+        frontend: Frontend,
+        options: &LoadOptions,
+        report: &mut LoadReport,
+    ) -> Result<Self, Error<E>> {
+        let backend = match document {
+            Some(document) if options.is_lenient() => match Backend::load(document) {
+                Ok(backend) => backend,
+                Err(e) => {
+                    report.discarded_document = Some(DiscardedDocument {
+                        data: None,
+                        error: e.to_string(),
+                    });
+                    Backend::default()
+                }
+            },
+            Some(document) => Backend::load(document).map_err(backend_error)?,
+            None => Backend::default(),
+        };
+        let frontend_actor_id = frontend.actor_id.clone();
+        let automerge = AutomergeBuilder::default()
+            .with_frontend(frontend)
+            .with_backend(backend)
+            .build();
+        Ok(Self {
+            automerge,
+            sync_states: HashMap::new(),
+            actor_id: None,
+            compaction_policy: None,
+            changes_since_compaction: 0,
+            frontend_actor_id,
+            queued: HashMap::new(),
+        })
+    }
+
+    /// Decode and apply a batch of stored changes during a load.
+    pub fn load_batch<E>(
+        &mut self,
+        batch: load::StoredChanges,
+        options: &LoadOptions,
+        report: &mut LoadReport,
+        undecoded: &mut load::Undecoded,
+    ) -> Result<(), Error<E>> {
+        let changes =
+            load::decode_changes(batch, options, report, undecoded).map_err(backend_error)?;
+        self.apply_changes(changes)?;
+        // only changes persisted after loading count towards the compaction policy
+        self.changes_since_compaction = 0;
+        Ok(())
+    }
+
+    /// Make a change with the frontend.
+    pub fn change<F, O, E, PE>(
+        &mut self,
+        message: Option<String>,
+        change_closure: F,
+    ) -> Result<Applied<O>, Error<PE>>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+        F: FnOnce(&mut dyn MutableDocument) -> Result<O, E>,
+    {
+        let heads = self.automerge.get_heads();
+        let (result, _) = self
+            .automerge
+            .change(message, change_closure)
+            .map_err(|e| Error::ChangeError(Box::new(e)))?;
+        Ok(Applied { result, heads })
+    }
+
+    /// Whether the change with the given hash is applied or queued.
+    pub fn has_change(&self, hash: &ChangeHash) -> bool {
+        self.queued.contains_key(hash) || self.automerge.get_change_by_hash(hash).is_some()
+    }
+
+    /// The `changes` made elsewhere that need persisting.
+    pub fn received_changes<'a>(&self, changes: &'a [Change]) -> Vec<&'a Change> {
+        received_changes(changes, |hash| self.has_change(hash))
+    }
+
+    /// The `changes` that aren't applied yet, to track which the backend queues.
+    fn unapplied(&self, changes: &[Change]) -> Vec<Change> {
+        changes
+            .iter()
+            .filter(|c| self.automerge.get_change_by_hash(&c.hash).is_none())
+            .cloned()
+            .collect()
+    }
+
+    /// Apply changes made elsewhere, which should already have been persisted.
+    pub fn apply_changes<E>(&mut self, changes: Vec<Change>) -> Result<(), Error<E>> {
+        let received = self.unapplied(&changes);
+        self.automerge
+            .apply_changes(changes)
+            .map_err(Error::AutomergeError)?;
+        self.track_queued(received);
+        Ok(())
+    }
+
+    fn track_queued(&mut self, received: Vec<Change>) {
+        let automerge = &self.automerge;
+        self.changes_since_compaction += track_queued(&mut self.queued, received, |hash| {
+            automerge.get_change_by_hash(hash)
+        });
+    }
+
+    /// The changes that `applied` added, to be inserted into the persister.
+    pub fn changes_to_insert<T>(&self, applied: &Applied<T>) -> Vec<(&ActorId, u64, &[u8])> {
+        self.automerge
+            .get_changes(&applied.heads)
+            .into_iter()
+            .map(|c| (c.actor_id(), c.seq, c.raw_bytes()))
+            .collect()
+    }
+
+    /// Record that `count` local changes were inserted into the persister.
+    pub fn inserted(&mut self, count: usize) {
+        self.changes_since_compaction += count;
+    }
+
+    /// Rebuild the document from only the changes it had at `heads`.
+    ///
+    /// This replays the whole history so is only used when a local change fails to persist,
+    /// received changes are persisted before they are applied so never need rolling back.
+    pub fn rollback<E>(&mut self, heads: &[ChangeHash]) -> Result<(), Error<E>> {
+        let new_changes = self
+            .automerge
+            .get_changes(heads)
+            .into_iter()
+            .map(|c| c.hash)
+            .collect::<HashSet<_>>();
+        let old_changes = self
+            .automerge
+            .get_changes(&[])
+            .into_iter()
+            .filter(|c| !new_changes.contains(&c.hash))
+            .cloned()
+            .collect();
+        self.rebuild(old_changes)
+    }
+
+    /// Replace the document with one built from just `changes` and the queued ones, keeping the
+    /// actor id of the frontend.
+    pub fn rebuild<E>(&mut self, changes: Vec<Change>) -> Result<(), Error<E>> {
+        self.automerge = self.build(changes, &self.frontend_actor_id)?;
+        Ok(())
+    }
+
+    /// Build a document from `changes` and the queued ones, using `actor_id` for local changes.
+    fn build<E>(
+        &self,
+        mut changes: Vec<Change>,
+        actor_id: &ActorId,
+    ) -> Result<Automerge, Error<E>> {
+        changes.extend(self.queued.values().cloned());
+        let mut backend = Backend::default();
+        backend.apply_changes(changes).map_err(backend_error)?;
+        let frontend = Frontend::new_with_actor_id(&actor_id.to_bytes());
+        Ok(AutomergeBuilder::default()
+            .with_frontend(frontend)
+            .with_backend(backend)
+            .build())
+    }
+
+    /// A copy of the document that uses `actor_id` for local changes, to switch to with
+    /// [`Self::switch_actor_id`] once the actor id has been persisted.
+    pub fn with_actor_id<E>(&self, actor_id: &ActorId) -> Result<Automerge, Error<E>> {
+        let changes = self
+            .automerge
+            .get_changes(&[])
+            .into_iter()
+            .cloned()
+            .collect();
+        self.build(changes, actor_id)
+    }
+
+    /// Switch to the `automerge` document from [`Self::with_actor_id`].
+    pub fn switch_actor_id(&mut self, actor_id: ActorId, automerge: Automerge) {
+        self.automerge = automerge;
+        self.frontend_actor_id = actor_id.clone();
+        self.actor_id = Some(actor_id);
+    }
+
+    /// Whether the compaction policy says to compact storage of the given `sizes`.
+    pub fn should_compact(&mut self, sizes: &StoredSizes) -> bool {
+        let changes_since_compaction = self.changes_since_compaction;
+        let sizes = sizes_without_queued(sizes, &self.queued);
+        self.compaction_policy.as_mut().map_or(false, |policy| {
+            policy.should_compact(&sizes, changes_since_compaction)
+        })
+    }
+
+    /// Let the compaction policy choose which of the stored `sync_states` to prune, forgetting
+    /// them.
+    pub fn select_peers_to_prune(&mut self, sync_states: Vec<SyncStateInfo>) -> Vec<PeerId> {
+        let old_peer_ids = self
+            .compaction_policy
+            .as_mut()
+            .map_or_else(Vec::new, |policy| policy.select_peers_to_prune(sync_states));
+        for peer_id in &old_peer_ids {
+            self.sync_states.remove(peer_id);
+        }
+        old_peer_ids
+    }
+
+    /// The saved document and the keys of the changes it covers, for [`Persister::compact`].
+    #[allow(clippy::type_complexity)]
+    pub fn compaction<E>(&self) -> Result<(Vec<u8>, Vec<(&ActorId, u64)>), Error<E>> {
+        let changes = self.automerge.get_changes(&[]);
+        let saved_document = self.automerge.save().map_err(backend_error)?;
+        Ok((
+            saved_document,
+            changes.into_iter().map(|c| (c.actor_id(), c.seq)).collect(),
+        ))
+    }
+
+    /// Record that the storage was compacted.
+    pub fn compacted(&mut self) {
+        self.changes_since_compaction = 0;
+    }
+
+    /// Whether the sync state for `peer_id` is held in memory, otherwise it should be read from
+    /// the persister.
+    pub fn has_sync_state(&self, peer_id: &[u8]) -> bool {
+        self.sync_states.contains_key(peer_id)
+    }
+
+    /// Hold the sync state for `peer_id` that was read from the persister.
+    pub fn insert_sync_state<E>(
+        &mut self,
+        peer_id: &[u8],
+        sync_state: &[u8],
+    ) -> Result<(), Error<E>> {
+        let s = SyncState::decode(sync_state).map_err(|e| backend_error(e.into()))?;
+        self.sync_states.insert(peer_id.to_vec(), s);
+        Ok(())
+    }
+
+    /// The encoded sync state for `peer_id`, to be stored after using it.
+    pub fn encode_sync_state<E>(&mut self, peer_id: &[u8]) -> Result<Vec<u8>, Error<E>> {
+        self.sync_states
+            .entry(peer_id.to_vec())
+            .or_default()
+            .encode()
+            .map_err(|e| backend_error(e.into()))
+    }
+
+    /// Generate a sync message for `peer_id`.
+    pub fn generate_sync_message(&mut self, peer_id: &[u8]) -> Option<SyncMessage> {
+        let sync_state = self.sync_states.entry(peer_id.to_vec()).or_default();
+        self.automerge.generate_sync_message(sync_state)
+    }
+
+    /// Receive a sync message from `peer_id`, whose changes should already have been persisted.
+    pub fn receive_sync_message<E>(
+        &mut self,
+        peer_id: &[u8],
+        message: SyncMessage,
+    ) -> Result<(), Error<E>> {
+        let received = self.unapplied(&message.changes);
+        let sync_state = self.sync_states.entry(peer_id.to_vec()).or_default();
+        self.automerge
+            .receive_sync_message(sync_state, message)
+            .map_err(Error::AutomergeError)?;
+        self.track_queued(received);
+        Ok(())
+    }
+}
 
 /// A wrapper for a persister and an automerge document.
 #[derive(Debug)]
 pub struct PersistentAutomerge<P> {
-    automerge: Automerge,
-    sync_states: HashMap<PeerId, SyncState>,
+    core: DocumentCore,
     persister: P,
 }
 
@@ -37,9 +343,105 @@ impl<P> PersistentAutomerge<P>
 where
     P: Persister + 'static,
 {
+    /// Persist the local change added by an operation and compact if needed, returning the result
+    /// of the operation.
+    ///
+    /// If the persister fails then the document is rolled back to before the operation so that it
+    /// does not hold changes that were never stored.
+    fn persist<T>(&mut self, applied: Applied<T>) -> Result<T, Error<P::Error>> {
+        let result = self.insert_changes(applied)?;
+        self.compact_if_needed()?;
+        Ok(result)
+    }
+
+    /// Persist the `changes` made elsewhere that aren't known yet, returning their keys.
+    ///
+    /// Persisting before [`receive`](Self::receive) applies them means the document never holds
+    /// changes that weren't stored.
+    fn persist_received(&mut self, changes: &[Change]) -> Result<ReceivedKeys, Error<P::Error>> {
+        let received = self.core.received_changes(changes);
+        if !received.is_empty() {
+            self.persister
+                .insert_changes(received_to_insert(&received))
+                .map_err(Error::PersisterError)?;
+        }
+        Ok(received_keys(&received))
+    }
+
+    /// Apply changes persisted by [`persist_received`](Self::persist_received) with `apply`,
+    /// removing the `received` ones from storage again if that fails.
+    fn receive<T>(
+        &mut self,
+        received: &[(ActorId, u64)],
+        apply: impl FnOnce(&mut DocumentCore) -> Result<T, Error<P::Error>>,
+    ) -> Result<T, Error<P::Error>> {
+        match apply(&mut self.core) {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                // the apply error is the one worth returning, changes left behind fail to apply
+                // again when loading and are dealt with there
+                if let Err(cleanup) = self.persister.remove_changes(received_to_remove(received)) {
+                    log::warn!(
+                        "failed to remove received changes that could not be applied: {}",
+                        cleanup
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn insert_changes<T>(&mut self, applied: Applied<T>) -> Result<T, Error<P::Error>> {
+        let changes = self.core.changes_to_insert(&applied);
+        let count = changes.len();
+        if let Err(e) = self.persister.insert_changes(changes) {
+            self.core.rollback(&applied.heads)?;
+            return Err(Error::PersisterError(e));
+        }
+        self.core.inserted(count);
+        Ok(applied.result)
+    }
+
+    /// Compact the storage if the compaction policy says so.
+    fn compact_if_needed(&mut self) -> Result<(), Error<P::Error>> {
+        if !self.core.should_compact(&self.persister.sizes()) {
+            return Ok(());
+        }
+        let sync_states = self
+            .persister
+            .get_sync_state_infos()
+            .map_err(Error::PersisterError)?;
+        let old_peer_ids = self.core.select_peers_to_prune(sync_states);
+        self.compact(&old_peer_ids.iter().map(Vec::as_slice).collect::<Vec<_>>())
+    }
+
+    /// Read the sync state for `peer_id` from the persister unless it is already held.
+    fn load_sync_state(&mut self, peer_id: &[u8]) -> Result<(), Error<P::Error>> {
+        if !self.core.has_sync_state(peer_id) {
+            if let Some(sync_state) = self
+                .persister
+                .get_sync_state(peer_id)
+                .map_err(Error::PersisterError)?
+            {
+                self.core.insert_sync_state(peer_id, &sync_state)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn store_sync_state(&mut self, peer_id: PeerId) -> Result<(), Error<P::Error>> {
+        let sync_state = self.core.encode_sync_state(&peer_id)?;
+        self.persister
+            .set_sync_state(peer_id, sync_state)
+            .map_err(Error::PersisterError)
+    }
+
     /// Load the persisted changes (both individual changes and whole document) from storage and
     /// rebuild the document.
     ///
+    /// The frontend uses the actor id stored in the persister, a new one is generated and stored if
+    /// there isn't one yet. This keeps the same actor id across restarts.
+    ///
     /// ```rust
     /// # use automerge_persistent::MemoryPersister;
     /// # use automerge_persistent::PersistentAutomerge;
@@ -47,105 +449,152 @@ where
     /// let document = PersistentAutomerge::<_>::load(persister).unwrap();
     /// ```
     pub fn load(persister: P) -> Result<Self, Error<P::Error>> {
-        let document = persister.get_document().map_err(Error::PersisterError)?;
-        let mut automerge = if let Some(document) = document {
-            Automerge::load(document).map_err(Error::AutomergeError)?
-        } else {
-            Automerge::default()
-        };
-
-        let change_bytes = persister.get_changes().map_err(Error::PersisterError)?;
-
-        let mut changes = Vec::new();
-        for change_bytes in change_bytes {
-            changes.push(
-                Change::from_bytes(change_bytes)
-                    .map_err(|e| Error::AutomergeError(AutomergeError::BackendError(e.into())))?,
-            );
-        }
+        Self::load_with_options(persister, &LoadOptions::default()).map(|(s, _)| s)
+    }
 
-        automerge
-            .apply_changes(changes)
-            .map_err(Error::AutomergeError)?;
-        Ok(Self {
-            automerge,
-            sync_states: HashMap::new(),
-            persister,
-        })
+    /// Load from storage with the given options, returning what was skipped.
+    ///
+    /// The actor id is handled as in [`load`](Self::load).
+    pub fn load_with_options(
+        mut persister: P,
+        options: &LoadOptions,
+    ) -> Result<(Self, LoadReport), Error<P::Error>> {
+        let actor_id =
+            if let Some(actor_id) = persister.get_actor_id().map_err(Error::PersisterError)? {
+                actor_id
+            } else {
+                let actor_id = ActorId::random();
+                persister
+                    .set_actor_id(actor_id.clone())
+                    .map_err(Error::PersisterError)?;
+                actor_id
+            };
+        let frontend = Frontend::new_with_actor_id(&actor_id.to_bytes());
+        let (mut s, report) = Self::load_with_frontend_and_options(persister, frontend, options)?;
+        s.core.actor_id = Some(actor_id);
+        Ok((s, report))
     }
 
+    /// Load the persisted changes using the given frontend.
+    ///
+    /// The actor id stored in the persister is not used or updated.
     pub fn load_with_frontend(persister: P, frontend: Frontend) -> Result<Self, Error<P::Error>> {
-        let document = persister.get_document().map_err(Error::PersisterError)?;
-        let mut automerge = if let Some(document) = document {
-            AutomergeBuilder::default()
-                .with_frontend(frontend)
-                .with_backend(
-                    Backend::load(document)
-                        .map_err(|e| Error::AutomergeError(AutomergeError::BackendError(e)))?,
-                )
-                .build()
-        } else {
-            AutomergeBuilder::default().with_frontend(frontend).build()
-        };
+        Self::load_with_frontend_and_options(persister, frontend, &LoadOptions::default())
+            .map(|(s, _)| s)
+    }
 
-        let change_bytes = persister.get_changes().map_err(Error::PersisterError)?;
+    /// Load the persisted changes using the given frontend and options, returning what was
+    /// skipped.
+    ///
+    /// The actor id stored in the persister is not used or updated.
+    pub fn load_with_frontend_and_options(
+        mut persister: P,
+        frontend: Frontend,
+        options: &LoadOptions,
+    ) -> Result<(Self, LoadReport), Error<P::Error>> {
+        let mut report = LoadReport::default();
+        let document = load::document(persister.get_document(), options, &mut report)
+            .map_err(Error::PersisterError)?;
+        let stored_document = document.is_some();
+        let mut core = DocumentCore::load(document, frontend, options, &mut report)?;
+        if stored_document && report.discarded_document.is_some() {
+            load::fill_discarded_document(&mut report, persister.get_document());
+        }
 
-        let mut changes = Vec::new();
-        for change_bytes in change_bytes {
-            changes.push(
-                Change::from_bytes(change_bytes)
-                    .map_err(|e| Error::AutomergeError(AutomergeError::BackendError(e.into())))?,
-            );
+        let mut stored =
+            load::stored_changes(&persister, options).map_err(Error::PersisterError)?;
+        let mut undecoded = load::Undecoded::default();
+        loop {
+            let batch = load::next_batch(&mut stored, options).map_err(Error::PersisterError)?;
+            if batch.is_empty() {
+                break;
+            }
+            core.load_batch(batch, options, &mut report, &mut undecoded)?;
+        }
+        drop(stored);
+        if !undecoded.is_empty() {
+            let stored =
+                load::stored_changes(&persister, options).map_err(Error::PersisterError)?;
+            load::fill_discarded(&mut report, &undecoded, stored).map_err(Error::PersisterError)?;
         }
 
-        automerge
-            .apply_changes(changes)
-            .map_err(Error::AutomergeError)?;
-        Ok(Self {
-            automerge,
-            sync_states: HashMap::new(),
-            persister,
-        })
+        load::set_aside(&mut persister, options, &mut report).map_err(Error::PersisterError)?;
+        Ok((Self { core, persister }, report))
+    }
+
+    /// The actor id used for local changes, if it is managed by this document.
+    ///
+    /// This is `None` when the document was loaded with a user supplied frontend.
+    pub const fn actor_id(&self) -> Option<&ActorId> {
+        self.core.actor_id.as_ref()
+    }
+
+    /// Replace the actor id used for local changes with a new random one and persist it.
+    ///
+    /// Existing changes keep their actor ids, only changes made after this use the new one. If the
+    /// new actor id can't be persisted the document keeps using the old one.
+    pub fn rotate_actor_id(&mut self) -> Result<ActorId, Error<P::Error>> {
+        let actor_id = ActorId::random();
+        let automerge = self.core.with_actor_id(&actor_id)?;
+        self.persister
+            .set_actor_id(actor_id.clone())
+            .map_err(Error::PersisterError)?;
+        self.core.switch_actor_id(actor_id.clone(), automerge);
+        Ok(actor_id)
     }
 
     pub fn state(&mut self) -> &Value {
-        self.automerge.state()
+        self.core.automerge.state()
     }
 
     pub fn value_ref(&self) -> RootRef {
-        self.automerge.value_ref()
+        self.core.automerge.value_ref()
     }
 
+    /// Make a change to the document and persist it.
+    ///
+    /// If the change cannot be persisted the document is left as it was before this call.
     pub fn change<F, O, E>(
         &mut self,
         message: Option<String>,
         change_closure: F,
     ) -> Result<O, Error<P::Error>>
     where
-        E: std::error::Error + 'static,
+        E: std::error::Error + Send + Sync + 'static,
         F: FnOnce(&mut dyn MutableDocument) -> Result<O, E>,
     {
-        let heads = self.automerge.get_heads();
-        let (res, _) = self
-            .automerge
-            .change(message, change_closure)
-            .map_err(|e| Error::ChangeError(Box::new(e)))?;
-        let changes = self.automerge.get_changes(&heads);
-        self.persister
-            .insert_changes(
-                changes
-                    .into_iter()
-                    .map(|c| (c.actor_id().clone(), c.seq, c.raw_bytes().to_vec()))
-                    .collect(),
-            )
-            .map_err(Error::PersisterError)?;
-        Ok(res)
+        let applied = self.core.change(message, change_closure)?;
+        self.persist(applied)
+    }
+
+    /// Apply changes made elsewhere, such as by another document sharing the same storage, and
+    /// persist them.
+    ///
+    /// The changes are persisted before they are applied, so if they cannot be persisted the
+    /// document is left as it was before this call.
+    pub fn apply_changes(&mut self, changes: Vec<Change>) -> Result<(), Error<P::Error>> {
+        let received = self.persist_received(&changes)?;
+        self.receive(&received, |core| core.apply_changes(changes))?;
+        self.compact_if_needed()
+    }
+
+    /// Apply changes that are already stored, such as those written by another document sharing
+    /// the storage, without persisting them again.
+    pub fn apply_stored_changes(&mut self, changes: Vec<Change>) -> Result<(), Error<P::Error>> {
+        self.core.apply_changes(changes)
+    }
+
+    /// Whether the change with the given hash has been applied, or is queued waiting for its
+    /// dependencies.
+    pub fn has_change(&self, hash: &ChangeHash) -> bool {
+        self.core.has_change(hash)
     }
 
     /// Compact the storage.
     ///
-    /// This first obtains the changes currently in the document, saves the document and persists the
-    /// saved document. We then can remove the previously obtained changes one by one.
+    /// This first obtains the changes currently in the document, saves the document and hands the
+    /// saved document to the persister along with the changes it now covers so they can be
+    /// replaced in one step, see [`Persister::compact`].
     ///
     /// It also clears out the storage used up by old sync states for peers by removing those given
     /// in `old_peers`.
@@ -158,29 +607,30 @@ where
     /// document.compact(&[]).unwrap();
     /// ```
     pub fn compact(&mut self, old_peer_ids: &[&[u8]]) -> Result<(), Error<P::Error>> {
-        let changes = self.automerge.get_changes(&[]);
-        let saved_document = self
-            .automerge
-            .save()
-            .map_err(|e| Error::AutomergeError(AutomergeError::BackendError(e)))?;
-        self.persister
-            .set_document(saved_document)
-            .map_err(Error::PersisterError)?;
+        let (saved_document, changes) = self.core.compaction()?;
         self.persister
-            .remove_changes(changes.into_iter().map(|c| (c.actor_id(), c.seq)).collect())
-            .map_err(Error::PersisterError)?;
-        self.persister
-            .remove_sync_states(old_peer_ids)
+            .compact(saved_document, changes, old_peer_ids)
             .map_err(Error::PersisterError)?;
+        self.core.compacted();
         Ok(())
     }
 
+    /// Set the policy used to compact the storage automatically.
+    ///
+    /// See [`PersistentBackend::set_compaction_policy`](crate::PersistentBackend::set_compaction_policy).
+    pub fn set_compaction_policy<C>(&mut self, policy: C)
+    where
+        C: CompactionPolicy + 'static,
+    {
+        self.core.compaction_policy = Some(Box::new(policy));
+    }
+
     pub fn get_conflicts(&self, path: &Path) -> Option<HashMap<OpId, Value>> {
-        self.automerge.get_conflicts(path)
+        self.core.automerge.get_conflicts(path)
     }
 
     pub fn get_value(&self, path: &Path) -> Option<Value> {
-        self.automerge.get_value(path)
+        self.core.automerge.get_value(path)
     }
 
     /// Get all changes that have the given dependencies (transitively obtains more recent ones).
@@ -193,7 +643,7 @@ where
     /// let all_changes = document.get_changes(&[]);
     /// ```
     pub fn get_changes(&self, have_deps: &[ChangeHash]) -> Vec<&Change> {
-        self.automerge.get_changes(have_deps)
+        self.core.automerge.get_changes(have_deps)
     }
 
     /// Get the current heads of the hash graph (changes without successors).
@@ -206,7 +656,7 @@ where
     /// let heads = document.get_heads();
     /// ```
     pub fn get_heads(&self) -> Vec<ChangeHash> {
-        self.automerge.get_heads()
+        self.core.automerge.get_heads()
     }
 
     /// Generate a sync message to be sent to a peer document.
@@ -228,27 +678,9 @@ where
         &mut self,
         peer_id: PeerId,
     ) -> Result<Option<SyncMessage>, Error<P::Error>> {
-        if !self.sync_states.contains_key(&peer_id) {
-            if let Some(sync_state) = self
-                .persister
-                .get_sync_state(&peer_id)
-                .map_err(Error::PersisterError)?
-            {
-                let s = SyncState::decode(&sync_state)
-                    .map_err(|e| Error::AutomergeError(AutomergeError::BackendError(e.into())))?;
-                self.sync_states.insert(peer_id.clone(), s);
-            }
-        }
-        let sync_state = self.sync_states.entry(peer_id.clone()).or_default();
-        let message = self.automerge.generate_sync_message(sync_state);
-        self.persister
-            .set_sync_state(
-                peer_id,
-                sync_state
-                    .encode()
-                    .map_err(|e| Error::AutomergeError(AutomergeError::BackendError(e.into())))?,
-            )
-            .map_err(Error::PersisterError)?;
+        self.load_sync_state(&peer_id)?;
+        let message = self.core.generate_sync_message(&peer_id);
+        self.store_sync_state(peer_id)?;
         Ok(message)
     }
 
@@ -259,47 +691,21 @@ where
     ///
     /// This internally retrieves the previous sync state from storage and saves the new one
     /// afterwards.
+    ///
+    /// The received changes are persisted before the message is applied, so if they cannot be
+    /// persisted the document and sync state are left as they were before this call.
     pub fn receive_sync_message(
         &mut self,
         peer_id: PeerId,
         message: SyncMessage,
     ) -> Result<(), Error<P::Error>> {
-        if !self.sync_states.contains_key(&peer_id) {
-            if let Some(sync_state) = self
-                .persister
-                .get_sync_state(&peer_id)
-                .map_err(Error::PersisterError)?
-            {
-                let s = SyncState::decode(&sync_state)
-                    .map_err(|e| Error::AutomergeError(AutomergeError::BackendError(e.into())))?;
-                self.sync_states.insert(peer_id.clone(), s);
-            }
-        }
-        let sync_state = self.sync_states.entry(peer_id.clone()).or_default();
-
-        let heads = self.automerge.get_heads();
-        self.automerge
-            .receive_sync_message(sync_state, message)
-            .map_err(Error::AutomergeError)?;
-        let changes = self.automerge.get_changes(&heads);
-        self.persister
-            .insert_changes(
-                changes
-                    .into_iter()
-                    .map(|c| (c.actor_id().clone(), c.seq, c.raw_bytes().to_vec()))
-                    .collect(),
-            )
-            .unwrap();
-
-        self.persister
-            .set_sync_state(
-                peer_id,
-                sync_state
-                    .encode()
-                    .map_err(|e| Error::AutomergeError(AutomergeError::BackendError(e.into())))?,
-            )
-            .map_err(Error::PersisterError)?;
-        Ok(())
+        self.load_sync_state(&peer_id)?;
+        let received = self.persist_received(&message.changes)?;
+        self.receive(&received, |core| {
+            core.receive_sync_message(&peer_id, message)
+        })?;
+        self.store_sync_state(peer_id)?;
+        self.compact_if_needed()
     }
 
     /// Flush any data out to storage.
@@ -327,4 +733,12 @@ where
     pub fn persister(&self) -> &P {
         &self.persister
     }
+
+    /// Obtain a mutable reference to the persister.
+    ///
+    /// Writing through it bypasses the document, so it is for updating persister state that the
+    /// document doesn't track.
+    pub fn persister_mut(&mut self) -> &mut P {
+        &mut self.persister
+    }
 }
@@ -0,0 +1,601 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::{TryFrom, TryInto},
+    fmt,
+};
+
+use automerge_protocol::ActorId;
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+
+use crate::{ChangeIter, KeyedChangeIter, Persister, StoredSizes, SyncStateInfo};
+
+/// The version of the layout of sealed blobs.
+const FORMAT: u8 = 1;
+const NONCE_LEN: usize = 24;
+/// The format, the little endian key id and the nonce.
+const HEADER_LEN: usize = 1 + 4 + NONCE_LEN;
+/// The length of the Poly1305 tag after the ciphertext.
+const TAG_LEN: usize = 16;
+
+const CHANGE_TAG: u8 = b'c';
+const DOCUMENT_TAG: u8 = b'd';
+const SYNC_STATE_TAG: u8 = b's';
+
+/// Errors returned by an [`EncryptedPersister`].
+#[derive(Debug, thiserror::Error)]
+pub enum EncryptedPersisterError<E> {
+    /// The stored data is not in a format this version knows about, it may not be encrypted.
+    #[error("stored data is not in a known encrypted format")]
+    UnknownFormat,
+    /// The stored data was sealed with a key that hasn't been given to the persister.
+    #[error("no key with id {0}")]
+    UnknownKey(u32),
+    /// The stored data failed authentication, it has been corrupted or tampered with.
+    #[error("stored data failed authentication")]
+    DecryptError,
+    /// Generating a random nonce failed.
+    #[error("failed to generate a nonce")]
+    NonceError(#[source] getrandom::Error),
+    /// A key was given an id that is already in use.
+    #[error("key id {0} is already in use")]
+    DuplicateKeyId(u32),
+    /// Old keys can't be removed until a compaction has re-encrypted the data sealed with them.
+    #[error("data sealed with old keys has not been re-encrypted yet")]
+    ReencryptionPending,
+    /// An error from the wrapped persister.
+    #[error(transparent)]
+    PersisterError(E),
+}
+
+/// A persister wrapper that encrypts the data before handing it to the wrapped persister.
+///
+/// Each change, document and sync state is sealed with XChaCha20-Poly1305 under a random nonce
+/// and checked when read back. The keys the data is stored under, like actor ids and peer ids,
+/// are left in the clear and are authenticated along with the data, so sealed data can't be moved
+/// to another key. Changes also store their actor id and sequence number in the clear as the
+/// stored changes are read back without their keys. Sealed data is tagged with the id of its key so that keys can be
+/// rotated: [`rotate_key`](Self::rotate_key) keeps the previous key for reading, the next
+/// [`compact`](Persister::compact) re-encrypts whatever is left under the new key and
+/// [`remove_old_keys`](Self::remove_old_keys) then drops the keys that are no longer needed.
+///
+/// Sizes are those of the sealed data, which is [`OVERHEAD`](Self::OVERHEAD) bytes longer than
+/// the plaintext, and for changes another 12 bytes plus the length of the actor id.
+///
+/// ```rust
+/// # use automerge_persistent::{EncryptedPersister, MemoryPersister, PersistentBackend};
+/// let key = [7; 32];
+/// let persister = EncryptedPersister::new(MemoryPersister::default(), 1, key);
+/// let backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
+/// ```
+pub struct EncryptedPersister<P> {
+    persister: P,
+    key_id: u32,
+    keys: HashMap<u32, XChaCha20Poly1305>,
+    /// Whether data may still be sealed with an old key, so the next compaction re-encrypts.
+    reencrypt_pending: bool,
+}
+
+impl<P> fmt::Debug for EncryptedPersister<P>
+where
+    P: fmt::Debug,
+{
+    // only show the key ids, not the keys
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedPersister")
+            .field("persister", &self.persister)
+            .field("key_id", &self.key_id)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .field("reencrypt_pending", &self.reencrypt_pending)
+            .finish()
+    }
+}
+
+impl<P> EncryptedPersister<P>
+where
+    P: Persister,
+{
+    /// The number of bytes that sealing adds to each stored value.
+    pub const OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+
+    /// Wrap a persister, sealing new data with `key` under the id `key_id`.
+    pub fn new(persister: P, key_id: u32, key: [u8; 32]) -> Self {
+        let mut keys = HashMap::new();
+        keys.insert(key_id, XChaCha20Poly1305::new(&Key::from(key)));
+        Self {
+            persister,
+            key_id,
+            keys,
+            reencrypt_pending: false,
+        }
+    }
+
+    /// Add a key that is only used to read data that was sealed with it.
+    ///
+    /// The next compaction re-encrypts the data that remains under the current key.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`EncryptedPersisterError::DuplicateKeyId`] if `key_id` is already in use.
+    pub fn with_old_key(
+        mut self,
+        key_id: u32,
+        key: [u8; 32],
+    ) -> Result<Self, EncryptedPersisterError<P::Error>> {
+        if self.keys.contains_key(&key_id) {
+            return Err(EncryptedPersisterError::DuplicateKeyId(key_id));
+        }
+        self.keys
+            .insert(key_id, XChaCha20Poly1305::new(&Key::from(key)));
+        self.reencrypt_pending = true;
+        Ok(self)
+    }
+
+    /// Seal new data with `key` under the id `key_id`.
+    ///
+    /// The previous keys are kept for reading, they should be kept around until a compaction has
+    /// completed with the new key as it re-encrypts the data that remains.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`EncryptedPersisterError::DuplicateKeyId`] if `key_id` is already in use, as data
+    /// sealed with the old key would no longer be readable.
+    pub fn rotate_key(
+        &mut self,
+        key_id: u32,
+        key: [u8; 32],
+    ) -> Result<(), EncryptedPersisterError<P::Error>> {
+        if self.keys.contains_key(&key_id) {
+            return Err(EncryptedPersisterError::DuplicateKeyId(key_id));
+        }
+        self.keys
+            .insert(key_id, XChaCha20Poly1305::new(&Key::from(key)));
+        self.key_id = key_id;
+        self.reencrypt_pending = true;
+        Ok(())
+    }
+
+    /// Whether data may still be sealed with an old key, until the next compaction completes.
+    pub const fn reencrypt_pending(&self) -> bool {
+        self.reencrypt_pending
+    }
+
+    /// Drop the keys other than the current one, returning their ids.
+    ///
+    /// Quarantined changes sealed with an old key can no longer be decrypted afterwards and are
+    /// returned as they were stored.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`EncryptedPersisterError::ReencryptionPending`] if a compaction hasn't completed
+    /// since the last key was added, as data sealed with the old keys could still be stored.
+    pub fn remove_old_keys(&mut self) -> Result<Vec<u32>, EncryptedPersisterError<P::Error>> {
+        if self.reencrypt_pending {
+            return Err(EncryptedPersisterError::ReencryptionPending);
+        }
+        let key_id = self.key_id;
+        let old = self
+            .keys
+            .keys()
+            .copied()
+            .filter(|id| *id != key_id)
+            .collect::<Vec<_>>();
+        self.keys.retain(|id, _| *id == key_id);
+        Ok(old)
+    }
+
+    /// The id of the key that new data is sealed with.
+    pub const fn key_id(&self) -> u32 {
+        self.key_id
+    }
+
+    /// Obtain a reference to the wrapped persister.
+    pub const fn persister(&self) -> &P {
+        &self.persister
+    }
+
+    /// Return the wrapped persister.
+    #[allow(clippy::missing_const_for_fn)]
+    pub fn into_persister(self) -> P {
+        self.persister
+    }
+
+    /// Seal `data` with the current key, binding it to the kind of value with `tag` and `extra`.
+    ///
+    /// `clear` is stored in the clear after the header and authenticated with it.
+    fn seal(
+        &self,
+        data: &[u8],
+        tag: u8,
+        clear: &[u8],
+        extra: &[u8],
+    ) -> Result<Vec<u8>, EncryptedPersisterError<P::Error>> {
+        let mut header = Vec::with_capacity(HEADER_LEN + clear.len() + data.len() + TAG_LEN);
+        header.push(FORMAT);
+        header.extend(&self.key_id.to_le_bytes());
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).map_err(EncryptedPersisterError::NonceError)?;
+        header.extend(&nonce);
+        header.extend(clear);
+
+        let aad = make_aad(&header, tag, extra);
+        let ciphertext = self.keys[&self.key_id]
+            .encrypt(
+                &XNonce::from(nonce),
+                Payload {
+                    msg: data,
+                    aad: &aad,
+                },
+            )
+            // only fails for data far larger than could be stored anyway
+            .expect("failed to encrypt");
+        let mut sealed = header;
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    /// Check and decrypt `sealed` data that was bound to `tag` and `extra`, with the header and
+    /// anything stored in the clear ending at `header_end`.
+    fn open(
+        &self,
+        sealed: &[u8],
+        tag: u8,
+        header_end: usize,
+        extra: &[u8],
+    ) -> Result<Vec<u8>, EncryptedPersisterError<P::Error>> {
+        let key_id = sealed_key_id(sealed).ok_or(EncryptedPersisterError::UnknownFormat)?;
+        if sealed.len() < header_end + TAG_LEN {
+            return Err(EncryptedPersisterError::UnknownFormat);
+        }
+        let cipher = self
+            .keys
+            .get(&key_id)
+            .ok_or(EncryptedPersisterError::UnknownKey(key_id))?;
+        let (header, ciphertext) = sealed.split_at(header_end);
+        let nonce: [u8; NONCE_LEN] = header[5..HEADER_LEN].try_into().unwrap();
+        let aad = make_aad(header, tag, extra);
+        cipher
+            .decrypt(
+                &XNonce::from(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| EncryptedPersisterError::DecryptError)
+    }
+
+    fn seal_change(
+        &self,
+        actor_id: &ActorId,
+        seq: u64,
+        change: &[u8],
+    ) -> Result<Vec<u8>, EncryptedPersisterError<P::Error>> {
+        self.seal(change, CHANGE_TAG, &change_key(actor_id, seq), &[])
+    }
+
+    /// Check and decrypt a sealed change, returning it with the key it was sealed for.
+    fn open_change(
+        &self,
+        sealed: &[u8],
+    ) -> Result<(ActorId, u64, Vec<u8>), EncryptedPersisterError<P::Error>> {
+        let (actor_id, seq, header_end) =
+            parse_change_key(sealed).ok_or(EncryptedPersisterError::UnknownFormat)?;
+        let change = self.open(sealed, CHANGE_TAG, header_end, &[])?;
+        Ok((actor_id, seq, change))
+    }
+
+    /// Re-encrypt the changes and sync states that were sealed with an old key, skipping those
+    /// that are about to be removed.
+    ///
+    /// The changes are re-sealed under the keys stored with them so they don't need decoding.
+    fn reencrypt(
+        &mut self,
+        removed_changes: &HashSet<(&ActorId, u64)>,
+        removed_peer_ids: &HashSet<&[u8]>,
+    ) -> Result<(), EncryptedPersisterError<P::Error>> {
+        let mut changes = Vec::new();
+        for sealed in self
+            .persister
+            .get_changes()
+            .map_err(EncryptedPersisterError::PersisterError)?
+        {
+            if sealed_key_id(&sealed) == Some(self.key_id) {
+                continue;
+            }
+            let (actor_id, seq, _) =
+                parse_change_key(&sealed).ok_or(EncryptedPersisterError::UnknownFormat)?;
+            if !removed_changes.contains(&(&actor_id, seq)) {
+                let (actor_id, seq, data) = self.open_change(&sealed)?;
+                let resealed = self.seal_change(&actor_id, seq, &data)?;
+                changes.push((actor_id, seq, resealed));
+            }
+        }
+        if !changes.is_empty() {
+            self.persister
+                .insert_changes(changes.iter().map(|(a, s, c)| (a, *s, &c[..])).collect())
+                .map_err(EncryptedPersisterError::PersisterError)?;
+        }
+
+        for peer_id in self
+            .persister
+            .get_peer_ids()
+            .map_err(EncryptedPersisterError::PersisterError)?
+            .into_iter()
+            .filter(|p| !removed_peer_ids.contains(p.as_slice()))
+        {
+            let sealed = self
+                .persister
+                .get_sync_state(&peer_id)
+                .map_err(EncryptedPersisterError::PersisterError)?;
+            if let Some(sealed) = sealed.filter(|s| sealed_key_id(s) != Some(self.key_id)) {
+                let data = self.open(&sealed, SYNC_STATE_TAG, HEADER_LEN, &peer_id)?;
+                let resealed = self.seal(&data, SYNC_STATE_TAG, &[], &peer_id)?;
+                self.persister
+                    .set_sync_state(peer_id, resealed)
+                    .map_err(EncryptedPersisterError::PersisterError)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The id of the key that the data was sealed with, if it looks like sealed data.
+fn sealed_key_id(sealed: &[u8]) -> Option<u32> {
+    if sealed.len() < HEADER_LEN + TAG_LEN || sealed[0] != FORMAT {
+        return None;
+    }
+    Some(u32::from_le_bytes(sealed[1..5].try_into().unwrap()))
+}
+
+/// The actor id and sequence number stored in the clear with a change.
+fn change_key(actor_id: &ActorId, seq: u64) -> Vec<u8> {
+    let actor_id = actor_id.to_bytes();
+    let actor_id_len = u32::try_from(actor_id.len()).expect("actor id longer than u32::MAX");
+    let mut key = actor_id_len.to_le_bytes().to_vec();
+    key.extend(actor_id);
+    key.extend(&seq.to_le_bytes());
+    key
+}
+
+/// Read the actor id and sequence number from a sealed change along with where they end.
+fn parse_change_key(sealed: &[u8]) -> Option<(ActorId, u64, usize)> {
+    let len_end = HEADER_LEN + 4;
+    let actor_id_len = u32::from_le_bytes(sealed.get(HEADER_LEN..len_end)?.try_into().ok()?);
+    let actor_id_end = len_end.checked_add(usize::try_from(actor_id_len).ok()?)?;
+    let actor_id = ActorId::from_bytes(sealed.get(len_end..actor_id_end)?);
+    let seq_end = actor_id_end + 8;
+    let seq = u64::from_le_bytes(sealed.get(actor_id_end..seq_end)?.try_into().ok()?);
+    Some((actor_id, seq, seq_end))
+}
+
+/// The additional data to authenticate along with the ciphertext.
+///
+/// Including the kind of value, the key of changes in the header and the peer id for sync states
+/// stops sealed data being swapped between them.
+fn make_aad(header: &[u8], tag: u8, extra: &[u8]) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.push(tag);
+    aad.extend(extra);
+    aad
+}
+
+impl<P> Persister for EncryptedPersister<P>
+where
+    P: Persister,
+{
+    type Error = EncryptedPersisterError<P::Error>;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.persister
+            .get_changes()
+            .map_err(EncryptedPersisterError::PersisterError)?
+            .iter()
+            .map(|c| Ok(self.open_change(c)?.2))
+            .collect()
+    }
+
+    fn iter_changes(&self) -> Result<ChangeIter<'_, Self::Error>, Self::Error> {
+        Ok(Box::new(
+            self.persister
+                .iter_changes()
+                .map_err(EncryptedPersisterError::PersisterError)?
+                .map(move |c| {
+                    Ok(self
+                        .open_change(&c.map_err(EncryptedPersisterError::PersisterError)?)?
+                        .2)
+                }),
+        ))
+    }
+
+    /// A change stored under a different key than it was sealed for fails authentication.
+    fn get_changes_with_keys(&self) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        self.persister
+            .get_changes_with_keys()
+            .map_err(EncryptedPersisterError::PersisterError)?
+            .map(|changes| {
+                changes
+                    .into_iter()
+                    .map(|(a, s, c)| {
+                        let (actor_id, seq, change) = self.open_change(&c)?;
+                        if actor_id != a || seq != s {
+                            return Err(EncryptedPersisterError::DecryptError);
+                        }
+                        Ok((a, s, change))
+                    })
+                    .collect()
+            })
+            .transpose()
+    }
+
+    /// A change stored under a different key than it was sealed for fails authentication.
+    fn iter_changes_with_keys(
+        &self,
+    ) -> Result<Option<KeyedChangeIter<'_, Self::Error>>, Self::Error> {
+        Ok(self
+            .persister
+            .iter_changes_with_keys()
+            .map_err(EncryptedPersisterError::PersisterError)?
+            .map(|changes| {
+                Box::new(changes.map(move |c| {
+                    let (a, s, c) = c.map_err(EncryptedPersisterError::PersisterError)?;
+                    let (actor_id, seq, change) = self.open_change(&c)?;
+                    if actor_id != a || seq != s {
+                        return Err(EncryptedPersisterError::DecryptError);
+                    }
+                    Ok((a, s, change))
+                })) as KeyedChangeIter<'_, _>
+            }))
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error> {
+        let sealed = changes
+            .iter()
+            .map(|(a, s, c)| self.seal_change(a, *s, c))
+            .collect::<Result<Vec<_>, Self::Error>>()?;
+        self.persister
+            .insert_changes(
+                changes
+                    .iter()
+                    .zip(&sealed)
+                    .map(|((a, s, _), c)| (*a, *s, &c[..]))
+                    .collect(),
+            )
+            .map_err(EncryptedPersisterError::PersisterError)
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.persister
+            .remove_changes(changes)
+            .map_err(EncryptedPersisterError::PersisterError)
+    }
+
+    fn quarantine_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<bool, Self::Error> {
+        self.persister
+            .quarantine_changes(changes)
+            .map_err(EncryptedPersisterError::PersisterError)
+    }
+
+    /// Changes that can't be decrypted are returned as they were stored.
+    fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        Ok(self
+            .persister
+            .get_quarantined_changes()
+            .map_err(EncryptedPersisterError::PersisterError)?
+            .into_iter()
+            .map(|(a, s, c)| match self.open_change(&c) {
+                Ok((actor_id, seq, change)) if actor_id == a && seq == s => (a, s, change),
+                _ => (a, s, c),
+            })
+            .collect())
+    }
+
+    fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        self.persister
+            .remove_quarantined_changes(changes)
+            .map_err(EncryptedPersisterError::PersisterError)
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.persister
+            .get_document()
+            .map_err(EncryptedPersisterError::PersisterError)?
+            .map(|d| self.open(&d, DOCUMENT_TAG, HEADER_LEN, &[]))
+            .transpose()
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let sealed = self.seal(&data, DOCUMENT_TAG, &[], &[])?;
+        self.persister
+            .set_document(sealed)
+            .map_err(EncryptedPersisterError::PersisterError)
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.persister
+            .get_sync_state(peer_id)
+            .map_err(EncryptedPersisterError::PersisterError)?
+            .map(|s| self.open(&s, SYNC_STATE_TAG, HEADER_LEN, peer_id))
+            .transpose()
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let sealed = self.seal(&sync_state, SYNC_STATE_TAG, &[], &peer_id)?;
+        self.persister
+            .set_sync_state(peer_id, sealed)
+            .map_err(EncryptedPersisterError::PersisterError)
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.persister
+            .remove_sync_states(peer_ids)
+            .map_err(EncryptedPersisterError::PersisterError)
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.persister
+            .get_peer_ids()
+            .map_err(EncryptedPersisterError::PersisterError)
+    }
+
+    fn get_sync_state_infos(&self) -> Result<Vec<SyncStateInfo>, Self::Error> {
+        self.persister
+            .get_sync_state_infos()
+            .map_err(EncryptedPersisterError::PersisterError)
+    }
+
+    fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        self.persister
+            .get_actor_id()
+            .map_err(EncryptedPersisterError::PersisterError)
+    }
+
+    fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        self.persister
+            .set_actor_id(actor_id)
+            .map_err(EncryptedPersisterError::PersisterError)
+    }
+
+    /// Re-encrypt the changes and sync states that are kept and were sealed with an old key,
+    /// then compact the wrapped persister with the document sealed under the current key.
+    ///
+    /// Re-encrypting reads everything back so it is only done while a rotation is pending, that
+    /// is after [`rotate_key`](Self::rotate_key) or [`with_old_key`](Self::with_old_key) and
+    /// until a compaction completes.
+    ///
+    /// Everything is readable with the keys held at any point, so a failure part way through
+    /// leaves the old document and changes to load from.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        if self.reencrypt_pending {
+            let removed_changes = changes.iter().copied().collect::<HashSet<_>>();
+            let removed_peer_ids = old_peer_ids.iter().copied().collect::<HashSet<_>>();
+            self.reencrypt(&removed_changes, &removed_peer_ids)?;
+        }
+        let sealed = self.seal(&document, DOCUMENT_TAG, &[], &[])?;
+        self.persister
+            .compact(sealed, changes, old_peer_ids)
+            .map_err(EncryptedPersisterError::PersisterError)?;
+        self.reencrypt_pending = false;
+        Ok(())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.persister.sizes()
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.persister
+            .flush()
+            .map_err(EncryptedPersisterError::PersisterError)
+    }
+}
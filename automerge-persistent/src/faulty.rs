@@ -0,0 +1,590 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use automerge_protocol::ActorId;
+
+use crate::{
+    Backend, ChangeIter, Error, KeyedChangeIter, PersistentBackend, Persister, StoredSizes,
+    SyncStateInfo,
+};
+
+/// A fault to inject into a [`FaultyPersister`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation returns an error without having any effect.
+    Error,
+    /// The process crashes during the operation: it has no effect, every write since the last
+    /// `flush` is lost and every following operation fails until [`FaultyPersister::restart`].
+    Crash,
+}
+
+/// Errors returned by a [`FaultyPersister`].
+#[derive(Debug, thiserror::Error)]
+pub enum FaultyPersisterError<E> {
+    /// An injected [`Fault::Error`].
+    #[error("injected error in {operation} (call {call})")]
+    Injected {
+        /// The number of the call that failed, starting from 1.
+        call: usize,
+        /// The name of the operation that failed.
+        operation: &'static str,
+    },
+    /// An injected [`Fault::Crash`].
+    #[error("injected crash in {operation} (call {call})")]
+    Crash {
+        /// The number of the call that crashed, starting from 1.
+        call: usize,
+        /// The name of the operation that crashed.
+        operation: &'static str,
+    },
+    /// The persister has crashed and needs restarting.
+    #[error("persister has crashed")]
+    Crashed,
+    /// An error from the wrapped persister.
+    #[error(transparent)]
+    PersisterError(E),
+}
+
+/// **For Testing** A persister wrapper that injects failures and simulated crashes.
+///
+/// Every call to a fallible [`Persister`] method is counted, starting from 1, and faults can be
+/// set to trigger at specific call numbers with [`fault_at`](Self::fault_at) or randomly with
+/// [`fault_randomly`](Self::fault_randomly).
+///
+/// To be able to drop unflushed writes on a crash a second copy of the wrapped persister is kept
+/// as of the last `flush`, with the writes since then replayed onto it on each `flush`. The
+/// wrapped persister should be a value type like [`MemoryPersister`](crate::MemoryPersister)
+/// rather than a handle to shared storage.
+///
+/// ```rust
+/// # use automerge::{Frontend, InvalidChangeRequest, LocalChange, Path, Primitive, Value};
+/// # use automerge_persistent::{Fault, FaultyPersister, MemoryPersister, PersistentBackend};
+/// # use automerge_persistent::sync_until_converged;
+/// // loading makes 2 calls and each change an insert and a flush, so this crashes while storing
+/// // the second change
+/// let persister = FaultyPersister::new(MemoryPersister::default()).fault_at(5, Fault::Crash);
+/// let mut backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
+/// let mut frontend = Frontend::new();
+/// for i in 0..3 {
+///     let ((), change) = frontend
+///         .change::<_, _, InvalidChangeRequest>(None, |doc| {
+///             doc.add_change(LocalChange::set(
+///                 Path::root().key("value"),
+///                 Value::Primitive(Primitive::Str(i.to_string().into())),
+///             ))
+///         })
+///         .unwrap();
+///     if backend.apply_local_change(change.unwrap()).is_err() {
+///         break;
+///     }
+///     backend.flush().unwrap();
+/// }
+/// assert!(backend.persister().is_crashed());
+///
+/// // pick up from whatever made it to storage
+/// let mut reloaded = backend
+///     .persister()
+///     .reload_backend::<automerge::Backend>()
+///     .unwrap();
+/// assert_eq!(reloaded.get_changes(&[]).len(), 1);
+/// let mut reference = PersistentBackend::<_, automerge::Backend>::load(FaultyPersister::new(
+///     MemoryPersister::default(),
+/// ))
+/// .unwrap();
+/// assert!(sync_until_converged(&mut reloaded, &mut reference, 10).unwrap());
+/// ```
+#[derive(Debug)]
+pub struct FaultyPersister<P> {
+    live: P,
+    durable: P,
+    /// The writes made to `live` since the last flush, to replay onto `durable`.
+    unflushed: Vec<Write>,
+    faults: BTreeMap<usize, Fault>,
+    random: Option<RandomFaults>,
+    calls: AtomicUsize,
+    crashed: AtomicBool,
+}
+
+/// A write made to the wrapped persister.
+#[derive(Debug)]
+enum Write {
+    InsertChanges(Vec<(ActorId, u64, Vec<u8>)>),
+    RemoveChanges(Vec<(ActorId, u64)>),
+    QuarantineChanges(Vec<(ActorId, u64)>),
+    RemoveQuarantinedChanges(Vec<(ActorId, u64)>),
+    SetDocument(Vec<u8>),
+    SetSyncState(Vec<u8>, Vec<u8>),
+    RemoveSyncStates(Vec<Vec<u8>>),
+    SetActorId(ActorId),
+    Compact(Vec<u8>, Vec<(ActorId, u64)>, Vec<Vec<u8>>),
+}
+
+impl Write {
+    /// Apply the write to `persister`, copying the data that it takes ownership of so that a
+    /// failed write can be retried.
+    fn apply<P>(&self, persister: &mut P) -> Result<(), P::Error>
+    where
+        P: Persister,
+    {
+        match self {
+            Self::InsertChanges(changes) => persister.insert_changes(
+                changes
+                    .iter()
+                    .map(|(a, s, c)| (a, *s, c.as_slice()))
+                    .collect(),
+            ),
+            Self::RemoveChanges(changes) => {
+                persister.remove_changes(changes.iter().map(|(a, s)| (a, *s)).collect())
+            }
+            Self::QuarantineChanges(changes) => persister
+                .quarantine_changes(changes.iter().map(|(a, s)| (a, *s)).collect())
+                .map(drop),
+            Self::RemoveQuarantinedChanges(changes) => {
+                persister.remove_quarantined_changes(changes.iter().map(|(a, s)| (a, *s)).collect())
+            }
+            Self::SetDocument(document) => persister.set_document(document.clone()),
+            Self::SetSyncState(peer_id, sync_state) => {
+                persister.set_sync_state(peer_id.clone(), sync_state.clone())
+            }
+            Self::RemoveSyncStates(peer_ids) => persister
+                .remove_sync_states(&peer_ids.iter().map(Vec::as_slice).collect::<Vec<_>>()),
+            Self::SetActorId(actor_id) => persister.set_actor_id(actor_id.clone()),
+            Self::Compact(document, changes, old_peer_ids) => persister.compact(
+                document.clone(),
+                changes.iter().map(|(a, s)| (a, *s)).collect(),
+                &old_peer_ids.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RandomFaults {
+    probability: f64,
+    fault: Fault,
+    state: AtomicU64,
+}
+
+impl RandomFaults {
+    /// Draw the next number in `[0, 1)` using xorshift64*.
+    fn next(&self) -> f64 {
+        let mut x = self.state.load(Ordering::Relaxed);
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state.store(x, Ordering::Relaxed);
+        #[allow(clippy::cast_precision_loss)]
+        let f = (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1_u64 << 53) as f64;
+        f
+    }
+}
+
+impl<P> FaultyPersister<P>
+where
+    P: Persister + Clone,
+{
+    /// Wrap a persister, with no faults set.
+    ///
+    /// The current contents of the persister are taken to be durable.
+    pub fn new(persister: P) -> Self {
+        Self {
+            durable: persister.clone(),
+            live: persister,
+            unflushed: Vec::new(),
+            faults: BTreeMap::new(),
+            random: None,
+            calls: AtomicUsize::new(0),
+            crashed: AtomicBool::new(false),
+        }
+    }
+
+    /// Inject `fault` at the given call number, counting from 1.
+    #[must_use]
+    pub fn fault_at(mut self, call: usize, fault: Fault) -> Self {
+        self.faults.insert(call, fault);
+        self
+    }
+
+    /// Inject `fault` into each call with the given `probability`, using a generator seeded with
+    /// `seed` so that runs are reproducible.
+    #[must_use]
+    pub fn fault_randomly(mut self, probability: f64, fault: Fault, seed: u64) -> Self {
+        self.random = Some(RandomFaults {
+            probability,
+            fault,
+            // xorshift gets stuck at 0
+            state: AtomicU64::new(seed.max(1)),
+        });
+        self
+    }
+
+    /// Remove all faults, both at call numbers and random ones.
+    pub fn clear_faults(&mut self) {
+        self.faults.clear();
+        self.random = None;
+    }
+
+    /// The number of calls made so far.
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    /// Whether an injected crash has happened and not been restarted from.
+    pub fn is_crashed(&self) -> bool {
+        self.crashed.load(Ordering::Relaxed)
+    }
+
+    /// Restart after a crash, continuing from the state as of the last flush.
+    pub fn restart(&mut self) {
+        self.live = self.durable.clone();
+        self.unflushed.clear();
+        self.crashed.store(false, Ordering::Relaxed);
+    }
+
+    /// A copy of the state that survives a crash, as of the last flush.
+    pub fn surviving(&self) -> P {
+        self.durable.clone()
+    }
+
+    /// Load a new backend from the state that survives a crash, as of the last flush.
+    ///
+    /// The new persister has no faults set.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors from [`PersistentBackend::load`].
+    #[allow(clippy::type_complexity)]
+    pub fn reload_backend<B>(
+        &self,
+    ) -> Result<PersistentBackend<Self, B>, Error<FaultyPersisterError<P::Error>, B::Error>>
+    where
+        P: 'static,
+        B: Backend,
+    {
+        PersistentBackend::load(Self::new(self.surviving()))
+    }
+
+    /// Count the call and work out whether it should fail.
+    fn check(&self, operation: &'static str) -> Result<(), FaultyPersisterError<P::Error>> {
+        if self.crashed.load(Ordering::Relaxed) {
+            return Err(FaultyPersisterError::Crashed);
+        }
+        let call = self.calls.fetch_add(1, Ordering::Relaxed) + 1;
+        let fault = self.faults.get(&call).copied().or_else(|| {
+            self.random
+                .as_ref()
+                .filter(|random| random.next() < random.probability)
+                .map(|random| random.fault)
+        });
+        match fault {
+            None => Ok(()),
+            Some(Fault::Error) => Err(FaultyPersisterError::Injected { call, operation }),
+            Some(Fault::Crash) => {
+                self.crashed.store(true, Ordering::Relaxed);
+                Err(FaultyPersisterError::Crash { call, operation })
+            }
+        }
+    }
+}
+
+impl<P> Persister for FaultyPersister<P>
+where
+    P: Persister + Clone,
+{
+    type Error = FaultyPersisterError<P::Error>;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.check("get_changes")?;
+        self.live
+            .get_changes()
+            .map_err(FaultyPersisterError::PersisterError)
+    }
+
+    /// Only starting the iteration counts as a call, no faults are injected while reading changes.
+    fn iter_changes(&self) -> Result<ChangeIter<'_, Self::Error>, Self::Error> {
+        self.check("iter_changes")?;
+        Ok(Box::new(
+            self.live
+                .iter_changes()
+                .map_err(FaultyPersisterError::PersisterError)?
+                .map(|c| c.map_err(FaultyPersisterError::PersisterError)),
+        ))
+    }
+
+    fn get_changes_with_keys(&self) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        self.check("get_changes_with_keys")?;
+        self.live
+            .get_changes_with_keys()
+            .map_err(FaultyPersisterError::PersisterError)
+    }
+
+    fn iter_changes_with_keys(
+        &self,
+    ) -> Result<Option<KeyedChangeIter<'_, Self::Error>>, Self::Error> {
+        self.check("iter_changes_with_keys")?;
+        Ok(self
+            .live
+            .iter_changes_with_keys()
+            .map_err(FaultyPersisterError::PersisterError)?
+            .map(|changes| {
+                Box::new(changes.map(|c| c.map_err(FaultyPersisterError::PersisterError)))
+                    as KeyedChangeIter<'_, _>
+            }))
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error> {
+        self.check("insert_changes")?;
+        let write = Write::InsertChanges(
+            changes
+                .iter()
+                .map(|(a, s, c)| ((*a).clone(), *s, c.to_vec()))
+                .collect(),
+        );
+        self.live
+            .insert_changes(changes)
+            .map_err(FaultyPersisterError::PersisterError)?;
+        self.unflushed.push(write);
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.check("remove_changes")?;
+        let write = Write::RemoveChanges(changes.iter().map(|(a, s)| ((*a).clone(), *s)).collect());
+        self.live
+            .remove_changes(changes)
+            .map_err(FaultyPersisterError::PersisterError)?;
+        self.unflushed.push(write);
+        Ok(())
+    }
+
+    fn quarantine_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<bool, Self::Error> {
+        self.check("quarantine_changes")?;
+        let write =
+            Write::QuarantineChanges(changes.iter().map(|(a, s)| ((*a).clone(), *s)).collect());
+        let quarantined = self
+            .live
+            .quarantine_changes(changes)
+            .map_err(FaultyPersisterError::PersisterError)?;
+        self.unflushed.push(write);
+        Ok(quarantined)
+    }
+
+    fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        self.check("get_quarantined_changes")?;
+        self.live
+            .get_quarantined_changes()
+            .map_err(FaultyPersisterError::PersisterError)
+    }
+
+    fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        self.check("remove_quarantined_changes")?;
+        let write = Write::RemoveQuarantinedChanges(
+            changes.iter().map(|(a, s)| ((*a).clone(), *s)).collect(),
+        );
+        self.live
+            .remove_quarantined_changes(changes)
+            .map_err(FaultyPersisterError::PersisterError)?;
+        self.unflushed.push(write);
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.check("get_document")?;
+        self.live
+            .get_document()
+            .map_err(FaultyPersisterError::PersisterError)
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.check("set_document")?;
+        self.live
+            .set_document(data.clone())
+            .map_err(FaultyPersisterError::PersisterError)?;
+        self.unflushed.push(Write::SetDocument(data));
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.check("get_sync_state")?;
+        self.live
+            .get_sync_state(peer_id)
+            .map_err(FaultyPersisterError::PersisterError)
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.check("set_sync_state")?;
+        self.live
+            .set_sync_state(peer_id.clone(), sync_state.clone())
+            .map_err(FaultyPersisterError::PersisterError)?;
+        self.unflushed
+            .push(Write::SetSyncState(peer_id, sync_state));
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.check("remove_sync_states")?;
+        self.live
+            .remove_sync_states(peer_ids)
+            .map_err(FaultyPersisterError::PersisterError)?;
+        self.unflushed.push(Write::RemoveSyncStates(
+            peer_ids.iter().map(|p| p.to_vec()).collect(),
+        ));
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.check("get_peer_ids")?;
+        self.live
+            .get_peer_ids()
+            .map_err(FaultyPersisterError::PersisterError)
+    }
+
+    fn get_sync_state_infos(&self) -> Result<Vec<SyncStateInfo>, Self::Error> {
+        self.check("get_sync_state_infos")?;
+        self.live
+            .get_sync_state_infos()
+            .map_err(FaultyPersisterError::PersisterError)
+    }
+
+    fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        self.check("get_actor_id")?;
+        self.live
+            .get_actor_id()
+            .map_err(FaultyPersisterError::PersisterError)
+    }
+
+    fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        self.check("set_actor_id")?;
+        self.live
+            .set_actor_id(actor_id.clone())
+            .map_err(FaultyPersisterError::PersisterError)?;
+        self.unflushed.push(Write::SetActorId(actor_id));
+        Ok(())
+    }
+
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        self.check("compact")?;
+        let write = Write::Compact(
+            document.clone(),
+            changes.iter().map(|(a, s)| ((*a).clone(), *s)).collect(),
+            old_peer_ids.iter().map(|p| p.to_vec()).collect(),
+        );
+        self.live
+            .compact(document, changes, old_peer_ids)
+            .map_err(FaultyPersisterError::PersisterError)?;
+        self.unflushed.push(write);
+        Ok(())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.live.sizes()
+    }
+
+    /// Flush the wrapped persister and make its current state durable by replaying the writes
+    /// since the last flush.
+    ///
+    /// Writes that fail to replay are kept, along with those after them, for the next flush.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.check("flush")?;
+        let flushed = self
+            .live
+            .flush()
+            .map_err(FaultyPersisterError::PersisterError)?;
+        let mut replayed = 0;
+        let mut result = Ok(flushed);
+        for write in &self.unflushed {
+            if let Err(e) = write.apply(&mut self.durable) {
+                result = Err(FaultyPersisterError::PersisterError(e));
+                break;
+            }
+            replayed += 1;
+        }
+        self.unflushed.drain(..replayed);
+        result
+    }
+}
+
+/// Sync two backends with each other until neither has anything more to send, returning whether
+/// they ended up with the same heads.
+///
+/// The sync states are kept under peer ids reserved for this, so those for real peers are left
+/// alone, and are removed again afterwards. Gives up after `max_rounds` round trips.
+///
+/// # Errors
+///
+/// Returns the first error from generating or receiving a sync message, or otherwise from
+/// removing the sync states.
+pub fn sync_until_converged<P, B>(
+    left: &mut PersistentBackend<P, B>,
+    right: &mut PersistentBackend<P, B>,
+    max_rounds: usize,
+) -> Result<bool, Error<P::Error, B::Error>>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    const LEFT: &[u8] = b"sync-until-converged-left";
+    const RIGHT: &[u8] = b"sync-until-converged-right";
+    let synced = sync_rounds(left, right, LEFT, RIGHT, max_rounds);
+    let forgot_left = forget_sync_state(left, RIGHT);
+    let forgot_right = forget_sync_state(right, LEFT);
+    synced?;
+    forgot_left?;
+    forgot_right?;
+    let left_heads = left.get_heads().into_iter().collect::<HashSet<_>>();
+    let right_heads = right.get_heads().into_iter().collect::<HashSet<_>>();
+    Ok(left_heads == right_heads)
+}
+
+fn sync_rounds<P, B>(
+    left: &mut PersistentBackend<P, B>,
+    right: &mut PersistentBackend<P, B>,
+    left_id: &[u8],
+    right_id: &[u8],
+    max_rounds: usize,
+) -> Result<(), Error<P::Error, B::Error>>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    for _ in 0..max_rounds {
+        let to_right = left.generate_sync_message(right_id.to_vec())?;
+        let to_left = right.generate_sync_message(left_id.to_vec())?;
+        if to_right.is_none() && to_left.is_none() {
+            break;
+        }
+        if let Some(message) = to_right {
+            right.receive_sync_message(left_id.to_vec(), message)?;
+        }
+        if let Some(message) = to_left {
+            left.receive_sync_message(right_id.to_vec(), message)?;
+        }
+    }
+    Ok(())
+}
+
+/// Forget the sync state for `peer_id`, both in memory and in storage.
+fn forget_sync_state<P, B>(
+    backend: &mut PersistentBackend<P, B>,
+    peer_id: &[u8],
+) -> Result<(), Error<P::Error, B::Error>>
+where
+    P: Persister + 'static,
+    B: Backend,
+{
+    backend.reset_sync_state(peer_id);
+    backend
+        .persister_mut()
+        .remove_sync_states(&[peer_id])
+        .map_err(Error::PersisterError)
+}
@@ -0,0 +1,488 @@
+use std::{
+    collections::BTreeMap,
+    convert::{Infallible, TryFrom, TryInto},
+    error::Error,
+};
+
+use automerge_protocol::ActorId;
+
+use crate::{ChangeIter, KeyedChangeIter, Persister, StoredSizes};
+
+/// An iterator over key-value pairs, as returned by [`KvStore::scan_prefix`].
+pub type KvIter<'a, E> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>), E>> + 'a>;
+
+/// A `KvStore` is an ordered key-value store that a [`KvPersister`] can be built on.
+///
+/// Implementing this is all that is needed to persist documents in a new store, the key layout,
+/// size accounting and peer tracking are handled by the [`KvPersister`].
+pub trait KvStore {
+    /// The error type that the operations can produce
+    type Error: Error + 'static;
+
+    /// Returns the value stored at `key`, if there is one.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Stores `value` at `key`, replacing any existing value.
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Removes the value at `key`.
+    ///
+    /// If there is no value this should not return an error.
+    fn delete(&mut self, key: &[u8]) -> Result<(), Self::Error>;
+
+    /// Returns an iterator over the key-value pairs whose key starts with `prefix`, in key order,
+    /// reading them from the store as it goes.
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvIter<'_, Self::Error>, Self::Error>;
+
+    /// Applies all of the operations in the batch.
+    ///
+    /// This should be atomic where the store supports it, it is used for compaction. The default
+    /// implementation just applies each operation in turn.
+    fn batch(&mut self, batch: Vec<KvOp>) -> Result<(), Self::Error> {
+        for op in batch {
+            match op {
+                KvOp::Put(key, value) => self.put(key, value)?,
+                KvOp::Delete(key) => self.delete(&key)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush the data out to disk, returning the number of bytes flushed.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
+
+/// A single operation in a [`KvStore::batch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvOp {
+    /// Store the value at the key.
+    Put(Vec<u8>, Vec<u8>),
+    /// Remove the value at the key.
+    Delete(Vec<u8>),
+}
+
+/// **For Testing** A `BTreeMap` is an in-memory `KvStore`.
+impl KvStore for BTreeMap<Vec<u8>, Vec<u8>> {
+    type Error = Infallible;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        // the inherent method, not this one
+        Ok(self.get(key).cloned())
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.insert(key, value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.remove(key);
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvIter<'_, Self::Error>, Self::Error> {
+        let prefix = prefix.to_vec();
+        Ok(Box::new(
+            self.range(prefix.clone()..)
+                .take_while(move |(k, _)| k.starts_with(&prefix))
+                .map(|(k, v)| Ok((k.clone(), v.clone()))),
+        ))
+    }
+}
+
+/// Errors returned by a [`KvPersister`].
+#[derive(Debug, thiserror::Error)]
+pub enum KvPersisterError<E> {
+    /// A change was stored under a key that doesn't hold an actor id and sequence number.
+    #[error("malformed change key {0:?}")]
+    MalformedChangeKey(Vec<u8>),
+    /// An error from the store.
+    #[error(transparent)]
+    StoreError(E),
+}
+
+const CHANGE_TAG: u8 = b'c';
+const DOCUMENT_TAG: u8 = b'd';
+const SYNC_STATE_TAG: u8 = b's';
+const ACTOR_ID_TAG: u8 = b'a';
+const QUARANTINE_TAG: u8 = b'q';
+
+/// A [`Persister`] for any [`KvStore`].
+///
+/// All keys start with the length of the prefix followed by the prefix itself so multiple
+/// documents can share the same store without one prefix being able to see the keys of another.
+/// Next is a tag for the kind of value:
+///
+/// - changes: `c`, the length of the actor id, the actor id and the big endian sequence number
+/// - document: `d`
+/// - sync states: `s` and the peer id
+/// - actor id: `a`
+/// - quarantined changes: `q` followed by the same as for changes
+///
+/// ```rust
+/// # use std::collections::BTreeMap;
+/// # use automerge_persistent::KvPersister;
+/// # use automerge_persistent::PersistentBackend;
+/// let store = BTreeMap::new();
+/// let persister = KvPersister::new(store, "document").unwrap();
+/// let backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvPersister<S> {
+    store: S,
+    prefix: Vec<u8>,
+    sizes: StoredSizes,
+}
+
+impl<S> KvPersister<S>
+where
+    S: KvStore,
+{
+    /// Construct a new persister storing its data in `store` under `prefix`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the store when reading the existing data to calculate the sizes.
+    pub fn new<P>(store: S, prefix: P) -> Result<Self, S::Error>
+    where
+        P: Into<Vec<u8>>,
+    {
+        let prefix = prefix.into();
+        let mut s = Self {
+            store,
+            prefix,
+            sizes: StoredSizes::default(),
+        };
+        s.sizes = StoredSizes {
+            changes: s.sum_lengths(CHANGE_TAG)?,
+            document: s.sum_lengths(DOCUMENT_TAG)?,
+            sync_states: s.sum_lengths(SYNC_STATE_TAG)?,
+            uncompressed: None,
+        };
+        Ok(s)
+    }
+
+    /// Obtain a reference to the underlying store.
+    pub const fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Return the underlying store.
+    #[allow(clippy::missing_const_for_fn)]
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    fn sum_lengths(&self, tag: u8) -> Result<usize, S::Error> {
+        let mut sum = 0;
+        for kv in self.store.scan_prefix(&self.make_tag_key(tag))? {
+            sum += kv?.1.len();
+        }
+        Ok(sum)
+    }
+
+    /// Make a key from the length of the prefix, the prefix and the tag.
+    fn make_tag_key(&self, tag: u8) -> Vec<u8> {
+        let prefix_len = u32::try_from(self.prefix.len()).expect("prefix longer than u32::MAX");
+        let mut key = prefix_len.to_be_bytes().to_vec();
+        key.extend(&self.prefix);
+        key.push(tag);
+        key
+    }
+
+    fn make_change_key(&self, actor_id: &ActorId, seq: u64) -> Vec<u8> {
+        self.make_keyed_change_key(CHANGE_TAG, actor_id, seq)
+    }
+
+    fn make_quarantine_key(&self, actor_id: &ActorId, seq: u64) -> Vec<u8> {
+        self.make_keyed_change_key(QUARANTINE_TAG, actor_id, seq)
+    }
+
+    fn make_keyed_change_key(&self, tag: u8, actor_id: &ActorId, seq: u64) -> Vec<u8> {
+        let actor_id = actor_id.to_bytes();
+        let actor_id_len = u32::try_from(actor_id.len()).expect("actor id longer than u32::MAX");
+        let mut key = self.make_tag_key(tag);
+        key.extend(&actor_id_len.to_be_bytes());
+        key.extend(actor_id);
+        key.extend(&seq.to_be_bytes());
+        key
+    }
+
+    /// Read the actor id and sequence number back out of the part of a change key after the tag.
+    fn parse_change_key(key: &[u8]) -> Option<(ActorId, u64)> {
+        let actor_id_len = u32::from_be_bytes(key.get(..4)?.try_into().ok()?) as usize;
+        let actor_id = key.get(4..4_usize.checked_add(actor_id_len)?)?;
+        let seq = u64::from_be_bytes(key[4 + actor_id_len..].try_into().ok()?);
+        Some((ActorId::from_bytes(actor_id), seq))
+    }
+
+    /// Scan the changes stored under `tag` along with the actor id and sequence number from their
+    /// keys.
+    fn keyed_changes(
+        &self,
+        tag: u8,
+    ) -> Result<KeyedChangeIter<'_, KvPersisterError<S::Error>>, KvPersisterError<S::Error>> {
+        let prefix = self.make_tag_key(tag);
+        let prefix_len = prefix.len();
+        Ok(Box::new(
+            self.store
+                .scan_prefix(&prefix)
+                .map_err(KvPersisterError::StoreError)?
+                .map(move |kv| {
+                    let (k, v) = kv.map_err(KvPersisterError::StoreError)?;
+                    let (actor_id, seq) = Self::parse_change_key(&k[prefix_len..])
+                        .ok_or_else(|| KvPersisterError::MalformedChangeKey(k.clone()))?;
+                    Ok((actor_id, seq, v))
+                }),
+        ))
+    }
+
+    fn make_peer_key(&self, peer_id: &[u8]) -> Vec<u8> {
+        let mut key = self.make_tag_key(SYNC_STATE_TAG);
+        key.extend(peer_id);
+        key
+    }
+
+    /// Store `value` at `key` returning the bytes added and removed.
+    fn put(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<(usize, usize), KvPersisterError<S::Error>> {
+        let old = self
+            .store
+            .get(&key)
+            .map_err(KvPersisterError::StoreError)?
+            .map_or(0, |v| v.len());
+        let new = value.len();
+        self.store
+            .put(key, value)
+            .map_err(KvPersisterError::StoreError)?;
+        Ok((new, old))
+    }
+
+    /// Delete the value at each key returning the bytes removed and the delete operations.
+    fn deletes(
+        &self,
+        keys: Vec<Vec<u8>>,
+    ) -> Result<(usize, Vec<KvOp>), KvPersisterError<S::Error>> {
+        let mut removed = 0;
+        let mut ops = Vec::new();
+        for key in keys {
+            if let Some(old) = self.store.get(&key).map_err(KvPersisterError::StoreError)? {
+                removed += old.len();
+                ops.push(KvOp::Delete(key));
+            }
+        }
+        Ok((removed, ops))
+    }
+}
+
+impl<S> Persister for KvPersister<S>
+where
+    S: KvStore,
+{
+    type Error = KvPersisterError<S::Error>;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.iter_changes()?.collect()
+    }
+
+    /// Scan the store for the changes as they are iterated.
+    fn iter_changes(&self) -> Result<ChangeIter<'_, Self::Error>, Self::Error> {
+        Ok(Box::new(
+            self.store
+                .scan_prefix(&self.make_tag_key(CHANGE_TAG))
+                .map_err(KvPersisterError::StoreError)?
+                .map(|kv| kv.map(|(_, v)| v).map_err(KvPersisterError::StoreError)),
+        ))
+    }
+
+    /// Read the actor id and sequence number back out of each change key.
+    fn get_changes_with_keys(&self) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        self.keyed_changes(CHANGE_TAG)?
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    /// Scan the store for the changes as they are iterated, reading the actor id and sequence
+    /// number back out of each change key.
+    fn iter_changes_with_keys(
+        &self,
+    ) -> Result<Option<KeyedChangeIter<'_, Self::Error>>, Self::Error> {
+        self.keyed_changes(CHANGE_TAG).map(Some)
+    }
+
+    /// Insert the changes in a single batch.
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error> {
+        // the last of any duplicates wins, as it would when putting them in turn
+        let changes = changes
+            .into_iter()
+            .map(|(a, s, c)| (self.make_change_key(a, s), c))
+            .collect::<BTreeMap<_, _>>();
+        let mut added = 0;
+        let mut removed = 0;
+        let mut ops = Vec::new();
+        for (key, change) in changes {
+            if let Some(old) = self.store.get(&key).map_err(KvPersisterError::StoreError)? {
+                removed += old.len();
+            }
+            added += change.len();
+            ops.push(KvOp::Put(key, change.to_vec()));
+        }
+        self.store
+            .batch(ops)
+            .map_err(KvPersisterError::StoreError)?;
+        self.sizes.changes = self.sizes.changes + added - removed;
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let keys = changes
+            .into_iter()
+            .map(|(a, s)| self.make_change_key(a, s))
+            .collect();
+        let (removed, ops) = self.deletes(keys)?;
+        self.store
+            .batch(ops)
+            .map_err(KvPersisterError::StoreError)?;
+        self.sizes.changes -= removed;
+        Ok(())
+    }
+
+    /// Move the changes under the quarantine tag in a single batch.
+    fn quarantine_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<bool, Self::Error> {
+        let mut removed = 0;
+        let mut ops = Vec::new();
+        for (a, s) in changes {
+            let key = self.make_change_key(a, s);
+            if let Some(change) = self.store.get(&key).map_err(KvPersisterError::StoreError)? {
+                removed += change.len();
+                ops.push(KvOp::Put(self.make_quarantine_key(a, s), change));
+                ops.push(KvOp::Delete(key));
+            }
+        }
+        self.store
+            .batch(ops)
+            .map_err(KvPersisterError::StoreError)?;
+        self.sizes.changes -= removed;
+        Ok(true)
+    }
+
+    fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        self.keyed_changes(QUARANTINE_TAG)?.collect()
+    }
+
+    fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        let keys = changes
+            .into_iter()
+            .map(|(a, s)| self.make_quarantine_key(a, s))
+            .collect();
+        let (_, ops) = self.deletes(keys)?;
+        self.store.batch(ops).map_err(KvPersisterError::StoreError)
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.store
+            .get(&self.make_tag_key(DOCUMENT_TAG))
+            .map_err(KvPersisterError::StoreError)
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.sizes.document = data.len();
+        self.store
+            .put(self.make_tag_key(DOCUMENT_TAG), data)
+            .map_err(KvPersisterError::StoreError)
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.store
+            .get(&self.make_peer_key(peer_id))
+            .map_err(KvPersisterError::StoreError)
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let key = self.make_peer_key(&peer_id);
+        let (added, removed) = self.put(key, sync_state)?;
+        self.sizes.sync_states = self.sizes.sync_states + added - removed;
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let keys = peer_ids.iter().map(|p| self.make_peer_key(p)).collect();
+        let (removed, ops) = self.deletes(keys)?;
+        self.store
+            .batch(ops)
+            .map_err(KvPersisterError::StoreError)?;
+        self.sizes.sync_states -= removed;
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let prefix = self.make_tag_key(SYNC_STATE_TAG);
+        self.store
+            .scan_prefix(&prefix)
+            .map_err(KvPersisterError::StoreError)?
+            .map(|kv| {
+                let (k, _) = kv.map_err(KvPersisterError::StoreError)?;
+                Ok(k[prefix.len()..].to_vec())
+            })
+            .collect()
+    }
+
+    fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        Ok(self
+            .store
+            .get(&self.make_tag_key(ACTOR_ID_TAG))
+            .map_err(KvPersisterError::StoreError)?
+            .map(|v| ActorId::from_bytes(&v)))
+    }
+
+    fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        self.store
+            .put(self.make_tag_key(ACTOR_ID_TAG), actor_id.to_bytes())
+            .map_err(KvPersisterError::StoreError)
+    }
+
+    /// Set the document and remove the changes and sync states in a single batch.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let change_keys = changes
+            .into_iter()
+            .map(|(a, s)| self.make_change_key(a, s))
+            .collect();
+        let (removed_changes, change_ops) = self.deletes(change_keys)?;
+        let peer_keys = old_peer_ids.iter().map(|p| self.make_peer_key(p)).collect();
+        let (removed_sync_states, sync_state_ops) = self.deletes(peer_keys)?;
+
+        let document_len = document.len();
+        let mut ops = vec![KvOp::Put(self.make_tag_key(DOCUMENT_TAG), document)];
+        ops.extend(change_ops);
+        ops.extend(sync_state_ops);
+        self.store
+            .batch(ops)
+            .map_err(KvPersisterError::StoreError)?;
+
+        self.sizes.document = document_len;
+        self.sizes.changes -= removed_changes;
+        self.sizes.sync_states -= removed_sync_states;
+        Ok(())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.store.flush().map_err(KvPersisterError::StoreError)
+    }
+}
@@ -7,7 +7,8 @@
 //! A library for constructing efficient persistent automerge documents.
 //!
 //! A [`PersistentBackend`] wraps an [`automerge::Backend`] and handles making the changes applied
-//! to it durable. This works by persisting every change before it is applied to the backend. Then
+//! to it durable. This works by persisting every change as it is applied to the backend, if the
+//! change cannot be persisted then the backend does not keep it. Then
 //! occasionally the user should call `compact` to save the backend in a more compact format and
 //! cleanup the included changes. This strategy aims to be fast while also being space efficient
 //! (up to the user's requirements).
@@ -22,21 +23,62 @@
 //! # Ok(())
 //! # }
 //! ```
+//!
+//! For use from async code there is an [`AsyncPersister`] trait along with the
+//! [`AsyncPersistentBackend`] and [`AsyncPersistentAutomerge`] wrappers. Any [`Persister`] can be
+//! used where an [`AsyncPersister`] is expected but its operations then block the executor
+//! thread, see [`AsyncPersister`].
+//!
+//! The persister wrappers are behind features, all enabled by default: `checksum` for the
+//! `ChecksumPersister`, `compression` for the `CompressedPersister` and `encryption` for the
+//! `EncryptedPersister`.
 
+mod async_backend;
+mod async_document;
+mod async_persister;
 mod backend;
+#[cfg(feature = "checksum")]
+mod checksum;
+mod compaction;
+#[cfg(feature = "compression")]
+mod compressed;
 mod document;
+#[cfg(feature = "encryption")]
+mod encrypted;
+mod faulty;
+mod kv;
+mod load;
 mod mem;
 mod persister;
+mod store;
 
-use std::{collections::HashMap, fmt::Debug};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt::Debug,
+    time::SystemTime,
+};
 
+pub use async_backend::AsyncPersistentBackend;
+pub use async_document::AsyncPersistentAutomerge;
+pub use async_persister::AsyncPersister;
 use automerge::Change;
 use automerge_backend::{AutomergeError, SyncMessage, SyncState};
 use automerge_protocol::{ActorId, ChangeHash, Patch};
 pub use backend::Backend;
+#[cfg(feature = "checksum")]
+pub use checksum::{ChecksumPersister, ChecksumPersisterError};
+pub use compaction::{CompactionPolicy, ThresholdPolicy};
+#[cfg(feature = "compression")]
+pub use compressed::{CompressedPersister, CompressedPersisterError};
 pub use document::{Error as PersistentAutomergeError, PersistentAutomerge};
+#[cfg(feature = "encryption")]
+pub use encrypted::{EncryptedPersister, EncryptedPersisterError};
+pub use faulty::{sync_until_converged, Fault, FaultyPersister, FaultyPersisterError};
+pub use kv::{KvIter, KvOp, KvPersister, KvPersisterError, KvStore};
+pub use load::{DiscardedChange, DiscardedDocument, LoadOptions, LoadReport};
 pub use mem::MemoryPersister;
-pub use persister::Persister;
+pub use persister::{ChangeIter, KeyedChangeIter, Persister};
+pub use store::DocumentStore;
 
 /// Bytes stored for each of the stored types.
 #[derive(Debug, Default, Clone)]
@@ -47,6 +89,62 @@ pub struct StoredSizes {
     pub document: usize,
     /// Total bytes stored for all sync states.
     pub sync_states: usize,
+    /// The sizes before compression, for persisters that compress what they store.
+    pub uncompressed: Option<UncompressedSizes>,
+}
+
+/// Bytes for each of the stored types before they were compressed.
+#[derive(Debug, Default, Clone)]
+pub struct UncompressedSizes {
+    /// Total bytes of all changes.
+    pub changes: usize,
+    /// Total bytes of the document.
+    pub document: usize,
+    /// Total bytes of all sync states.
+    pub sync_states: usize,
+}
+
+/// A sync state held by a persister, for choosing which to prune.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncStateInfo {
+    /// The id of the peer that the sync state is for.
+    pub peer_id: Vec<u8>,
+    /// The size of the stored sync state in bytes.
+    pub size: usize,
+    /// When the sync state was last set, if the persister records it.
+    pub updated: Option<SystemTime>,
+}
+
+/// Identifies a value held by a persister.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoredKey {
+    /// The change with the given actor id and sequence number.
+    Change {
+        /// The actor id of the change.
+        actor_id: ActorId,
+        /// The sequence number of the change.
+        seq: u64,
+    },
+    /// A change that couldn't be identified as its key was damaged along with it.
+    UnidentifiedChange,
+    /// The saved document.
+    Document,
+    /// The sync state for a peer.
+    SyncState {
+        /// The id of the peer.
+        peer_id: Vec<u8>,
+    },
+}
+
+impl std::fmt::Display for StoredKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Change { actor_id, seq } => write!(f, "change {} from actor {}", seq, actor_id),
+            Self::UnidentifiedChange => write!(f, "unidentified change"),
+            Self::Document => write!(f, "document"),
+            Self::SyncState { peer_id } => write!(f, "sync state for peer {:?}", peer_id),
+        }
+    }
 }
 
 /// Errors that persistent backends can return.
@@ -65,11 +163,331 @@ pub enum Error<E, B> {
 
 type PeerId = Vec<u8>;
 
-/// A wrapper for a persister and an automerge Backend.
+/// The keys of changes received from a peer that were persisted before applying them, so that
+/// they can be removed again if applying fails.
+type ReceivedKeys = Vec<(ActorId, u64)>;
+
+/// Select the changes received from a peer that need persisting, each only once.
+///
+/// These are persisted before they are applied, and even if the backend only queues them because
+/// their dependencies are missing, otherwise they would be lost on restart. Loading replays them
+/// and the backend queues them again.
+///
+/// Changes that are `known`, already applied or queued, are left out as they are stored already
+/// or have been compacted into the document.
+fn received_changes<'a>(
+    changes: &'a [Change],
+    known: impl Fn(&ChangeHash) -> bool,
+) -> Vec<&'a Change> {
+    let mut selected = HashSet::new();
+    changes
+        .iter()
+        .filter(|c| !known(&c.hash) && selected.insert(c.hash))
+        .collect()
+}
+
+/// Borrow `received` changes for [`Persister::insert_changes`], without copying them.
+fn received_to_insert<'a>(received: &[&'a Change]) -> Vec<(&'a ActorId, u64, &'a [u8])> {
+    received
+        .iter()
+        .map(|c| (c.actor_id(), c.seq, c.raw_bytes()))
+        .collect()
+}
+
+/// The keys of `received` changes, kept to remove them again if applying them fails.
+fn received_keys(received: &[&Change]) -> ReceivedKeys {
+    received
+        .iter()
+        .map(|c| (c.actor_id().clone(), c.seq))
+        .collect()
+}
+
+/// Borrow `received` keys for [`Persister::remove_changes`].
+fn received_to_remove(received: &[(ActorId, u64)]) -> Vec<(&ActorId, u64)> {
+    received.iter().map(|(a, s)| (a, *s)).collect()
+}
+
+/// Update the changes that the backend has queued after applying `received` ones that it didn't
+/// have, returning how many new changes were applied.
+///
+/// The backend can't be asked for its queue, so it is tracked here to be able to rebuild the
+/// backend without losing it.
+fn track_queued<'a>(
+    queued: &mut HashMap<ChangeHash, Change>,
+    received: Vec<Change>,
+    get_change_by_hash: impl Fn(&ChangeHash) -> Option<&'a Change>,
+) -> usize {
+    let mut before = queued.len();
+    for change in received {
+        if let Entry::Vacant(entry) = queued.entry(change.hash) {
+            entry.insert(change);
+            before += 1;
+        }
+    }
+    queued.retain(|hash, _| get_change_by_hash(hash).is_none());
+    before - queued.len()
+}
+
+/// The `sizes` to give the compaction policy, leaving out the `queued` changes as compacting
+/// can't remove them.
+///
+/// The stored size of a change isn't known here so this assumes it is stored as is.
+fn sizes_without_queued(sizes: &StoredSizes, queued: &HashMap<ChangeHash, Change>) -> StoredSizes {
+    let queued_bytes = queued.values().map(|c| c.raw_bytes().len()).sum::<usize>();
+    let mut sizes = sizes.clone();
+    sizes.changes = sizes.changes.saturating_sub(queued_bytes);
+    if let Some(uncompressed) = sizes.uncompressed.as_mut() {
+        uncompressed.changes = uncompressed.changes.saturating_sub(queued_bytes);
+    }
+    sizes
+}
+
+/// The result of a local change applied in memory, along with what is needed to persist it or
+/// roll it back.
+struct Applied<T> {
+    result: T,
+    /// The heads before the change.
+    heads: Vec<ChangeHash>,
+}
+
+/// The in-memory state of a persistent backend.
+///
+/// This holds the logic of [`PersistentBackend`] without doing any storage I/O, so that
+/// [`AsyncPersistentBackend`] can share it and only differ in how it calls the persister.
 #[derive(Debug)]
-pub struct PersistentBackend<P, B> {
+struct BackendCore<B> {
     backend: B,
     sync_states: HashMap<PeerId, SyncState>,
+    compaction_policy: Option<Box<dyn CompactionPolicy>>,
+    changes_since_compaction: usize,
+    /// The received changes that the backend has queued as their dependencies are missing.
+    queued: HashMap<ChangeHash, Change>,
+}
+
+impl<B> BackendCore<B>
+where
+    B: Backend,
+{
+    /// Start from the stored document, with a lenient load falling back to an empty backend if it
+    /// can't be loaded.
+    ///
+    /// Loading takes the document rather than a copy, so one that fails is reported without its
+    /// data, see [`load::fill_discarded_document`].
+    fn load<E>(
+        document: Option<Vec<u8>>,
+        options: &LoadOptions,
+        report: &mut LoadReport,
+    ) -> Result<Self, Error<E, B::Error>> {
+        let backend = match document {
+            Some(document) if options.is_lenient() => match B::load(document) {
+                Ok(backend) => backend,
+                Err(e) => {
+                    report.discarded_document = Some(DiscardedDocument {
+                        data: None,
+                        error: e.to_string(),
+                    });
+                    B::default()
+                }
+            },
+            Some(document) => B::load(document).map_err(Error::BackendError)?,
+            None => B::default(),
+        };
+        Ok(Self {
+            backend,
+            sync_states: HashMap::new(),
+            compaction_policy: None,
+            changes_since_compaction: 0,
+            queued: HashMap::new(),
+        })
+    }
+
+    /// Decode and apply a batch of stored changes during a load.
+    fn load_batch<E>(
+        &mut self,
+        batch: load::StoredChanges,
+        options: &LoadOptions,
+        report: &mut LoadReport,
+        undecoded: &mut load::Undecoded,
+    ) -> Result<(), Error<E, B::Error>> {
+        let changes = load::decode_changes(batch, options, report, undecoded)?;
+        self.apply_changes(changes).map_err(Error::BackendError)?;
+        // only changes persisted after loading count towards the compaction policy
+        self.changes_since_compaction = 0;
+        Ok(())
+    }
+
+    /// Whether the change with the given hash is applied or queued.
+    fn has_change(&self, hash: &ChangeHash) -> bool {
+        self.queued.contains_key(hash) || self.backend.get_change_by_hash(hash).is_some()
+    }
+
+    /// The `changes` received from a peer that need persisting.
+    fn received_changes<'a>(&self, changes: &'a [Change]) -> Vec<&'a Change> {
+        received_changes(changes, |hash| self.has_change(hash))
+    }
+
+    /// The `changes` that aren't applied yet, to track which the backend queues.
+    fn unapplied(&self, changes: &[Change]) -> Vec<Change> {
+        changes
+            .iter()
+            .filter(|c| self.backend.get_change_by_hash(&c.hash).is_none())
+            .cloned()
+            .collect()
+    }
+
+    /// Apply changes received from a peer, which should already have been persisted.
+    fn apply_changes(&mut self, changes: Vec<Change>) -> Result<Patch, B::Error> {
+        let received = self.unapplied(&changes);
+        let patch = self.backend.apply_changes(changes)?;
+        self.track_queued(received);
+        Ok(patch)
+    }
+
+    fn track_queued(&mut self, received: Vec<Change>) {
+        let backend = &self.backend;
+        self.changes_since_compaction += track_queued(&mut self.queued, received, |hash| {
+            backend.get_change_by_hash(hash)
+        });
+    }
+
+    fn apply_local_change(
+        &mut self,
+        change: automerge_protocol::Change,
+    ) -> Result<Applied<Patch>, B::Error> {
+        let heads = self.backend.get_heads();
+        let (patch, _) = self.backend.apply_local_change(change)?;
+        Ok(Applied {
+            result: patch,
+            heads,
+        })
+    }
+
+    /// The changes that `applied` added, to be inserted into the persister.
+    fn changes_to_insert<T>(&self, applied: &Applied<T>) -> Vec<(&ActorId, u64, &[u8])> {
+        self.backend
+            .get_changes(&applied.heads)
+            .into_iter()
+            .map(|c| (c.actor_id(), c.seq, c.raw_bytes()))
+            .collect()
+    }
+
+    /// Record that `count` local changes were inserted into the persister.
+    fn inserted(&mut self, count: usize) {
+        self.changes_since_compaction += count;
+    }
+
+    /// Rebuild the backend from only the changes it had at `heads`, along with the queued ones.
+    ///
+    /// This replays the whole history so is only used when a local change fails to persist,
+    /// received changes are persisted before they are applied so never need rolling back.
+    fn rollback(&mut self, heads: &[ChangeHash]) -> Result<(), B::Error> {
+        let new_changes = self
+            .backend
+            .get_changes(heads)
+            .into_iter()
+            .map(|c| c.hash)
+            .collect::<HashSet<_>>();
+        let old_changes = self
+            .backend
+            .get_changes(&[])
+            .into_iter()
+            .filter(|c| !new_changes.contains(&c.hash))
+            .chain(self.queued.values())
+            .cloned()
+            .collect();
+        let mut backend = B::default();
+        backend.apply_changes(old_changes)?;
+        self.backend = backend;
+        Ok(())
+    }
+
+    /// Whether the compaction policy says to compact storage of the given `sizes`.
+    fn should_compact(&mut self, sizes: &StoredSizes) -> bool {
+        let changes_since_compaction = self.changes_since_compaction;
+        let sizes = sizes_without_queued(sizes, &self.queued);
+        self.compaction_policy.as_mut().map_or(false, |policy| {
+            policy.should_compact(&sizes, changes_since_compaction)
+        })
+    }
+
+    /// Let the compaction policy choose which of the stored `sync_states` to prune, forgetting
+    /// them.
+    fn select_peers_to_prune(&mut self, sync_states: Vec<SyncStateInfo>) -> Vec<PeerId> {
+        let old_peer_ids = self
+            .compaction_policy
+            .as_mut()
+            .map_or_else(Vec::new, |policy| policy.select_peers_to_prune(sync_states));
+        for peer_id in &old_peer_ids {
+            self.sync_states.remove(peer_id);
+        }
+        old_peer_ids
+    }
+
+    /// The saved backend and the keys of the changes it covers, for [`Persister::compact`].
+    #[allow(clippy::type_complexity)]
+    fn compaction(&self) -> Result<(Vec<u8>, Vec<(&ActorId, u64)>), B::Error> {
+        let changes = self.backend.get_changes(&[]);
+        let saved_backend = self.backend.save()?;
+        Ok((
+            saved_backend,
+            changes.into_iter().map(|c| (c.actor_id(), c.seq)).collect(),
+        ))
+    }
+
+    /// Record that the storage was compacted.
+    fn compacted(&mut self) {
+        self.changes_since_compaction = 0;
+    }
+
+    /// Whether the sync state for `peer_id` is held in memory, otherwise it should be read from
+    /// the persister.
+    fn has_sync_state(&self, peer_id: &[u8]) -> bool {
+        self.sync_states.contains_key(peer_id)
+    }
+
+    /// Hold the sync state for `peer_id` that was read from the persister.
+    fn insert_sync_state<E>(
+        &mut self,
+        peer_id: &[u8],
+        sync_state: &[u8],
+    ) -> Result<(), Error<E, B::Error>> {
+        let s = SyncState::decode(sync_state).map_err(|e| Error::AutomergeError(e.into()))?;
+        self.sync_states.insert(peer_id.to_vec(), s);
+        Ok(())
+    }
+
+    /// The encoded sync state for `peer_id`, to be stored after using it.
+    fn encode_sync_state<E>(&mut self, peer_id: &[u8]) -> Result<Vec<u8>, Error<E, B::Error>> {
+        self.sync_states
+            .entry(peer_id.to_vec())
+            .or_default()
+            .encode()
+            .map_err(|e| Error::AutomergeError(e.into()))
+    }
+
+    fn generate_sync_message(&mut self, peer_id: &[u8]) -> Result<Option<SyncMessage>, B::Error> {
+        let sync_state = self.sync_states.entry(peer_id.to_vec()).or_default();
+        self.backend.generate_sync_message(sync_state)
+    }
+
+    /// Receive a sync message, whose changes should already have been persisted.
+    fn receive_sync_message(
+        &mut self,
+        peer_id: &[u8],
+        message: SyncMessage,
+    ) -> Result<Option<Patch>, B::Error> {
+        let received = self.unapplied(&message.changes);
+        let sync_state = self.sync_states.entry(peer_id.to_vec()).or_default();
+        let patch = self.backend.receive_sync_message(sync_state, message)?;
+        self.track_queued(received);
+        Ok(patch)
+    }
+}
+
+/// A wrapper for a persister and an automerge Backend.
+#[derive(Debug)]
+pub struct PersistentBackend<P, B> {
+    core: BackendCore<B>,
     persister: P,
 }
 
@@ -78,22 +496,102 @@ where
     P: Persister + 'static,
     B: Backend,
 {
-    fn with_insert_changes<F, O>(&mut self, f: F) -> Result<O, Error<P::Error, B::Error>>
-    where
-        F: FnOnce(&mut Self) -> Result<O, B::Error>,
-    {
-        let heads = self.backend.get_heads();
-        let res = f(self).map_err(Error::BackendError)?;
-        let changes = self.backend.get_changes(&heads);
-        self.persister
-            .insert_changes(
-                changes
-                    .into_iter()
-                    .map(|c| (c.actor_id().clone(), c.seq, c.raw_bytes().to_vec()))
-                    .collect(),
-            )
+    /// Persist the local change added by an operation and compact if needed, returning the result
+    /// of the operation.
+    ///
+    /// If the persister fails then the backend is rolled back to before the operation so that it
+    /// does not hold changes that were never stored.
+    fn persist<T>(&mut self, applied: Applied<T>) -> Result<T, Error<P::Error, B::Error>> {
+        let result = self.insert_changes(applied)?;
+        self.compact_if_needed()?;
+        Ok(result)
+    }
+
+    /// Persist the `changes` received from a peer that aren't known yet, returning their keys.
+    ///
+    /// Persisting before [`receive`](Self::receive) applies them means the backend never holds
+    /// changes that weren't stored.
+    fn persist_received(
+        &mut self,
+        changes: &[Change],
+    ) -> Result<ReceivedKeys, Error<P::Error, B::Error>> {
+        let received = self.core.received_changes(changes);
+        if !received.is_empty() {
+            self.persister
+                .insert_changes(received_to_insert(&received))
+                .map_err(Error::PersisterError)?;
+        }
+        Ok(received_keys(&received))
+    }
+
+    /// Apply changes persisted by [`persist_received`](Self::persist_received) with `apply`,
+    /// removing the `received` ones from storage again if that fails.
+    fn receive<T>(
+        &mut self,
+        received: &[(ActorId, u64)],
+        apply: impl FnOnce(&mut BackendCore<B>) -> Result<T, B::Error>,
+    ) -> Result<T, Error<P::Error, B::Error>> {
+        match apply(&mut self.core) {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                // the apply error is the one worth returning, changes left behind fail to apply
+                // again when loading and are dealt with there
+                if let Err(cleanup) = self.persister.remove_changes(received_to_remove(received)) {
+                    log::warn!(
+                        "failed to remove received changes that could not be applied: {}",
+                        cleanup
+                    );
+                }
+                Err(Error::BackendError(e))
+            }
+        }
+    }
+
+    fn insert_changes<T>(&mut self, applied: Applied<T>) -> Result<T, Error<P::Error, B::Error>> {
+        let changes = self.core.changes_to_insert(&applied);
+        let count = changes.len();
+        if let Err(e) = self.persister.insert_changes(changes) {
+            self.core
+                .rollback(&applied.heads)
+                .map_err(Error::BackendError)?;
+            return Err(Error::PersisterError(e));
+        }
+        self.core.inserted(count);
+        Ok(applied.result)
+    }
+
+    /// Compact the storage if the compaction policy says so.
+    fn compact_if_needed(&mut self) -> Result<(), Error<P::Error, B::Error>> {
+        if !self.core.should_compact(&self.persister.sizes()) {
+            return Ok(());
+        }
+        let sync_states = self
+            .persister
+            .get_sync_state_infos()
             .map_err(Error::PersisterError)?;
-        Ok(res)
+        let old_peer_ids = self.core.select_peers_to_prune(sync_states);
+        self.compact(&old_peer_ids.iter().map(Vec::as_slice).collect::<Vec<_>>())
+    }
+
+    /// Read the sync state for `peer_id` from the persister unless it is already held.
+    fn load_sync_state(&mut self, peer_id: &[u8]) -> Result<(), Error<P::Error, B::Error>> {
+        if !self.core.has_sync_state(peer_id) {
+            if let Some(sync_state) = self
+                .persister
+                .get_sync_state(peer_id)
+                .map_err(Error::PersisterError)?
+            {
+                self.core.insert_sync_state(peer_id, &sync_state)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn store_sync_state(&mut self, peer_id: PeerId) -> Result<(), Error<P::Error, B::Error>> {
+        let sync_state = self.core.encode_sync_state(&peer_id)?;
+        self.persister
+            .set_sync_state(peer_id, sync_state)
+            .map_err(Error::PersisterError)
     }
 
     /// Returns a serialized version of the current document.
@@ -110,7 +608,7 @@ where
     /// let data = backend.save().unwrap();
     /// ```
     pub fn save(&self) -> Result<Vec<u8>, B::Error> {
-        self.backend.save()
+        self.core.backend.save()
     }
 
     /// Load the persisted changes (both individual changes and a document) from storage and
@@ -123,34 +621,54 @@ where
     /// let backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
     /// ```
     pub fn load(persister: P) -> Result<Self, Error<P::Error, B::Error>> {
-        let document = persister.get_document().map_err(Error::PersisterError)?;
-        let mut backend = if let Some(document) = document {
-            B::load(document).map_err(Error::BackendError)?
-        } else {
-            B::default()
-        };
+        Self::load_with_options(persister, &LoadOptions::default()).map(|(s, _)| s)
+    }
 
-        let change_bytes = persister.get_changes().map_err(Error::PersisterError)?;
+    /// Load from storage with the given options, returning what was skipped.
+    ///
+    /// See [`LoadOptions`] for an example.
+    pub fn load_with_options(
+        mut persister: P,
+        options: &LoadOptions,
+    ) -> Result<(Self, LoadReport), Error<P::Error, B::Error>> {
+        let mut report = LoadReport::default();
+        let document = load::document(persister.get_document(), options, &mut report)
+            .map_err(Error::PersisterError)?;
+        let stored_document = document.is_some();
+        let mut core = BackendCore::load(document, options, &mut report)?;
+        if stored_document && report.discarded_document.is_some() {
+            load::fill_discarded_document(&mut report, persister.get_document());
+        }
 
-        let mut changes = Vec::new();
-        for change_bytes in change_bytes {
-            changes.push(
-                Change::from_bytes(change_bytes).map_err(|e| Error::AutomergeError(e.into()))?,
-            )
+        let mut stored =
+            load::stored_changes(&persister, options).map_err(Error::PersisterError)?;
+        let mut undecoded = load::Undecoded::default();
+        loop {
+            let batch = load::next_batch(&mut stored, options).map_err(Error::PersisterError)?;
+            if batch.is_empty() {
+                break;
+            }
+            core.load_batch(batch, options, &mut report, &mut undecoded)?;
+        }
+        drop(stored);
+        if !undecoded.is_empty() {
+            let stored =
+                load::stored_changes(&persister, options).map_err(Error::PersisterError)?;
+            load::fill_discarded(&mut report, &undecoded, stored).map_err(Error::PersisterError)?;
         }
 
-        backend
-            .apply_changes(changes)
-            .map_err(Error::BackendError)?;
-        Ok(Self {
-            backend,
-            sync_states: HashMap::new(),
-            persister,
-        })
+        load::set_aside(&mut persister, options, &mut report).map_err(Error::PersisterError)?;
+        Ok((Self { core, persister }, report))
     }
 
     /// Apply a sequence of changes, typically from a remote backend.
     ///
+    /// Changes that cannot be applied yet because their dependencies are missing are still
+    /// persisted so that they are not lost on restart.
+    ///
+    /// The changes are persisted before they are applied, so if they cannot be persisted the
+    /// backend is left as it was before this call.
+    ///
     /// ```rust
     /// # use automerge_persistent::MemoryPersister;
     /// # use automerge_persistent::PersistentBackend;
@@ -162,24 +680,49 @@ where
         &mut self,
         changes: Vec<Change>,
     ) -> Result<Patch, Error<P::Error, B::Error>> {
-        self.with_insert_changes(|s| s.backend.apply_changes(changes))
+        let received = self.persist_received(&changes)?;
+        let patch = self.receive(&received, |core| core.apply_changes(changes))?;
+        self.compact_if_needed()?;
+        Ok(patch)
+    }
+
+    /// Apply changes that are already stored, such as those written by another backend sharing
+    /// the storage, without persisting them again.
+    pub fn apply_stored_changes(
+        &mut self,
+        changes: Vec<Change>,
+    ) -> Result<Patch, Error<P::Error, B::Error>> {
+        self.core
+            .apply_changes(changes)
+            .map_err(Error::BackendError)
+    }
+
+    /// Whether the change with the given hash has been applied, or is queued waiting for its
+    /// dependencies.
+    pub fn has_change(&self, hash: &ChangeHash) -> bool {
+        self.core.has_change(hash)
     }
 
     /// Apply a local change, typically from a local frontend.
+    ///
+    /// If the new change cannot be persisted the backend is left as it was before this call, the
+    /// frontend that produced it should then be reset from [`Self::get_patch`].
     pub fn apply_local_change(
         &mut self,
         change: automerge_protocol::Change,
     ) -> Result<Patch, Error<P::Error, B::Error>> {
-        self.with_insert_changes(|s| {
-            let (patch, _) = s.backend.apply_local_change(change)?;
-            Ok(patch)
-        })
+        let applied = self
+            .core
+            .apply_local_change(change)
+            .map_err(Error::BackendError)?;
+        self.persist(applied)
     }
 
     /// Compact the storage.
     ///
-    /// This first obtains the changes currently in the backend, saves the backend and persists the
-    /// saved document. We then can remove the previously obtained changes one by one.
+    /// This first obtains the changes currently in the backend, saves the backend and hands the
+    /// saved document to the persister along with the changes it now covers so they can be
+    /// replaced in one step, see [`Persister::compact`].
     ///
     /// It also clears out the storage used up by old sync states for peers by removing those given
     /// in `old_peers`.
@@ -192,20 +735,26 @@ where
     /// backend.compact(&[]).unwrap();
     /// ```
     pub fn compact(&mut self, old_peer_ids: &[&[u8]]) -> Result<(), Error<P::Error, B::Error>> {
-        let changes = self.backend.get_changes(&[]);
-        let saved_backend = self.backend.save().map_err(Error::BackendError)?;
+        let (saved_backend, changes) = self.core.compaction().map_err(Error::BackendError)?;
         self.persister
-            .set_document(saved_backend)
-            .map_err(Error::PersisterError)?;
-        self.persister
-            .remove_changes(changes.into_iter().map(|c| (c.actor_id(), c.seq)).collect())
-            .map_err(Error::PersisterError)?;
-        self.persister
-            .remove_sync_states(old_peer_ids)
+            .compact(saved_backend, changes, old_peer_ids)
             .map_err(Error::PersisterError)?;
+        self.core.compacted();
         Ok(())
     }
 
+    /// Set the policy used to compact the storage automatically.
+    ///
+    /// The policy is checked after each operation that persists changes and, if it says so,
+    /// [`Self::compact`] is run before the operation returns. Errors from this compaction are
+    /// returned by that operation, but the changes it made have already been persisted.
+    pub fn set_compaction_policy<C>(&mut self, policy: C)
+    where
+        C: CompactionPolicy + 'static,
+    {
+        self.core.compaction_policy = Some(Box::new(policy));
+    }
+
     /// Get a patch from the current data in the backend to populate a frontend.
     ///
     /// ```rust
@@ -216,7 +765,7 @@ where
     /// let patch = backend.get_patch().unwrap();
     /// ```
     pub fn get_patch(&self) -> Result<Patch, Error<P::Error, B::Error>> {
-        self.backend.get_patch().map_err(Error::BackendError)
+        self.core.backend.get_patch().map_err(Error::BackendError)
     }
 
     /// Get the changes performed by the given `actor_id`.
@@ -224,7 +773,8 @@ where
         &self,
         actor_id: &ActorId,
     ) -> Result<Vec<&Change>, Error<P::Error, B::Error>> {
-        self.backend
+        self.core
+            .backend
             .get_changes_for_actor_id(actor_id)
             .map_err(Error::BackendError)
     }
@@ -239,7 +789,7 @@ where
     /// let all_changes = backend.get_changes(&[]);
     /// ```
     pub fn get_changes(&self, have_deps: &[ChangeHash]) -> Vec<&Change> {
-        self.backend.get_changes(have_deps)
+        self.core.backend.get_changes(have_deps)
     }
 
     /// Get the missing dependencies in the hash graph that are required to be able to apply some
@@ -255,7 +805,7 @@ where
     /// let all_missing_changes = backend.get_missing_deps(&[]);
     /// ```
     pub fn get_missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash> {
-        self.backend.get_missing_deps(heads)
+        self.core.backend.get_missing_deps(heads)
     }
 
     /// Get the current heads of the hash graph (changes without successors).
@@ -268,7 +818,7 @@ where
     /// let heads = backend.get_heads();
     /// ```
     pub fn get_heads(&self) -> Vec<ChangeHash> {
-        self.backend.get_heads()
+        self.core.backend.get_heads()
     }
 
     /// Generate a sync message to be sent to a peer backend.
@@ -290,30 +840,12 @@ where
         &mut self,
         peer_id: PeerId,
     ) -> Result<Option<SyncMessage>, Error<P::Error, B::Error>> {
-        if !self.sync_states.contains_key(&peer_id) {
-            if let Some(sync_state) = self
-                .persister
-                .get_sync_state(&peer_id)
-                .map_err(Error::PersisterError)?
-            {
-                let s =
-                    SyncState::decode(&sync_state).map_err(|e| Error::AutomergeError(e.into()))?;
-                self.sync_states.insert(peer_id.clone(), s);
-            }
-        }
-        let sync_state = self.sync_states.entry(peer_id.clone()).or_default();
+        self.load_sync_state(&peer_id)?;
         let message = self
-            .backend
-            .generate_sync_message(sync_state)
+            .core
+            .generate_sync_message(&peer_id)
             .map_err(Error::BackendError)?;
-        self.persister
-            .set_sync_state(
-                peer_id,
-                sync_state
-                    .encode()
-                    .map_err(|e| Error::AutomergeError(e.into()))?,
-            )
-            .map_err(Error::PersisterError)?;
+        self.store_sync_state(peer_id)?;
         Ok(message)
     }
 
@@ -324,47 +856,21 @@ where
     ///
     /// This internally retrieves the previous sync state from storage and saves the new one
     /// afterwards.
+    ///
+    /// The received changes are persisted before the message is applied, so if they cannot be
+    /// persisted the backend and sync state are left as they were before this call.
     pub fn receive_sync_message(
         &mut self,
         peer_id: PeerId,
         message: SyncMessage,
     ) -> Result<Option<Patch>, Error<P::Error, B::Error>> {
-        if !self.sync_states.contains_key(&peer_id) {
-            if let Some(sync_state) = self
-                .persister
-                .get_sync_state(&peer_id)
-                .map_err(Error::PersisterError)?
-            {
-                let s =
-                    SyncState::decode(&sync_state).map_err(|e| Error::AutomergeError(e.into()))?;
-                self.sync_states.insert(peer_id.clone(), s);
-            }
-        }
-        let sync_state = self.sync_states.entry(peer_id.clone()).or_default();
-
-        let heads = self.backend.get_heads();
-        let patch = self
-            .backend
-            .receive_sync_message(sync_state, message)
-            .map_err(Error::BackendError)?;
-        let changes = self.backend.get_changes(&heads);
-        self.persister
-            .insert_changes(
-                changes
-                    .into_iter()
-                    .map(|c| (c.actor_id().clone(), c.seq, c.raw_bytes().to_vec()))
-                    .collect(),
-            )
-            .map_err(Error::PersisterError)?;
-
-        self.persister
-            .set_sync_state(
-                peer_id,
-                sync_state
-                    .encode()
-                    .map_err(|e| Error::AutomergeError(e.into()))?,
-            )
-            .map_err(Error::PersisterError)?;
+        self.load_sync_state(&peer_id)?;
+        let received = self.persist_received(&message.changes)?;
+        let patch = self.receive(&received, |core| {
+            core.receive_sync_message(&peer_id, message)
+        })?;
+        self.store_sync_state(peer_id)?;
+        self.compact_if_needed()?;
         Ok(patch)
     }
 
@@ -394,11 +900,19 @@ where
         &self.persister
     }
 
+    /// Obtain a mutable reference to the persister.
+    ///
+    /// Writing through it bypasses the backend, so it is for updating persister state that the
+    /// backend doesn't track.
+    pub fn persister_mut(&mut self) -> &mut P {
+        &mut self.persister
+    }
+
     /// Reset the sync state for a peer.
     ///
     /// This is typically used when a peer disconnects, we need to reset the sync state for them as
     /// they may come back up with different state.
     pub fn reset_sync_state(&mut self, peer_id: &[u8]) {
-        self.sync_states.remove(peer_id);
+        self.core.sync_states.remove(peer_id);
     }
 }
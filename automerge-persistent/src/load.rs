@@ -0,0 +1,375 @@
+use automerge::Change;
+use automerge_backend::AutomergeError;
+use automerge_protocol::ActorId;
+
+use crate::{AsyncPersister, Persister};
+
+/// The number of changes decoded and applied at a time by default.
+const DEFAULT_BATCH_SIZE: usize = 1024;
+
+/// Options for loading a document from a persister.
+///
+/// By default loading is strict and fails if anything stored can't be decoded.
+///
+/// ```rust
+/// # use automerge_persistent::{LoadOptions, MemoryPersister, PersistentBackend};
+/// let persister = MemoryPersister::default();
+/// let (backend, report) = PersistentBackend::<_, automerge::Backend>::load_with_options(
+///     persister,
+///     &LoadOptions::default().lenient(),
+/// )
+/// .unwrap();
+/// assert!(report.is_empty());
+/// ```
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    lenient: bool,
+    quarantine: bool,
+    batch_size: usize,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        Self {
+            lenient: false,
+            quarantine: false,
+            batch_size: DEFAULT_BATCH_SIZE,
+        }
+    }
+}
+
+impl LoadOptions {
+    /// Skip changes that can't be decoded and fall back to loading from the changes alone if the
+    /// document can't be loaded.
+    ///
+    /// The missing data can then be resynced from peers. What was skipped is returned in a
+    /// [`LoadReport`] and the skipped changes are removed from the persister.
+    #[must_use]
+    pub const fn lenient(mut self) -> Self {
+        self.lenient = true;
+        self
+    }
+
+    /// Quarantine the changes skipped by a lenient load rather than removing them.
+    ///
+    /// They are moved out of the changes that loads read, with
+    /// [`Persister::quarantine_changes`], so later loads (strict or lenient) don't see them while
+    /// they can still be inspected or recovered with [`Persister::get_quarantined_changes`].
+    /// Persisters that don't support quarantining leave them where they are.
+    #[must_use]
+    pub const fn quarantine(mut self) -> Self {
+        self.quarantine = true;
+        self
+    }
+
+    /// Decode and apply the stored changes `batch_size` at a time, 1024 by default.
+    ///
+    /// Persisters that read changes from storage as they go, through
+    /// [`Persister::iter_changes`] or [`Persister::iter_changes_with_keys`] for a lenient load,
+    /// then only need to hold a batch of changes in memory at once.
+    ///
+    /// An [`AsyncPersister`] has no way to read changes as it goes, so async loads read all of
+    /// the stored changes up front and only decode them in batches.
+    #[must_use]
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Whether the load is lenient.
+    #[must_use]
+    pub const fn is_lenient(&self) -> bool {
+        self.lenient
+    }
+}
+
+/// What a lenient load skipped.
+#[derive(Debug, Default, Clone)]
+pub struct LoadReport {
+    /// The changes that couldn't be decoded.
+    pub discarded_changes: Vec<DiscardedChange>,
+    /// The stored document, if it couldn't be read or loaded.
+    ///
+    /// The document is left in the persister, to be replaced by the next compaction.
+    pub discarded_document: Option<DiscardedDocument>,
+}
+
+impl LoadReport {
+    /// Whether nothing was skipped.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.discarded_changes.is_empty() && self.discarded_document.is_none()
+    }
+
+    /// The keys of the discarded changes, which can be set aside in the persister.
+    fn discarded_keys(&self) -> Vec<(ActorId, u64)> {
+        self.discarded_changes
+            .iter()
+            .filter_map(|change| change.key.clone())
+            .collect()
+    }
+
+    /// Mark the discarded changes with keys as removed or quarantined.
+    fn set_aside(&mut self, quarantined: bool) {
+        for change in &mut self.discarded_changes {
+            if change.key.is_some() {
+                if quarantined {
+                    change.quarantined = true;
+                } else {
+                    change.removed = true;
+                }
+            }
+        }
+    }
+}
+
+/// A change that a lenient load skipped.
+#[derive(Debug, Clone)]
+pub struct DiscardedChange {
+    /// The actor id and sequence number of the change, if the persister could tell.
+    pub key: Option<(ActorId, u64)>,
+    /// The data stored for the change.
+    pub data: Vec<u8>,
+    /// Why the change couldn't be decoded.
+    pub error: String,
+    /// Whether the change was removed from the persister.
+    pub removed: bool,
+    /// Whether the change was moved to the persister's quarantined changes.
+    ///
+    /// Changes are left in place when the persister can't tell what their keys are or when it
+    /// doesn't support quarantining them.
+    pub quarantined: bool,
+}
+
+/// A document that a lenient load skipped.
+#[derive(Debug, Clone)]
+pub struct DiscardedDocument {
+    /// The data stored for the document, or `None` if it couldn't be read.
+    pub data: Option<Vec<u8>>,
+    /// Why the document couldn't be loaded.
+    pub error: String,
+}
+
+/// A stored change along with its key if the persister could provide it.
+pub type StoredChange = (Option<(ActorId, u64)>, Vec<u8>);
+
+/// Stored changes along with their keys if the persister could provide them.
+pub type StoredChanges = Vec<StoredChange>;
+
+/// An iterator over stored changes along with their keys if the persister could provide them.
+pub type StoredChangeIter<'a, E> = Box<dyn Iterator<Item = Result<StoredChange, E>> + 'a>;
+
+/// Stored changes whose keys the persister provided.
+pub fn keyed(changes: Vec<(ActorId, u64, Vec<u8>)>) -> StoredChanges {
+    changes
+        .into_iter()
+        .map(|(a, s, c)| (Some((a, s)), c))
+        .collect()
+}
+
+/// Stored changes whose keys aren't known.
+pub fn unkeyed(changes: Vec<Vec<u8>>) -> StoredChanges {
+    changes.into_iter().map(|c| (None, c)).collect()
+}
+
+/// The changes stored in `persister`, read as they are iterated, along with their keys for a
+/// lenient load.
+pub fn stored_changes<'a, P>(
+    persister: &'a P,
+    options: &LoadOptions,
+) -> Result<StoredChangeIter<'a, P::Error>, P::Error>
+where
+    P: Persister,
+{
+    if options.lenient {
+        if let Some(changes) = persister.iter_changes_with_keys()? {
+            return Ok(Box::new(
+                changes.map(|c| c.map(|(a, s, c)| (Some((a, s)), c))),
+            ));
+        }
+    }
+    Ok(Box::new(
+        persister.iter_changes()?.map(|c| c.map(|c| (None, c))),
+    ))
+}
+
+/// The changes stored in `persister`, read up front as an [`AsyncPersister`] can't read them as
+/// they are iterated, along with their keys for a lenient load.
+pub async fn stored_changes_async<P>(
+    persister: &P,
+    options: &LoadOptions,
+) -> Result<StoredChanges, P::Error>
+where
+    P: AsyncPersister,
+{
+    if options.lenient {
+        if let Some(changes) = persister.get_changes_with_keys().await? {
+            return Ok(keyed(changes));
+        }
+    }
+    Ok(unkeyed(persister.get_changes().await?))
+}
+
+/// Take the next batch of stored changes, empty once there are none left.
+pub fn next_batch<I, E>(stored: &mut I, options: &LoadOptions) -> Result<StoredChanges, E>
+where
+    I: Iterator<Item = Result<StoredChange, E>>,
+{
+    stored.by_ref().take(options.batch_size).collect()
+}
+
+/// The positions among the stored changes of those that a lenient load couldn't decode.
+#[derive(Debug, Default)]
+pub struct Undecoded {
+    /// The number of stored changes decoded so far.
+    read: usize,
+    positions: Vec<usize>,
+}
+
+impl Undecoded {
+    /// Whether every stored change could be decoded.
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+}
+
+/// Decode the stored changes, with a lenient load skipping the ones that fail.
+///
+/// Decoding takes the data of each change rather than a copy, so the skipped changes are
+/// reported without it and their positions are kept in `undecoded` for [`fill_discarded`] to
+/// read it again.
+pub fn decode_changes(
+    stored: StoredChanges,
+    options: &LoadOptions,
+    report: &mut LoadReport,
+    undecoded: &mut Undecoded,
+) -> Result<Vec<Change>, AutomergeError> {
+    if !options.lenient {
+        return stored
+            .into_iter()
+            .map(|(_, data)| Change::from_bytes(data).map_err(Into::into))
+            .collect();
+    }
+    let mut changes = Vec::new();
+    for (key, data) in stored {
+        match Change::from_bytes(data) {
+            Ok(change) => changes.push(change),
+            Err(e) => {
+                undecoded.positions.push(undecoded.read);
+                report.discarded_changes.push(DiscardedChange {
+                    key,
+                    data: Vec::new(),
+                    error: e.to_string(),
+                    removed: false,
+                    quarantined: false,
+                });
+            }
+        }
+        undecoded.read += 1;
+    }
+    Ok(changes)
+}
+
+/// Fill in the data of the changes that [`decode_changes`] skipped from the `stored` changes read
+/// again.
+pub fn fill_discarded<I, E>(
+    report: &mut LoadReport,
+    undecoded: &Undecoded,
+    stored: I,
+) -> Result<(), E>
+where
+    I: IntoIterator<Item = Result<StoredChange, E>>,
+{
+    let mut stored = stored.into_iter();
+    let mut read = 0;
+    for (position, change) in undecoded
+        .positions
+        .iter()
+        .zip(&mut report.discarded_changes)
+    {
+        if let Some(data) = stored.nth(position - read) {
+            change.data = data?.1;
+        }
+        read = position + 1;
+    }
+    Ok(())
+}
+
+/// The stored document, with a lenient load discarding it if it can't be read.
+pub fn document<E>(
+    document: Result<Option<Vec<u8>>, E>,
+    options: &LoadOptions,
+    report: &mut LoadReport,
+) -> Result<Option<Vec<u8>>, E>
+where
+    E: std::error::Error,
+{
+    match document {
+        Err(e) if options.lenient => {
+            report.discarded_document = Some(DiscardedDocument {
+                data: None,
+                error: e.to_string(),
+            });
+            Ok(None)
+        }
+        document => document,
+    }
+}
+
+/// Fill in the data of a document that a lenient load couldn't load from the document read again,
+/// as loading took the data rather than a copy.
+pub fn fill_discarded_document<E>(report: &mut LoadReport, document: Result<Option<Vec<u8>>, E>) {
+    if let Some(discarded) = &mut report.discarded_document {
+        discarded.data = document.ok().flatten();
+    }
+}
+
+/// Remove or quarantine the changes a lenient load discarded.
+pub fn set_aside<P>(
+    persister: &mut P,
+    options: &LoadOptions,
+    report: &mut LoadReport,
+) -> Result<(), P::Error>
+where
+    P: Persister,
+{
+    let keys = report.discarded_keys();
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let keys = keys.iter().map(|(a, s)| (a, *s)).collect();
+    if options.quarantine {
+        if persister.quarantine_changes(keys)? {
+            report.set_aside(true);
+        }
+    } else {
+        persister.remove_changes(keys)?;
+        report.set_aside(false);
+    }
+    Ok(())
+}
+
+/// Remove or quarantine the changes a lenient load discarded.
+pub async fn set_aside_async<P>(
+    persister: &mut P,
+    options: &LoadOptions,
+    report: &mut LoadReport,
+) -> Result<(), P::Error>
+where
+    P: AsyncPersister,
+{
+    let keys = report.discarded_keys();
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let keys = keys.iter().map(|(a, s)| (a, *s)).collect();
+    if options.quarantine {
+        if persister.quarantine_changes(keys).await? {
+            report.set_aside(true);
+        }
+    } else {
+        persister.remove_changes(keys).await?;
+        report.set_aside(false);
+    }
+    Ok(())
+}
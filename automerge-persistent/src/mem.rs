@@ -2,17 +2,19 @@ use std::collections::HashMap;
 
 use automerge_protocol::ActorId;
 
-use crate::{Persister, StoredSizes};
+use crate::{ChangeIter, KeyedChangeIter, Persister, StoredSizes};
 
 /// **For Testing** An in-memory persister.
 ///
 /// As this provides no actual persistence it should not be used for any real application, it
 /// actually reduces performance of the plain backend slightly due to tracking the changes itself.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct MemoryPersister {
     changes: HashMap<(ActorId, u64), Vec<u8>>,
+    quarantined: HashMap<(ActorId, u64), Vec<u8>>,
     document: Option<Vec<u8>>,
     sync_states: HashMap<Vec<u8>, Vec<u8>>,
+    actor_id: Option<ActorId>,
     sizes: StoredSizes,
 }
 
@@ -24,11 +26,35 @@ impl Persister for MemoryPersister {
         Ok(self.changes.values().cloned().collect())
     }
 
+    /// Copy the changes out one at a time.
+    fn iter_changes(&self) -> Result<ChangeIter<'_, Self::Error>, Self::Error> {
+        Ok(Box::new(self.changes.values().cloned().map(Ok)))
+    }
+
+    fn get_changes_with_keys(&self) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        Ok(Some(
+            self.changes
+                .iter()
+                .map(|((a, s), c)| (a.clone(), *s, c.clone()))
+                .collect(),
+        ))
+    }
+
+    fn iter_changes_with_keys(
+        &self,
+    ) -> Result<Option<KeyedChangeIter<'_, Self::Error>>, Self::Error> {
+        Ok(Some(Box::new(
+            self.changes
+                .iter()
+                .map(|((a, s), c)| Ok((a.clone(), *s, c.clone()))),
+        )))
+    }
+
     /// Insert changes into the map.
-    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error> {
         for (a, u, c) in changes {
             self.sizes.changes += c.len();
-            if let Some(old) = self.changes.insert((a, u), c) {
+            if let Some(old) = self.changes.insert((a.clone(), u), c.to_vec()) {
                 self.sizes.changes -= old.len();
             }
         }
@@ -45,6 +71,36 @@ impl Persister for MemoryPersister {
         Ok(())
     }
 
+    /// Move changes from the map of changes to the map of quarantined changes.
+    fn quarantine_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<bool, Self::Error> {
+        for (a, u) in changes {
+            let key = (a.clone(), u);
+            if let Some(old) = self.changes.remove(&key) {
+                self.sizes.changes -= old.len();
+                self.quarantined.insert(key, old);
+            }
+        }
+        Ok(true)
+    }
+
+    fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        Ok(self
+            .quarantined
+            .iter()
+            .map(|((a, s), c)| (a.clone(), *s, c.clone()))
+            .collect())
+    }
+
+    fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        for (a, u) in changes {
+            self.quarantined.remove(&(a.clone(), u));
+        }
+        Ok(())
+    }
+
     /// Get the document.
     fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
         Ok(self.document.clone())
@@ -82,6 +138,15 @@ impl Persister for MemoryPersister {
         Ok(self.sync_states.keys().cloned().collect())
     }
 
+    fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        Ok(self.actor_id.clone())
+    }
+
+    fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        self.actor_id = Some(actor_id);
+        Ok(())
+    }
+
     fn sizes(&self) -> StoredSizes {
         self.sizes.clone()
     }
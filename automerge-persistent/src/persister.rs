@@ -2,7 +2,14 @@ use std::error::Error;
 
 use automerge_protocol::ActorId;
 
-use crate::StoredSizes;
+use crate::{StoredSizes, SyncStateInfo};
+
+/// An iterator over stored changes, as returned by [`Persister::iter_changes`].
+pub type ChangeIter<'a, E> = Box<dyn Iterator<Item = Result<Vec<u8>, E>> + 'a>;
+
+/// An iterator over stored changes along with their `actor_id` and `sequence_number`, as
+/// returned by [`Persister::iter_changes_with_keys`].
+pub type KeyedChangeIter<'a, E> = Box<dyn Iterator<Item = Result<(ActorId, u64, Vec<u8>), E>> + 'a>;
 
 /// A Persister persists both changes and documents to durable storage.
 ///
@@ -12,6 +19,9 @@ use crate::StoredSizes;
 /// Changes are identified by a pair of `actor_id` and `sequence_number`. This uniquely identifies a
 /// change and so is suitable for use as a key in the implementation.
 ///
+/// The stored changes can include ones received from peers whose dependencies are not yet
+/// available. These are kept until they have been applied and compacted into the document.
+///
 /// Documents are saved automerge Backends so are more compact than the raw changes they represent.
 pub trait Persister {
     /// The error type that the operations can produce
@@ -19,16 +29,85 @@ pub trait Persister {
 
     /// Returns all of the changes that have been persisted through this persister.
     /// Ordering is not specified as the automerge Backend should handle that.
+    ///
+    /// The changes are returned as owned buffers since decoding a change takes ownership of its
+    /// bytes, a shared buffer would only move the copy there.
     fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
 
+    /// Returns an iterator over the changes that have been persisted through this persister,
+    /// reading them from storage as it goes.
+    ///
+    /// This lets `load` decode and apply the changes in batches rather than holding all of them in
+    /// memory at once. The default implementation reads them all with `get_changes` up front.
+    fn iter_changes(&self) -> Result<ChangeIter<'_, Self::Error>, Self::Error> {
+        Ok(Box::new(self.get_changes()?.into_iter().map(Ok)))
+    }
+
+    /// Returns all of the changes along with their `actor_id` and `sequence_number`, or `None` if
+    /// the persister can't tell what they are.
+    ///
+    /// This lets a lenient load remove changes that can't be decoded. The default implementation
+    /// returns `None`.
+    #[allow(clippy::type_complexity)]
+    fn get_changes_with_keys(&self) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Returns an iterator over the changes along with their `actor_id` and `sequence_number`, or
+    /// `None` if the persister can't tell what they are, reading them from storage as it goes.
+    ///
+    /// This is what a lenient load reads the changes with. The default implementation reads them
+    /// all with `get_changes_with_keys` up front.
+    fn iter_changes_with_keys(
+        &self,
+    ) -> Result<Option<KeyedChangeIter<'_, Self::Error>>, Self::Error> {
+        Ok(self
+            .get_changes_with_keys()?
+            .map(|changes| Box::new(changes.into_iter().map(Ok)) as KeyedChangeIter<'_, _>))
+    }
+
     /// Inserts the given change at the unique address specified by the `actor_id` and `sequence_number`.
-    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error>;
+    ///
+    /// The changes are borrowed so that they don't need copying out of the backend first, a
+    /// persister only needs to copy what it keeps.
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error>;
 
     /// Removes the change at the unique address specified by the `actor_id` and `sequence_number`.
     ///
     /// If the change does not exist this should not return an error.
     fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error>;
 
+    /// Moves the changes at the given addresses out of the changes that are loaded, keeping their
+    /// data for [`get_quarantined_changes`](Self::get_quarantined_changes).
+    ///
+    /// This is used by a quarantining lenient load to set aside changes that can't be decoded.
+    /// Quarantined changes don't count towards the stored sizes. Returns whether the changes were
+    /// moved, the default implementation doesn't support quarantining so leaves them where they
+    /// are and returns `false`.
+    fn quarantine_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<bool, Self::Error> {
+        let _ = changes;
+        Ok(false)
+    }
+
+    /// Returns the quarantined changes along with their `actor_id` and `sequence_number`.
+    ///
+    /// The default implementation returns none.
+    #[allow(clippy::type_complexity)]
+    fn get_quarantined_changes(&self) -> Result<Vec<(ActorId, u64, Vec<u8>)>, Self::Error> {
+        Ok(Vec::new())
+    }
+
+    /// Removes the quarantined changes at the given addresses, once they have been dealt with.
+    ///
+    /// If a change does not exist this should not return an error.
+    fn remove_quarantined_changes(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> Result<(), Self::Error> {
+        let _ = changes;
+        Ok(())
+    }
+
     /// Returns the document, if one has been persisted previously.
     fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error>;
 
@@ -56,6 +135,61 @@ pub trait Persister {
     /// removed during a compaction.
     fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
 
+    /// Returns the stored sync states along with their sizes and when they were last set, for
+    /// choosing which to prune.
+    ///
+    /// The default implementation reads every sync state to find its size and doesn't know when
+    /// they were set.
+    fn get_sync_state_infos(&self) -> Result<Vec<SyncStateInfo>, Self::Error> {
+        self.get_peer_ids()?
+            .into_iter()
+            .map(|peer_id| {
+                let size = self.get_sync_state(&peer_id)?.map_or(0, |s| s.len());
+                Ok(SyncStateInfo {
+                    peer_id,
+                    size,
+                    updated: None,
+                })
+            })
+            .collect()
+    }
+
+    /// Replaces the document and removes the changes and sync states it supersedes.
+    ///
+    /// This is called by `compact` and should be applied atomically where the storage supports
+    /// it, so that a crash leaves either the old document with all of its changes or the new
+    /// document without them.
+    ///
+    /// The default implementation is not atomic, it just calls `set_document`,
+    /// `remove_changes` and `remove_sync_states` in turn.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        self.set_document(document)?;
+        self.remove_changes(changes)?;
+        self.remove_sync_states(old_peer_ids)?;
+        Ok(())
+    }
+
+    /// Returns the actor id used for local changes to the document, if one has been set.
+    ///
+    /// The default implementation doesn't store an actor id so always returns `None`.
+    fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Sets the actor id used for local changes to the document.
+    ///
+    /// Reusing this across restarts avoids growing the set of actors in the document. The default
+    /// implementation discards it, so documents get a new actor id each time they are loaded.
+    fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        let _ = actor_id;
+        Ok(())
+    }
+
     /// Returns the sizes components being stored consume.
     ///
     /// This can be used as an indicator of when to compact the storage.
@@ -0,0 +1,61 @@
+use crate::{
+    Backend, Error, PersistentAutomerge, PersistentAutomergeError, PersistentBackend, Persister,
+};
+
+/// A `DocumentStore` keeps many documents in shared storage and hands out persisters for them by
+/// id.
+///
+/// The store keeps a catalog of its documents so they can be listed and checked for, and can
+/// remove or rename all of a document's data at once.
+///
+/// Deleting or renaming a document that has an open persister leaves that persister pointing at
+/// the old id, so it should be dropped first.
+pub trait DocumentStore {
+    /// The persister for a single document in the store.
+    type Persister: Persister<Error = Self::Error> + 'static;
+
+    /// The error type that the operations can produce.
+    type Error: std::error::Error + 'static;
+
+    /// Returns the ids of all of the documents in the store.
+    fn list(&self) -> Result<Vec<String>, Self::Error>;
+
+    /// Returns whether there is a document with the given id.
+    fn exists(&self, id: &str) -> Result<bool, Self::Error>;
+
+    /// Creates an empty document with the given id, failing if it already exists.
+    fn create(&self, id: &str) -> Result<Self::Persister, Self::Error>;
+
+    /// Opens the persister for an existing document, failing if it doesn't exist.
+    fn open(&self, id: &str) -> Result<Self::Persister, Self::Error>;
+
+    /// Removes all of the data for the given document, returning whether it existed.
+    fn delete(&self, id: &str) -> Result<bool, Self::Error>;
+
+    /// Moves all of the data for the document `from` to `to`, failing if `from` doesn't exist or
+    /// `to` already does.
+    fn rename(&self, from: &str, to: &str) -> Result<(), Self::Error>;
+
+    /// Opens the document with the given id and loads a [`PersistentBackend`] from it.
+    #[allow(clippy::type_complexity)]
+    fn load_backend<B>(
+        &self,
+        id: &str,
+    ) -> Result<PersistentBackend<Self::Persister, B>, Error<Self::Error, B::Error>>
+    where
+        B: Backend,
+    {
+        PersistentBackend::load(self.open(id).map_err(Error::PersisterError)?)
+    }
+
+    /// Opens the document with the given id and loads a [`PersistentAutomerge`] from it.
+    fn load_document(
+        &self,
+        id: &str,
+    ) -> Result<PersistentAutomerge<Self::Persister>, PersistentAutomergeError<Self::Error>> {
+        PersistentAutomerge::load(
+            self.open(id)
+                .map_err(PersistentAutomergeError::PersisterError)?,
+        )
+    }
+}
@@ -0,0 +1,284 @@
+use automerge::{Frontend, InvalidChangeRequest, LocalChange, Path, Primitive, Value};
+use automerge_persistent::{
+    AsyncPersistentAutomerge, AsyncPersistentBackend, Error, Fault, FaultyPersister,
+    FaultyPersisterError, LoadOptions, MemoryPersister, PersistentAutomergeError, Persister,
+};
+use futures::executor::block_on;
+
+type Backend = AsyncPersistentBackend<FaultyPersister<MemoryPersister>, automerge::Backend>;
+type Document = AsyncPersistentAutomerge<FaultyPersister<MemoryPersister>>;
+
+fn set(doc: &mut dyn automerge::MutableDocument, key: &str) -> Result<(), InvalidChangeRequest> {
+    doc.add_change(LocalChange::set(
+        Path::root().key(key),
+        Value::Primitive(Primitive::Str(key.into())),
+    ))
+}
+
+fn local_change(frontend: &mut Frontend, key: &str) -> automerge_protocol::Change {
+    let ((), change) = frontend.change(None, |doc| set(doc, key)).unwrap();
+    change.unwrap()
+}
+
+/// A persister failing the `n`th call made after loading a backend from it.
+fn failing_after_load(n: usize) -> FaultyPersister<MemoryPersister> {
+    let loaded = block_on(Backend::load(FaultyPersister::new(
+        MemoryPersister::default(),
+    )))
+    .unwrap();
+    FaultyPersister::new(MemoryPersister::default())
+        .fault_at(loaded.persister().calls() + n, Fault::Error)
+}
+
+fn is_injected<B: std::fmt::Debug>(
+    result: Result<
+        B,
+        Error<FaultyPersisterError<std::convert::Infallible>, automerge_backend::AutomergeError>,
+    >,
+) -> bool {
+    matches!(
+        result,
+        Err(Error::PersisterError(FaultyPersisterError::Injected { .. }))
+    )
+}
+
+#[test]
+fn backend_persists_local_changes() {
+    block_on(async {
+        let mut backend = Backend::load(FaultyPersister::new(MemoryPersister::default()))
+            .await
+            .unwrap();
+        let mut frontend = Frontend::new();
+        let patch = backend
+            .apply_local_change(local_change(&mut frontend, "a"))
+            .await
+            .unwrap();
+        frontend.apply_patch(patch).unwrap();
+        assert_eq!(backend.persister().get_changes().unwrap().len(), 1);
+
+        let backend = Backend::load(backend.close().await.unwrap()).await.unwrap();
+        assert_eq!(backend.get_changes(&[]).len(), 1);
+    });
+}
+
+#[test]
+fn backend_rolls_back_local_change_that_fails_to_persist() {
+    let persister = failing_after_load(1);
+    block_on(async {
+        let mut backend = Backend::load(persister).await.unwrap();
+        let result = backend
+            .apply_local_change(local_change(&mut Frontend::new(), "a"))
+            .await;
+        assert!(is_injected(result));
+        assert!(backend.get_heads().is_empty());
+        assert!(backend.persister().get_changes().unwrap().is_empty());
+
+        backend
+            .apply_local_change(local_change(&mut Frontend::new(), "b"))
+            .await
+            .unwrap();
+        assert_eq!(backend.get_changes(&[]).len(), 1);
+    });
+}
+
+#[test]
+fn backend_applies_changes_only_once_persisted() {
+    let persister = failing_after_load(1);
+    block_on(async {
+        let mut source = Backend::load(FaultyPersister::new(MemoryPersister::default()))
+            .await
+            .unwrap();
+        source
+            .apply_local_change(local_change(&mut Frontend::new(), "a"))
+            .await
+            .unwrap();
+        let changes = source
+            .get_changes(&[])
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut backend = Backend::load(persister).await.unwrap();
+        assert!(is_injected(backend.apply_changes(changes.clone()).await));
+        assert!(backend.get_heads().is_empty());
+        assert!(backend.persister().get_changes().unwrap().is_empty());
+
+        backend.apply_changes(changes).await.unwrap();
+        assert_eq!(backend.get_heads(), source.get_heads());
+        assert_eq!(backend.persister().get_changes().unwrap().len(), 1);
+    });
+}
+
+#[test]
+fn backends_sync() {
+    block_on(async {
+        let mut backend1 = Backend::load(FaultyPersister::new(MemoryPersister::default()))
+            .await
+            .unwrap();
+        let mut backend2 = Backend::load(FaultyPersister::new(MemoryPersister::default()))
+            .await
+            .unwrap();
+        backend1
+            .apply_local_change(local_change(&mut Frontend::new(), "a"))
+            .await
+            .unwrap();
+        backend2
+            .apply_local_change(local_change(&mut Frontend::new(), "b"))
+            .await
+            .unwrap();
+
+        loop {
+            let message1 = backend1.generate_sync_message(b"2".to_vec()).await.unwrap();
+            if let Some(message) = message1.clone() {
+                backend2
+                    .receive_sync_message(b"1".to_vec(), message)
+                    .await
+                    .unwrap();
+            }
+            let message2 = backend2.generate_sync_message(b"1".to_vec()).await.unwrap();
+            if let Some(message) = message2.clone() {
+                backend1
+                    .receive_sync_message(b"2".to_vec(), message)
+                    .await
+                    .unwrap();
+            }
+            if message1.is_none() && message2.is_none() {
+                break;
+            }
+        }
+        assert_eq!(backend1.get_heads(), backend2.get_heads());
+        assert_eq!(backend1.persister().get_changes().unwrap().len(), 2);
+        assert_eq!(backend2.persister().get_changes().unwrap().len(), 2);
+        assert_eq!(
+            backend1.persister().get_peer_ids().unwrap(),
+            vec![b"2".to_vec()]
+        );
+    });
+}
+
+#[test]
+fn backend_compacts() {
+    block_on(async {
+        let mut backend = Backend::load(FaultyPersister::new(MemoryPersister::default()))
+            .await
+            .unwrap();
+        let mut frontend = Frontend::new();
+        for key in &["a", "b"] {
+            let patch = backend
+                .apply_local_change(local_change(&mut frontend, key))
+                .await
+                .unwrap();
+            frontend.apply_patch(patch).unwrap();
+        }
+        backend.compact(&[]).await.unwrap();
+        assert!(backend.persister().get_changes().unwrap().is_empty());
+        assert!(backend.persister().get_document().unwrap().is_some());
+
+        let heads = backend.get_heads();
+        let backend = Backend::load(backend.close().await.unwrap()).await.unwrap();
+        assert_eq!(backend.get_heads(), heads);
+    });
+}
+
+#[test]
+fn failed_compaction_keeps_the_changes() {
+    let persister = failing_after_load(2);
+    block_on(async {
+        let mut backend = Backend::load(persister).await.unwrap();
+        backend
+            .apply_local_change(local_change(&mut Frontend::new(), "a"))
+            .await
+            .unwrap();
+        assert!(is_injected(backend.compact(&[]).await));
+        assert_eq!(backend.persister().get_changes().unwrap().len(), 1);
+        assert_eq!(backend.persister().get_document().unwrap(), None);
+    });
+}
+
+#[test]
+fn document_persists_and_rolls_back_changes() {
+    block_on(async {
+        let loaded = Document::load(FaultyPersister::new(MemoryPersister::default()))
+            .await
+            .unwrap();
+        let calls = loaded.persister().calls();
+        let persister =
+            FaultyPersister::new(MemoryPersister::default()).fault_at(calls + 3, Fault::Error);
+        let mut document = Document::load(persister).await.unwrap();
+
+        // the change and reading the changes back are the first two calls
+        document.change(None, |doc| set(doc, "a")).await.unwrap();
+        assert_eq!(document.persister().get_changes().unwrap().len(), 1);
+
+        let heads = document.get_heads();
+        let result = document.change(None, |doc| set(doc, "b")).await;
+        assert!(matches!(
+            result,
+            Err(PersistentAutomergeError::PersisterError(
+                FaultyPersisterError::Injected { .. }
+            ))
+        ));
+        assert_eq!(document.get_heads(), heads);
+        assert_eq!(document.get_value(&Path::root().key("b")), None);
+        assert_eq!(document.persister().get_changes().unwrap().len(), 1);
+
+        document.change(None, |doc| set(doc, "b")).await.unwrap();
+        let (document, _) =
+            Document::load_with_options(document.close().await.unwrap(), &LoadOptions::default())
+                .await
+                .unwrap();
+        assert_eq!(
+            document.get_value(&Path::root().key("b")),
+            Some(Value::Primitive(Primitive::Str("b".into())))
+        );
+    });
+}
+
+fn assert_send<T: Send>(_: T) {}
+
+/// Checks that the futures of every public async method can be sent to another thread, never
+/// run.
+#[allow(dead_code, unused_must_use)]
+fn async_methods_are_send(
+    mut backend: AsyncPersistentBackend<MemoryPersister, automerge::Backend>,
+    mut document: AsyncPersistentAutomerge<MemoryPersister>,
+    change: automerge_protocol::Change,
+    message: automerge_backend::SyncMessage,
+) {
+    type SendBackend = AsyncPersistentBackend<MemoryPersister, automerge::Backend>;
+    assert_send(SendBackend::load(MemoryPersister::default()));
+    assert_send(SendBackend::load_with_options(
+        MemoryPersister::default(),
+        &LoadOptions::default(),
+    ));
+    assert_send(backend.apply_changes(Vec::new()));
+    assert_send(backend.apply_local_change(change));
+    assert_send(backend.compact(&[]));
+    assert_send(backend.generate_sync_message(Vec::new()));
+    assert_send(backend.receive_sync_message(Vec::new(), message.clone()));
+    assert_send(backend.flush());
+    assert_send(backend.close());
+
+    type SendDocument = AsyncPersistentAutomerge<MemoryPersister>;
+    assert_send(SendDocument::load(MemoryPersister::default()));
+    assert_send(SendDocument::load_with_options(
+        MemoryPersister::default(),
+        &LoadOptions::default(),
+    ));
+    assert_send(SendDocument::load_with_frontend(
+        MemoryPersister::default(),
+        Frontend::new(),
+    ));
+    assert_send(SendDocument::load_with_frontend_and_options(
+        MemoryPersister::default(),
+        Frontend::new(),
+        &LoadOptions::default(),
+    ));
+    assert_send(document.rotate_actor_id());
+    assert_send(document.change(None, |doc| set(doc, "a")));
+    assert_send(document.compact(&[]));
+    assert_send(document.generate_sync_message(Vec::new()));
+    assert_send(document.receive_sync_message(Vec::new(), message));
+    assert_send(document.flush());
+    assert_send(document.close());
+}
@@ -0,0 +1,154 @@
+use automerge_persistent::{
+    ChecksumPersister, ChecksumPersisterError, MemoryPersister, Persister, StoredKey,
+};
+use automerge_protocol::ActorId;
+
+/// Flip a bit in the last byte of a stored value.
+fn damage(mut value: Vec<u8>) -> Vec<u8> {
+    let last = value.len() - 1;
+    value[last] ^= 1;
+    value
+}
+
+fn corrupt_key<T: std::fmt::Debug>(
+    result: Result<T, ChecksumPersisterError<std::convert::Infallible>>,
+) -> StoredKey {
+    match result {
+        Err(ChecksumPersisterError::Corrupt(key)) => key,
+        other => panic!("expected corrupt data, got {:?}", other),
+    }
+}
+
+#[test]
+fn damaged_document_and_sync_state_are_corrupt() {
+    let mut persister = ChecksumPersister::new(MemoryPersister::default());
+    persister.set_document(vec![1; 10]).unwrap();
+    persister
+        .set_sync_state(b"peer".to_vec(), vec![2; 10])
+        .unwrap();
+    let mut inner = persister.into_persister();
+    let document = damage(inner.get_document().unwrap().unwrap());
+    inner.set_document(document).unwrap();
+    let sync_state = damage(inner.get_sync_state(b"peer").unwrap().unwrap());
+    inner.set_sync_state(b"peer".to_vec(), sync_state).unwrap();
+
+    let persister = ChecksumPersister::new(inner);
+    assert_eq!(corrupt_key(persister.get_document()), StoredKey::Document);
+    assert_eq!(
+        corrupt_key(persister.get_sync_state(b"peer")),
+        StoredKey::SyncState {
+            peer_id: b"peer".to_vec()
+        }
+    );
+}
+
+#[test]
+fn damaged_change_is_named_by_its_stored_key() {
+    let actor_id = ActorId::random();
+    let mut persister = ChecksumPersister::new(MemoryPersister::default());
+    persister
+        .insert_changes(vec![
+            (&actor_id, 1, &[1; 10][..]),
+            (&actor_id, 2, &[2; 10][..]),
+        ])
+        .unwrap();
+    let mut inner = persister.into_persister();
+    let (_, _, stored) = inner
+        .get_changes_with_keys()
+        .unwrap()
+        .unwrap()
+        .into_iter()
+        .find(|(_, s, _)| *s == 2)
+        .unwrap();
+    // damage the sequence number stored with the change too
+    let mut damaged = damage(stored);
+    damaged[8 + 4 + 16] ^= 1;
+    inner
+        .insert_changes(vec![(&actor_id, 2, &damaged[..])])
+        .unwrap();
+
+    let persister = ChecksumPersister::new(inner);
+    let expected = StoredKey::Change {
+        actor_id: actor_id.clone(),
+        seq: 2,
+    };
+    assert_eq!(corrupt_key(persister.get_changes()), expected);
+    let iterated = persister
+        .iter_changes()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>();
+    assert_eq!(corrupt_key(iterated), expected);
+
+    // with keys the damaged change is returned as it was stored for a lenient load to discard
+    let mut changes = persister.get_changes_with_keys().unwrap().unwrap();
+    changes.sort_by_key(|(_, s, _)| *s);
+    assert_eq!(
+        changes,
+        vec![(actor_id.clone(), 1, vec![1; 10]), (actor_id, 2, damaged)]
+    );
+}
+
+#[test]
+fn swapped_changes_are_corrupt() {
+    let actor_id = ActorId::random();
+    let mut persister = ChecksumPersister::new(MemoryPersister::default());
+    persister
+        .insert_changes(vec![
+            (&actor_id, 1, &[1; 10][..]),
+            (&actor_id, 2, &[2; 10][..]),
+        ])
+        .unwrap();
+    let mut inner = persister.into_persister();
+    let mut stored = inner.get_changes_with_keys().unwrap().unwrap();
+    stored.sort_by_key(|(_, s, _)| *s);
+    inner
+        .insert_changes(vec![
+            (&actor_id, 1, &stored[1].2[..]),
+            (&actor_id, 2, &stored[0].2[..]),
+        ])
+        .unwrap();
+
+    let persister = ChecksumPersister::new(inner);
+    // named by the key it is stored under, whichever is read first
+    assert!(matches!(
+        corrupt_key(persister.get_changes()),
+        StoredKey::Change { .. }
+    ));
+    let iterated = persister
+        .iter_changes()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>();
+    assert!(matches!(corrupt_key(iterated), StoredKey::Change { .. }));
+
+    // both are returned as they were stored for a lenient load to discard
+    let mut changes = persister.get_changes_with_keys().unwrap().unwrap();
+    changes.sort_by_key(|(_, s, _)| *s);
+    assert_eq!(
+        changes,
+        vec![
+            (actor_id.clone(), 1, stored[1].2.clone()),
+            (actor_id, 2, stored[0].2.clone())
+        ]
+    );
+}
+
+#[test]
+fn unchecked_values_are_corrupt_unless_allowed() {
+    let actor_id = ActorId::random();
+    let mut inner = MemoryPersister::default();
+    inner.set_document(vec![1; 10]).unwrap();
+    inner
+        .insert_changes(vec![(&actor_id, 1, &[2; 10][..])])
+        .unwrap();
+
+    let persister = ChecksumPersister::new(inner.clone());
+    assert_eq!(corrupt_key(persister.get_document()), StoredKey::Document);
+    assert_eq!(
+        corrupt_key(persister.get_changes()),
+        StoredKey::Change { actor_id, seq: 1 }
+    );
+
+    let persister = ChecksumPersister::new(inner).allow_unchecked();
+    assert_eq!(persister.get_document().unwrap(), Some(vec![1; 10]));
+    assert_eq!(persister.get_changes().unwrap(), vec![vec![2; 10]]);
+}
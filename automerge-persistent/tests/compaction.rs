@@ -0,0 +1,142 @@
+use std::sync::{Arc, Mutex};
+
+use automerge::{Change, Frontend, InvalidChangeRequest, LocalChange, Path, Primitive, Value};
+use automerge_persistent::{
+    CompactionPolicy, MemoryPersister, PersistentAutomerge, PersistentBackend, Persister,
+    StoredSizes, SyncStateInfo, ThresholdPolicy,
+};
+
+fn local_change(frontend: &mut Frontend, key: &str) -> automerge_protocol::Change {
+    let ((), change) = frontend
+        .change::<_, _, InvalidChangeRequest>(None, |doc| {
+            doc.add_change(LocalChange::set(
+                Path::root().key(key),
+                Value::Primitive(Primitive::Str(key.into())),
+            ))
+        })
+        .unwrap();
+    change.unwrap()
+}
+
+/// Two changes from another actor, the second depending on the first.
+fn remote_changes() -> (Change, Change) {
+    let mut frontend = Frontend::new();
+    let mut backend = automerge::Backend::default();
+    let (patch, first) = backend
+        .apply_local_change(local_change(&mut frontend, "first"))
+        .unwrap();
+    let first = first.clone();
+    frontend.apply_patch(patch).unwrap();
+    let (_, second) = backend
+        .apply_local_change(local_change(&mut frontend, "second"))
+        .unwrap();
+    (first, second.clone())
+}
+
+#[test]
+fn percent_of_document_waits_for_changes_without_document() {
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(MemoryPersister::default()).unwrap();
+    backend.set_compaction_policy(
+        ThresholdPolicy::new()
+            .max_changes_percent_of_document(50)
+            .min_changes_without_document(3),
+    );
+    let mut frontend = Frontend::new();
+    for key in &["a", "b"] {
+        let patch = backend
+            .apply_local_change(local_change(&mut frontend, key))
+            .unwrap();
+        frontend.apply_patch(patch).unwrap();
+        assert_eq!(backend.persister().get_document().unwrap(), None);
+    }
+    backend
+        .apply_local_change(local_change(&mut frontend, "c"))
+        .unwrap();
+    assert!(backend.persister().get_document().unwrap().is_some());
+    assert!(backend.persister().get_changes().unwrap().is_empty());
+}
+
+#[test]
+fn queued_changes_do_not_count_towards_thresholds() {
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(MemoryPersister::default()).unwrap();
+    backend.set_compaction_policy(ThresholdPolicy::new().max_changes_bytes(0).max_changes(1));
+    let (first, second) = remote_changes();
+
+    backend.apply_changes(vec![second.clone()]).unwrap();
+    assert_eq!(backend.persister().get_document().unwrap(), None);
+    assert_eq!(
+        backend.persister().get_changes().unwrap(),
+        vec![second.raw_bytes().to_vec()]
+    );
+
+    backend.apply_changes(vec![first]).unwrap();
+    assert!(backend.persister().get_document().unwrap().is_some());
+    assert!(backend.persister().get_changes().unwrap().is_empty());
+}
+
+#[test]
+fn document_queued_changes_do_not_count_towards_thresholds() {
+    let mut document = PersistentAutomerge::load(MemoryPersister::default()).unwrap();
+    document.set_compaction_policy(ThresholdPolicy::new().max_changes(1));
+    let (first, second) = remote_changes();
+
+    document.apply_changes(vec![second]).unwrap();
+    assert_eq!(document.persister().get_document().unwrap(), None);
+
+    document.apply_changes(vec![first]).unwrap();
+    assert!(document.persister().get_document().unwrap().is_some());
+}
+
+/// Compacts after every change and prunes the sync state for `b"old"`, recording what it was
+/// given.
+#[derive(Debug, Default)]
+struct PruneOld {
+    seen: Arc<Mutex<Vec<SyncStateInfo>>>,
+}
+
+impl CompactionPolicy for PruneOld {
+    fn should_compact(&mut self, _sizes: &StoredSizes, changes_since_compaction: usize) -> bool {
+        changes_since_compaction > 0
+    }
+
+    fn select_peers_to_prune(&mut self, sync_states: Vec<SyncStateInfo>) -> Vec<Vec<u8>> {
+        let old = sync_states
+            .iter()
+            .filter(|info| info.peer_id == b"old")
+            .map(|info| info.peer_id.clone())
+            .collect();
+        self.seen.lock().unwrap().extend(sync_states);
+        old
+    }
+}
+
+#[test]
+fn policy_prunes_selected_sync_states() {
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(MemoryPersister::default()).unwrap();
+    let policy = PruneOld::default();
+    let seen = Arc::clone(&policy.seen);
+    backend.set_compaction_policy(policy);
+    backend.generate_sync_message(b"old".to_vec()).unwrap();
+    backend.generate_sync_message(b"new".to_vec()).unwrap();
+
+    backend
+        .apply_local_change(local_change(&mut Frontend::new(), "a"))
+        .unwrap();
+
+    let mut seen = seen.lock().unwrap().clone();
+    seen.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+    assert_eq!(
+        seen.iter()
+            .map(|info| &info.peer_id[..])
+            .collect::<Vec<_>>(),
+        vec![&b"new"[..], &b"old"[..]]
+    );
+    assert!(seen.iter().all(|info| info.size > 0));
+    assert_eq!(
+        backend.persister().get_peer_ids().unwrap(),
+        vec![b"new".to_vec()]
+    );
+}
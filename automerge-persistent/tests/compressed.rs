@@ -0,0 +1,165 @@
+use automerge_persistent::{
+    CompressedPersister, CompressedPersisterError, FaultyPersister, MemoryPersister, Persister,
+    UncompressedSizes,
+};
+use automerge_protocol::ActorId;
+
+/// The uncompressed sizes of the changes, document and sync states.
+fn uncompressed(persister: &CompressedPersister<MemoryPersister>) -> (usize, usize, usize) {
+    let UncompressedSizes {
+        changes,
+        document,
+        sync_states,
+    } = persister.sizes().uncompressed.unwrap();
+    (changes, document, sync_states)
+}
+
+fn compressed(persister: MemoryPersister) -> CompressedPersister<MemoryPersister> {
+    CompressedPersister::new(persister)
+        .unwrap()
+        .compress_changes()
+        .compress_document()
+        .compress_sync_states()
+}
+
+#[test]
+fn legacy_uncompressed_data_still_loads() {
+    let actor_id = ActorId::random();
+    let mut legacy = MemoryPersister::default();
+    legacy
+        .insert_changes(vec![(&actor_id, 1, &[1; 100][..])])
+        .unwrap();
+    legacy.set_document(vec![2; 200]).unwrap();
+    legacy
+        .set_sync_state(b"peer".to_vec(), vec![3; 50])
+        .unwrap();
+
+    let mut persister = compressed(legacy);
+    assert_eq!(persister.get_changes().unwrap(), vec![vec![1; 100]]);
+    assert_eq!(persister.get_document().unwrap(), Some(vec![2; 200]));
+    assert_eq!(
+        persister.get_sync_state(b"peer").unwrap(),
+        Some(vec![3; 50])
+    );
+    let sizes = persister.sizes();
+    assert_eq!(
+        (sizes.changes, sizes.document, sizes.sync_states),
+        (100, 200, 50)
+    );
+    assert_eq!(uncompressed(&persister), (100, 200, 50));
+
+    // removing a change that wasn't written through the wrapper is still accounted for
+    persister.remove_changes(vec![(&actor_id, 1)]).unwrap();
+    assert_eq!(persister.sizes().uncompressed.unwrap().changes, 0);
+}
+
+#[test]
+fn sizes_report_stored_and_uncompressed_bytes() {
+    let actor_id = ActorId::random();
+    let mut persister = compressed(MemoryPersister::default());
+    persister
+        .insert_changes(vec![(&actor_id, 1, &[1; 1000][..])])
+        .unwrap();
+    persister.set_document(vec![2; 2000]).unwrap();
+    persister
+        .set_sync_state(b"peer".to_vec(), vec![3; 500])
+        .unwrap();
+
+    let sizes = persister.sizes();
+    assert!(sizes.changes < 1000);
+    assert!(sizes.document < 2000);
+    assert!(sizes.sync_states < 500);
+    assert_eq!(uncompressed(&persister), (1000, 2000, 500));
+    assert_eq!(
+        persister.persister().sizes().changes,
+        persister.persister().get_changes().unwrap()[0].len()
+    );
+    assert_eq!(persister.get_changes().unwrap(), vec![vec![1; 1000]]);
+
+    persister.remove_changes(vec![(&actor_id, 1)]).unwrap();
+    persister.remove_sync_states(&[b"peer"]).unwrap();
+    let sizes = persister.sizes();
+    assert_eq!((sizes.changes, sizes.sync_states), (0, 0));
+    assert_eq!(uncompressed(&persister), (0, 2000, 0));
+}
+
+#[test]
+fn incompressible_and_lookalike_data_round_trips() {
+    let actor_id = ActorId::random();
+    let mut persister = compressed(MemoryPersister::default());
+    let small = vec![7, 1, 9];
+    // starts with the header magic but wasn't written compressed
+    let lookalike = b"\0amz not compressed".to_vec();
+    persister
+        .insert_changes(vec![
+            (&actor_id, 1, &small[..]),
+            (&actor_id, 2, &lookalike[..]),
+        ])
+        .unwrap();
+    assert_eq!(
+        persister.sizes().uncompressed.unwrap().changes,
+        3 + lookalike.len()
+    );
+    let mut changes = persister.get_changes().unwrap();
+    changes.sort();
+    assert_eq!(changes, vec![lookalike, small]);
+}
+
+#[test]
+fn damaged_compressed_data_is_an_error() {
+    let mut inner = compressed(MemoryPersister::default());
+    inner.set_document(vec![2; 2000]).unwrap();
+    let mut inner = inner.into_persister();
+    let mut document = inner.get_document().unwrap().unwrap();
+    document.truncate(document.len() / 2);
+    inner.set_document(document).unwrap();
+
+    assert!(matches!(
+        compressed(inner).get_document(),
+        Err(CompressedPersisterError::DecompressError(_))
+    ));
+}
+
+#[test]
+fn removing_changes_stored_before_wrapping_reads_nothing_back() {
+    let actor_id = ActorId::random();
+    let mut legacy = compressed(MemoryPersister::default());
+    legacy
+        .insert_changes(vec![
+            (&actor_id, 1, &[1; 100][..]),
+            (&actor_id, 2, &[2; 300][..]),
+        ])
+        .unwrap();
+
+    let mut persister =
+        CompressedPersister::new(FaultyPersister::new(legacy.into_persister())).unwrap();
+    assert_eq!(persister.sizes().uncompressed.unwrap().changes, 400);
+    let calls = persister.persister().calls();
+    persister.remove_changes(vec![(&actor_id, 2)]).unwrap();
+    // only the removal, the sizes of the remaining changes were already known
+    assert_eq!(persister.persister().calls(), calls + 1);
+    assert_eq!(persister.sizes().uncompressed.unwrap().changes, 100);
+
+    persister.remove_changes(vec![(&actor_id, 3)]).unwrap();
+    assert_eq!(persister.persister().calls(), calls + 2);
+    assert_eq!(persister.sizes().uncompressed.unwrap().changes, 100);
+}
+
+#[test]
+fn decompression_stops_past_the_declared_length() {
+    let mut inner = compressed(MemoryPersister::default());
+    inner.set_document(vec![0; 100_000]).unwrap();
+    let mut inner = inner.into_persister();
+    let mut document = inner.get_document().unwrap().unwrap();
+    // the little endian length follows the magic bytes and the format
+    document[5..13].copy_from_slice(&10_u64.to_le_bytes());
+    inner.set_document(document).unwrap();
+
+    assert!(matches!(
+        compressed(inner).get_document(),
+        Err(CompressedPersisterError::LengthMismatch {
+            expected: 10,
+            actual: 11
+        })
+    ));
+}
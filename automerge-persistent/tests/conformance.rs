@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+
+use automerge_persistent::{FaultyPersister, KvPersister, MemoryPersister};
+use automerge_persistent_test_suite::{persister_tests, PersisterFixture, StoreFixture};
+
+type Store = BTreeMap<Vec<u8>, Vec<u8>>;
+
+mod memory {
+    use super::*;
+
+    struct MemoryFixture;
+
+    impl PersisterFixture for MemoryFixture {
+        type Persister = MemoryPersister;
+
+        fn open(&mut self, _name: &str) -> Self::Persister {
+            MemoryPersister::default()
+        }
+
+        fn persistent(&self) -> bool {
+            false
+        }
+    }
+
+    persister_tests!(MemoryFixture);
+}
+
+mod kv {
+    use super::*;
+
+    fn fixture() -> StoreFixture<Store, KvPersister<Store>> {
+        StoreFixture::new(
+            |store, name| KvPersister::new(store, name).unwrap(),
+            KvPersister::into_store,
+        )
+    }
+
+    persister_tests!(fixture());
+}
+
+mod faulty {
+    use super::*;
+
+    /// Reopens what survived the previous persister, with no faults injected.
+    fn fixture() -> StoreFixture<Store, FaultyPersister<KvPersister<Store>>> {
+        StoreFixture::new(
+            |store, name| FaultyPersister::new(KvPersister::new(store, name).unwrap()),
+            |persister| persister.surviving().into_store(),
+        )
+    }
+
+    persister_tests!(fixture());
+}
+
+#[cfg(feature = "compression")]
+mod compressed {
+    use automerge_persistent::CompressedPersister;
+
+    use super::*;
+
+    fn fixture() -> StoreFixture<Store, CompressedPersister<KvPersister<Store>>> {
+        StoreFixture::new(
+            |store, name| {
+                CompressedPersister::new(KvPersister::new(store, name).unwrap())
+                    .unwrap()
+                    .compress_changes()
+                    .compress_document()
+                    .compress_sync_states()
+            },
+            |persister| persister.into_persister().into_store(),
+        )
+    }
+
+    persister_tests!(fixture());
+}
+
+#[cfg(feature = "encryption")]
+mod encrypted {
+    use automerge_persistent::EncryptedPersister;
+
+    use super::*;
+
+    /// Changes also store their actor id with its length and the sequence number.
+    fn fixture() -> StoreFixture<Store, EncryptedPersister<KvPersister<Store>>> {
+        StoreFixture::new(
+            |store, name| {
+                EncryptedPersister::new(KvPersister::new(store, name).unwrap(), 1, [1; 32])
+            },
+            |persister| persister.into_persister().into_store(),
+        )
+        .with_overhead(EncryptedPersister::<KvPersister<Store>>::OVERHEAD)
+        .with_change_overhead(EncryptedPersister::<KvPersister<Store>>::OVERHEAD + 4 + 16 + 8)
+    }
+
+    persister_tests!(fixture());
+}
+
+#[cfg(feature = "checksum")]
+mod checksum {
+    use automerge_persistent::ChecksumPersister;
+
+    use super::*;
+
+    /// The magic bytes and checksum, and for changes also the actor id with its length and the
+    /// sequence number.
+    fn fixture() -> StoreFixture<Store, ChecksumPersister<KvPersister<Store>>> {
+        StoreFixture::new(
+            |store, name| ChecksumPersister::new(KvPersister::new(store, name).unwrap()),
+            |persister| persister.into_persister().into_store(),
+        )
+        .with_overhead(8)
+        .with_change_overhead(8 + 4 + 16 + 8)
+    }
+
+    persister_tests!(fixture());
+}
@@ -0,0 +1,173 @@
+use automerge_persistent::{
+    EncryptedPersister, EncryptedPersisterError, MemoryPersister, Persister,
+};
+use automerge_protocol::ActorId;
+
+const OLD_KEY: [u8; 32] = [1; 32];
+const NEW_KEY: [u8; 32] = [2; 32];
+
+fn sorted_changes(persister: &EncryptedPersister<MemoryPersister>) -> Vec<Vec<u8>> {
+    let mut changes = persister.get_changes().unwrap();
+    changes.sort();
+    changes
+}
+
+#[test]
+fn rotated_key_reencrypts_on_compact() {
+    let actor_id = ActorId::random();
+    let mut persister = EncryptedPersister::new(MemoryPersister::default(), 1, OLD_KEY);
+    persister
+        .insert_changes(vec![
+            (&actor_id, 1, &[1; 10][..]),
+            (&actor_id, 2, &[2; 10][..]),
+        ])
+        .unwrap();
+    persister.set_sync_state(b"kept".to_vec(), vec![3]).unwrap();
+    persister
+        .set_sync_state(b"pruned".to_vec(), vec![4])
+        .unwrap();
+
+    persister.rotate_key(2, NEW_KEY).unwrap();
+    assert_eq!(persister.key_id(), 2);
+    persister
+        .insert_changes(vec![(&actor_id, 3, &[5; 10][..])])
+        .unwrap();
+    assert_eq!(
+        sorted_changes(&persister),
+        vec![vec![1; 10], vec![2; 10], vec![5; 10]]
+    );
+
+    persister
+        .compact(vec![6], vec![(&actor_id, 1)], &[b"pruned"])
+        .unwrap();
+
+    // everything left is readable without the old key
+    assert!(!persister.reencrypt_pending());
+    assert_eq!(persister.remove_old_keys().unwrap(), vec![1]);
+    assert_eq!(sorted_changes(&persister), vec![vec![2; 10], vec![5; 10]]);
+    let persister = EncryptedPersister::new(persister.into_persister(), 2, NEW_KEY);
+    assert_eq!(sorted_changes(&persister), vec![vec![2; 10], vec![5; 10]]);
+    assert_eq!(persister.get_document().unwrap(), Some(vec![6]));
+    assert_eq!(persister.get_sync_state(b"kept").unwrap(), Some(vec![3]));
+    assert_eq!(persister.get_peer_ids().unwrap(), vec![b"kept".to_vec()]);
+}
+
+#[test]
+fn old_key_is_needed_before_compacting() {
+    let actor_id = ActorId::random();
+    let mut persister = EncryptedPersister::new(MemoryPersister::default(), 1, OLD_KEY);
+    persister
+        .insert_changes(vec![(&actor_id, 1, &[1; 10][..])])
+        .unwrap();
+
+    let without_old = EncryptedPersister::new(persister.persister().clone(), 2, NEW_KEY);
+    assert!(matches!(
+        without_old.get_changes(),
+        Err(EncryptedPersisterError::UnknownKey(1))
+    ));
+    let with_old = EncryptedPersister::new(persister.into_persister(), 2, NEW_KEY)
+        .with_old_key(1, OLD_KEY)
+        .unwrap();
+    assert_eq!(with_old.get_changes().unwrap(), vec![vec![1; 10]]);
+}
+
+#[test]
+fn compacting_reencrypts_only_while_a_rotation_is_pending() {
+    let actor_id = ActorId::random();
+    let mut persister = EncryptedPersister::new(MemoryPersister::default(), 1, OLD_KEY);
+    persister
+        .insert_changes(vec![(&actor_id, 1, &[1; 10][..])])
+        .unwrap();
+    let sealed = persister.persister().get_changes().unwrap();
+    persister.compact(vec![1], Vec::new(), &[]).unwrap();
+    // the change wasn't read back and sealed again
+    assert_eq!(persister.persister().get_changes().unwrap(), sealed);
+
+    persister.rotate_key(2, NEW_KEY).unwrap();
+    assert!(persister.reencrypt_pending());
+    assert!(matches!(
+        persister.remove_old_keys(),
+        Err(EncryptedPersisterError::ReencryptionPending)
+    ));
+    persister.compact(vec![2], Vec::new(), &[]).unwrap();
+    assert_ne!(persister.persister().get_changes().unwrap(), sealed);
+    assert_eq!(persister.remove_old_keys().unwrap(), vec![1]);
+    assert_eq!(persister.get_changes().unwrap(), vec![vec![1; 10]]);
+}
+
+#[test]
+fn adding_an_old_key_with_a_used_id_fails() {
+    let persister = EncryptedPersister::new(MemoryPersister::default(), 1, NEW_KEY);
+    assert!(matches!(
+        persister.with_old_key(1, OLD_KEY),
+        Err(EncryptedPersisterError::DuplicateKeyId(1))
+    ));
+}
+
+#[test]
+fn rotating_to_a_used_key_id_fails() {
+    let mut persister = EncryptedPersister::new(MemoryPersister::default(), 1, OLD_KEY);
+    assert!(matches!(
+        persister.rotate_key(1, NEW_KEY),
+        Err(EncryptedPersisterError::DuplicateKeyId(1))
+    ));
+    assert_eq!(persister.key_id(), 1);
+    persister.set_document(vec![1]).unwrap();
+    assert_eq!(persister.get_document().unwrap(), Some(vec![1]));
+}
+
+#[test]
+fn tampered_ciphertext_fails_authentication() {
+    let mut persister = EncryptedPersister::new(MemoryPersister::default(), 1, OLD_KEY);
+    persister.set_document(vec![1; 10]).unwrap();
+    let mut inner = persister.into_persister();
+    let mut document = inner.get_document().unwrap().unwrap();
+    let last = document.len() - 1;
+    document[last] ^= 1;
+    inner.set_document(document).unwrap();
+
+    let persister = EncryptedPersister::new(inner, 1, OLD_KEY);
+    assert!(matches!(
+        persister.get_document(),
+        Err(EncryptedPersisterError::DecryptError)
+    ));
+}
+
+#[test]
+fn changes_moved_to_another_key_fail_authentication() {
+    let first = ActorId::random();
+    let second = ActorId::random();
+    let mut persister = EncryptedPersister::new(MemoryPersister::default(), 1, OLD_KEY);
+    persister
+        .insert_changes(vec![(&first, 1, &[1; 10][..])])
+        .unwrap();
+    let mut inner = persister.into_persister();
+    let sealed = inner.get_changes().unwrap().remove(0);
+
+    // the key stored in the clear is authenticated
+    let mut relabelled = sealed.clone();
+    let seq_end = relabelled.len() - 10 - 16;
+    relabelled[seq_end - 8] ^= 1;
+    inner.remove_changes(vec![(&first, 1)]).unwrap();
+    inner
+        .insert_changes(vec![(&first, 1, &relabelled[..])])
+        .unwrap();
+    let persister = EncryptedPersister::new(inner, 1, OLD_KEY);
+    assert!(matches!(
+        persister.get_changes(),
+        Err(EncryptedPersisterError::DecryptError)
+    ));
+
+    // and has to match the key the change is stored under
+    let mut inner = persister.into_persister();
+    inner.remove_changes(vec![(&first, 1)]).unwrap();
+    inner
+        .insert_changes(vec![(&second, 1, &sealed[..])])
+        .unwrap();
+    let persister = EncryptedPersister::new(inner, 1, OLD_KEY);
+    assert_eq!(persister.get_changes().unwrap(), vec![vec![1; 10]]);
+    assert!(matches!(
+        persister.get_changes_with_keys(),
+        Err(EncryptedPersisterError::DecryptError)
+    ));
+}
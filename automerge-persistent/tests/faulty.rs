@@ -0,0 +1,255 @@
+use std::{cell::Cell, collections::BTreeMap, io, rc::Rc};
+
+use automerge::{Frontend, InvalidChangeRequest, LocalChange, Path, Primitive, Value};
+use automerge_persistent::{
+    sync_until_converged, Error, Fault, FaultyPersister, FaultyPersisterError, KvIter, KvPersister,
+    KvPersisterError, KvStore, MemoryPersister, PersistentBackend, Persister,
+};
+use automerge_protocol::ActorId;
+
+fn local_change(frontend: &mut Frontend, key: &str) -> automerge_protocol::Change {
+    let ((), change) = frontend
+        .change::<_, _, InvalidChangeRequest>(None, |doc| {
+            doc.add_change(LocalChange::set(
+                Path::root().key(key),
+                Value::Primitive(Primitive::Str(key.into())),
+            ))
+        })
+        .unwrap();
+    change.unwrap()
+}
+
+/// A `BTreeMap` store failing the next `failures` writes made through it or any of its clones.
+#[derive(Debug, Clone, Default)]
+struct FailingStore {
+    map: BTreeMap<Vec<u8>, Vec<u8>>,
+    failures: Rc<Cell<usize>>,
+}
+
+impl FailingStore {
+    fn write(&self) -> Result<(), io::Error> {
+        match self.failures.get() {
+            0 => Ok(()),
+            n => {
+                self.failures.set(n - 1);
+                Err(io::ErrorKind::Other.into())
+            }
+        }
+    }
+}
+
+impl KvStore for FailingStore {
+    type Error = io::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.map.get(key).cloned())
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.write()?;
+        self.map.insert(key, value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.write()?;
+        self.map.remove(key);
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvIter<'_, Self::Error>, Self::Error> {
+        let entries = self.map.scan_prefix(prefix).unwrap_or_else(|e| match e {});
+        Ok(Box::new(
+            entries.map(|entry| Ok(entry.unwrap_or_else(|e| match e {}))),
+        ))
+    }
+}
+
+fn insert(persister: &mut FaultyPersister<MemoryPersister>, actor_id: &ActorId, seq: u64) {
+    persister
+        .insert_changes(vec![(actor_id, seq, &[seq as u8][..])])
+        .unwrap();
+}
+
+#[test]
+fn error_fault_has_no_effect() {
+    let actor_id = ActorId::random();
+    let mut persister = FaultyPersister::new(MemoryPersister::default()).fault_at(2, Fault::Error);
+    insert(&mut persister, &actor_id, 1);
+    let result = persister.insert_changes(vec![(&actor_id, 2, &[2][..])]);
+    assert!(matches!(
+        result,
+        Err(FaultyPersisterError::Injected {
+            call: 2,
+            operation: "insert_changes"
+        })
+    ));
+    assert!(!persister.is_crashed());
+    assert_eq!(persister.get_changes().unwrap(), vec![vec![1]]);
+    assert_eq!(persister.calls(), 3);
+}
+
+#[test]
+fn crash_loses_unflushed_writes_until_restart() {
+    let actor_id = ActorId::random();
+    let mut persister = FaultyPersister::new(MemoryPersister::default()).fault_at(4, Fault::Crash);
+    insert(&mut persister, &actor_id, 1);
+    persister.flush().unwrap();
+    insert(&mut persister, &actor_id, 2);
+    assert!(matches!(
+        persister.set_document(vec![1, 2, 3]),
+        Err(FaultyPersisterError::Crash {
+            call: 4,
+            operation: "set_document"
+        })
+    ));
+    assert!(persister.is_crashed());
+    assert!(matches!(
+        persister.get_changes(),
+        Err(FaultyPersisterError::Crashed)
+    ));
+    assert_eq!(persister.surviving().get_changes().unwrap(), vec![vec![1]]);
+
+    persister.restart();
+    assert!(!persister.is_crashed());
+    assert_eq!(persister.get_changes().unwrap(), vec![vec![1]]);
+    assert_eq!(persister.get_document().unwrap(), None);
+
+    // writes after the restart are made durable by the next flush
+    insert(&mut persister, &actor_id, 3);
+    persister.flush().unwrap();
+    let mut changes = persister.surviving().get_changes().unwrap();
+    changes.sort();
+    assert_eq!(changes, vec![vec![1], vec![3]]);
+}
+
+#[test]
+fn random_faults_are_reproducible() {
+    let failures = |seed| {
+        let actor_id = ActorId::random();
+        let mut persister = FaultyPersister::new(MemoryPersister::default()).fault_randomly(
+            0.5,
+            Fault::Error,
+            seed,
+        );
+        (0..32)
+            .filter(|seq| {
+                persister
+                    .insert_changes(vec![(&actor_id, *seq, &[][..])])
+                    .is_err()
+            })
+            .collect::<Vec<_>>()
+    };
+    let first = failures(7);
+    assert!(!first.is_empty() && first.len() < 32);
+    assert_eq!(failures(7), first);
+
+    let mut persister = FaultyPersister::new(MemoryPersister::default())
+        .fault_randomly(1.0, Fault::Error, 7)
+        .fault_at(1, Fault::Error);
+    persister.clear_faults();
+    insert(&mut persister, &ActorId::random(), 1);
+}
+
+#[test]
+fn reload_backend_continues_from_last_flush() {
+    let persister = FaultyPersister::new(MemoryPersister::default()).fault_at(5, Fault::Crash);
+    let mut backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
+    let mut frontend = Frontend::new();
+    backend
+        .apply_local_change(local_change(&mut frontend, "a"))
+        .unwrap();
+    backend.flush().unwrap();
+    let result = backend.apply_local_change(local_change(&mut frontend, "b"));
+    assert!(matches!(
+        result,
+        Err(Error::PersisterError(FaultyPersisterError::Crash { .. }))
+    ));
+
+    let reloaded = backend
+        .persister()
+        .reload_backend::<automerge::Backend>()
+        .unwrap();
+    assert_eq!(reloaded.get_changes(&[]).len(), 1);
+    assert_eq!(reloaded.persister().calls(), 2);
+    assert!(!reloaded.persister().is_crashed());
+}
+
+#[test]
+fn sync_until_converged_removes_its_sync_states() {
+    let load = || {
+        PersistentBackend::<_, automerge::Backend>::load(FaultyPersister::new(
+            MemoryPersister::default(),
+        ))
+        .unwrap()
+    };
+    let mut left = load();
+    let mut right = load();
+    left.apply_local_change(local_change(&mut Frontend::new(), "a"))
+        .unwrap();
+    right
+        .apply_local_change(local_change(&mut Frontend::new(), "b"))
+        .unwrap();
+
+    assert!(sync_until_converged(&mut left, &mut right, 10).unwrap());
+    assert_eq!(left.get_heads().len(), 2);
+    assert!(left.persister().get_peer_ids().unwrap().is_empty());
+    assert!(right.persister().get_peer_ids().unwrap().is_empty());
+
+    // syncing again starts from scratch and picks up the new change
+    left.apply_local_change(local_change(&mut Frontend::new(), "c"))
+        .unwrap();
+    assert!(sync_until_converged(&mut left, &mut right, 10).unwrap());
+    assert_eq!(right.get_heads(), left.get_heads());
+}
+
+#[test]
+fn failed_flush_keeps_the_writes_for_the_next() {
+    let store = FailingStore::default();
+    let failures = store.failures.clone();
+    let actor_id = ActorId::random();
+    let mut persister = FaultyPersister::new(KvPersister::new(store, "doc").unwrap());
+    persister
+        .insert_changes(vec![(&actor_id, 1, &[1][..])])
+        .unwrap();
+    persister.set_document(vec![1, 2, 3]).unwrap();
+
+    // only the replay onto the durable copy writes now
+    failures.set(1);
+    assert!(matches!(
+        persister.flush(),
+        Err(FaultyPersisterError::PersisterError(
+            KvPersisterError::StoreError(_)
+        ))
+    ));
+    assert!(persister.surviving().get_changes().unwrap().is_empty());
+
+    persister.flush().unwrap();
+    assert_eq!(persister.surviving().get_changes().unwrap(), vec![vec![1]]);
+    assert_eq!(
+        persister.surviving().get_document().unwrap(),
+        Some(vec![1, 2, 3])
+    );
+}
+
+#[test]
+fn sync_until_converged_returns_the_sync_error() {
+    let load = |persister| PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
+    let mut right = load(FaultyPersister::new(MemoryPersister::default()));
+    right
+        .apply_local_change(local_change(&mut Frontend::new(), "a"))
+        .unwrap();
+    // crash on the first call made while syncing
+    let calls = load(FaultyPersister::new(MemoryPersister::default()))
+        .persister()
+        .calls();
+    let mut left =
+        load(FaultyPersister::new(MemoryPersister::default()).fault_at(calls + 1, Fault::Crash));
+
+    // removing the sync states afterwards fails too, as the persister has crashed
+    assert!(matches!(
+        sync_until_converged(&mut left, &mut right, 10),
+        Err(Error::PersisterError(FaultyPersisterError::Crash { .. }))
+    ));
+    assert!(left.persister().is_crashed());
+}
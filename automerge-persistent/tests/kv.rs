@@ -0,0 +1,101 @@
+use std::{collections::BTreeMap, convert::Infallible};
+
+use automerge_persistent::{KvIter, KvOp, KvPersister, KvPersisterError, KvStore, Persister};
+use automerge_protocol::ActorId;
+
+/// A `BTreeMap` store that records the batches applied to it and only allows writes through them.
+#[derive(Debug, Default)]
+struct BatchStore {
+    map: BTreeMap<Vec<u8>, Vec<u8>>,
+    batches: Vec<Vec<KvOp>>,
+}
+
+impl KvStore for BatchStore {
+    type Error = Infallible;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        KvStore::get(&self.map, key)
+    }
+
+    fn put(&mut self, _key: Vec<u8>, _value: Vec<u8>) -> Result<(), Self::Error> {
+        panic!("changes should be written in a batch")
+    }
+
+    fn delete(&mut self, _key: &[u8]) -> Result<(), Self::Error> {
+        panic!("changes should be removed in a batch")
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<KvIter<'_, Self::Error>, Self::Error> {
+        self.map.scan_prefix(prefix)
+    }
+
+    fn batch(&mut self, batch: Vec<KvOp>) -> Result<(), Self::Error> {
+        self.batches.push(batch.clone());
+        self.map.batch(batch)
+    }
+}
+
+#[test]
+fn changes_are_inserted_in_one_batch() {
+    let mut persister = KvPersister::new(BatchStore::default(), "doc").unwrap();
+    let actor_id = ActorId::random();
+    persister
+        .insert_changes(vec![
+            (&actor_id, 1, &[1; 4][..]),
+            (&actor_id, 2, &[2; 8][..]),
+        ])
+        .unwrap();
+    assert_eq!(persister.store().batches.len(), 1);
+    assert_eq!(persister.store().batches[0].len(), 2);
+    assert_eq!(persister.sizes().changes, 12);
+
+    // replacing a change and repeating one in the same call only counts what ends up stored
+    persister
+        .insert_changes(vec![
+            (&actor_id, 1, &[1; 2][..]),
+            (&actor_id, 1, &[1; 6][..]),
+        ])
+        .unwrap();
+    assert_eq!(persister.store().batches.len(), 2);
+    assert_eq!(persister.sizes().changes, 14);
+    assert_eq!(
+        KvPersister::new(persister.into_store(), "doc")
+            .unwrap()
+            .sizes()
+            .changes,
+        14
+    );
+}
+
+#[test]
+fn malformed_change_key_is_an_error() {
+    let mut persister = KvPersister::new(BTreeMap::new(), "doc").unwrap();
+    let actor_id = ActorId::random();
+    persister
+        .insert_changes(vec![(&actor_id, 1, &[1; 4][..])])
+        .unwrap();
+    let mut store = persister.into_store();
+
+    // a change key whose actor id length runs past the end of the key
+    let mut key = 3_u32.to_be_bytes().to_vec();
+    key.extend(b"doc");
+    key.push(b'c');
+    key.extend(&100_u32.to_be_bytes());
+    key.push(1);
+    store.insert(key.clone(), vec![2; 4]);
+
+    let persister = KvPersister::new(store, "doc").unwrap();
+    assert!(matches!(
+        persister.get_changes_with_keys(),
+        Err(KvPersisterError::MalformedChangeKey(k)) if k == key
+    ));
+
+    // the change before it is read first when iterating
+    let mut changes = persister.iter_changes_with_keys().unwrap().unwrap();
+    assert_eq!(changes.next().unwrap().unwrap(), (actor_id, 1, vec![1; 4]));
+    assert!(matches!(
+        changes.next(),
+        Some(Err(KvPersisterError::MalformedChangeKey(k))) if k == key
+    ));
+    assert_eq!(persister.iter_changes().unwrap().count(), 2);
+}
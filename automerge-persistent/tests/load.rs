@@ -0,0 +1,281 @@
+use automerge::{Frontend, InvalidChangeRequest, LocalChange, Path, Primitive, Value};
+use automerge_persistent::{
+    AsyncPersistentAutomerge, ChangeIter, ChecksumPersister, KeyedChangeIter, LoadOptions,
+    MemoryPersister, PersistentBackend, Persister, StoredSizes,
+};
+use automerge_protocol::ActorId;
+
+fn local_change(frontend: &mut Frontend, key: &str) -> automerge_protocol::Change {
+    let ((), change) = frontend
+        .change::<_, _, InvalidChangeRequest>(None, |doc| {
+            doc.add_change(LocalChange::set(
+                Path::root().key(key),
+                Value::Primitive(Primitive::Str(key.into())),
+            ))
+        })
+        .unwrap();
+    change.unwrap()
+}
+
+/// A persister holding a checksummed document and a change made after it was compacted.
+fn compacted() -> MemoryPersister {
+    let mut backend = PersistentBackend::<_, automerge::Backend>::load(ChecksumPersister::new(
+        MemoryPersister::default(),
+    ))
+    .unwrap();
+    let mut frontend = Frontend::new();
+    for key in &["a", "b"] {
+        let patch = backend
+            .apply_local_change(local_change(&mut frontend, key))
+            .unwrap();
+        frontend.apply_patch(patch).unwrap();
+    }
+    backend.compact(&[]).unwrap();
+    let patch = backend
+        .apply_local_change(local_change(&mut frontend, "c"))
+        .unwrap();
+    frontend.apply_patch(patch).unwrap();
+    backend.persister().persister().clone()
+}
+
+/// Flip a bit in the last byte of the stored document, so the checksum no longer matches.
+fn damage_document(mut persister: MemoryPersister) -> MemoryPersister {
+    let mut document = persister.get_document().unwrap().unwrap();
+    let last = document.len() - 1;
+    document[last] ^= 1;
+    persister.set_document(document).unwrap();
+    persister
+}
+
+/// A persister holding one good change and one that can't be decoded.
+fn with_bad_change() -> (MemoryPersister, ActorId) {
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(MemoryPersister::default()).unwrap();
+    backend
+        .apply_local_change(local_change(&mut Frontend::new(), "a"))
+        .unwrap();
+    let mut persister = backend.persister().clone();
+    let actor_id = ActorId::random();
+    persister
+        .insert_changes(vec![(&actor_id, 1, &[1, 2, 3][..])])
+        .unwrap();
+    (persister, actor_id)
+}
+
+#[test]
+fn lenient_load_discards_unreadable_document() {
+    let persister = ChecksumPersister::new(damage_document(compacted()));
+    assert!(PersistentBackend::<_, automerge::Backend>::load(persister).is_err());
+
+    let persister = ChecksumPersister::new(damage_document(compacted()));
+    let (backend, report) = PersistentBackend::<_, automerge::Backend>::load_with_options(
+        persister,
+        &LoadOptions::default().lenient(),
+    )
+    .unwrap();
+    let discarded = report.discarded_document.unwrap();
+    assert_eq!(discarded.data, None);
+    assert!(!discarded.error.is_empty());
+    assert!(report.discarded_changes.is_empty());
+    // the change made after compacting is kept, waiting for the rest to be resynced
+    assert_eq!(backend.persister().get_changes().unwrap().len(), 1);
+}
+
+#[test]
+fn async_lenient_load_discards_unreadable_document() {
+    let persister = ChecksumPersister::new(damage_document(compacted()));
+    let (_, report) = futures::executor::block_on(AsyncPersistentAutomerge::load_with_options(
+        persister,
+        &LoadOptions::default().lenient(),
+    ))
+    .unwrap();
+    assert_eq!(report.discarded_document.unwrap().data, None);
+}
+
+#[test]
+fn lenient_load_reports_document_that_fails_to_load() {
+    let mut persister = MemoryPersister::default();
+    persister.set_document(vec![1, 2, 3]).unwrap();
+    let (_, report) = PersistentBackend::<_, automerge::Backend>::load_with_options(
+        persister.clone(),
+        &LoadOptions::default().lenient(),
+    )
+    .unwrap();
+    assert_eq!(report.discarded_document.unwrap().data, Some(vec![1, 2, 3]));
+
+    let (_, report) = futures::executor::block_on(AsyncPersistentAutomerge::load_with_options(
+        persister,
+        &LoadOptions::default().lenient(),
+    ))
+    .unwrap();
+    assert_eq!(report.discarded_document.unwrap().data, Some(vec![1, 2, 3]));
+}
+
+#[test]
+fn lenient_load_removes_bad_changes() {
+    let (persister, actor_id) = with_bad_change();
+    let (backend, report) = PersistentBackend::<_, automerge::Backend>::load_with_options(
+        persister,
+        &LoadOptions::default().lenient(),
+    )
+    .unwrap();
+    let discarded = &report.discarded_changes[0];
+    assert_eq!(report.discarded_changes.len(), 1);
+    assert_eq!(discarded.key, Some((actor_id, 1)));
+    assert_eq!(discarded.data, vec![1, 2, 3]);
+    assert!(discarded.removed);
+    assert!(!discarded.quarantined);
+    assert_eq!(backend.persister().get_changes().unwrap().len(), 1);
+    assert!(backend
+        .persister()
+        .get_quarantined_changes()
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn async_lenient_load_reports_bad_changes() {
+    let (persister, actor_id) = with_bad_change();
+    let (_, report) = futures::executor::block_on(AsyncPersistentAutomerge::load_with_options(
+        persister,
+        &LoadOptions::default().lenient().batch_size(1),
+    ))
+    .unwrap();
+    assert_eq!(report.discarded_changes.len(), 1);
+    assert_eq!(report.discarded_changes[0].key, Some((actor_id, 1)));
+    assert_eq!(report.discarded_changes[0].data, vec![1, 2, 3]);
+}
+
+#[test]
+fn quarantined_changes_are_not_loaded_again() {
+    let (persister, actor_id) = with_bad_change();
+    let (backend, report) = PersistentBackend::<_, automerge::Backend>::load_with_options(
+        persister,
+        &LoadOptions::default().lenient().quarantine(),
+    )
+    .unwrap();
+    let discarded = &report.discarded_changes[0];
+    assert!(discarded.quarantined);
+    assert!(!discarded.removed);
+    let persister = backend.persister().clone();
+    assert_eq!(
+        persister.get_quarantined_changes().unwrap(),
+        vec![(actor_id, 1, vec![1, 2, 3])]
+    );
+
+    // a strict load no longer sees the bad change
+    let backend = PersistentBackend::<_, automerge::Backend>::load(persister).unwrap();
+    assert_eq!(backend.get_changes(&[]).len(), 1);
+}
+
+/// Wraps a memory persister, only passing on quarantining if asked to and failing if the changes
+/// are read up front.
+#[derive(Debug)]
+struct Streaming {
+    inner: MemoryPersister,
+    quarantine: bool,
+}
+
+impl Persister for Streaming {
+    type Error = std::convert::Infallible;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        panic!("changes should be iterated")
+    }
+
+    fn iter_changes(&self) -> Result<ChangeIter<'_, Self::Error>, Self::Error> {
+        self.inner.iter_changes()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn get_changes_with_keys(&self) -> Result<Option<Vec<(ActorId, u64, Vec<u8>)>>, Self::Error> {
+        panic!("changes should be iterated")
+    }
+
+    fn iter_changes_with_keys(
+        &self,
+    ) -> Result<Option<KeyedChangeIter<'_, Self::Error>>, Self::Error> {
+        self.inner.iter_changes_with_keys()
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error> {
+        self.inner.insert_changes(changes)
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.inner.remove_changes(changes)
+    }
+
+    fn quarantine_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<bool, Self::Error> {
+        if self.quarantine {
+            self.inner.quarantine_changes(changes)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner.get_document()
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.inner.set_document(data)
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner.get_sync_state(peer_id)
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.inner.set_sync_state(peer_id, sync_state)
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.inner.remove_sync_states(peer_ids)
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.inner.get_peer_ids()
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.inner.sizes()
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn lenient_load_streams_changes() {
+    let (inner, _) = with_bad_change();
+    let (backend, report) = PersistentBackend::<_, automerge::Backend>::load_with_options(
+        Streaming {
+            inner,
+            quarantine: true,
+        },
+        &LoadOptions::default().lenient().quarantine().batch_size(1),
+    )
+    .unwrap();
+    assert!(report.discarded_changes[0].quarantined);
+    assert_eq!(report.discarded_changes[0].data, vec![1, 2, 3]);
+    assert_eq!(backend.get_changes(&[]).len(), 1);
+}
+
+#[test]
+fn quarantine_leaves_changes_if_unsupported() {
+    let (inner, _) = with_bad_change();
+    let (backend, report) = PersistentBackend::<_, automerge::Backend>::load_with_options(
+        Streaming {
+            inner,
+            quarantine: false,
+        },
+        &LoadOptions::default().lenient().quarantine(),
+    )
+    .unwrap();
+    let discarded = &report.discarded_changes[0];
+    assert!(!discarded.quarantined);
+    assert!(!discarded.removed);
+    assert_eq!(backend.persister().inner.get_changes().unwrap().len(), 2);
+}
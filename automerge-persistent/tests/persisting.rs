@@ -0,0 +1,351 @@
+use automerge::{Change, Frontend, InvalidChangeRequest, LocalChange, Path, Primitive, Value};
+use automerge_persistent::{
+    Error, MemoryPersister, PersistentAutomerge, PersistentBackend, Persister,
+};
+use automerge_persistent::{PersistentAutomergeError, StoredSizes, ThresholdPolicy};
+use automerge_protocol::ActorId;
+
+#[derive(Debug, thiserror::Error)]
+#[error("insert failed")]
+struct InsertFailed;
+
+/// A memory persister that records the changes inserted and whose inserts and actor id updates, or
+/// removals, can be made to fail.
+#[derive(Debug, Default)]
+struct TestPersister {
+    persister: MemoryPersister,
+    fail: bool,
+    fail_removes: bool,
+    inserted: Vec<(ActorId, u64)>,
+}
+
+impl Persister for TestPersister {
+    type Error = InsertFailed;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.persister.get_changes().unwrap())
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(&ActorId, u64, &[u8])>) -> Result<(), Self::Error> {
+        if self.fail {
+            return Err(InsertFailed);
+        }
+        self.inserted
+            .extend(changes.iter().map(|(a, s, _)| ((*a).clone(), *s)));
+        self.persister.insert_changes(changes).unwrap();
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        if self.fail_removes {
+            return Err(InsertFailed);
+        }
+        self.persister.remove_changes(changes).unwrap();
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.persister.get_document().unwrap())
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.persister.set_document(data).unwrap();
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.persister.get_sync_state(peer_id).unwrap())
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.persister.set_sync_state(peer_id, sync_state).unwrap();
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.persister.remove_sync_states(peer_ids).unwrap();
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.persister.get_peer_ids().unwrap())
+    }
+
+    fn get_actor_id(&self) -> Result<Option<ActorId>, Self::Error> {
+        Ok(self.persister.get_actor_id().unwrap())
+    }
+
+    fn set_actor_id(&mut self, actor_id: ActorId) -> Result<(), Self::Error> {
+        if self.fail {
+            return Err(InsertFailed);
+        }
+        self.persister.set_actor_id(actor_id).unwrap();
+        Ok(())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.persister.sizes()
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
+
+fn set(doc: &mut dyn automerge::MutableDocument, key: &str) -> Result<(), InvalidChangeRequest> {
+    doc.add_change(LocalChange::set(
+        Path::root().key(key),
+        Value::Primitive(Primitive::Str(key.into())),
+    ))
+}
+
+fn local_change(frontend: &mut Frontend, key: &str) -> automerge_protocol::Change {
+    let ((), change) = frontend.change(None, |doc| set(doc, key)).unwrap();
+    change.unwrap()
+}
+
+/// Two changes from another actor, the second depending on the first.
+fn remote_changes() -> (Change, Change) {
+    let mut frontend = Frontend::new();
+    let mut backend = automerge::Backend::default();
+    let (patch, first) = backend
+        .apply_local_change(local_change(&mut frontend, "first"))
+        .unwrap();
+    let first = first.clone();
+    frontend.apply_patch(patch).unwrap();
+    let (_, second) = backend
+        .apply_local_change(local_change(&mut frontend, "second"))
+        .unwrap();
+    (first, second.clone())
+}
+
+/// A change from `actor_id` with the same sequence number as the first one it made locally.
+fn conflicting_change(actor_id: &ActorId) -> Change {
+    let mut frontend = Frontend::new_with_actor_id(&actor_id.to_bytes());
+    let mut backend = automerge::Backend::default();
+    let (_, change) = backend
+        .apply_local_change(local_change(&mut frontend, "conflicting"))
+        .unwrap();
+    change.clone()
+}
+
+#[test]
+fn backend_rollback_keeps_queued_changes() {
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(TestPersister::default()).unwrap();
+    let (first, second) = remote_changes();
+    backend.apply_changes(vec![second.clone()]).unwrap();
+    assert_eq!(backend.get_missing_deps(&[]), vec![first.hash]);
+
+    let heads = backend.get_heads();
+    backend.persister_mut().fail = true;
+    assert!(backend
+        .apply_local_change(local_change(&mut Frontend::new(), "local"))
+        .is_err());
+    assert_eq!(backend.get_heads(), heads);
+    assert_eq!(backend.get_missing_deps(&[]), vec![first.hash]);
+
+    backend.persister_mut().fail = false;
+    backend.apply_changes(vec![first]).unwrap();
+    assert_eq!(backend.get_heads(), vec![second.hash]);
+}
+
+#[test]
+fn backend_received_changes_are_not_applied_if_not_persisted() {
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(TestPersister::default()).unwrap();
+    let (first, _) = remote_changes();
+
+    backend.persister_mut().fail = true;
+    assert!(backend.apply_changes(vec![first.clone()]).is_err());
+    assert!(backend.get_heads().is_empty());
+
+    backend.persister_mut().fail = false;
+    backend.apply_changes(vec![first.clone()]).unwrap();
+    assert_eq!(backend.get_heads(), vec![first.hash]);
+}
+
+#[test]
+fn document_rollback_keeps_actor_and_queued_changes() {
+    let actor_id = ActorId::random();
+    let mut document = PersistentAutomerge::load_with_frontend(
+        TestPersister::default(),
+        Frontend::new_with_actor_id(&actor_id.to_bytes()),
+    )
+    .unwrap();
+    let (first, second) = remote_changes();
+    document.apply_changes(vec![second.clone()]).unwrap();
+    document.change(None, |doc| set(doc, "a")).unwrap();
+
+    let heads = document.get_heads();
+    document.persister_mut().fail = true;
+    let result = document.change(None, |doc| set(doc, "b"));
+    assert!(matches!(
+        result,
+        Err(PersistentAutomergeError::PersisterError(InsertFailed))
+    ));
+    assert_eq!(document.get_heads(), heads);
+    assert_eq!(document.get_value(&Path::root().key("b")), None);
+
+    document.persister_mut().fail = false;
+    document.change(None, |doc| set(doc, "b")).unwrap();
+    let change = document.get_changes(&heads)[0];
+    assert_eq!(change.actor_id(), &actor_id);
+    assert_eq!(change.seq, 2);
+
+    document.apply_changes(vec![first]).unwrap();
+    assert!(document.get_heads().contains(&second.hash));
+}
+
+#[test]
+fn backend_persists_received_changes_once() {
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(TestPersister::default()).unwrap();
+    let (first, second) = remote_changes();
+    backend
+        .apply_changes(vec![second.clone(), second.clone()])
+        .unwrap();
+    backend.apply_changes(vec![second.clone()]).unwrap();
+    backend
+        .apply_changes(vec![first.clone(), second.clone()])
+        .unwrap();
+    backend.apply_changes(vec![first.clone()]).unwrap();
+
+    assert_eq!(
+        backend.persister().inserted,
+        vec![
+            (second.actor_id().clone(), second.seq),
+            (first.actor_id().clone(), first.seq)
+        ]
+    );
+    assert_eq!(
+        backend.persister().sizes().changes,
+        first.raw_bytes().len() + second.raw_bytes().len()
+    );
+}
+
+#[test]
+fn backend_does_not_store_compacted_changes_again() {
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(TestPersister::default()).unwrap();
+    let (first, _) = remote_changes();
+    backend.apply_changes(vec![first.clone()]).unwrap();
+    backend.compact(&[]).unwrap();
+
+    backend.apply_changes(vec![first]).unwrap();
+    assert_eq!(backend.persister().inserted.len(), 1);
+    assert!(backend.persister().get_changes().unwrap().is_empty());
+    assert_eq!(backend.persister().sizes().changes, 0);
+}
+
+#[test]
+fn backend_counts_only_new_changes_towards_compaction() {
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(TestPersister::default()).unwrap();
+    backend.set_compaction_policy(ThresholdPolicy::new().max_changes(2));
+    let (first, second) = remote_changes();
+    backend.apply_changes(vec![first.clone()]).unwrap();
+    backend.apply_changes(vec![first]).unwrap();
+    assert_eq!(backend.persister().get_document().unwrap(), None);
+
+    backend.apply_changes(vec![second]).unwrap();
+    assert!(backend.persister().get_document().unwrap().is_some());
+}
+
+#[test]
+fn document_persists_received_changes_once() {
+    let mut document = PersistentAutomerge::load(TestPersister::default()).unwrap();
+    let (first, second) = remote_changes();
+    document
+        .apply_changes(vec![second.clone(), second.clone()])
+        .unwrap();
+    document
+        .apply_changes(vec![first.clone(), second.clone()])
+        .unwrap();
+    document.compact(&[]).unwrap();
+    document
+        .apply_changes(vec![first.clone(), second.clone()])
+        .unwrap();
+
+    assert_eq!(
+        document.persister().inserted,
+        vec![
+            (second.actor_id().clone(), second.seq),
+            (first.actor_id().clone(), first.seq)
+        ]
+    );
+    assert!(document.persister().get_changes().unwrap().is_empty());
+}
+
+#[test]
+fn document_rotates_and_persists_actor_id() {
+    let mut document = PersistentAutomerge::load(TestPersister::default()).unwrap();
+    let old = document.actor_id().unwrap().clone();
+    document.change(None, |doc| set(doc, "a")).unwrap();
+    let heads = document.get_heads();
+
+    let new = document.rotate_actor_id().unwrap();
+    assert_ne!(new, old);
+    assert_eq!(document.actor_id(), Some(&new));
+    assert_eq!(
+        document.persister().get_actor_id().unwrap(),
+        Some(new.clone())
+    );
+    assert_eq!(document.get_changes(&[])[0].actor_id(), &old);
+
+    document.change(None, |doc| set(doc, "b")).unwrap();
+    assert_eq!(document.get_changes(&heads)[0].actor_id(), &new);
+
+    let document = PersistentAutomerge::load(std::mem::take(document.persister_mut())).unwrap();
+    assert_eq!(document.actor_id(), Some(&new));
+}
+
+#[test]
+fn document_keeps_actor_id_if_rotation_is_not_persisted() {
+    let mut document = PersistentAutomerge::load(TestPersister::default()).unwrap();
+    let old = document.actor_id().unwrap().clone();
+
+    document.persister_mut().fail = true;
+    let result = document.rotate_actor_id();
+    assert!(matches!(
+        result,
+        Err(PersistentAutomergeError::PersisterError(InsertFailed))
+    ));
+    assert_eq!(document.actor_id(), Some(&old));
+
+    document.persister_mut().fail = false;
+    document.change(None, |doc| set(doc, "a")).unwrap();
+    assert_eq!(document.get_changes(&[])[0].actor_id(), &old);
+    assert_eq!(document.persister().get_actor_id().unwrap(), Some(old));
+}
+
+#[test]
+fn backend_returns_the_apply_error_if_received_changes_cannot_be_removed() {
+    let actor_id = ActorId::random();
+    let mut backend =
+        PersistentBackend::<_, automerge::Backend>::load(TestPersister::default()).unwrap();
+    backend
+        .apply_local_change(local_change(
+            &mut Frontend::new_with_actor_id(&actor_id.to_bytes()),
+            "local",
+        ))
+        .unwrap();
+
+    backend.persister_mut().fail_removes = true;
+    let result = backend.apply_changes(vec![conflicting_change(&actor_id)]);
+    assert!(matches!(result, Err(Error::BackendError(_))));
+}
+
+#[test]
+fn document_returns_the_apply_error_if_received_changes_cannot_be_removed() {
+    let mut document = PersistentAutomerge::load(TestPersister::default()).unwrap();
+    document.change(None, |doc| set(doc, "local")).unwrap();
+    let actor_id = document.actor_id().unwrap().clone();
+
+    document.persister_mut().fail_removes = true;
+    let result = document.apply_changes(vec![conflicting_change(&actor_id)]);
+    assert!(matches!(
+        result,
+        Err(PersistentAutomergeError::AutomergeError(_))
+    ));
+}